@@ -0,0 +1,239 @@
+use rusqlite::functions::{Aggregate, Context, FunctionFlags};
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, Error, Result};
+
+use super::cidr::Cidr;
+use super::timestamp::{self, Timestamp, Zone};
+
+/// The shorthand percentile functions registered along with the general `percentile(x, p)`.
+const SHORTHAND_PERCENTILES: &[(&str, f64)] =
+    &[("p50", 50.0), ("p90", 90.0), ("p95", 95.0), ("p99", 99.0)];
+
+/// Register our custom functions on the connection.
+pub(crate) fn register(conn: &Connection) -> Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+
+    conn.create_aggregate_function("percentile", 2, flags, Percentile(None))?;
+    for &(name, p) in SHORTHAND_PERCENTILES {
+        conn.create_aggregate_function(name, 1, flags, Percentile(Some(p)))?;
+    }
+    conn.create_aggregate_function("median", 1, flags, Percentile(Some(50.0)))?;
+    conn.create_aggregate_function("stddev", 1, flags, StdDev)?;
+    register_time_bucket(conn, Zone::Logged)?;
+    conn.create_scalar_function("unix_time", 1, flags, unix_time)?;
+    conn.create_scalar_function("is_address", 1, flags, is_address)?;
+
+    Ok(())
+}
+
+/// Register time_bucket so that it truncates times in the given timezone, replacing the one there.
+pub(crate) fn register_time_bucket(conn: &Connection, zone: Zone) -> Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    conn.create_scalar_function("time_bucket", 2, flags, move |ctx| time_bucket(ctx, zone))
+}
+
+// Values are stored as text unless we know better, so accept anything that looks like a number.
+// Everything else, including NULL, is skipped just like the built in aggregates do.
+fn numeric(value: ValueRef) -> Option<f64> {
+    match value {
+        ValueRef::Integer(i) => Some(i as f64),
+        ValueRef::Real(r) => Some(r),
+        ValueRef::Text(t) => std::str::from_utf8(t)
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+            .filter(|v: &f64| v.is_finite()),
+        _ => None,
+    }
+}
+
+/// Compute the given percentile (0 to 100) of the sorted values, interpolating linearly between
+/// the closest ranks.
+fn percentile_of_sorted(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+
+    let rank = (p.clamp(0.0, 100.0) / 100.0) * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    let weight = rank - lower as f64;
+
+    Some(sorted[lower] + (sorted[upper] - sorted[lower]) * weight)
+}
+
+// time_bucket(time, seconds) truncates a logged time to the start of its bucket, in the timezone it
+// was logged in unless another one was chosen. Times that cannot be parsed give NULL.
+fn time_bucket(ctx: &Context<'_>, zone: Zone) -> Result<Option<String>> {
+    let seconds = match numeric(ctx.get_raw(1)) {
+        Some(s) if s >= 1.0 => s as i64,
+        _ => {
+            return Err(Error::UserFunctionError(
+                "the bucket size must be at least one second".into(),
+            ))
+        }
+    };
+    Ok(logged_time(ctx.get_raw(0)).map(|t| {
+        let local = t.in_zone(zone);
+        timestamp::format_local(local - local.rem_euclid(seconds))
+    }))
+}
+
+// unix_time(time) gives the seconds since the epoch of a logged time, or NULL when it cannot be
+// parsed.
+fn unix_time(ctx: &Context<'_>) -> Result<Option<i64>> {
+    Ok(logged_time(ctx.get_raw(0)).map(|t| t.epoch))
+}
+
+// is_address(value) tells whether the value is an IP address or a CIDR block, unlike the "-" or
+// "unix:" logged for requests that did not come over IP.
+fn is_address(ctx: &Context<'_>) -> Result<bool> {
+    Ok(match ctx.get_raw(0) {
+        ValueRef::Text(t) => std::str::from_utf8(t).is_ok_and(|s| s.parse::<Cidr>().is_ok()),
+        _ => false,
+    })
+}
+
+// A time in any of the formats NGINX logs it in, or a number of seconds since the epoch as $msec
+// is stored.
+fn logged_time(value: ValueRef) -> Option<Timestamp> {
+    match value {
+        ValueRef::Text(t) => std::str::from_utf8(t).ok().and_then(timestamp::parse_any),
+        ValueRef::Integer(i) => timestamp::from_epoch(i as f64),
+        ValueRef::Real(r) => timestamp::from_epoch(r),
+        _ => None,
+    }
+}
+
+// The percentile is either fixed when registered or given as the second argument.
+struct Percentile(Option<f64>);
+
+impl Aggregate<(Vec<f64>, f64), Option<f64>> for Percentile {
+    fn init(&self) -> (Vec<f64>, f64) {
+        (vec![], self.0.unwrap_or(0.0))
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, state: &mut (Vec<f64>, f64)) -> Result<()> {
+        if self.0.is_none() {
+            state.1 = match numeric(ctx.get_raw(1)) {
+                Some(p) if (0.0..=100.0).contains(&p) => p,
+                _ => {
+                    return Err(Error::UserFunctionError(
+                        "the percentile must be between 0 and 100".into(),
+                    ))
+                }
+            };
+        }
+
+        if let Some(v) = numeric(ctx.get_raw(0)) {
+            state.0.push(v);
+        }
+
+        Ok(())
+    }
+
+    fn finalize(&self, state: Option<(Vec<f64>, f64)>) -> Result<Option<f64>> {
+        Ok(state.and_then(|(mut values, p)| {
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            percentile_of_sorted(&values, p)
+        }))
+    }
+}
+
+// The sample standard deviation, computed in a single pass with Welford's algorithm. It is NULL for
+// fewer than two values.
+struct StdDev;
+
+impl Aggregate<(u64, f64, f64), Option<f64>> for StdDev {
+    fn init(&self) -> (u64, f64, f64) {
+        (0, 0.0, 0.0)
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, state: &mut (u64, f64, f64)) -> Result<()> {
+        if let Some(v) = numeric(ctx.get_raw(0)) {
+            let (count, mean, m2) = state;
+            *count += 1;
+            let delta = v - *mean;
+            *mean += delta / *count as f64;
+            *m2 += delta * (v - *mean);
+        }
+        Ok(())
+    }
+
+    fn finalize(&self, state: Option<(u64, f64, f64)>) -> Result<Option<f64>> {
+        Ok(state
+            .filter(|&(count, _, _)| count > 1)
+            .map(|(count, _, m2)| (m2 / (count - 1) as f64).sqrt()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        let conn = Connection::open_in_memory().unwrap();
+        register(&conn).unwrap();
+        let valid: Vec<bool> = [
+            "'10.0.0.1'",
+            "'2001:db8::1'",
+            "'10.0.0.0/24'",
+            "'-'",
+            "'unix:'",
+            "NULL",
+        ]
+        .iter()
+        .map(|v| {
+            conn.query_row(
+                &format!("SELECT is_address({})", v),
+                rusqlite::params![],
+                |r| r.get(0),
+            )
+            .unwrap()
+        })
+        .collect();
+        assert_eq!(valid, [true, true, true, false, false, false]);
+    }
+
+    #[test]
+    fn percentiles() {
+        let conn = Connection::open_in_memory().unwrap();
+        register(&conn).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t (v);
+            INSERT INTO t VALUES ('1'), (2), (3.0), ('4'), (NULL), ('-'), ('5');",
+        )
+        .unwrap();
+
+        let (p50, p95, p25): (f64, f64, f64) = conn
+            .query_row(
+                "SELECT p50(v), p95(v), percentile(v, 25) FROM t",
+                rusqlite::params![],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(p50, 3.0);
+        assert!((p95 - 4.8).abs() < 1e-9);
+        assert_eq!(p25, 2.0);
+
+        let (median, stddev): (f64, f64) = conn
+            .query_row(
+                "SELECT median(v), stddev(v) FROM t",
+                rusqlite::params![],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(median, 3.0);
+        assert!((stddev - 2.5f64.sqrt()).abs() < 1e-9);
+        let none: Option<f64> = conn
+            .query_row(
+                "SELECT stddev(v) FROM t WHERE v = 2",
+                rusqlite::params![],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(none, None);
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Result};
+
+pub const APACHE_COMMON: &str = "apache-common";
+pub const APACHE_COMBINED: &str = "apache-combined";
+const LOG_FORMAT_COMMON: &str = r#"%h %l %u %t "%r" %>s %b"#;
+const LOG_FORMAT_COMBINED: &str = r#"%h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-agent}i""#;
+
+/// Whether the format is one of the Apache presets or uses Apache's `%` directives rather than
+/// NGINX variables.
+pub fn is_apache_format(format: &str) -> bool {
+    format == APACHE_COMMON
+        || format == APACHE_COMBINED
+        || (format.contains('%') && !format.contains('$'))
+}
+
+/// Translate an Apache LogFormat into the equivalent NGINX log format so that the fields can be
+/// queried with the same names as in NGINX logs.
+pub fn to_nginx_format(format: &str) -> Result<String> {
+    let format = match format {
+        APACHE_COMMON => LOG_FORMAT_COMMON,
+        APACHE_COMBINED => LOG_FORMAT_COMBINED,
+        f => f,
+    };
+
+    let mut nginx = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            nginx.push(c);
+            continue;
+        }
+
+        // Skip the conditions on status codes, such as %!200,304{Referer}i, and the < or > that
+        // chooses between the original and final request.
+        while let Some(&m) = chars.peek() {
+            if m.is_ascii_digit() || m == '!' || m == ',' || m == '<' || m == '>' {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let mut argument = None;
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            let mut arg = String::new();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => arg.push(c),
+                    None => return Err(anyhow!("unterminated %{{ in Apache log format")),
+                }
+            }
+            argument = Some(arg);
+        }
+
+        let directive = chars
+            .next()
+            .ok_or_else(|| anyhow!("Apache log format ends with %"))?;
+        let variable = match (directive, argument) {
+            ('%', None) => {
+                nginx.push('%');
+                continue;
+            }
+            // Apache puts the brackets around the time itself.
+            ('t', None) => {
+                nginx.push_str("[$time_local]");
+                continue;
+            }
+            ('a', None) | ('h', None) => String::from("remote_addr"),
+            ('A', None) => String::from("server_addr"),
+            ('b', None) | ('B', None) => String::from("body_bytes_sent"),
+            // Queried as $request_time in seconds too, see parse::request_time.
+            ('D', None) => String::from("request_time_us"),
+            ('H', None) => String::from("server_protocol"),
+            ('I', None) => String::from("request_length"),
+            ('k', None) => String::from("connection_requests"),
+            ('l', None) => String::from("remote_ident"),
+            ('m', None) => String::from("request_method"),
+            ('O', None) => String::from("bytes_sent"),
+            ('p', None) => String::from("server_port"),
+            ('P', None) => String::from("pid"),
+            ('q', None) => String::from("query_string"),
+            ('r', None) => String::from("request"),
+            ('s', None) => String::from("status"),
+            ('T', None) => String::from("request_time"),
+            ('u', None) => String::from("remote_user"),
+            ('U', None) => String::from("uri"),
+            ('v', None) => String::from("server_name"),
+            ('V', None) => String::from("host"),
+            ('i', Some(header)) => format!("http_{}", header_variable(&header)),
+            ('o', Some(header)) => format!("sent_http_{}", header_variable(&header)),
+            ('C', Some(cookie)) => format!("cookie_{}", header_variable(&cookie)),
+            (d, Some(arg)) => {
+                return Err(anyhow!(
+                    "unsupported Apache log format directive %{{{}}}{}",
+                    arg,
+                    d
+                ))
+            }
+            (d, None) => return Err(anyhow!("unsupported Apache log format directive %{}", d)),
+        };
+        nginx.push('$');
+        nginx.push_str(&variable);
+    }
+
+    Ok(nginx)
+}
+
+// NGINX names header variables in lower case with dashes replaced by underscores.
+fn header_variable(name: &str) -> String {
+    name.to_ascii_lowercase().replace('-', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nginx::{ColumnType, LineParser};
+    use rusqlite::types::Value;
+
+    #[test]
+    fn translates_formats() {
+        assert_eq!(
+            to_nginx_format(APACHE_COMBINED).unwrap(),
+            r#"$remote_addr $remote_ident $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#
+        );
+        assert_eq!(
+            to_nginx_format("%v:%p %D %{X-Forwarded-For}i %!200{Cookie}C 100%%").unwrap(),
+            "$server_name:$server_port $request_time_us $http_x_forwarded_for $cookie_cookie 100%"
+        );
+        assert_eq!(
+            ColumnType::of("request_time_us").value("1500"),
+            Value::Integer(1500)
+        );
+        assert!(to_nginx_format("%{%Y}t").is_err());
+        assert!(to_nginx_format("%Z").is_err());
+        assert!(is_apache_format(APACHE_COMMON));
+        assert!(!is_apache_format("$remote_addr 100%"));
+
+        let parser = LineParser::new(APACHE_COMBINED).unwrap();
+        let line = r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326 "http://www.example.com/start.html" "Mozilla/4.08 [en] (Win98; I ;Nav)""#;
+        let c = parser.parse(line).unwrap();
+        assert_eq!(c.get("remote_user"), Some("frank"));
+        assert_eq!(c.get("time_local"), Some("10/Oct/2000:13:55:36 -0700"));
+        assert_eq!(c.get("status"), Some("200"));
+        assert_eq!(
+            c.get("http_user_agent"),
+            Some("Mozilla/4.08 [en] (Win98; I ;Nav)")
+        );
+    }
+}
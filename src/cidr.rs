@@ -0,0 +1,150 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+
+/// A block of IPv4 or IPv6 addresses such as 10.0.0.0/8. A single address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// The block with the given prefix length that the address is in, using the first length for
+    /// IPv4 addresses, including those mapped into IPv6, and the second for IPv6 addresses.
+    pub fn containing(ip: IpAddr, v4_prefix: u8, v6_prefix: u8) -> Cidr {
+        match unmapped(ip) {
+            IpAddr::V4(a) => {
+                let prefix = v4_prefix.min(32);
+                let network = mask(u32::from(a).into(), prefix, 32) as u32;
+                Cidr {
+                    network: IpAddr::V4(network.into()),
+                    prefix,
+                }
+            }
+            IpAddr::V6(a) => {
+                let prefix = v6_prefix.min(128);
+                Cidr {
+                    network: IpAddr::V6(mask(u128::from(a), prefix, 128).into()),
+                    prefix,
+                }
+            }
+        }
+    }
+
+    /// The first address of the block.
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    /// Whether the address is in the block. IPv4 addresses mapped into IPv6, such as
+    /// ::ffff:10.0.0.1, are treated as the IPv4 address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, unmapped(ip)) {
+            (IpAddr::V4(n), IpAddr::V4(a)) => {
+                mask(u32::from(a).into(), self.prefix, 32) == u32::from(n).into()
+            }
+            (IpAddr::V6(n), IpAddr::V6(a)) => {
+                mask(u128::from(a), self.prefix, 128) == u128::from(n)
+            }
+            _ => false,
+        }
+    }
+}
+
+// The IPv4 address for one mapped into IPv6, such as ::ffff:10.0.0.1, or the address as it is.
+fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+// Clear the bits of the address after the prefix.
+fn mask(bits: u128, prefix: u8, width: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        p => bits & (u128::MAX << (width - p)) & (u128::MAX >> (128 - width)),
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let invalid = || anyhow!("invalid IP address or CIDR block {}", s);
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s.trim(), None),
+        };
+        let network: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let width = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= width)
+                .ok_or_else(invalid)?,
+            None => width,
+        };
+
+        // Store the network with the host bits cleared so that 10.1.2.3/8 is 10.0.0.0/8.
+        let network = match network {
+            IpAddr::V4(n) => IpAddr::V4((mask(u32::from(n).into(), prefix, 32) as u32).into()),
+            IpAddr::V6(n) => IpAddr::V6(mask(u128::from(n), prefix, 128).into()),
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn blocks() {
+        let private: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(private.to_string(), "10.0.0.0/8");
+        assert!(private.contains(ip("10.255.0.1")));
+        assert!(private.contains(ip("::ffff:10.0.0.1")));
+        assert!(!private.contains(ip("11.0.0.1")));
+
+        let single: Cidr = "192.168.1.1".parse().unwrap();
+        assert!(single.contains(ip("192.168.1.1")));
+        assert!(!single.contains(ip("192.168.1.2")));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:1::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+
+        assert_eq!(
+            Cidr::containing(ip("192.0.2.77"), 24, 48).to_string(),
+            "192.0.2.0/24"
+        );
+        assert_eq!(
+            Cidr::containing(ip("::ffff:192.0.2.77"), 16, 48).to_string(),
+            "192.0.0.0/16"
+        );
+        assert_eq!(
+            Cidr::containing(ip("2001:db8:1:2::1"), 24, 48).to_string(),
+            "2001:db8:1::/48"
+        );
+
+        for invalid in &["10.0.0.0/33", "10.0.0/8", "::/129", "x"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+}
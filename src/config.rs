@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use structopt::clap::ArgMatches;
+
+use super::options::Options;
+
+// The table holding the named log formats.
+const FORMATS: &str = "formats";
+// The table holding the named queries.
+const QUERIES: &str = "queries";
+
+/// A value in the configuration file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// Defaults for the command line options read from a TOML file. Only the subset of TOML needed for
+/// this is supported: tables, strings, integers, floats, booleans and arrays.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// The values of each table, with the keys before the first table header under "".
+    pub tables: HashMap<String, HashMap<String, Value>>,
+}
+
+impl Config {
+    /// The default location, `$XDG_CONFIG_HOME/topngx/config.toml` or
+    /// `~/.config/topngx/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+            Some(d) => PathBuf::from(d),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(base.join("topngx").join("config.toml"))
+    }
+
+    /// Load the given configuration file, or the default one when it exists.
+    pub fn load(path: Option<&str>) -> Result<Config> {
+        let path = match path {
+            Some(p) => PathBuf::from(p),
+            None => match Config::default_path().filter(|p| p.is_file()) {
+                Some(p) => p,
+                None => return Ok(Config::default()),
+            },
+        };
+        info!("config file: {}", path.display());
+        Config::parse(&path)
+    }
+
+    /// Parse the configuration file at the given path.
+    pub fn parse(path: &Path) -> Result<Config> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+        Config::parse_str(&contents).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    pub fn parse_str(contents: &str) -> Result<Config> {
+        let mut config = Config::default();
+        let mut table = String::new();
+        let mut lines = contents.lines().enumerate();
+
+        while let Some((n, line)) = lines.next() {
+            let mut line = strip_comment(line).trim().to_string();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') || line.starts_with("[[") {
+                    return Err(anyhow!("line {}: invalid table header", n + 1));
+                }
+                table = line[1..line.len() - 1].trim().to_string();
+                config.tables.entry(table.clone()).or_default();
+                continue;
+            }
+
+            // Arrays may span several lines.
+            while line.contains('[') && !array_closed(&line) {
+                match lines.next() {
+                    Some((_, next)) => {
+                        line.push(' ');
+                        line.push_str(strip_comment(next).trim());
+                    }
+                    None => return Err(anyhow!("line {}: unterminated array", n + 1)),
+                }
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("line {}: expected key = value", n + 1))?;
+            let key = unquote_key(key.trim());
+            let (value, rest) = parse_value(value.trim())
+                .ok_or_else(|| anyhow!("line {}: invalid value", n + 1))?;
+            if !rest.trim().is_empty() {
+                return Err(anyhow!("line {}: unexpected {}", n + 1, rest.trim()));
+            }
+            config
+                .tables
+                .entry(table.clone())
+                .or_default()
+                .insert(key, value);
+        }
+
+        Ok(config)
+    }
+
+    /// Look up a value in a table, using "" for the top level keys.
+    pub fn get(&self, table: &str, key: &str) -> Option<&Value> {
+        self.tables.get(table).and_then(|t| t.get(key))
+    }
+
+    /// A named log format, defined either as `name = "..."` under `[formats]` or with a
+    /// `format = "..."` key in its own `[formats.name]` table.
+    pub fn format(&self, name: &str) -> Option<&str> {
+        self.get(&format!("{}.{}", FORMATS, name), "format")
+            .or_else(|| self.get(FORMATS, name))
+            .and_then(Value::as_str)
+    }
+
+    /// A named query, defined either as `name = "..."` under `[queries]` or with a `query = "..."`
+    /// key in its own `[queries.name]` table.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.get(&format!("{}.{}", QUERIES, name), "query")
+            .or_else(|| self.get(QUERIES, name))
+            .and_then(Value::as_str)
+    }
+
+    /// Fill in any options that were not given on the command line with the configured defaults,
+    /// then resolve the format if it is one of the named formats.
+    pub fn apply(&self, opts: &mut Options, matches: &ArgMatches) -> Result<()> {
+        if let Some(top) = self.tables.get("") {
+            apply_defaults(top, opts, matches)?;
+        }
+        if let Some(format) = self.format(&opts.format) {
+            opts.format = format.to_string();
+        }
+
+        Ok(())
+    }
+}
+
+// Set each option from the top level keys unless it was given on the command line.
+fn apply_defaults(
+    top: &HashMap<String, Value>,
+    opts: &mut Options,
+    matches: &ArgMatches,
+) -> Result<()> {
+    for (key, value) in top {
+        // Keys use the same names as the long flags, with either dashes or underscores.
+        let name = key.replace('_', "-");
+        let arg = match name.as_str() {
+            "where" => "filter",
+            n => n,
+        };
+        if matches.occurrences_of(arg) > 0 {
+            continue;
+        }
+
+        let invalid = || anyhow!("invalid value for {} in the config", key);
+        match arg {
+            "access-log" => opts.access_log = value.as_strings().ok_or_else(invalid)?,
+            "anonymize-ips" => {
+                opts.anonymize_ips = match value {
+                    Value::Boolean(false) => None,
+                    Value::Boolean(true) => Some(None),
+                    Value::String(s) => Some(Some(s.clone())),
+                    _ => return Err(invalid()),
+                }
+            }
+            "asn-db" => opts.asn_db = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "asset-extensions" => {
+                opts.asset_extensions = value.as_str().ok_or_else(invalid)?.to_string()
+            }
+            "bot-patterns" => {
+                opts.bot_patterns = Some(value.as_str().ok_or_else(invalid)?.to_string())
+            }
+            "cidr" => {
+                opts.cidr = Some(match value {
+                    Value::String(s) => s.clone(),
+                    Value::Integer(i) => i.to_string(),
+                    _ => return Err(invalid()),
+                })
+            }
+            "db" => opts.db = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "delta" => opts.delta = value.as_bool().ok_or_else(invalid)?,
+            "error-log" => opts.error_log = value.as_bool().ok_or_else(invalid)?,
+            "errors-threshold" => opts.errors_threshold = value.as_f64().ok_or_else(invalid)?,
+            "exclude-bots" => opts.exclude_bots = value.as_bool().ok_or_else(invalid)?,
+            "exclude-ip" => opts.exclude_ip = value.as_strings().ok_or_else(invalid)?,
+            "exclude-status" => {
+                opts.exclude_status = Some(value.as_str().ok_or_else(invalid)?.to_string())
+            }
+            "filter" => opts.filter = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "format" => opts.format = value.as_str().ok_or_else(invalid)?.to_string(),
+            "geoip-db" => opts.geoip_db = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "group-by" => opts.group_by = value.as_str().ok_or_else(invalid)?.to_string(),
+            "having" => {
+                opts.having = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Integer(i) => i.to_string(),
+                    _ => return Err(invalid()),
+                }
+            }
+            "human" => opts.human = value.as_bool().ok_or_else(invalid)?,
+            "ignore-assets" => opts.ignore_assets = value.as_bool().ok_or_else(invalid)?,
+            "interval" => opts.interval = value.as_u64().ok_or_else(invalid)?,
+            "jobs" => opts.jobs = Some(value.as_u64().ok_or_else(invalid)? as usize),
+            "journal" => opts.journal = value.as_bool().ok_or_else(invalid)?,
+            "limit" => opts.limit = value.as_u64().ok_or_else(invalid)?,
+            "listen-syslog" => {
+                opts.listen_syslog = Some(value.as_str().ok_or_else(invalid)?.to_string())
+            }
+            "max-records" => opts.max_records = Some(value.as_u64().ok_or_else(invalid)?),
+            "nginx-config" => {
+                opts.nginx_config = Some(value.as_str().ok_or_else(invalid)?.to_string())
+            }
+            "no-color" => opts.no_color = value.as_bool().ok_or_else(invalid)?,
+            "no-follow" => opts.no_follow = value.as_bool().ok_or_else(invalid)?,
+            "normalize-paths" => opts.normalize_paths = value.as_bool().ok_or_else(invalid)?,
+            "only-bots" => opts.only_bots = value.as_bool().ok_or_else(invalid)?,
+            "only-ip" => opts.only_ip = value.as_strings().ok_or_else(invalid)?,
+            "order-by" => opts.order_by = value.as_str().ok_or_else(invalid)?.to_string(),
+            "output" => opts.output = value.as_str().ok_or_else(invalid)?.parse()?,
+            "output-file" => {
+                opts.output_file = Some(value.as_str().ok_or_else(invalid)?.to_string())
+            }
+            "redact" => opts.redact = value.as_strings().ok_or_else(invalid)?,
+            "resolve" => opts.resolve = value.as_bool().ok_or_else(invalid)?,
+            "retention" => opts.retention = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "routes" => opts.routes = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "sample" => {
+                opts.sample = Some(match value {
+                    Value::String(s) => s.clone(),
+                    Value::Float(f) => f.to_string(),
+                    Value::Integer(i) => i.to_string(),
+                    _ => return Err(invalid()),
+                })
+            }
+            "show-unparsed" => opts.show_unparsed = value.as_bool().ok_or_else(invalid)?,
+            "since" => opts.since = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "slow-threshold" => opts.slow_threshold = value.as_f64().ok_or_else(invalid)?,
+            "state" => opts.state = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "status" => opts.status = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "strict" => opts.strict = value.as_bool().ok_or_else(invalid)?,
+            "stream" => opts.stream = value.as_bool().ok_or_else(invalid)?,
+            "strip-query" => opts.strip_query = value.as_bool().ok_or_else(invalid)?,
+            "timezone" => opts.timezone = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "totals" => opts.totals = value.as_bool().ok_or_else(invalid)?,
+            "unit" => opts.unit = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "until" => opts.until = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            "utc" => opts.utc = value.as_bool().ok_or_else(invalid)?,
+            "vhost" => opts.vhost = value.as_strings().ok_or_else(invalid)?,
+            "window" => opts.window = Some(value.as_str().ok_or_else(invalid)?.to_string()),
+            _ => return Err(anyhow!("unknown option {} in the config", key)),
+        }
+    }
+
+    Ok(())
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// A string or an array of strings, for options that can be given multiple times.
+    pub fn as_strings(&self) -> Option<Vec<String>> {
+        match self {
+            Value::String(s) => Some(vec![s.clone()]),
+            Value::Array(a) => a.iter().map(|v| v.as_str().map(String::from)).collect(),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Integer(i) if *i >= 0 => Some(*i as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Integer(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+// Remove a trailing comment, ignoring any # inside of strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..i],
+            None => {}
+        }
+    }
+    line
+}
+
+// Whether every bracket outside of strings has been closed.
+fn array_closed(line: &str) -> bool {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in line.chars() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '[' => depth += 1,
+            None if c == ']' => depth -= 1,
+            None => {}
+        }
+    }
+    depth <= 0
+}
+
+fn unquote_key(key: &str) -> String {
+    match parse_string(key) {
+        Some((k, "")) => k,
+        _ => key.to_string(),
+    }
+}
+
+// Parse a single value from the start of the input, returning it along with what follows it.
+fn parse_value(s: &str) -> Option<(Value, &str)> {
+    let s = s.trim_start();
+    if s.starts_with('"') || s.starts_with('\'') {
+        let (string, rest) = parse_string(s)?;
+        return Some((Value::String(string), rest));
+    }
+
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut values = vec![];
+        loop {
+            rest = rest.trim_start();
+            if let Some(r) = rest.strip_prefix(']') {
+                return Some((Value::Array(values), r));
+            }
+            let (value, r) = parse_value(rest)?;
+            values.push(value);
+            rest = r.trim_start();
+            if let Some(r) = rest.strip_prefix(',') {
+                rest = r;
+            } else if !rest.starts_with(']') {
+                return None;
+            }
+        }
+    }
+
+    let end = s
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(s.len());
+    let (token, rest) = s.split_at(end);
+    let value = match token {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        t => {
+            let t = t.replace('_', "");
+            match t.parse() {
+                Ok(i) => Value::Integer(i),
+                Err(_) => Value::Float(t.parse().ok().filter(|f: &f64| f.is_finite())?),
+            }
+        }
+    };
+    Some((value, rest))
+}
+
+// Parse a basic string with escapes or a literal string in single quotes.
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    if let Some(rest) = s.strip_prefix('\'') {
+        let end = rest.find('\'')?;
+        return Some((rest[..end].to_string(), &rest[end + 1..]));
+    }
+
+    let rest = s.strip_prefix('"')?;
+    let mut string = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, &rest[i + 1..])),
+            '\\' => match chars.next()?.1 {
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                'r' => string.push('\r'),
+                '"' => string.push('"'),
+                '\\' => string.push('\\'),
+                'u' => {
+                    let hex = (0..4)
+                        .map(|_| chars.next().map(|(_, c)| c))
+                        .collect::<Option<String>>()?;
+                    string.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                _ => return None,
+            },
+            c => string.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn every_option_has_a_key() {
+        let mut help = vec![];
+        Options::clap().write_long_help(&mut help).unwrap();
+        let help = String::from_utf8(help).unwrap();
+        let matches = Options::clap().get_matches_from(vec!["topngx"]);
+        let mut opts = Options::from_clap(&matches);
+
+        let names = help
+            .split_whitespace()
+            .filter_map(|w| w.strip_prefix("--"))
+            .map(|w| {
+                w.split(|c: char| c != '-' && !c.is_ascii_alphanumeric())
+                    .next()
+                    .unwrap()
+            })
+            .filter(|&n| !["config", "help", "version"].contains(&n));
+        for name in names {
+            let key = name.replace('-', "_");
+            let top = vec![(key, Value::String(String::from("1")))]
+                .into_iter()
+                .collect();
+            if let Err(e) = apply_defaults(&top, &mut opts, &matches) {
+                assert!(!e.to_string().starts_with("unknown option"), "{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_toml_subset() {
+        let config = Config::parse_str(
+            r#"
+# Defaults for topngx.
+access_log = [
+    "/var/log/nginx/access.log", # The current log.
+    '/var/log/nginx/access.log.1',
+]
+format = "$remote_addr \"$request\" # not a comment"
+limit = 20
+no-follow = true
+slow-threshold = 0.25
+
+[formats]
+"main" = 'x'
+
+[formats.vhost]
+format = '$host $remote_addr'
+
+[queries]
+slowest = "SELECT request_path, AVG(request_time) FROM {table} GROUP BY 1 LIMIT {limit}"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.get("", "access_log"),
+            Some(&Value::Array(vec![
+                Value::String(String::from("/var/log/nginx/access.log")),
+                Value::String(String::from("/var/log/nginx/access.log.1")),
+            ]))
+        );
+        assert_eq!(
+            config.get("", "format").and_then(Value::as_str),
+            Some("$remote_addr \"$request\" # not a comment")
+        );
+        assert_eq!(config.get("", "limit").and_then(Value::as_u64), Some(20));
+        assert_eq!(config.get("", "no-follow"), Some(&Value::Boolean(true)));
+        assert_eq!(
+            config.get("", "slow-threshold").and_then(Value::as_f64),
+            Some(0.25)
+        );
+        assert_eq!(config.format("main"), Some("x"));
+        assert_eq!(config.format("vhost"), Some("$host $remote_addr"));
+        assert_eq!(config.format("combined"), None);
+        assert_eq!(
+            config.query("slowest"),
+            Some("SELECT request_path, AVG(request_time) FROM {table} GROUP BY 1 LIMIT {limit}")
+        );
+        assert!(Config::parse_str("limit = ").is_err());
+        assert!(Config::parse_str("limit = 1 2").is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::mem;
+use std::net::IpAddr;
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use rusqlite::types::Value;
+
+use super::output::QueryResult;
+
+// The most lookups made at the same time, so a long report does not flood the resolver.
+const CONCURRENT_LOOKUPS: usize = 8;
+
+// The columns of the reports holding client addresses.
+const ADDRESS_COLUMNS: &[&str] = &["remote_addr", "client"];
+
+/// The column added after the addresses with their hostnames.
+pub(crate) const HOSTNAME: &str = "hostname";
+
+/// Looks up the hostnames of addresses with reverse DNS, remembering them so that reports that are
+/// refreshed while following only look up the new addresses.
+#[derive(Default)]
+pub(crate) struct Resolver {
+    cache: Mutex<HashMap<IpAddr, Option<String>>>,
+}
+
+impl Resolver {
+    /// Add a hostname column after the first column of client addresses in the result, if any.
+    /// Addresses without a name, and values that are not addresses, get NULL.
+    pub(crate) fn annotate(&self, result: &mut QueryResult) {
+        let column = match result
+            .columns
+            .iter()
+            .position(|c| ADDRESS_COLUMNS.contains(&c.as_str()))
+        {
+            Some(i) => i,
+            None => return,
+        };
+        let addresses: Vec<Option<IpAddr>> = result
+            .rows
+            .iter()
+            .map(|row| match &row[column] {
+                Value::Text(t) => t.parse().ok(),
+                _ => None,
+            })
+            .collect();
+        self.resolve(&addresses.iter().flatten().copied().collect::<Vec<IpAddr>>());
+
+        let cache = self.cache.lock().unwrap();
+        result.columns.insert(column + 1, String::from(HOSTNAME));
+        for (row, addr) in result.rows.iter_mut().zip(addresses) {
+            let hostname = addr.and_then(|a| cache.get(&a).cloned().flatten());
+            row.insert(column + 1, hostname.map_or(Value::Null, Value::Text));
+        }
+    }
+
+    // Look up the addresses that are not cached yet, a few at a time.
+    fn resolve(&self, addresses: &[IpAddr]) {
+        let mut pending: Vec<IpAddr> = {
+            let cache = self.cache.lock().unwrap();
+            addresses
+                .iter()
+                .filter(|a| !cache.contains_key(a))
+                .copied()
+                .collect()
+        };
+        pending.sort_unstable();
+        pending.dedup();
+
+        let next = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..CONCURRENT_LOOKUPS.min(pending.len()) {
+                s.spawn(|| {
+                    while let Some(&addr) = pending.get(next.fetch_add(1, Ordering::SeqCst)) {
+                        let hostname = reverse_lookup(addr);
+                        self.cache.lock().unwrap().insert(addr, hostname);
+                    }
+                });
+            }
+        });
+    }
+}
+
+// The name the address resolves back to, if it has one.
+fn reverse_lookup(addr: IpAddr) -> Option<String> {
+    // SAFETY: the socket addresses are zeroed before the fields getnameinfo reads are filled in,
+    // and it writes at most the given length into the buffer.
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let len = match addr {
+            IpAddr::V4(v4) => {
+                let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_addr.s_addr = u32::from_ne_bytes(v4.octets());
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            IpAddr::V6(v6) => {
+                let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_addr.s6_addr = v6.octets();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        let mut host = [0 as c_char; libc::NI_MAXHOST as usize];
+        let status = libc::getnameinfo(
+            &storage as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        );
+        if status != 0 {
+            return None;
+        }
+        Some(CStr::from_ptr(host.as_ptr()).to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annotates_addresses() {
+        let mut result = QueryResult {
+            columns: vec![String::from("remote_addr"), String::from("count")],
+            rows: vec![
+                vec![Value::Text(String::from("-")), Value::Integer(2)],
+                vec![Value::Null, Value::Integer(1)],
+            ],
+        };
+        Resolver::default().annotate(&mut result);
+        assert_eq!(result.columns, vec!["remote_addr", HOSTNAME, "count"]);
+        assert_eq!(result.rows[0][1], Value::Null);
+        assert_eq!(result.rows[1][2], Value::Integer(1));
+    }
+}
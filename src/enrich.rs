@@ -0,0 +1,377 @@
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use regex::{Captures, Regex};
+use rusqlite::types::Value;
+
+use super::cidr::Cidr;
+use super::geoip::GeoIp;
+use super::nginx::{split_request, Variables};
+use super::options::Options;
+use super::parse::{queryable_fields, request_path};
+use super::paths::{self, Routes};
+use super::{ASN, AS_ORG, CITY, COUNTRY, REQUEST_PATH, ROUTE};
+
+const REMOTE_ADDR: &str = "remote_addr";
+// The address of the client in the error log.
+const CLIENT: &str = "client";
+
+// The prefix length IPv6 addresses are grouped by unless another one is given with --cidr.
+const DEFAULT_V6_PREFIX: u8 = 64;
+
+// The variables holding client addresses, which are anonymized. Proxies log lists of them.
+const CLIENT_ADDRESSES: &[&str] = &[
+    REMOTE_ADDR,
+    CLIENT,
+    "realip_remote_addr",
+    "http_x_forwarded_for",
+    "http_x_real_ip",
+];
+
+// How much of the client addresses is kept by --anonymize-ips: the first three octets of IPv4
+// addresses and the first 48 bits of IPv6 ones.
+const ANONYMIZED_V4_PREFIX: u8 = 24;
+const ANONYMIZED_V6_PREFIX: u8 = 48;
+
+// What secrets are replaced with by --redact.
+const REDACTED: &str = "REDACTED";
+
+// How client addresses are anonymized.
+enum Anonymize {
+    // The host part of the address is zeroed.
+    Truncate,
+    // The address is replaced with a hash keyed for this run only, so clients can still be told
+    // apart but not looked up.
+    Hash(RandomState),
+}
+
+/// Fields that are not logged but looked up from the logged variables, such as where the client
+/// is located, and the rewrites made to logged ones.
+#[derive(Default)]
+pub struct Enrichment {
+    anonymize: Option<Anonymize>,
+    asn: Option<GeoIp>,
+    // The prefix lengths of the networks to group IPv4 and IPv6 clients into.
+    cidr: Option<(u8, u8)>,
+    geoip: Option<GeoIp>,
+    normalize_paths: bool,
+    // The patterns scrubbed from the values of each field.
+    redactions: Vec<(String, Regex)>,
+    routes: Option<Routes>,
+    strip_query: bool,
+}
+
+impl Enrichment {
+    pub fn new(opts: &Options) -> Result<Enrichment> {
+        let anonymize = match opts.anonymize_ips.as_ref().map(|m| m.as_deref()) {
+            None => None,
+            Some(None) | Some(Some("truncate")) => Some(Anonymize::Truncate),
+            Some(Some("hash")) => Some(Anonymize::Hash(RandomState::new())),
+            Some(Some(mode)) => {
+                return Err(anyhow!(
+                    "invalid --anonymize-ips {}, expected truncate or hash",
+                    mode
+                ))
+            }
+        };
+        let redactions = opts
+            .redact
+            .iter()
+            .map(|r| parse_redaction(r))
+            .collect::<Result<Vec<(String, Regex)>>>()?;
+        // A misspelled field would silently leave the secrets in place.
+        if let Some(available) = queryable_fields(opts)? {
+            if let Some((field, _)) = redactions.iter().find(|(f, _)| !available.contains(f)) {
+                return Err(anyhow!(
+                    "cannot redact {}, it is not a variable of the log format",
+                    field
+                ));
+            }
+        }
+
+        Ok(Enrichment {
+            anonymize,
+            asn: opts
+                .asn_db
+                .as_deref()
+                .map(|p| GeoIp::open(Path::new(p)))
+                .transpose()?,
+            cidr: opts.cidr.as_deref().map(parse_prefixes).transpose()?,
+            geoip: opts
+                .geoip_db
+                .as_deref()
+                .map(|p| GeoIp::open(Path::new(p)))
+                .transpose()?,
+            normalize_paths: opts.normalize_paths,
+            redactions,
+            routes: opts
+                .routes
+                .as_deref()
+                .map(|p| Routes::load(Path::new(p)))
+                .transpose()?,
+            strip_query: opts.strip_query,
+        })
+    }
+
+    /// The path of the request, without the query string when stripping it and with the IDs in it
+    /// replaced when normalizing paths.
+    pub fn request_path<'a>(&self, c: &'a Variables) -> Cow<'a, str> {
+        let mut path = request_path(c);
+        if self.strip_query {
+            path = paths::strip_query(path);
+        }
+        if self.normalize_paths {
+            paths::normalize(path)
+        } else {
+            Cow::Borrowed(path)
+        }
+    }
+
+    /// The value with the secrets the --redact rules for the field match replaced. When a pattern
+    /// has groups only they are replaced, so token=([^&]+) keeps the token= in front. The rules for
+    /// request_path also apply to $request_uri and the path in $request, which it is taken from.
+    pub fn redact<'a>(&self, field: &str, value: Cow<'a, str>) -> Cow<'a, str> {
+        let mut value = match field {
+            "request" => self.redact_request_line(value),
+            "request_uri" => self.redact(REQUEST_PATH, value),
+            _ => value,
+        };
+        for (_, pattern) in self.redactions.iter().filter(|(f, _)| f == field) {
+            if let Cow::Owned(redacted) = pattern.replace_all(&value, redact_match) {
+                value = Cow::Owned(redacted);
+            }
+        }
+        value
+    }
+
+    // The request line with the rules for request_path applied to its path, so that the method and
+    // protocol are left alone.
+    fn redact_request_line<'a>(&self, request: Cow<'a, str>) -> Cow<'a, str> {
+        let redacted = {
+            let (method, path, protocol) = split_request(&request);
+            match self.redact(REQUEST_PATH, Cow::Borrowed(path)) {
+                Cow::Owned(path) => Some(
+                    [method, &path, protocol]
+                        .iter()
+                        .filter(|p| !p.is_empty())
+                        .copied()
+                        .collect::<Vec<&str>>()
+                        .join(" "),
+                ),
+                Cow::Borrowed(_) => None,
+            }
+        };
+        redacted.map_or(request, Cow::Owned)
+    }
+
+    /// The line with the secrets any of the --redact rules match replaced, for showing lines that
+    /// did not parse and so could not be split into fields.
+    pub fn redact_line<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let mut line = Cow::Borrowed(line);
+        for (_, pattern) in &self.redactions {
+            if let Cow::Owned(redacted) = pattern.replace_all(&line, redact_match) {
+                line = Cow::Owned(redacted);
+            }
+        }
+        line
+    }
+
+    /// The value of an enriched field for the line, or None when it is not an enriched field.
+    /// Lookups that find nothing are NULL.
+    pub fn value(&self, field: &str, c: &Variables) -> Option<Value> {
+        if CLIENT_ADDRESSES.contains(&field) && (self.anonymize.is_some() || self.cidr.is_some()) {
+            let addrs = match c.get(field).filter(|a| !a.is_empty()) {
+                Some(a) => a,
+                None => return Some(Value::Null),
+            };
+            let cidr = self
+                .cidr
+                .filter(|_| field == REMOTE_ADDR || field == CLIENT);
+            let value = addrs
+                .split(',')
+                .map(|a| self.client_address(a.trim(), cidr))
+                .collect::<Vec<String>>()
+                .join(", ");
+            return Some(Value::Text(value));
+        }
+
+        if field == ROUTE {
+            // Routes are matched against the path as logged, without the query string.
+            let path = paths::strip_query(request_path(c));
+            let route = self.routes.as_ref()?.find(path);
+            return Some(route.map_or(Value::Null, |r| Value::Text(r.to_string())));
+        }
+
+        let db = if field == COUNTRY || field == CITY {
+            self.geoip.as_ref()?
+        } else if field == ASN || field == AS_ORG {
+            self.asn.as_ref()?
+        } else {
+            return None;
+        };
+        let location = c
+            .get(REMOTE_ADDR)
+            .or_else(|| c.get(CLIENT))
+            .and_then(|a| a.parse::<IpAddr>().ok())
+            .and_then(|ip| db.lookup(ip))
+            .unwrap_or_default();
+        let value = if field == COUNTRY {
+            location.country
+        } else if field == CITY {
+            location.city
+        } else if field == ASN {
+            return Some(
+                location
+                    .asn
+                    .map_or(Value::Null, |n| Value::Integer(n as i64)),
+            );
+        } else {
+            location.as_org
+        };
+        Some(value.map_or(Value::Null, Value::Text))
+    }
+
+    // The client address as it is stored: anonymized and grouped into its network when asked to.
+    // Anything that is not an address, such as "-", is kept as it is.
+    fn client_address(&self, addr: &str, cidr: Option<(u8, u8)>) -> String {
+        let mut ip = match addr.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return addr.to_string(),
+        };
+        if let Some(Anonymize::Truncate) = self.anonymize {
+            ip = Cidr::containing(ip, ANONYMIZED_V4_PREFIX, ANONYMIZED_V6_PREFIX).network();
+        }
+        let addr = match cidr {
+            Some((v4, v6)) => Cidr::containing(ip, v4, v6).to_string(),
+            None => ip.to_string(),
+        };
+        match &self.anonymize {
+            Some(Anonymize::Hash(key)) => format!("{:016x}", key.hash_one(&addr)),
+            _ => addr,
+        }
+    }
+}
+
+// A --redact rule such as request_path=token=[^&]+, which is the field and then the pattern.
+fn parse_redaction(rule: &str) -> Result<(String, Regex)> {
+    let (field, pattern) = rule.split_once('=').ok_or_else(|| {
+        anyhow!(
+            "invalid --redact {}, expected a field and a pattern such as request_path=token=[^&]+",
+            rule
+        )
+    })?;
+    let pattern =
+        Regex::new(pattern).with_context(|| format!("invalid pattern in --redact {}", rule))?;
+    Ok((field.trim().to_string(), pattern))
+}
+
+// The replacement for a match of a --redact pattern, keeping what is outside of its groups.
+fn redact_match(caps: &Captures) -> String {
+    let whole = caps.get(0).unwrap();
+    if caps.len() == 1 {
+        return String::from(REDACTED);
+    }
+    let mut redacted = String::new();
+    let mut end = whole.start();
+    for group in caps.iter().skip(1).flatten() {
+        // Nested groups are already covered by the group around them.
+        if group.start() < end {
+            continue;
+        }
+        redacted.push_str(&whole.as_str()[end - whole.start()..group.start() - whole.start()]);
+        redacted.push_str(REDACTED);
+        end = group.end();
+    }
+    redacted.push_str(&whole.as_str()[end - whole.start()..]);
+    redacted
+}
+
+// The prefix lengths given with --cidr, such as 24 or 24,48.
+fn parse_prefixes(s: &str) -> Result<(u8, u8)> {
+    let invalid = || {
+        anyhow!(
+            "invalid --cidr {}, expected a prefix length such as 24 or 24,48 for IPv6",
+            s
+        )
+    };
+    let (v4, v6) = match s.split_once(',') {
+        Some((v4, v6)) => (v4, Some(v6)),
+        None => (s, None),
+    };
+    let v4 = v4
+        .trim()
+        .parse::<u8>()
+        .ok()
+        .filter(|&p| p <= 32)
+        .ok_or_else(invalid)?;
+    let v6 = match v6 {
+        Some(v6) => v6
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .filter(|&p| p <= 128)
+            .ok_or_else(invalid)?,
+        None => DEFAULT_V6_PREFIX,
+    };
+    Ok((v4, v6))
+}
+
+/// The fields the options make available in addition to the ones in the log format.
+pub fn enriched_variables(opts: &Options) -> Vec<String> {
+    let mut variables = vec![];
+    if opts.geoip_db.is_some() {
+        variables.push(String::from(COUNTRY));
+        variables.push(String::from(CITY));
+    }
+    if opts.asn_db.is_some() {
+        variables.push(String::from(ASN));
+        variables.push(String::from(AS_ORG));
+    }
+    if opts.routes.is_some() {
+        variables.push(String::from(ROUTE));
+    }
+    variables
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn redactions() {
+        let enrichment = Enrichment {
+            redactions: vec![
+                parse_redaction("request_path=(?:token|key)=([^&]+)").unwrap(),
+                parse_redaction("http_referer=secret").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let redact = |field, value| enrichment.redact(field, Cow::Borrowed(value)).into_owned();
+        assert_eq!(
+            redact("request_path", "/a?token=abc&x=1&key=def"),
+            "/a?token=REDACTED&x=1&key=REDACTED"
+        );
+        assert_eq!(redact("request_path", "/a?x=1"), "/a?x=1");
+        assert_eq!(redact("http_referer", "/secret/1"), "/REDACTED/1");
+        assert_eq!(
+            redact("request", "GET /a?token=abc HTTP/1.1"),
+            "GET /a?token=REDACTED HTTP/1.1"
+        );
+        assert_eq!(redact("request_uri", "/a?key=abc"), "/a?key=REDACTED");
+        assert_eq!(redact("http_user_agent", "/a?token=abc"), "/a?token=abc");
+        assert!(parse_redaction("request_path").is_err());
+        assert!(parse_redaction("request_path=(").is_err());
+        assert_eq!(
+            enrichment.redact_line("/secret?token=abc&x=1"),
+            "/REDACTED?token=REDACTED&x=1"
+        );
+
+        let opts = Options::from_iter(&["topngx", "--redact", "requst_path=token"]);
+        assert!(Enrichment::new(&opts).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use super::cidr::Cidr;
+use super::nginx::Variables;
+use super::options::Options;
+use super::parse::{queryable_fields, request_path, vhost};
+use super::paths;
+use super::timestamp::{self, Bound, Zone};
+use super::ua;
+use super::{ERROR_TIME, MSEC, TIME_ISO8601, TIME_LOCAL, VHOST};
+
+const STATUS: &str = "status";
+const USER_AGENT: &str = "http_user_agent";
+const REMOTE_ADDR: &str = "remote_addr";
+// The address of the client in the error log.
+const CLIENT: &str = "client";
+
+/// Checks applied to each parsed line to decide whether it is inserted at all.
+pub struct RecordFilter {
+    // The extensions of the static assets to skip, such as ".css".
+    assets: Vec<String>,
+    // Whether to keep only the bots or only everyone else.
+    bots: Option<bool>,
+    // Extra patterns, in lower case, that make a User-Agent a bot.
+    bot_patterns: Vec<String>,
+    error_log: bool,
+    exclude_ips: Vec<Cidr>,
+    exclude_status: Option<StatusSet>,
+    only_ips: Vec<Cidr>,
+    // Lines are sampled when their hash is below this.
+    sample: Option<u64>,
+    since: Option<Bound>,
+    status: Option<StatusSet>,
+    until: Option<Bound>,
+    // The virtual hosts to keep, in lower case.
+    vhosts: Vec<String>,
+    // The timezone of --since and --until when they have no offset.
+    zone: Zone,
+}
+
+impl RecordFilter {
+    pub fn new(opts: &Options) -> Result<RecordFilter> {
+        if opts.status.is_some() || opts.exclude_status.is_some() {
+            if opts.error_log {
+                return Err(anyhow!(
+                    "--status and --exclude-status only apply to access logs"
+                ));
+            }
+            if let Some(variables) = queryable_fields(opts)? {
+                if !variables.iter().any(|v| v == STATUS) {
+                    return Err(anyhow!(
+                        "--status and --exclude-status need $status in the log format"
+                    ));
+                }
+            }
+        }
+        let bots = match (opts.only_bots, opts.exclude_bots) {
+            (false, false) => None,
+            (only, _) => Some(only),
+        };
+        if bots.is_some() {
+            if opts.error_log {
+                return Err(anyhow!(
+                    "--exclude-bots and --only-bots only apply to access logs"
+                ));
+            }
+            if let Some(variables) = queryable_fields(opts)? {
+                if !variables.iter().any(|v| v == USER_AGENT) {
+                    return Err(anyhow!(
+                        "--exclude-bots and --only-bots need $http_user_agent in the log format"
+                    ));
+                }
+            }
+        }
+        if !opts.vhost.is_empty() {
+            if opts.error_log {
+                return Err(anyhow!("--vhost only applies to access logs"));
+            }
+            if let Some(variables) = queryable_fields(opts)? {
+                if !variables.iter().any(|v| v == VHOST) {
+                    return Err(anyhow!(
+                        "--vhost needs $host, $server_name or $http_host in the log format"
+                    ));
+                }
+            }
+        }
+        if opts.since.is_some() || opts.until.is_some() {
+            if let Some(variables) = queryable_fields(opts)? {
+                let has_time = variables
+                    .iter()
+                    .any(|v| v == TIME_LOCAL || v == TIME_ISO8601 || v == MSEC);
+                if !opts.error_log && !has_time {
+                    return Err(anyhow!(
+                        "--since and --until need $time_local, $time_iso8601 or $msec in the log format"
+                    ));
+                }
+            }
+        }
+
+        let mut assets = vec![];
+        if opts.ignore_assets && !opts.error_log {
+            assets = opts
+                .asset_extensions
+                .split(',')
+                .map(|e| e.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|e| !e.is_empty())
+                .map(|e| format!(".{}", e))
+                .collect();
+        }
+
+        Ok(RecordFilter {
+            assets,
+            bots,
+            bot_patterns: match &opts.bot_patterns {
+                Some(path) => read_patterns(Path::new(path))?,
+                None => vec![],
+            },
+            error_log: opts.error_log,
+            exclude_ips: parse_cidrs(&opts.exclude_ip)?,
+            exclude_status: opts
+                .exclude_status
+                .as_deref()
+                .map(StatusSet::parse)
+                .transpose()?,
+            since: opts.since.as_deref().map(Bound::parse).transpose()?,
+            only_ips: parse_cidrs(&opts.only_ip)?,
+            sample: opts
+                .sample
+                .as_deref()
+                .map(sample_fraction)
+                .transpose()?
+                .map(|f| (f * u64::MAX as f64) as u64),
+            status: opts.status.as_deref().map(StatusSet::parse).transpose()?,
+            until: opts.until.as_deref().map(Bound::parse).transpose()?,
+            vhosts: opts.vhost.iter().map(|v| v.to_ascii_lowercase()).collect(),
+            zone: Zone::from_options(opts)?,
+        })
+    }
+
+    /// Whether the line is in the sample, which is checked before parsing it so that the lines left
+    /// out cost next to nothing.
+    pub fn sampled(&self, line: &str) -> bool {
+        self.sample
+            .is_none_or(|threshold| fnv1a(line.as_bytes()) <= threshold)
+    }
+
+    /// Whether the record should be kept.
+    pub fn matches(&self, vars: &Variables) -> bool {
+        if !self.assets.is_empty() && self.is_asset(vars) {
+            return false;
+        }
+        if !self.vhosts.is_empty() && !self.is_wanted_vhost(vars) {
+            return false;
+        }
+        if let Some(only_bots) = self.bots {
+            if self.is_bot(vars) != only_bots {
+                return false;
+            }
+        }
+        if !self.only_ips.is_empty() || !self.exclude_ips.is_empty() {
+            let ip = vars
+                .get(REMOTE_ADDR)
+                .or_else(|| vars.get(CLIENT))
+                .and_then(|a| a.parse::<IpAddr>().ok());
+            if !self.only_ips.is_empty()
+                && !ip.is_some_and(|ip| self.only_ips.iter().any(|c| c.contains(ip)))
+            {
+                return false;
+            }
+            if ip.is_some_and(|ip| self.exclude_ips.iter().any(|c| c.contains(ip))) {
+                return false;
+            }
+        }
+        if self.status.is_some() || self.exclude_status.is_some() {
+            let status = vars.get(STATUS).and_then(|s| s.parse::<u16>().ok());
+            // A request without a status can not be one of the wanted ones.
+            if let Some(wanted) = &self.status {
+                if !status.is_some_and(|s| wanted.contains(s)) {
+                    return false;
+                }
+            }
+            if let Some(unwanted) = &self.exclude_status {
+                if status.is_some_and(|s| unwanted.contains(s)) {
+                    return false;
+                }
+            }
+        }
+
+        if self.since.is_some() || self.until.is_some() {
+            let t = if self.error_log {
+                vars.get(ERROR_TIME).and_then(timestamp::parse_error_time)
+            } else {
+                vars.get(TIME_LOCAL)
+                    .and_then(timestamp::parse_time_local)
+                    .or_else(|| {
+                        vars.get(TIME_ISO8601)
+                            .and_then(timestamp::parse_time_iso8601)
+                    })
+                    .or_else(|| vars.get(MSEC).and_then(timestamp::parse_msec))
+            };
+            let t = match t {
+                Some(t) => t,
+                // Without a time there is no telling whether it is in range.
+                None => return false,
+            };
+            if let Some(since) = &self.since {
+                if since.cmp_timestamp(&t, self.zone) == Ordering::Less {
+                    return false;
+                }
+            }
+            if let Some(until) = &self.until {
+                if until.cmp_timestamp(&t, self.zone) != Ordering::Less {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    fn is_wanted_vhost(&self, vars: &Variables) -> bool {
+        let host = match vhost(vars) {
+            Some(h) => h.to_ascii_lowercase(),
+            None => return false,
+        };
+        self.vhosts.iter().any(|v| match v.strip_prefix("*.") {
+            Some(domain) => host.ends_with(&v[1..]) && host.len() > domain.len() + 1,
+            None => host == *v,
+        })
+    }
+
+    fn is_bot(&self, vars: &Variables) -> bool {
+        let user_agent = vars.get(USER_AGENT).unwrap_or("");
+        if ua::is_bot(user_agent) {
+            return true;
+        }
+        let user_agent = user_agent.to_ascii_lowercase();
+        self.bot_patterns.iter().any(|p| user_agent.contains(p))
+    }
+
+    fn is_asset(&self, vars: &Variables) -> bool {
+        let path = paths::strip_query(request_path(vars));
+        // Compare the ends in lower case without copying the whole path.
+        let len = path.len();
+        self.assets.iter().any(|ext| {
+            len >= ext.len()
+                && path.is_char_boundary(len - ext.len())
+                && path[len - ext.len()..].eq_ignore_ascii_case(ext)
+        })
+    }
+}
+
+/// The fraction of lines to sample, given as a number such as 0.01 or a ratio such as 1/100.
+pub fn sample_fraction(sample: &str) -> Result<f64> {
+    let invalid = || {
+        anyhow!(
+            "invalid sample {}, expected a fraction such as 0.01 or 1/100",
+            sample
+        )
+    };
+    let fraction = match sample.split_once('/') {
+        Some((n, d)) => {
+            let n = n.trim().parse::<f64>().map_err(|_| invalid())?;
+            let d = d.trim().parse::<f64>().map_err(|_| invalid())?;
+            n / d
+        }
+        None => sample.trim().parse::<f64>().map_err(|_| invalid())?,
+    };
+    if fraction > 0.0 && fraction <= 1.0 {
+        Ok(fraction)
+    } else {
+        Err(invalid())
+    }
+}
+
+// The 64-bit FNV-1a hash, which spreads similar lines evenly enough to sample them.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Read the bot patterns file, skipping blank lines and comments.
+fn read_patterns(path: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read bot patterns {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_ascii_lowercase)
+        .collect())
+}
+
+fn parse_cidrs(blocks: &[String]) -> Result<Vec<Cidr>> {
+    blocks.iter().map(|b| b.parse()).collect()
+}
+
+/// Statuses given as a list of codes and classes, such as "404,410,5xx".
+#[derive(Debug, PartialEq)]
+struct StatusSet {
+    codes: Vec<u16>,
+    // The first digit of the classes, such as 5 for 5xx.
+    classes: Vec<u16>,
+}
+
+impl StatusSet {
+    fn parse(list: &str) -> Result<StatusSet> {
+        let mut set = StatusSet {
+            codes: vec![],
+            classes: vec![],
+        };
+        for item in list.split(',').map(str::trim) {
+            let invalid = || {
+                anyhow!(
+                    "invalid status {}, expected a code like 404 or a class like 5xx",
+                    item
+                )
+            };
+            let class = item.strip_suffix("xx").or_else(|| item.strip_suffix("XX"));
+            match class {
+                Some(c) => match c.as_bytes() {
+                    [d @ b'1'..=b'5'] => set.classes.push(u16::from(d - b'0')),
+                    _ => return Err(invalid()),
+                },
+                None => match item.parse::<u16>() {
+                    Ok(code) if (100..600).contains(&code) => set.codes.push(code),
+                    _ => return Err(invalid()),
+                },
+            }
+        }
+        Ok(set)
+    }
+
+    fn contains(&self, status: u16) -> bool {
+        self.codes.contains(&status) || self.classes.contains(&(status / 100))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::*;
+
+    #[test]
+    fn statuses() {
+        let set = StatusSet::parse("404, 410,5xx").unwrap();
+        assert!(set.contains(404));
+        assert!(set.contains(503));
+        assert!(!set.contains(400));
+        assert!(!set.contains(200));
+        assert!(StatusSet::parse("2XX").unwrap().contains(204));
+
+        for invalid in &["", "6xx", "50x", "99", "abc", "404,"] {
+            assert!(StatusSet::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn sampling() {
+        assert_eq!(sample_fraction("0.25").unwrap(), 0.25);
+        assert_eq!(sample_fraction("1/100").unwrap(), 0.01);
+        for invalid in &["0", "1.5", "1/0", "-0.1", "half"] {
+            assert!(sample_fraction(invalid).is_err(), "{}", invalid);
+        }
+
+        let opts = Options::from_iter(&["topngx", "--sample", "1/10"]);
+        let filter = RecordFilter::new(&opts).unwrap();
+        let sampled = (0..10_000)
+            .filter(|i| filter.sampled(&format!("10.0.0.1 GET /{}", i)))
+            .count();
+        assert!((800..1200).contains(&sampled), "{}", sampled);
+    }
+}
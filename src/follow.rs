@@ -0,0 +1,129 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use log::{debug, info};
+
+use topngx::parse::to_string_lossy;
+
+/// Tails an access log, handing back complete lines as they are appended.
+pub(crate) struct Follower {
+    path: PathBuf,
+    reader: BufReader<File>,
+    inode: u64,
+    position: u64,
+    partial: Vec<u8>,
+}
+
+impl Follower {
+    /// Open the log and seek to the end so only new lines are reported.
+    pub(crate) fn open(path: &str) -> Result<Follower> {
+        let mut file = File::open(path)?;
+        let inode = file.metadata()?.ino();
+        let position = file.seek(SeekFrom::End(0))?;
+        debug!("following {} from offset {}", path, position);
+
+        Ok(Follower {
+            path: PathBuf::from(path),
+            reader: BufReader::new(file),
+            inode,
+            position,
+            partial: vec![],
+        })
+    }
+
+    /// Return every complete line written since the last call. A trailing line without a newline
+    /// is held back until the writer finishes it.
+    pub(crate) fn read_lines(&mut self) -> Result<Vec<String>> {
+        self.check_rotation()?;
+
+        let mut lines = vec![];
+        loop {
+            // Read bytes rather than a String so that invalid UTF-8 is replaced instead of failing.
+            let n = self.reader.read_until(b'\n', &mut self.partial)?;
+            if n == 0 {
+                break;
+            }
+            self.position += n as u64;
+
+            if self.partial.last() != Some(&b'\n') {
+                break;
+            }
+
+            let mut line = to_string_lossy(std::mem::take(&mut self.partial));
+            line.truncate(line.trim_end_matches(&['\r', '\n'][..]).len());
+            lines.push(line);
+        }
+
+        Ok(lines)
+    }
+
+    // If the file at the path is now another one it was rotated out from under us, and if it is
+    // shorter than what we have read it was truncated, so start again from the beginning of
+    // whatever is at the path now.
+    fn check_rotation(&mut self) -> Result<()> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(m) => m,
+            // The file may briefly not exist while it is being rotated.
+            Err(_) => return Ok(()),
+        };
+
+        if metadata.ino() != self.inode || metadata.len() < self.position {
+            info!(
+                "{} was truncated or rotated, reopening",
+                self.path.display()
+            );
+            let file = File::open(&self.path)?;
+            self.inode = file.metadata()?.ino();
+            self.reader = BufReader::new(file);
+            self.position = 0;
+            self.partial.clear();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn rotations() {
+        let dir = std::env::temp_dir().join(format!("topngx-follow-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("access.log");
+        let append = |s: &str| {
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&log)
+                .unwrap()
+                .write_all(s.as_bytes())
+                .unwrap();
+        };
+
+        fs::write(&log, "old\n").unwrap();
+        let mut follower = Follower::open(log.to_str().unwrap()).unwrap();
+        append("first\nsec");
+        assert_eq!(follower.read_lines().unwrap(), ["first"]);
+        append("ond\n");
+        assert_eq!(follower.read_lines().unwrap(), ["second"]);
+
+        // A log replaced by a longer one is read from its start.
+        fs::rename(&log, dir.join("access.log.1")).unwrap();
+        fs::write(&log, "rotated and longer\nthan the old one\n").unwrap();
+        assert_eq!(
+            follower.read_lines().unwrap(),
+            ["rotated and longer", "than the old one"]
+        );
+
+        // As is a truncated one.
+        fs::write(&log, "new\n").unwrap();
+        assert_eq!(follower.read_lines().unwrap(), ["new"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
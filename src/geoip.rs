@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+// The marker that comes right before the metadata at the end of the database.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+// The search tree is followed by 16 zero bytes before the data section starts.
+const DATA_SEPARATOR: usize = 16;
+
+/// The location of an IP address as found in the database.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Location {
+    /// The ISO 3166 country code, such as "US".
+    pub country: Option<String>,
+    /// The English name of the city.
+    pub city: Option<String>,
+    /// The number of the autonomous system the address belongs to, such as 15169.
+    pub asn: Option<u64>,
+    /// The organization running the autonomous system, such as "GOOGLE".
+    pub as_org: Option<String>,
+}
+
+/// A reader for MaxMind DB files such as GeoLite2-Country.mmdb, GeoLite2-City.mmdb or
+/// GeoLite2-ASN.mmdb.
+pub struct GeoIp {
+    db: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    data_start: usize,
+    ipv4_start: usize,
+}
+
+// The values stored in the data section. Only the types needed for lookups are kept, anything else
+// is skipped over.
+enum Value {
+    String(String),
+    Uint(u64),
+    Map(HashMap<String, Value>),
+    Other,
+}
+
+impl GeoIp {
+    /// Read the whole database into memory.
+    pub fn open(path: &Path) -> Result<GeoIp> {
+        let db = fs::read(path)
+            .with_context(|| format!("failed to read GeoIP database {}", path.display()))?;
+        GeoIp::from_bytes(db).with_context(|| format!("invalid GeoIP database {}", path.display()))
+    }
+
+    fn from_bytes(db: Vec<u8>) -> Result<GeoIp> {
+        let metadata_start = db
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or_else(|| anyhow!("the metadata was not found"))?
+            + METADATA_MARKER.len();
+        let metadata = match Decoder::new(&db[metadata_start..]).decode(0) {
+            Some((Value::Map(m), _)) => m,
+            _ => return Err(anyhow!("the metadata is not a map")),
+        };
+        let number = |key: &str| match metadata.get(key) {
+            Some(Value::Uint(n)) => Ok(*n),
+            _ => Err(anyhow!("the metadata is missing {}", key)),
+        };
+
+        let node_count = number("node_count")? as usize;
+        let record_size = number("record_size")? as usize;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(anyhow!("unsupported record size {}", record_size));
+        }
+        let data_start = node_count * record_size / 4 + DATA_SEPARATOR;
+        if data_start > metadata_start {
+            return Err(anyhow!("the search tree is larger than the database"));
+        }
+
+        let mut geoip = GeoIp {
+            db,
+            node_count,
+            record_size,
+            ip_version: number("ip_version")?,
+            data_start,
+            ipv4_start: 0,
+        };
+        // IPv4 addresses live under ::/96 in an IPv6 tree.
+        if geoip.ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = geoip.record(node, 0);
+            }
+            geoip.ipv4_start = node;
+        }
+
+        Ok(geoip)
+    }
+
+    /// Find the location of the address, if it is in the database.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let (bytes, mut node) = match ip {
+            IpAddr::V4(v4) => (v4.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(v6) if self.ip_version == 6 => (v6.octets().to_vec(), 0),
+            IpAddr::V6(_) => return None,
+        };
+
+        for i in 0..bytes.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bytes[i / 8] >> (7 - i % 8)) & 1;
+            node = self.record(node, bit as usize);
+        }
+        if node <= self.node_count {
+            return None;
+        }
+
+        let offset = node - self.node_count - DATA_SEPARATOR;
+        let data = self.db.get(self.data_start..)?;
+        match Decoder::new(data).decode(offset)?.0 {
+            Value::Map(m) => Some(Location {
+                country: lookup_path(&m, &["country", "iso_code"])
+                    .or_else(|| lookup_path(&m, &["registered_country", "iso_code"])),
+                city: lookup_path(&m, &["city", "names", "en"]),
+                asn: match m.get("autonomous_system_number") {
+                    Some(Value::Uint(n)) => Some(*n),
+                    _ => None,
+                },
+                as_org: lookup_path(&m, &["autonomous_system_organization"]),
+            }),
+            _ => None,
+        }
+    }
+
+    // Read the left (0) or right (1) record of a node in the search tree.
+    fn record(&self, node: usize, side: usize) -> usize {
+        let size = self.record_size * 2 / 8;
+        let n = &self.db[node * size..node * size + size];
+        match (self.record_size, side) {
+            (24, 0) => be(&n[0..3]),
+            (24, _) => be(&n[3..6]),
+            (28, 0) => (usize::from(n[3] >> 4) << 24) | be(&n[0..3]),
+            (28, _) => (usize::from(n[3] & 0x0f) << 24) | be(&n[4..7]),
+            (_, 0) => be(&n[0..4]),
+            (_, _) => be(&n[4..8]),
+        }
+    }
+}
+
+fn lookup_path(map: &HashMap<String, Value>, path: &[&str]) -> Option<String> {
+    let (last, parents) = path.split_last()?;
+    let mut map = map;
+    for key in parents {
+        map = match map.get(*key)? {
+            Value::Map(m) => m,
+            _ => return None,
+        };
+    }
+    match map.get(*last)? {
+        Value::String(s) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+fn be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, &b| (n << 8) | usize::from(b))
+}
+
+// Decodes values from the data section, where pointers are relative to its start.
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder { data }
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> Option<&'a [u8]> {
+        self.data.get(offset..offset.checked_add(len)?)
+    }
+
+    // Decode the value at the offset, returning it and the offset right after it.
+    fn decode(&self, offset: usize) -> Option<(Value, usize)> {
+        let control = *self.data.get(offset)?;
+        let mut offset = offset + 1;
+
+        let mut kind = control >> 5;
+        if kind == 1 {
+            let size = usize::from((control >> 3) & 0x3);
+            let bytes = self.bytes(offset, size + 1)?;
+            let low = usize::from(control & 0x7);
+            let pointer = match size {
+                0 => (low << 8) | be(bytes),
+                1 => ((low << 16) | be(bytes)) + 2048,
+                2 => ((low << 24) | be(bytes)) + 526_336,
+                _ => be(bytes),
+            };
+            // Pointers to pointers are not allowed, which also keeps a bad file from looping.
+            if *self.data.get(pointer)? >> 5 == 1 {
+                return None;
+            }
+            // A pointer is followed by whatever comes after it, not after what it points to.
+            let (value, _) = self.decode(pointer)?;
+            return Some((value, offset + size + 1));
+        }
+        if kind == 0 {
+            kind = self.data.get(offset)?.checked_add(7)?;
+            offset += 1;
+        }
+
+        let mut size = usize::from(control & 0x1f);
+        if size >= 29 {
+            let extra = size - 28;
+            let n = be(self.bytes(offset, extra)?);
+            offset += extra;
+            size = match extra {
+                1 => 29 + n,
+                2 => 285 + n,
+                _ => 65_821 + n,
+            };
+        }
+
+        let value = match kind {
+            2 => Value::String(String::from_utf8_lossy(self.bytes(offset, size)?).into_owned()),
+            3 => {
+                self.bytes(offset, 8)?;
+                return Some((Value::Other, offset + 8));
+            }
+            4 | 8 => {
+                self.bytes(offset, size)?;
+                Value::Other
+            }
+            5 | 6 | 9 | 10 => Value::Uint(
+                self.bytes(offset, size)?
+                    .iter()
+                    .fold(0, |n, &b| (n << 8) | u64::from(b)),
+            ),
+            7 => {
+                let mut map = HashMap::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset)?;
+                    let (value, next) = self.decode(next)?;
+                    offset = next;
+                    if let Value::String(key) = key {
+                        map.insert(key, value);
+                    }
+                }
+                return Some((Value::Map(map), offset));
+            }
+            11 => {
+                for _ in 0..size {
+                    offset = self.decode(offset)?.1;
+                }
+                return Some((Value::Other, offset));
+            }
+            // Booleans keep their value in the size.
+            14 => return Some((Value::Other, offset)),
+            15 => {
+                self.bytes(offset, 4)?;
+                return Some((Value::Other, offset + 4));
+            }
+            _ => return None,
+        };
+
+        Some((value, offset + size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encode a string, an unsigned integer or a map in the MaxMind DB data format.
+    fn string(s: &str) -> Vec<u8> {
+        // Sizes from 29 on take another byte.
+        let mut out = match s.len() {
+            n if n < 29 => vec![0x40 | n as u8],
+            n => vec![0x40 | 29, (n - 29) as u8],
+        };
+        out.extend_from_slice(s.as_bytes());
+        out
+    }
+
+    fn uint16(n: u16) -> Vec<u8> {
+        let mut out = vec![0xa0 | 2];
+        out.extend_from_slice(&n.to_be_bytes());
+        out
+    }
+
+    fn map(pairs: Vec<(&str, Vec<u8>)>) -> Vec<u8> {
+        let mut out = vec![0xe0 | pairs.len() as u8];
+        for (k, v) in pairs {
+            out.extend(string(k));
+            out.extend(v);
+        }
+        out
+    }
+
+    #[test]
+    fn looks_up_networks() {
+        // An IPv4 tree with 24 bit records where 1.2.3.0/24 points at the data.
+        let network = [1u8, 2, 3];
+        let node_count = 24;
+        let mut db = vec![];
+        for i in 0..node_count {
+            let bit = (network[i / 8] >> (7 - i % 8)) & 1;
+            let next = if i + 1 == node_count {
+                node_count + DATA_SEPARATOR
+            } else {
+                i + 1
+            };
+            let (left, right) = if bit == 0 {
+                (next, node_count)
+            } else {
+                (node_count, next)
+            };
+            db.extend_from_slice(&(left as u32).to_be_bytes()[1..]);
+            db.extend_from_slice(&(right as u32).to_be_bytes()[1..]);
+        }
+        db.extend_from_slice(&[0; DATA_SEPARATOR]);
+        db.extend(map(vec![
+            (
+                "city",
+                map(vec![("names", map(vec![("en", string("Sydney"))]))]),
+            ),
+            ("country", map(vec![("iso_code", string("AU"))])),
+            ("autonomous_system_number", uint16(4_739)),
+            ("autonomous_system_organization", string("Internode")),
+        ]));
+        db.extend_from_slice(METADATA_MARKER);
+        db.extend(map(vec![
+            ("node_count", uint16(node_count as u16)),
+            ("record_size", uint16(24)),
+            ("ip_version", uint16(4)),
+        ]));
+
+        let geoip = GeoIp::from_bytes(db).unwrap();
+        assert_eq!(
+            geoip.lookup("1.2.3.4".parse().unwrap()),
+            Some(Location {
+                country: Some(String::from("AU")),
+                city: Some(String::from("Sydney")),
+                asn: Some(4_739),
+                as_org: Some(String::from("Internode")),
+            })
+        );
+        assert_eq!(geoip.lookup("1.2.4.4".parse().unwrap()), None);
+        assert_eq!(geoip.lookup("::1".parse().unwrap()), None);
+    }
+}
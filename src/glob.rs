@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+/// Whether the path contains any glob wildcards.
+pub fn is_pattern(path: &str) -> bool {
+    path.contains(&['*', '?', '['][..])
+}
+
+/// Expand a glob pattern such as `/var/log/nginx/*.access.log` into the sorted list of files it
+/// matches. Wildcards may appear in any component of the path. Paths without wildcards are
+/// returned as they are, whether or not they exist.
+pub fn expand(pattern: &str) -> Result<Vec<PathBuf>> {
+    if !is_pattern(pattern) {
+        return Ok(vec![PathBuf::from(pattern)]);
+    }
+
+    let path = Path::new(pattern);
+    let mut matches = vec![PathBuf::new()];
+    for component in path.iter() {
+        let component = component.to_string_lossy();
+        if !is_pattern(&component) {
+            for m in &mut matches {
+                m.push(component.as_ref());
+            }
+            continue;
+        }
+
+        let re = component_regex(&component)?;
+        let mut next = vec![];
+        for dir in &matches {
+            let entries = match fs::read_dir(if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            }) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            for entry in entries {
+                let name = entry?.file_name();
+                let name = name.to_string_lossy();
+                // Like a shell, hidden files must be matched explicitly.
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                if re.is_match(&name) {
+                    next.push(dir.join(name.as_ref()));
+                }
+            }
+        }
+        matches = next;
+    }
+
+    let mut files = matches
+        .into_iter()
+        .filter(|p| p.is_file())
+        .collect::<Vec<PathBuf>>();
+    files.sort();
+
+    if files.is_empty() {
+        return Err(anyhow!("no files matched {}", pattern));
+    }
+    Ok(files)
+}
+
+// Translate a single path component into an anchored regular expression.
+fn component_regex(component: &str) -> Result<Regex> {
+    let mut re = String::from("^");
+    let mut chars = component.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                let rest = chars.as_str();
+                let (negated, class) = match rest.strip_prefix('!') {
+                    Some(r) => (true, r),
+                    None => (false, rest),
+                };
+                // A ] right after the opening bracket is part of the class rather than its end.
+                match class.char_indices().skip(1).find(|&(_, c)| c == ']') {
+                    Some((end, _)) => {
+                        re.push('[');
+                        if negated {
+                            re.push('^');
+                        }
+                        for c in class[..end].chars() {
+                            match c {
+                                '-' => re.push(c),
+                                c => re.push_str(&regex::escape(&c.to_string())),
+                            }
+                        }
+                        re.push(']');
+                        chars = class[end + 1..].chars();
+                    }
+                    // Like a shell, a bracket that is never closed is matched as it is.
+                    None => re.push_str(r"\["),
+                }
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+
+    Ok(Regex::new(&re)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn component_patterns() {
+        let re = component_regex("*.access.log").unwrap();
+        assert!(re.is_match("example.com.access.log"));
+        assert!(!re.is_match("example.com.access.log.1"));
+
+        let re = component_regex("access.log.[!0]?").unwrap();
+        assert!(re.is_match("access.log.1a"));
+        assert!(!re.is_match("access.log.0a"));
+        assert!(!re.is_match("access.log.1"));
+
+        let re = component_regex("access[0-9].log").unwrap();
+        assert!(re.is_match("access7.log"));
+        assert!(!re.is_match("access-.log"));
+
+        let re = component_regex("[]]access[.log").unwrap();
+        assert!(re.is_match("]access[.log"));
+        assert!(component_regex("[!]").unwrap().is_match("[!]"));
+        assert!(component_regex("[&&~^]").unwrap().is_match("^"));
+    }
+}
@@ -0,0 +1,455 @@
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+
+use once_cell::sync::Lazy;
+
+// Every gzip member starts with these two bytes.
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+const DEFLATE: u8 = 8;
+
+// Header flags.
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+// Back references can reach at most this far into the already decoded output.
+const WINDOW_SIZE: usize = 32 * 1024;
+
+const MAX_BITS: usize = 15;
+
+// Base lengths and extra bits for length codes 257 to 285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+// Base offsets and extra bits for distance codes 0 to 29.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// The order in which code length code lengths are stored in a dynamic block header.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+static CRC_TABLE: Lazy<[u32; 256]> = Lazy::new(|| {
+    let mut table = [0; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 == 1 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+        }
+        *entry = c;
+    }
+    table
+});
+
+// The fixed Huffman codes are the same for every stream so only build them once.
+static FIXED_CODES: Lazy<(Huffman, Huffman)> = Lazy::new(|| {
+    let mut lengths = [0; 288];
+    for (i, l) in lengths.iter_mut().enumerate() {
+        *l = match i {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+});
+
+/// Check the magic bytes at the start of a file to see if it is gzip compressed.
+pub fn is_compressed<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let mut magic = [0; 2];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Wrap the reader in a decoder if the data it holds is gzip compressed.
+pub fn maybe_decompress(mut input: Box<dyn BufRead>) -> io::Result<Box<dyn BufRead>> {
+    if input.fill_buf()?.starts_with(&MAGIC) {
+        Ok(Box::new(io::BufReader::new(GzDecoder::new(input))))
+    } else {
+        Ok(input)
+    }
+}
+
+// A canonical Huffman code stored as the number of codes of each length and the symbols ordered
+// by their code.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0; MAX_BITS + 1];
+        for &l in lengths {
+            counts[l as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0; MAX_BITS + 1];
+        for i in 1..MAX_BITS {
+            offsets[i + 1] = offsets[i] + counts[i];
+        }
+
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                symbols[offsets[l as usize] as usize] = symbol as u16;
+                offsets[l as usize] += 1;
+            }
+        }
+
+        Huffman { counts, symbols }
+    }
+}
+
+/// A streaming decoder for gzip files, including files made of several concatenated members.
+pub struct GzDecoder<R> {
+    input: R,
+    bit_buf: u64,
+    bit_count: u32,
+    // Decoded data, the first part of which is kept around as the window for back references.
+    out: Vec<u8>,
+    pos: usize,
+    // The number of bytes decoded and their checksum for the current member.
+    crc: u32,
+    size: u32,
+    state: State,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Header,
+    Block,
+    LastBlock,
+    Done,
+}
+
+impl<R: BufRead> GzDecoder<R> {
+    pub fn new(input: R) -> GzDecoder<R> {
+        GzDecoder {
+            input,
+            bit_buf: 0,
+            bit_count: 0,
+            out: Vec::with_capacity(WINDOW_SIZE * 4),
+            pos: 0,
+            crc: 0,
+            size: 0,
+            state: State::Header,
+        }
+    }
+
+    // Decode until there is some new output or the stream has ended.
+    fn fill(&mut self) -> io::Result<()> {
+        if self.out.len() > WINDOW_SIZE * 2 {
+            let excess = self.out.len() - WINDOW_SIZE;
+            self.out.drain(..excess);
+            self.pos -= excess;
+        }
+
+        while self.pos == self.out.len() {
+            match self.state {
+                State::Header => {
+                    if self.input.fill_buf()?.is_empty() {
+                        self.state = State::Done;
+                    } else {
+                        self.header()?;
+                        self.state = State::Block;
+                    }
+                }
+                State::Block => {
+                    let start = self.out.len();
+                    let last = self.block()?;
+                    self.update_crc(start);
+                    if last {
+                        self.state = State::LastBlock;
+                    }
+                }
+                State::LastBlock => {
+                    self.trailer()?;
+                    // Some tools produce gzip files with multiple members.
+                    self.state = State::Header;
+                }
+                State::Done => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut b = [0];
+        self.input.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.bit_count < n {
+            self.bit_buf |= u64::from(self.read_byte()?) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = (self.bit_buf & ((1 << n) - 1)) as u32;
+        self.bit_buf >>= n;
+        self.bit_count -= n;
+        Ok(value)
+    }
+
+    // Drop any remaining bits in the current byte.
+    fn align(&mut self) {
+        let skip = self.bit_count % 8;
+        self.bit_buf >>= skip;
+        self.bit_count -= skip;
+    }
+
+    fn aligned_byte(&mut self) -> io::Result<u8> {
+        if self.bit_count >= 8 {
+            Ok(self.bits(8)? as u8)
+        } else {
+            self.read_byte()
+        }
+    }
+
+    fn aligned_u32(&mut self) -> io::Result<u32> {
+        let mut value = 0;
+        for i in 0..4 {
+            value |= u32::from(self.aligned_byte()?) << (8 * i);
+        }
+        Ok(value)
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        let mut header = [0; 10];
+        self.input.read_exact(&mut header)?;
+        if header[..2] != MAGIC || header[2] != DEFLATE {
+            return Err(invalid("not a gzip stream"));
+        }
+
+        let flags = header[3];
+        if flags & FEXTRA != 0 {
+            let len = u16::from(self.read_byte()?) | u16::from(self.read_byte()?) << 8;
+            for _ in 0..len {
+                self.read_byte()?;
+            }
+        }
+        if flags & FNAME != 0 {
+            while self.read_byte()? != 0 {}
+        }
+        if flags & FCOMMENT != 0 {
+            while self.read_byte()? != 0 {}
+        }
+        if flags & FHCRC != 0 {
+            self.read_byte()?;
+            self.read_byte()?;
+        }
+
+        self.crc = 0;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn trailer(&mut self) -> io::Result<()> {
+        self.align();
+        let crc = self.aligned_u32()?;
+        let size = self.aligned_u32()?;
+        // Any bytes still buffered belong to the next member.
+        if self.bit_count != 0 {
+            return Err(invalid("unexpected data after deflate stream"));
+        }
+        if crc != self.crc || size != self.size {
+            return Err(invalid("gzip checksum mismatch"));
+        }
+        Ok(())
+    }
+
+    fn update_crc(&mut self, start: usize) {
+        let mut crc = !self.crc;
+        for &b in &self.out[start..] {
+            crc = CRC_TABLE[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8);
+        }
+        self.crc = !crc;
+        self.size = self.size.wrapping_add((self.out.len() - start) as u32);
+    }
+
+    // Decode a single deflate block, returning whether it was the final one.
+    fn block(&mut self) -> io::Result<bool> {
+        let last = self.bits(1)? == 1;
+        match self.bits(2)? {
+            0 => self.stored()?,
+            1 => {
+                let (lengths, distances) = &*FIXED_CODES;
+                self.codes(lengths, distances)?;
+            }
+            2 => {
+                let (lengths, distances) = self.dynamic_codes()?;
+                self.codes(&lengths, &distances)?;
+            }
+            _ => return Err(invalid("invalid deflate block type")),
+        }
+        Ok(last)
+    }
+
+    fn stored(&mut self) -> io::Result<()> {
+        self.align();
+        let len = self.aligned_byte()? as u16 | (self.aligned_byte()? as u16) << 8;
+        let nlen = self.aligned_byte()? as u16 | (self.aligned_byte()? as u16) << 8;
+        if len != !nlen {
+            return Err(invalid("invalid stored block length"));
+        }
+        for _ in 0..len {
+            let b = self.aligned_byte()?;
+            self.out.push(b);
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self, h: &Huffman) -> io::Result<u16> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..=MAX_BITS {
+            code |= self.bits(1)? as i32;
+            let count = i32::from(h.counts[len]);
+            if code - count < first {
+                return Ok(h.symbols[(index + (code - first)) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(invalid("invalid huffman code"))
+    }
+
+    fn dynamic_codes(&mut self) -> io::Result<(Huffman, Huffman)> {
+        let nlen = self.bits(5)? as usize + 257;
+        let ndist = self.bits(5)? as usize + 1;
+        let ncode = self.bits(4)? as usize + 4;
+        if nlen > 286 || ndist > 30 {
+            return Err(invalid("too many length or distance codes"));
+        }
+
+        let mut code_lengths = [0; 19];
+        for &i in CODE_LENGTH_ORDER.iter().take(ncode) {
+            code_lengths[i] = self.bits(3)? as u8;
+        }
+        let code_lengths = Huffman::new(&code_lengths);
+
+        let mut lengths = vec![0; nlen + ndist];
+        let mut i = 0;
+        while i < nlen + ndist {
+            let symbol = self.decode(&code_lengths)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 if i > 0 => (lengths[i - 1], 3 + self.bits(2)?),
+                17 => (0, 3 + self.bits(3)?),
+                18 => (0, 11 + self.bits(7)?),
+                _ => return Err(invalid("invalid code length repeat")),
+            };
+            for _ in 0..repeat {
+                if i == lengths.len() {
+                    return Err(invalid("too many code lengths"));
+                }
+                lengths[i] = value;
+                i += 1;
+            }
+        }
+
+        Ok((
+            Huffman::new(&lengths[..nlen]),
+            Huffman::new(&lengths[nlen..]),
+        ))
+    }
+
+    fn codes(&mut self, lengths: &Huffman, distances: &Huffman) -> io::Result<()> {
+        loop {
+            let symbol = self.decode(lengths)? as usize;
+            if symbol < 256 {
+                self.out.push(symbol as u8);
+            } else if symbol == 256 {
+                return Ok(());
+            } else {
+                let symbol = symbol - 257;
+                if symbol >= LENGTH_BASE.len() {
+                    return Err(invalid("invalid length symbol"));
+                }
+                let len = LENGTH_BASE[symbol] as usize
+                    + self.bits(u32::from(LENGTH_EXTRA[symbol]))? as usize;
+
+                let symbol = self.decode(distances)? as usize;
+                if symbol >= DIST_BASE.len() {
+                    return Err(invalid("invalid distance symbol"));
+                }
+                let dist =
+                    DIST_BASE[symbol] as usize + self.bits(u32::from(DIST_EXTRA[symbol]))? as usize;
+                if dist > self.out.len() {
+                    return Err(invalid("distance too far back"));
+                }
+
+                // The copy may overlap with itself so it has to be done a byte at a time.
+                let start = self.out.len() - dist;
+                for i in 0..len {
+                    let b = self.out[start + i];
+                    self.out.push(b);
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for GzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.out.len() {
+            self.fill()?;
+        }
+
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompresses_concatenated_members() {
+        // Two members produced by `printf 'GET /a\n' | gzip -n` and `printf 'GET /b\n' | gzip -n`.
+        let data: &[u8] = &[
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x73, 0x77, 0x0d, 0x51,
+            0xd0, 0x4f, 0xe4, 0x02, 0x00, 0x46, 0x7b, 0xd6, 0x81, 0x07, 0x00, 0x00, 0x00, 0x1f,
+            0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x73, 0x77, 0x0d, 0x51, 0xd0,
+            0x4f, 0xe2, 0x02, 0x00, 0x85, 0x28, 0xfb, 0xaa, 0x07, 0x00, 0x00, 0x00,
+        ];
+        let mut out = String::new();
+        GzDecoder::new(data).read_to_string(&mut out).unwrap();
+        assert_eq!(out, "GET /a\nGET /b\n");
+    }
+}
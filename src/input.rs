@@ -0,0 +1,472 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+
+use topngx::glob;
+use topngx::parse::lossy_lines;
+
+use super::follow::Follower;
+
+// Access logs on another machine start with this, as in ssh://user@host:/var/log/nginx/access.log.
+const SSH_PREFIX: &str = "ssh://";
+
+// The largest message a UDP datagram can hold, which is also the longest taken over TCP.
+const MAX_DATAGRAM: usize = 65_535;
+
+/// Somewhere new lines come from while following.
+pub(crate) trait LineSource {
+    /// Return every complete line that arrived since the last call without blocking.
+    fn read_lines(&mut self) -> Result<Vec<String>>;
+}
+
+impl LineSource for Follower {
+    fn read_lines(&mut self) -> Result<Vec<String>> {
+        Follower::read_lines(self)
+    }
+}
+
+/// The command that reads the entries of a systemd unit from the journal, printing only their
+/// messages. When following only new entries are printed, the same as with followed files.
+pub(crate) fn journalctl(unit: &str, follow: bool) -> Command {
+    let mut command = Command::new("journalctl");
+    command.args(["--output", "cat", "--no-pager", "--unit", unit]);
+    if follow {
+        command.args(["--follow", "--lines", "0"]);
+    }
+    command
+}
+
+/// Whether the access log is on another machine and has to be read over SSH.
+pub(crate) fn is_remote(access_log: &str) -> bool {
+    access_log.starts_with(SSH_PREFIX)
+}
+
+/// The command that reads a remote access log given as `ssh://[user@]host:/path`, tailing it from
+/// the end when following. An IPv6 host is given in brackets, as in `ssh://[::1]:/path`.
+pub(crate) fn ssh(access_log: &str, follow: bool) -> Result<Command> {
+    let (destination, path) = split_remote(access_log).ok_or_else(|| {
+        anyhow!(
+            "invalid remote log {}, expected ssh://[user@]host:/path",
+            access_log
+        )
+    })?;
+    // The path is quoted for the remote shell, so a pattern would only match itself there.
+    if glob::is_pattern(path) {
+        return Err(anyhow!(
+            "remote log {} can not be a glob pattern",
+            access_log
+        ));
+    }
+    let remote = if follow {
+        format!("tail -F -n 0 -- {}", shell_quote(path))
+    } else {
+        format!("cat -- {}", shell_quote(path))
+    };
+
+    let mut command = Command::new("ssh");
+    command.args(["--", &destination, &remote]);
+    Ok(command)
+}
+
+// Split a remote log into the destination ssh connects to and the path. The host ends at the first
+// colon unless it is in brackets, as IPv6 addresses have to be.
+fn split_remote(access_log: &str) -> Option<(String, &str)> {
+    let rest = access_log.strip_prefix(SSH_PREFIX)?;
+    let host_start = rest
+        .find(&['[', ':'][..])
+        .and_then(|i| rest[..i].rfind('@'))
+        .map_or(0, |i| i + 1);
+    let (user, rest) = rest.split_at(host_start);
+    let (host, path) = match rest.strip_prefix('[') {
+        Some(r) => r
+            .split_once(']')
+            .and_then(|(h, p)| Some((h, p.strip_prefix(':')?)))?,
+        None => rest.split_once(':')?,
+    };
+    if host.is_empty() || path.is_empty() {
+        return None;
+    }
+    Some((format!("{}{}", user, host), path))
+}
+
+// Quote a path for the remote shell so that it is passed as it is.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+// Start the command with its output piped back to us.
+fn spawn(command: &mut Command) -> Result<(Child, ChildStdout)> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {}", program))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("no output from {}", program))?;
+    Ok((child, stdout))
+}
+
+// Turn an unsuccessful exit into an error.
+fn check_exit(child: &mut Child) -> io::Result<()> {
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "input command failed with {}",
+            status
+        )))
+    }
+}
+
+/// Reads everything a command writes, failing at the end if the command did.
+pub(crate) struct CommandReader {
+    child: Child,
+    reader: BufReader<ChildStdout>,
+}
+
+impl CommandReader {
+    pub(crate) fn spawn(mut command: Command) -> Result<CommandReader> {
+        let (child, stdout) = spawn(&mut command)?;
+        Ok(CommandReader {
+            child,
+            reader: BufReader::new(stdout),
+        })
+    }
+}
+
+impl Read for CommandReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            check_exit(&mut self.child)?;
+        }
+        Ok(n)
+    }
+}
+
+impl BufRead for CommandReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.reader.fill_buf()?.is_empty() {
+            check_exit(&mut self.child)?;
+        }
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt)
+    }
+}
+
+/// Follows the output of a command that keeps running, such as `journalctl --follow`.
+pub(crate) struct CommandFollower {
+    child: Child,
+    lines: Receiver<String>,
+    done: bool,
+}
+
+impl CommandFollower {
+    pub(crate) fn spawn(mut command: Command) -> Result<CommandFollower> {
+        let (child, stdout) = spawn(&mut command)?;
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in lossy_lines(BufReader::new(stdout)) {
+                let sent = match line {
+                    Ok(line) => sender.send(line).is_ok(),
+                    Err(_) => false,
+                };
+                if !sent {
+                    return;
+                }
+            }
+        });
+
+        Ok(CommandFollower {
+            child,
+            lines,
+            done: false,
+        })
+    }
+}
+
+impl LineSource for CommandFollower {
+    fn read_lines(&mut self) -> Result<Vec<String>> {
+        let mut lines = vec![];
+        while !self.done {
+            match self.lines.try_recv() {
+                Ok(line) => lines.push(line),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.done = true;
+                    check_exit(&mut self.child)?;
+                }
+            }
+        }
+        Ok(lines)
+    }
+}
+
+impl Drop for CommandFollower {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Receives access log lines sent by NGINX with `access_log syslog:server=...`. Messages are
+/// accepted over both UDP and TCP on the same address.
+pub(crate) struct SyslogListener {
+    lines: Receiver<String>,
+}
+
+impl SyslogListener {
+    /// Bind to the address and start receiving messages in the background.
+    pub(crate) fn bind(address: &str) -> Result<SyslogListener> {
+        let udp = UdpSocket::bind(address)
+            .with_context(|| format!("failed to listen for syslog on udp {}", address))?;
+        let tcp = TcpListener::bind(address)
+            .with_context(|| format!("failed to listen for syslog on tcp {}", address))?;
+        info!("listening for syslog messages on {}", address);
+
+        let (sender, lines) = mpsc::channel();
+        let udp_sender = sender.clone();
+        thread::spawn(move || receive_udp(udp, udp_sender));
+        thread::spawn(move || accept_tcp(tcp, sender));
+
+        Ok(SyslogListener { lines })
+    }
+}
+
+impl LineSource for SyslogListener {
+    fn read_lines(&mut self) -> Result<Vec<String>> {
+        Ok(self.lines.try_iter().collect())
+    }
+}
+
+fn receive_udp(socket: UdpSocket, lines: Sender<String>) {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((n, _)) => {
+                let message = String::from_utf8_lossy(&buf[..n]);
+                if lines.send(strip_header(&message).to_string()).is_err() {
+                    return;
+                }
+            }
+            Err(e) => warn!("failed to receive syslog message: {}", e),
+        }
+    }
+}
+
+fn accept_tcp(listener: TcpListener, lines: Sender<String>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let lines = lines.clone();
+                thread::spawn(move || {
+                    if let Err(e) = receive_tcp(stream, lines) {
+                        debug!("syslog connection closed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("failed to accept syslog connection: {}", e),
+        }
+    }
+}
+
+// Messages over TCP are either split by newlines or prefixed with their length as in RFC 6587. A
+// client sending longer messages than a datagram could hold is disconnected.
+fn receive_tcp(stream: TcpStream, lines: Sender<String>) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let octet_counted = match reader.fill_buf()?.first() {
+            None => return Ok(()),
+            Some(b) => b.is_ascii_digit(),
+        };
+
+        let mut message = vec![];
+        let too_long = || anyhow!("syslog message over {} bytes", MAX_DATAGRAM);
+        if octet_counted {
+            // The digits of the longest message and the space after them.
+            let prefix = MAX_DATAGRAM.to_string().len() as u64 + 1;
+            reader
+                .by_ref()
+                .take(prefix)
+                .read_until(b' ', &mut message)?;
+            let len = String::from_utf8_lossy(&message).trim().parse::<usize>()?;
+            if len > MAX_DATAGRAM {
+                return Err(too_long());
+            }
+            message.clear();
+            reader.by_ref().take(len as u64).read_to_end(&mut message)?;
+        } else {
+            let limit = MAX_DATAGRAM as u64 + 1;
+            reader
+                .by_ref()
+                .take(limit)
+                .read_until(b'\n', &mut message)?;
+            if message.len() > MAX_DATAGRAM {
+                return Err(too_long());
+            }
+        }
+
+        let message = String::from_utf8_lossy(&message);
+        let message = strip_header(message.trim_end_matches(['\r', '\n']));
+        if !message.is_empty() && lines.send(message.to_string()).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Remove the RFC 3164 or RFC 5424 header from a syslog message, leaving the log line that was
+/// sent. Anything that does not start with a priority is returned as it is.
+pub(crate) fn strip_header(message: &str) -> &str {
+    let message = message.trim_end_matches(['\r', '\n']);
+    let rest = match message
+        .strip_prefix('<')
+        .and_then(|m| m.split_once('>'))
+        .filter(|(pri, _)| !pri.is_empty() && pri.bytes().all(|b| b.is_ascii_digit()))
+    {
+        Some((_, rest)) => rest,
+        None => return message,
+    };
+
+    // RFC 5424: VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+    if rest.starts_with("1 ") {
+        let mut fields = rest.splitn(7, ' ');
+        let structured = match fields.nth(6) {
+            Some(s) => s,
+            None => return "",
+        };
+        let msg = skip_structured_data(structured);
+        return msg.strip_prefix('\u{feff}').unwrap_or(msg);
+    }
+
+    // RFC 3164: "Jun  6 23:16:43 HOSTNAME TAG: MSG", where the timestamp is always 15 characters.
+    let rest = match rest.get(15..) {
+        Some(r) if rest.as_bytes().get(15) == Some(&b' ') => r.trim_start_matches(' '),
+        _ => return rest,
+    };
+    let rest = match rest.split_once(' ') {
+        Some((_, r)) => r,
+        None => return rest,
+    };
+    match rest.split_once(' ') {
+        Some((tag, msg)) if tag.ends_with(':') => msg,
+        _ => rest,
+    }
+}
+
+// Skip over "-" or one or more "[id param="value"]" elements, returning the message after them.
+fn skip_structured_data(s: &str) -> &str {
+    if let Some(msg) = s.strip_prefix('-') {
+        return msg.strip_prefix(' ').unwrap_or(msg);
+    }
+
+    let mut in_element = false;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' if in_element => quoted = !quoted,
+            '[' if !in_element => in_element = true,
+            ']' if in_element && !quoted => in_element = false,
+            ' ' if !in_element => return &s[i + 1..],
+            _ if !in_element => return &s[i..],
+            _ => {}
+        }
+    }
+    ""
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn ssh_commands() {
+        let command = ssh("ssh://deploy@web1:/var/log/nginx/it's.log", true).unwrap();
+        assert_eq!(command.get_program(), "ssh");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            vec![
+                "--",
+                "deploy@web1",
+                r"tail -F -n 0 -- '/var/log/nginx/it'\''s.log'"
+            ]
+        );
+        assert!(ssh("ssh://web1", false).is_err());
+
+        let destination = |log: &str| split_remote(log).map(|(d, p)| (d, p.to_string()));
+        assert_eq!(
+            destination("ssh://deploy@[::1]:/access.log"),
+            Some((String::from("deploy@::1"), String::from("/access.log")))
+        );
+        assert_eq!(
+            destination("ssh://[fe80::1]:/a:b.log"),
+            Some((String::from("fe80::1"), String::from("/a:b.log")))
+        );
+        assert_eq!(
+            destination("ssh://web1:/var/log/a@b.log"),
+            Some((String::from("web1"), String::from("/var/log/a@b.log")))
+        );
+        assert_eq!(destination("ssh://[::1]/access.log"), None);
+        assert_eq!(destination("ssh://deploy@:/access.log"), None);
+        assert!(ssh("ssh://web1:/var/log/nginx/*.log", false).is_err());
+        assert!(is_remote("ssh://web1:/access.log"));
+        assert!(!is_remote("/var/log/nginx/access.log"));
+    }
+
+    #[test]
+    fn syslog_over_tcp() {
+        let receive = |sent: &[u8]| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client.write_all(sent).unwrap();
+            drop(client);
+            let (tx, rx) = mpsc::channel();
+            let result = receive_tcp(listener.accept().unwrap().0, tx);
+            (result, rx.try_iter().collect::<Vec<String>>())
+        };
+
+        let (result, lines) = receive(b"5 first12 <190>second\nthird\n");
+        assert!(result.is_ok());
+        assert_eq!(lines, ["first", "second", "third"]);
+
+        // Lengths over the largest message, even ones too long to parse, drop the connection.
+        assert!(receive(b"65536 x").0.is_err());
+        assert!(receive(b"99999999999999999999999 x").0.is_err());
+        assert!(receive(&vec![b'x'; MAX_DATAGRAM + 1]).0.is_err());
+    }
+
+    #[test]
+    fn syslog_headers() {
+        let line =
+            r#"172.17.0.1 - - [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 612 "-" "curl""#;
+
+        let rfc3164 = format!("<190>Jun  6 23:16:43 web1 nginx: {}", line);
+        assert_eq!(strip_header(&rfc3164), line);
+        let rfc3164 = format!("<190>Jun 16 23:16:43 web1 nginx[42]: {}\n", line);
+        assert_eq!(strip_header(&rfc3164), line);
+
+        let rfc5424 = format!("<190>1 2020-06-06T23:16:43Z web1 nginx - - - {}", line);
+        assert_eq!(strip_header(&rfc5424), line);
+        let rfc5424 = format!(
+            r#"<190>1 2020-06-06T23:16:43Z web1 nginx 42 access [meta a="x\"]"][b] {}"#,
+            line
+        );
+        assert_eq!(strip_header(&rfc5424), line);
+
+        assert_eq!(strip_header(line), line);
+    }
+}
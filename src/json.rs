@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Parse a single JSON object into a map of its keys to their values. Strings are unescaped,
+/// numbers and booleans are kept as they were written, null values are left out, and nested arrays
+/// or objects are stored as their raw JSON text. Returns None if the line is not a valid object.
+pub(crate) fn parse_object(s: &str) -> Option<HashMap<String, String>> {
+    let mut chars = s.trim().chars().peekable();
+    let mut object = HashMap::new();
+
+    expect(&mut chars, '{')?;
+    skip_whitespace(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+        return end(&mut chars, object);
+    }
+
+    loop {
+        skip_whitespace(&mut chars);
+        expect(&mut chars, '"')?;
+        let key = parse_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        expect(&mut chars, ':')?;
+        skip_whitespace(&mut chars);
+        if let Some(value) = parse_value(&mut chars)? {
+            object.insert(key, value);
+        }
+        skip_whitespace(&mut chars);
+
+        match chars.next()? {
+            ',' => continue,
+            '}' => return end(&mut chars, object),
+            _ => return None,
+        }
+    }
+}
+
+/// Quote a string for use in JSON output, escaping it as needed.
+pub(crate) fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn end(
+    chars: &mut Peekable<Chars>,
+    object: HashMap<String, String>,
+) -> Option<HashMap<String, String>> {
+    skip_whitespace(chars);
+    match chars.next() {
+        None => Some(object),
+        Some(_) => None,
+    }
+}
+
+fn expect(chars: &mut Peekable<Chars>, c: char) -> Option<()> {
+    if chars.next()? == c {
+        Some(())
+    } else {
+        None
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+// The outer option signals a parse error while the inner one is None for a null value.
+fn parse_value(chars: &mut Peekable<Chars>) -> Option<Option<String>> {
+    match chars.peek()? {
+        '"' => {
+            chars.next();
+            parse_string(chars).map(Some)
+        }
+        '{' | '[' => parse_nested(chars).map(Some),
+        _ => {
+            let mut literal = String::new();
+            while let Some(&c) = chars.peek() {
+                if c == ',' || c == '}' || c.is_whitespace() {
+                    break;
+                }
+                literal.push(c);
+                chars.next();
+            }
+
+            match literal.as_str() {
+                "" => None,
+                "null" => Some(None),
+                _ => Some(Some(literal)),
+            }
+        }
+    }
+}
+
+// Called after the opening quote has been consumed.
+fn parse_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut s = String::new();
+
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => match chars.next()? {
+                'n' => s.push('\n'),
+                't' => s.push('\t'),
+                'r' => s.push('\r'),
+                'b' => s.push('\u{8}'),
+                'f' => s.push('\u{c}'),
+                'u' => {
+                    let high = parse_hex(chars)?;
+                    let code = if (0xd800..0xdc00).contains(&high) {
+                        // A surrogate pair has to be followed by its low half.
+                        expect(chars, '\\')?;
+                        expect(chars, 'u')?;
+                        let low = parse_hex(chars)?;
+                        0x10000 + ((high - 0xd800) << 10) + (low.checked_sub(0xdc00)?)
+                    } else {
+                        high
+                    };
+                    s.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                }
+                c => s.push(c),
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+fn parse_hex(chars: &mut Peekable<Chars>) -> Option<u32> {
+    let mut code = 0;
+    for _ in 0..4 {
+        code = code * 16 + chars.next()?.to_digit(16)?;
+    }
+    Some(code)
+}
+
+// Collect a nested array or object verbatim, keeping track of strings so brackets inside of them
+// are not counted.
+fn parse_nested(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut raw = String::new();
+    let mut depth = 0;
+    let mut in_string = false;
+
+    loop {
+        let c = chars.next()?;
+        raw.push(c);
+
+        if in_string {
+            match c {
+                '\\' => raw.push(chars.next()?),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(raw);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nginx_json_line() {
+        let line = r#"{"remote_addr":"172.17.0.1","request":"GET /a\"b HTTP/1.1","status": 200,"request_time":0.005,"http_referer":null,"tags":["a","]"],"ua":"caf\u00e9"}"#;
+        let object = parse_object(line).unwrap();
+
+        assert_eq!(object["remote_addr"], "172.17.0.1");
+        assert_eq!(object["request"], r#"GET /a"b HTTP/1.1"#);
+        assert_eq!(object["status"], "200");
+        assert_eq!(object["request_time"], "0.005");
+        assert!(!object.contains_key("http_referer"));
+        assert_eq!(object["tags"], r#"["a","]"]"#);
+        assert_eq!(object["ua"], "café");
+
+        assert!(parse_object("not json").is_none());
+        assert!(parse_object(r#"{"a":1} trailing"#).is_none());
+    }
+}
@@ -0,0 +1,102 @@
+//! Parse NGINX (or Apache) access logs and aggregate them with SQL, as done by the topngx binary.
+//!
+//! Lines are parsed with a [`nginx::LineParser`] into records that are stored in an in-memory
+//! SQLite table by a [`processor::Processor`], which then runs the report queries over them.
+//!
+//! ```
+//! use structopt::StructOpt;
+//! use topngx::enrich::Enrichment;
+//! use topngx::filter::RecordFilter;
+//! use topngx::nginx::LineParser;
+//! use topngx::options::Options;
+//! use topngx::parse::{parse_line, Unparsed};
+//! use topngx::processor::generate_processor;
+//!
+//! let opts = Options::from_iter(&["topngx", "--group-by", "request_method"]);
+//! let parser = LineParser::new(&opts.format).unwrap();
+//! let filter = RecordFilter::new(&opts).unwrap();
+//! let enrichment = Enrichment::new(&opts).unwrap();
+//! let unparsed = Unparsed::new(&opts);
+//! let processor = generate_processor(&opts, None, None).unwrap();
+//!
+//! let line = r#"127.0.0.1 - - [06/Jun/2020:23:16:43 +0000] "GET / HTTP/1.1" 200 612 "-" "curl""#;
+//! let fields = &processor.fields;
+//! let record = parse_line(line, &parser, &filter, &enrichment, &unparsed, fields).unwrap();
+//! assert_eq!(unparsed.count(), 0);
+//! processor.process(vec![record]).unwrap();
+//!
+//! let results = processor.results().unwrap();
+//! assert_eq!(results[1].columns[0], "request_method");
+//! ```
+
+mod aggregates;
+pub mod apache;
+pub mod cidr;
+pub mod config;
+mod dns;
+pub mod enrich;
+pub mod filter;
+pub mod geoip;
+pub mod glob;
+pub mod gzip;
+mod json;
+pub mod metrics;
+pub mod nginx;
+pub mod options;
+pub mod output;
+pub mod parse;
+mod paths;
+pub mod processor;
+pub mod queries;
+pub mod state;
+mod stream;
+mod timestamp;
+mod trend;
+pub mod ua;
+
+/// The class of the status code, such as 2 for any successful response.
+pub const STATUS_TYPE: &str = "status_type";
+/// The body bytes sent as a number.
+pub const BYTES_SENT: &str = "bytes_sent";
+/// The method from the request line.
+pub const REQUEST_METHOD: &str = "request_method";
+/// The request URI or the path from the request line.
+pub const REQUEST_PATH: &str = "request_path";
+/// The protocol from the request line.
+pub const REQUEST_PROTOCOL: &str = "request_protocol";
+/// The browser family from the User-Agent.
+pub const UA_BROWSER: &str = "ua_browser";
+/// The operating system from the User-Agent.
+pub const UA_OS: &str = "ua_os";
+/// Whether the User-Agent is a bot, as 1 or 0.
+pub const UA_IS_BOT: &str = "ua_is_bot";
+/// The time of the request as written by NGINX.
+pub const TIME_LOCAL: &str = "time_local";
+/// The time of the request in ISO 8601 format.
+pub const TIME_ISO8601: &str = "time_iso8601";
+/// The time of the request in seconds since the epoch, with milliseconds.
+pub const MSEC: &str = "msec";
+/// The time of an entry in the error log.
+pub const ERROR_TIME: &str = "time";
+/// The time of the request in UTC as "2006-01-02 15:04:05", which the SQLite date and time
+/// functions understand.
+pub const TIMESTAMP: &str = "timestamp";
+/// The country code of the client, looked up with the GeoIP database.
+pub const COUNTRY: &str = "country";
+/// The city of the client, looked up with the GeoIP database.
+pub const CITY: &str = "city";
+/// The number of the autonomous system of the client, looked up with the ASN database.
+pub const ASN: &str = "asn";
+/// The organization running the autonomous system of the client, such as a hosting provider.
+pub const AS_ORG: &str = "as_org";
+/// The virtual host of the request from $host, $server_name or the Host header, in lower case and
+/// without a port.
+pub const VHOST: &str = "vhost";
+/// The host of the page that referred the request, from the Referer header, in lower case and
+/// without the scheme, port or path.
+pub const REFERER_DOMAIN: &str = "referer_domain";
+/// How many upstream servers were tried for the request, counting the values of the upstream
+/// variables.
+pub const UPSTREAM_ATTEMPTS: &str = "upstream_attempts";
+/// The name of the route from the routes file that the request path matches.
+pub const ROUTE: &str = "route";
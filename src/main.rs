@@ -1,237 +1,1098 @@
 use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use log::{debug, info};
-use regex::Regex;
-use rusqlite::types::ToSql;
 use structopt::StructOpt;
 
-use nginx::{available_variables, format_to_pattern};
-use processor::{generate_processor, Processor};
+use topngx::config::Config;
+use topngx::enrich::{enriched_variables, Enrichment};
+use topngx::filter::RecordFilter;
+use topngx::metrics::{self, Metrics};
+use topngx::nginx::{
+    available_variables, error_log_variables, is_builtin_format, LineParser, NginxConfig, COMBINED,
+};
+use topngx::options::{
+    Apdex, Ban, Hist, Options, Query, Rate, Referrers, Security, Serve, Slow, SubCommand,
+    Timeseries, Top,
+};
+use topngx::output::OutputFormat;
+use topngx::parse::{
+    line_parser, lossy_lines, parse_input, parse_line, queryable_fields, request_time, Unparsed,
+};
+use topngx::processor::{
+    generate_processor, histogram_query, referenced_fields, Processor, Record,
+};
+use topngx::queries::{
+    aborts_report, apdex_report, backends_query, ban_query, cache_report, compression_report,
+    default_fields, expand_template, group_by, heavy_report, is_numeric, keepalive_report,
+    methods_query, numeric_where, order_by, profile_report, rate_report, referrers_query,
+    security_report, sizes_report, slow_query, table, time_field, timeseries_query, tls_report,
+    upstream_report, useragents_report, where_clause, REQUEST_TIME, UPSTREAM_ADDR,
+    UPSTREAM_CONNECT_TIME, UPSTREAM_HEADER_TIME, UPSTREAM_RESPONSE_TIME, UPSTREAM_STATUS,
+};
+use topngx::state::State;
+use topngx::{glob, gzip};
+use topngx::{
+    BYTES_SENT, REFERER_DOMAIN, REQUEST_METHOD, REQUEST_PATH, STATUS_TYPE, TIMESTAMP, UA_BROWSER,
+    UA_IS_BOT, UA_OS, VHOST,
+};
 
-mod nginx;
-mod processor;
+use follow::Follower;
+use input::{CommandFollower, CommandReader, LineSource, SyslogListener};
+use progress::Progress;
+use tui::Screen;
+
+mod follow;
+mod input;
+mod progress;
+mod shell;
+mod tui;
 
 const STDIN: &str = "STDIN";
 
-// Common field names.
-const STATUS_TYPE: &str = "status_type";
-const BYTES_SENT: &str = "bytes_sent";
-const REQUEST_PATH: &str = "request_path";
+// How much smaller gzip made the response.
+const GZIP_RATIO: &str = "gzip_ratio";
 
-#[derive(Debug, StructOpt)]
-#[structopt(
-    author,
-    name = "topngx",
-    about = "top for NGINX",
-    rename_all = "kebab-case"
-)]
-struct Options {
-    /// The access log to parse.
-    #[structopt(short, long)]
-    access_log: Option<String>,
+// The bytes received for the request, including the request line and headers.
+const REQUEST_LENGTH: &str = "request_length";
 
-    /// The specific log format with which to parse.
-    #[structopt(short, long, default_value = "combined")]
-    format: String,
+// The serial number of the connection and how many requests it has served.
+const CONNECTION: &str = "connection";
+const CONNECTION_REQUESTS: &str = "connection_requests";
 
-    /// Group by this variable.
-    #[structopt(short, long, default_value = "request_path")]
-    group_by: String,
+// Whether the request was pipelined, "p", or not, ".".
+const PIPE: &str = "pipe";
 
-    /// Having clause.
-    #[structopt(short = "w", long, default_value = "1")]
-    having: u64,
+// The TLS protocol and cipher negotiated for HTTPS requests.
+const SSL_PROTOCOL: &str = "ssl_protocol";
+const SSL_CIPHER: &str = "ssl_cipher";
 
-    /// Refresh the statistics using this interval which is given in seconds.
-    #[structopt(short = "t", long, conflicts_with = "no_follow", default_value = "2")]
-    interval: u64,
+// How proxy_cache served the request, such as HIT or MISS.
+const UPSTREAM_CACHE_STATUS: &str = "upstream_cache_status";
 
-    /// The number of records to limit for each query.
-    #[structopt(short, long, default_value = "10")]
-    limit: u64,
+// The systemd unit read from the journal unless another one is given.
+const DEFAULT_UNIT: &str = "nginx.service";
 
-    /// Do not tail the log file and only report what is currently there.
-    #[structopt(short, long)]
-    no_follow: bool,
+// How long to wait between checks for new lines when following a log.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
 
-    /// Order of output for the default queries.
-    #[structopt(short, long, default_value = "count")]
-    order_by: String,
+// Set by the signal handler when asked to stop following.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-    #[structopt(subcommand)]
-    subcommand: Option<SubCommand>,
-}
+// Fill in the access log and resolve named log formats using the NGINX configuration. The config
+// is only consulted when there is something that could not be determined from the arguments.
+fn apply_nginx_config(opts: &mut Options) -> Result<()> {
+    let needs_log =
+        opts.access_log.is_empty() && !other_inputs(opts) && atty::is(atty::Stream::Stdin);
+    // The error log always has the same format.
+    let needs_format = !opts.error_log && !is_builtin_format(&opts.format);
+    if !needs_log && !needs_format {
+        return Ok(());
+    }
 
-// The list of subcommands available to use.
-#[derive(Debug, StructOpt)]
-enum SubCommand {
-    /// Print the average of the given fields.
-    Avg(Fields),
+    let path = match &opts.nginx_config {
+        Some(p) => p.into(),
+        None => match NginxConfig::find_default() {
+            Some(p) => p,
+            None if needs_format => {
+                return Err(anyhow!("log format {} was not found", opts.format))
+            }
+            None => return Ok(()),
+        },
+    };
+    info!("nginx config: {}", path.display());
+    let config = NginxConfig::parse(&path)?;
+
+    if needs_log && opts.error_log {
+        if let Some(log) = config.error_logs.first() {
+            opts.access_log = vec![log.clone()];
+        }
+    } else if needs_log {
+        if let Some((log, format)) = config.access_logs.first() {
+            opts.access_log = vec![log.clone()];
+            // An explicitly chosen format takes precedence over the one in the config.
+            if opts.format == COMBINED {
+                opts.format = format.clone();
+            }
+        }
+    }
+    opts.format = config.resolve_format(&opts.format)?;
 
-    /// List the available fields as well as the access log and format being used.
-    Info,
+    Ok(())
+}
 
-    /// Print out the supplied fields with the given limit.
-    Print(Fields),
+// Either read from STDIN or the file specified, decompressing it if needed. Progress through files
+// is shown when standard error is a terminal.
+fn input_source(access_log: &str) -> Result<Box<dyn BufRead>> {
+    let input: Box<dyn BufRead> = if access_log == STDIN {
+        Box::new(BufReader::new(io::stdin()))
+    } else if input::is_remote(access_log) {
+        Box::new(CommandReader::spawn(input::ssh(access_log, false)?)?)
+    } else {
+        let file = File::open(access_log)?;
+        let size = file.metadata()?.len();
+        return file_source(access_log, file, size);
+    };
+    Ok(gzip::maybe_decompress(input)?)
+}
 
-    /// Supply a custom query.
-    Query(Query),
+// The state of how far the logs were read, when only what was added since the previous run is
+// read. The first run of --delta only notes where the logs end, so it reports nothing.
+fn load_state(opts: &Options) -> Result<Option<State>> {
+    let path = match &opts.state {
+        Some(p) => PathBuf::from(p),
+        None if opts.delta => State::default_path()
+            .ok_or_else(|| anyhow!("--delta needs --state when $HOME is not set"))?,
+        None => return Ok(None),
+    };
+    let mut state = State::load(path)?;
+    if opts.delta && state.is_first_run() {
+        eprintln!("the first run with --delta starts from the end of the logs");
+        state.skip_new_logs();
+    }
+    Ok(Some(state))
+}
 
-    /// Compute the sum of the given fields.
-    Sum(Fields),
+// Read what was added to a local log since the previous run that saved the state.
+fn resumed_source(access_log: &str, state: &mut State) -> Result<Box<dyn BufRead>> {
+    if access_log == STDIN || input::is_remote(access_log) {
+        return input_source(access_log);
+    }
+    let (file, size) = state.resume(access_log)?;
+    file_source(access_log, file, size)
+}
 
-    /// Find the top values for the given fields.
-    Top(Fields),
+// Read the bytes of a local log, showing the progress when it can be seen.
+fn file_source(access_log: &str, file: impl Read + 'static, size: u64) -> Result<Box<dyn BufRead>> {
+    if atty::is(atty::Stream::Stderr) {
+        return Ok(Progress::wrap(
+            access_log,
+            size,
+            file,
+            gzip::maybe_decompress,
+        )?);
+    }
+    Ok(gzip::maybe_decompress(Box::new(BufReader::new(file)))?)
 }
 
-#[derive(Debug, StructOpt)]
-struct Fields {
-    /// A space Separated list of field names.
-    fields: Vec<String>,
+// Expand any glob patterns in the given access logs.
+fn expand_access_logs(patterns: &[String]) -> Result<Vec<String>> {
+    let mut logs = vec![];
+    for pattern in patterns {
+        // Remote logs are passed on as they are.
+        if input::is_remote(pattern) {
+            logs.push(pattern.clone());
+            continue;
+        }
+        for path in glob::expand(pattern)? {
+            logs.push(path.to_string_lossy().into_owned());
+        }
+    }
+    Ok(logs)
 }
 
-#[derive(Debug, StructOpt)]
-struct Query {
-    /// A space separated list of field names.
-    #[structopt(short, long)]
-    fields: Vec<String>,
+// The access logs to read, falling back to STDIN when it is not a terminal. There may be none when
+// reading from the journal or listening for syslog messages instead.
+fn access_logs(opts: &Options) -> Result<Vec<String>> {
+    let access_logs = if !opts.access_log.is_empty() {
+        expand_access_logs(&opts.access_log)?
+    } else if other_inputs(opts) {
+        vec![]
+    } else if atty::isnt(atty::Stream::Stdin) {
+        vec![String::from(STDIN)]
+    } else {
+        return Err(anyhow!("STDIN is a TTY"));
+    };
+    info!("access logs: {}", access_logs.join(", "));
+    info!("access log format: {}", opts.format);
+
+    Ok(access_logs)
+}
 
-    /// The supplied query. You typically will want to use your shell to quote it.
-    #[structopt(short, long)]
-    query: String,
+// Standard input is always read until it is closed and compressed logs are never appended to, so
+// only plain files are followed.
+fn should_follow(opts: &Options, access_log: &str) -> Result<bool> {
+    if input::is_remote(access_log) {
+        return Ok(!opts.no_follow && !access_log.ends_with(".gz"));
+    }
+    Ok(access_log != STDIN
+        && !opts.no_follow
+        && opts.state.is_none()
+        && !opts.delta
+        && !gzip::is_compressed(access_log)?)
 }
 
-// Either read from STDIN or the file specified.
-fn input_source(opts: &Options, access_log: &str) -> Result<Box<dyn BufRead>> {
-    if access_log == STDIN {
-        Ok(Box::new(BufReader::new(io::stdin())))
-    } else if opts.no_follow {
-        Ok(Box::new(BufReader::new(File::open(access_log)?)))
+// Start following an access log, either locally or over SSH.
+fn follow(access_log: &str) -> Result<Box<dyn LineSource>> {
+    if input::is_remote(access_log) {
+        Ok(Box::new(CommandFollower::spawn(input::ssh(
+            access_log, true,
+        )?)?))
     } else {
-        Err(anyhow!("following log files is not currently implemented"))
+        Ok(Box::new(Follower::open(access_log)?))
+    }
+}
+
+// Whether lines come from somewhere other than the access logs.
+fn other_inputs(opts: &Options) -> bool {
+    opts.journal || opts.listen_syslog.is_some()
+}
+
+// Open the inputs besides the access logs, which are the journal and the syslog listener. Those that
+// are followed are added to the sources and the rest are passed to read in full.
+fn open_other_inputs(
+    opts: &Options,
+    names: &mut Vec<String>,
+    sources: &mut Vec<Box<dyn LineSource>>,
+    mut read: impl FnMut(Box<dyn BufRead>) -> Result<()>,
+) -> Result<()> {
+    if opts.journal {
+        let unit = opts.unit.as_deref().unwrap_or(DEFAULT_UNIT);
+        let command = input::journalctl(unit, !opts.no_follow);
+        if opts.no_follow {
+            read(Box::new(CommandReader::spawn(command)?))?;
+        } else {
+            sources.push(Box::new(CommandFollower::spawn(command)?));
+            names.push(format!("journal:{}", unit));
+        }
+    }
+    if let Some(address) = &opts.listen_syslog {
+        sources.push(Box::new(SyslogListener::bind(address)?));
+        names.push(format!("syslog://{}", address));
     }
+    Ok(())
+}
+
+// The number of threads to parse with, which is one per CPU unless given.
+fn jobs(opts: &Options) -> usize {
+    opts.jobs.unwrap_or_else(|| {
+        thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    })
 }
 
 fn run(opts: &Options, fields: Option<Vec<String>>, queries: Option<Vec<String>>) -> Result<()> {
-    let access_log = match &opts.access_log {
-        Some(l) => &l,
-        None => {
-            if atty::isnt(atty::Stream::Stdin) {
-                STDIN
-            } else {
-                return Err(anyhow!("STDIN is a TTY"));
-            }
+    let mut processor = generate_processor(opts, fields, queries)?;
+    let mut state = load_state(opts)?;
+    // The records are already in the database from an earlier run, and with the state only the
+    // lines added to the logs since are read into it.
+    if processor.existing() {
+        if opts.since.is_some() || opts.until.is_some() {
+            return Err(anyhow!(
+                "--since and --until only apply when parsing, not to an existing database"
+            ));
         }
+        if state.is_none() {
+            return processor.report();
+        }
+    }
+
+    let access_logs = access_logs(opts)?;
+    let parser = line_parser(opts)?;
+    let filter = RecordFilter::new(opts)?;
+    let enrichment = Enrichment::new(opts)?;
+    let unparsed = Unparsed::new(opts);
+    let jobs = jobs(opts);
+    // Lines that end up in a database but not in the state would be read into it again.
+    let checkpoint = match (&state, &opts.db) {
+        (Some(_), Some(_)) => Some(processor.checkpoint()?),
+        _ => None,
     };
-    info!("access log: {}", access_log);
-    info!("access log format: {}", opts.format);
 
-    let input = input_source(opts, access_log)?;
-    let pattern = format_to_pattern(&opts.format)?;
-    let processor = generate_processor(opts, fields, queries)?;
-    parse_input(input, &pattern, &processor)?;
-    processor.report()
-}
-
-fn parse_input(input: Box<dyn BufRead>, pattern: &Regex, processor: &Processor) -> Result<()> {
-    let mut records = vec![];
-
-    for line in input.lines() {
-        match pattern.captures(&line?) {
-            None => {}
-            Some(c) => {
-                let mut record: Vec<(String, Box<dyn ToSql>)> = vec![];
-
-                for field in &processor.fields {
-                    if field == STATUS_TYPE {
-                        let status = c.name("status").map_or("", |m| m.as_str());
-                        let status_type = status.parse::<u16>().unwrap_or(0) / 100;
-                        record.push((format!(":{}", field), Box::new(status_type)));
-                    } else if field == BYTES_SENT {
-                        let bytes_sent = c.name("body_bytes_sent").map_or("", |m| m.as_str());
-                        let bytes_sent = bytes_sent.parse::<u32>().unwrap_or(0);
-                        record.push((format!(":{}", field), Box::new(bytes_sent)));
-                    } else if field == REQUEST_PATH {
-                        if c.name("request_uri").is_some() {
-                            record.push((
-                                format!(":{}", field),
-                                Box::new(c.name("request_uri").unwrap().as_str().to_string()),
-                            ));
-                        } else {
-                            let uri = c.name("request").map_or("", |m| m.as_str());
-                            record.push((format!(":{}", field), Box::new(uri.to_string())));
-                        }
-                    } else {
-                        let value = c.name(field).map_or("", |m| m.as_str());
-                        record.push((format!(":{}", field), Box::new(String::from(value))));
-                    }
-                }
+    // Anything that is not followed is read in full up front.
+    let mut names = vec![];
+    let mut sources: Vec<Box<dyn LineSource>> = vec![];
+    for access_log in &access_logs {
+        if should_follow(opts, access_log)? {
+            sources.push(follow(access_log)?);
+            names.push(access_log.clone());
+        } else {
+            let input = match &mut state {
+                Some(s) => resumed_source(access_log, s)?,
+                None => input_source(access_log)?,
+            };
+            parse_input(
+                input,
+                &parser,
+                &filter,
+                &enrichment,
+                &unparsed,
+                &processor,
+                jobs,
+            )?;
+        }
+    }
+    open_other_inputs(opts, &mut names, &mut sources, |input| {
+        parse_input(
+            input,
+            &parser,
+            &filter,
+            &enrichment,
+            &unparsed,
+            &processor,
+            jobs,
+        )
+    })?;
+    report_unparsed(opts, &unparsed);
+
+    let reported = if !sources.is_empty() {
+        processor.set_trends(true);
+        follow_input(
+            &names.join(", "),
+            sources,
+            opts,
+            |l| {
+                parse_line(
+                    l,
+                    &parser,
+                    &filter,
+                    &enrichment,
+                    &unparsed,
+                    &processor.fields,
+                )
+            },
+            &unparsed,
+            &processor,
+        )
+    } else {
+        processor.report()
+    };
+    // Saved once the lines were reported on, so that a run that failed reads them again.
+    let saved = reported.and_then(|_| state.as_ref().map_or(Ok(()), |s| s.save()));
+    if let (Err(_), Some(row)) = (&saved, checkpoint) {
+        processor.rollback_to(row)?;
+    }
+    saved
+}
+
+// Say how many lines were skipped because they did not match the log format, and show the first
+// of them when asked to.
+fn report_unparsed(opts: &Options, unparsed: &Unparsed) {
+    let count = unparsed.count();
+    if count == 0 {
+        return;
+    }
+    if !opts.show_unparsed {
+        eprintln!(
+            "{} lines did not match the log format, show them with --show-unparsed",
+            count
+        );
+        return;
+    }
+    eprintln!("{} lines did not match the log format, including:", count);
+    for line in unparsed.samples() {
+        eprintln!("  {}", line);
+    }
+}
 
-                records.push(record);
+// Tail the access logs until interrupted, reporting on everything seen so far every interval. When
+// writing to a terminal the report is redrawn in place, otherwise each report is appended to the
+// output. Ctrl-C or SIGTERM ends it with a final report.
+fn follow_input(
+    title: &str,
+    mut sources: Vec<Box<dyn LineSource>>,
+    opts: &Options,
+    parse: impl Fn(&str) -> Option<Record>,
+    unparsed: &Unparsed,
+    processor: &Processor,
+) -> Result<()> {
+    let screen = if atty::is(atty::Stream::Stdout) && !processor.has_output_file() {
+        Some(Screen::new(title, opts.interval))
+    } else {
+        None
+    };
+    let interval = Duration::from_secs(opts.interval);
+    let mut last_report = Instant::now();
+    trap_interrupts();
+
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        for source in &mut sources {
+            let records = source
+                .read_lines()?
+                .iter()
+                .filter_map(|l| parse(l))
+                .collect();
+            processor.process(records)?;
+            unparsed.check()?;
+        }
+
+        if last_report.elapsed() >= interval {
+            match &screen {
+                Some(s) => s.draw(processor, unparsed.count())?,
+                None => processor.report()?,
             }
+            last_report = Instant::now();
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    debug!("interrupted, writing the final report");
+    match &screen {
+        Some(s) => s.draw(processor, unparsed.count())?,
+        None => processor.report()?,
+    }
+    report_unparsed(opts, unparsed);
+    Ok(())
+}
+
+// Catch SIGINT and SIGTERM so that following stops after the current refresh instead of the
+// process dying with nothing reported. A second signal kills it as usual, such as when the final
+// report hangs.
+fn trap_interrupts() {
+    extern "C" fn interrupted(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        // SAFETY: signal is async signal safe.
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGTERM, libc::SIG_DFL);
+        }
+    }
+
+    let handler = interrupted as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic and resets the signal, which are async signal
+    // safe.
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+// The fields that can be queried, which are all of them as far as is known when None.
+struct Available(Option<Vec<String>>);
+
+impl Available {
+    fn has(&self, field: &str) -> bool {
+        self.0.as_ref().is_none_or(|a| a.iter().any(|f| f == field))
+    }
+}
+
+// Check that the subcommand is given an access log whose format has the fields it needs, returning
+// the fields available for checking the ones it can do without.
+fn require_fields(opts: &Options, name: &str, needed: &[&str]) -> Result<Available> {
+    if opts.error_log {
+        return Err(anyhow!("{} only works with access logs", name));
+    }
+    let available = Available(queryable_fields(opts)?);
+
+    let mut missing: Vec<String> = vec![];
+    for field in needed.iter().filter(|f| !available.has(f)) {
+        let variable = format!("${}", source_variable(field));
+        if !missing.contains(&variable) {
+            missing.push(variable);
         }
     }
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "{} needs {} in the log format",
+            name,
+            missing.join(" and ")
+        ));
+    }
+    Ok(available)
+}
+
+// The variable of the log format that a field is taken from.
+fn source_variable(field: &str) -> &str {
+    match field {
+        BYTES_SENT => "body_bytes_sent",
+        REQUEST_METHOD | REQUEST_PATH => "request",
+        REFERER_DOMAIN => "http_referer",
+        UA_BROWSER | UA_IS_BOT | UA_OS => "http_user_agent",
+        f => f,
+    }
+}
 
-    processor.process(records)
+fn aborts_subcommand(opts: &Options) -> Result<()> {
+    let available = require_fields(opts, "aborts", &["status"])?;
+    let mut fields = vec![String::from("status"), REQUEST_PATH.to_string()];
+    if available.has(REQUEST_TIME) {
+        fields.push(REQUEST_TIME.to_string());
+    }
+    let queries = aborts_report(opts, available.has(REQUEST_TIME))?;
+    debug!("aborts sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
+
+fn apdex_subcommand(opts: &Options, apdex: &Apdex) -> Result<()> {
+    require_fields(opts, "apdex", &[REQUEST_TIME])?;
+    if !apdex.threshold.is_finite() || apdex.threshold <= 0.0 {
+        return Err(anyhow!("the Apdex threshold must be more than zero"));
+    }
+
+    let queries = apdex_report(opts, apdex.threshold)?;
+    let mut fields = vec![REQUEST_TIME.to_string()];
+    fields.extend(group_by(opts));
+    debug!("apdex sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
 }
 
 fn avg_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
     let avg_fields: Vec<String> = fields.iter().map(|f| format!("AVG({f})", f = f)).collect();
     let selections = avg_fields.join(", ");
-    let query = format!("SELECT {selections} FROM log", selections = selections);
+    let query = format!(
+        "SELECT {selections} FROM {table} {where_clause}",
+        selections = selections,
+        table = table(opts),
+        where_clause = where_clause(opts)
+    );
     debug!("average sub command query: {}", query);
     run(opts, Some(fields), Some(vec![query]))
 }
 
-fn info_subcommand(opts: &Options) -> Result<()> {
-    println!(
-        "access log file: {}",
-        opts.access_log
-            .clone()
-            .unwrap_or_else(|| String::from(STDIN))
+fn backends_subcommand(opts: &Options) -> Result<()> {
+    let available = require_fields(opts, "backends", &[UPSTREAM_ADDR])?;
+    let has_upstream_status = available.has(UPSTREAM_STATUS);
+    let time_field = [UPSTREAM_RESPONSE_TIME, REQUEST_TIME]
+        .iter()
+        .copied()
+        .find(|f| available.has(f));
+    let mut fields = vec![UPSTREAM_ADDR.to_string()];
+    fields.push(
+        if has_upstream_status {
+            UPSTREAM_STATUS
+        } else {
+            "status"
+        }
+        .to_string(),
     );
-    println!("access log format: {}", opts.format);
-    println!(
-        "available variables to query: {}",
-        available_variables(&opts.format)?
+    fields.extend(time_field.map(String::from));
+    let query = backends_query(opts, has_upstream_status, time_field)?;
+    debug!("backends sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn ban_subcommand(opts: &Options, ban: &Ban) -> Result<()> {
+    require_fields(opts, "ban", &["remote_addr"])?;
+    // Truncated or hashed addresses would ban the wrong clients, or make no sense as rules.
+    if opts.anonymize_ips.is_some() {
+        return Err(anyhow!("ban lists cannot be made with --anonymize-ips"));
+    }
+    let mut fields = vec![String::from("remote_addr")];
+    if ban.within.is_some() {
+        fields.push(time_field(opts, "ban --within")?.to_string());
+    }
+    let query = ban_query(opts, ban)?;
+    debug!("ban sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn cache_subcommand(opts: &Options) -> Result<()> {
+    require_fields(opts, "cache", &[UPSTREAM_CACHE_STATUS])?;
+    let fields = vec![UPSTREAM_CACHE_STATUS.to_string(), REQUEST_PATH.to_string()];
+    let queries = cache_report(opts)?;
+    debug!("cache sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
+
+fn compression_subcommand(opts: &Options) -> Result<()> {
+    require_fields(opts, "compression", &[GZIP_RATIO, BYTES_SENT])?;
+    let fields = [GZIP_RATIO, BYTES_SENT, REQUEST_PATH]
+        .iter()
+        .map(|f| f.to_string())
+        .collect();
+    let queries = compression_report(opts)?;
+    debug!("compression sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
+
+fn heavy_subcommand(opts: &Options) -> Result<()> {
+    let available = require_fields(opts, "heavy", &[BYTES_SENT])?;
+    let details: Vec<&str> = ["remote_addr", "status", TIMESTAMP]
+        .iter()
+        .copied()
+        .filter(|f| available.has(f))
+        .collect();
+    let mut fields = vec![BYTES_SENT.to_string(), REQUEST_PATH.to_string()];
+    fields.extend(details.iter().map(|d| d.to_string()));
+    let queries = heavy_report(opts, &details)?;
+    debug!("heavy sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
+
+fn hist_subcommand(opts: &Options, hist: &Hist) -> Result<()> {
+    let buckets = metrics::parse_buckets(&hist.buckets)?;
+    let query = histogram_query(
+        table(opts),
+        &hist.field,
+        &buckets,
+        &numeric_where(opts, &hist.field),
+        hist.bars,
     );
+    debug!("hist sub command query: {}", query);
+    run(opts, Some(vec![hist.field.clone()]), Some(vec![query]))
+}
+
+fn info_subcommand(opts: &Options) -> Result<()> {
+    let access_logs = if opts.access_log.is_empty() {
+        String::from(STDIN)
+    } else {
+        expand_access_logs(&opts.access_log)?.join(", ")
+    };
+    let mut variables = if opts.error_log {
+        println!("error log file: {}", access_logs);
+        println!("table: {}", table(opts));
+        error_log_variables()
+    } else {
+        println!("access log file: {}", access_logs);
+        println!("access log format: {}", opts.format);
+        vec![available_variables(&opts.format)?]
+    };
+    variables.extend(enriched_variables(opts));
+    println!("available variables to query: {}", variables.join(", "));
 
     Ok(())
 }
 
+fn keepalive_subcommand(opts: &Options) -> Result<()> {
+    let available = require_fields(opts, "keepalive", &[CONNECTION])?;
+    let mut fields = vec![CONNECTION.to_string()];
+    for field in &[CONNECTION_REQUESTS, "remote_addr", PIPE] {
+        if available.has(field) {
+            fields.push(field.to_string());
+        }
+    }
+    let queries = keepalive_report(
+        opts,
+        available.has(CONNECTION_REQUESTS),
+        available.has("remote_addr"),
+        available.has(PIPE),
+    )?;
+    debug!("keepalive sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
+
+fn max_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
+    let max_fields: Vec<String> = fields.iter().map(|f| format!("MAX({f})", f = f)).collect();
+    let selections = max_fields.join(", ");
+    let query = format!(
+        "SELECT {selections} FROM {table} {where_clause}",
+        selections = selections,
+        table = table(opts),
+        where_clause = where_clause(opts)
+    );
+    debug!("max sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn methods_subcommand(opts: &Options) -> Result<()> {
+    let available = require_fields(opts, "methods", &[REQUEST_METHOD, "status"])?;
+    let mut fields = vec![REQUEST_METHOD.to_string(), String::from("status")];
+    if available.has(REQUEST_TIME) {
+        fields.push(REQUEST_TIME.to_string());
+    }
+    let query = methods_query(opts, available.has(REQUEST_TIME))?;
+    debug!("methods sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn min_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
+    let min_fields: Vec<String> = fields.iter().map(|f| format!("MIN({f})", f = f)).collect();
+    let selections = min_fields.join(", ");
+    let query = format!(
+        "SELECT {selections} FROM {table} {where_clause}",
+        selections = selections,
+        table = table(opts),
+        where_clause = where_clause(opts)
+    );
+    debug!("min sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn percentile_subcommand(opts: &Options, fields: Vec<String>, percentiles: &str) -> Result<()> {
+    let mut selections = vec![];
+    for p in percentiles.split(',').map(str::trim) {
+        match p.parse::<f64>() {
+            Ok(n) if (0.0..=100.0).contains(&n) => {
+                for f in &fields {
+                    selections.push(format!(
+                        "percentile({f}, {n}) AS 'p{p}({f})'",
+                        f = f,
+                        n = n,
+                        p = p
+                    ));
+                }
+            }
+            _ => return Err(anyhow!("invalid percentile: {}", p)),
+        }
+    }
+
+    let query = format!(
+        "SELECT {selections} FROM {table} {where_clause}",
+        selections = selections.join(", "),
+        table = table(opts),
+        where_clause = where_clause(opts)
+    );
+    debug!("percentile sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
 fn print_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
     let selections = fields.join(", ");
     let query = format!(
-        "SELECT {selections} FROM log GROUP BY {selections}",
-        selections = selections
+        "SELECT {selections} FROM {table} {where_clause} GROUP BY {selections}",
+        selections = selections,
+        table = table(opts),
+        where_clause = where_clause(opts)
     );
     debug!("print sub command query: {}", query);
     run(opts, Some(fields), Some(vec![query]))
 }
 
-fn query_subcommand(opts: &Options, fields: Vec<String>, query: String) -> Result<()> {
+fn profile_subcommand(opts: &Options) -> Result<()> {
+    let time_field = time_field(opts, "profile")?;
+    let queries = profile_report(opts, time_field);
+    let mut fields = default_fields(opts, false);
+    fields.push(time_field.to_string());
+    debug!("profile sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
+
+fn query_subcommand(opts: &Options, config: &Config, q: &Query) -> Result<()> {
+    let template = match (&q.query, &q.name) {
+        (Some(query), _) => query.as_str(),
+        (None, Some(name)) => config
+            .query(name)
+            .ok_or_else(|| anyhow!("query {} was not found in the config", name))?,
+        (None, None) => return Err(anyhow!("either --query or --name is needed")),
+    };
+    let query = expand_template(template, opts, &q.params)?;
     debug!("custom query: {}", query);
+
+    // Without any fields store the ones the query uses, when the available ones are known. A query
+    // such as a plain count uses none, but the table still needs some columns.
+    let fields = match queryable_fields(opts)? {
+        Some(available) if q.fields.is_empty() => {
+            match referenced_fields(&query, Some(&available)) {
+                f if f.is_empty() => default_fields(opts, false),
+                f => f,
+            }
+        }
+        _ => q.fields.clone(),
+    };
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn rate_subcommand(opts: &Options, rate: &Rate) -> Result<()> {
+    let time_field = time_field(opts, "rate")?;
+    // The recent rate is only meaningful while new lines keep coming in.
+    let mut following = opts.listen_syslog.is_some() || (opts.journal && !opts.no_follow);
+    for access_log in &access_logs(opts)? {
+        following |= should_follow(opts, access_log)?;
+    }
+    let queries = rate_report(opts, time_field, Some(rate.window).filter(|_| following))?;
+    let mut fields = vec![time_field.to_string()];
+    fields.extend(group_by(opts));
+    debug!("rate sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
+
+fn referrers_subcommand(opts: &Options, referrers: &Referrers) -> Result<()> {
+    let available = require_fields(opts, "referrers", &[REFERER_DOMAIN])?;
+    // JSON logs may or may not have a host, which is simply NULL when they do not.
+    let has_vhost = available.has(VHOST);
+
+    let mut fields = vec![String::from(REFERER_DOMAIN)];
+    if has_vhost {
+        fields.push(String::from(VHOST));
+    }
+    let query = referrers_query(opts, &referrers.host, has_vhost)?;
+    debug!("referrers sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn security_subcommand(opts: &Options, security: &Security) -> Result<()> {
+    let needed = ["remote_addr", "status", REQUEST_METHOD, REQUEST_PATH];
+    require_fields(opts, "security", &needed)?;
+
+    let fields = needed.iter().map(|f| f.to_string()).collect();
+    let queries = security_report(opts, security.min_requests);
+    debug!("security sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
+
+fn serve_subcommand(opts: &Options, serve: &Serve) -> Result<()> {
+    require_fields(opts, "serve", &[])?;
+    let access_logs = access_logs(opts)?;
+    let parser = LineParser::new(&opts.format)?;
+    let filter = RecordFilter::new(opts)?;
+    let enrichment = Enrichment::new(opts)?;
+    let metrics = Arc::new(Mutex::new(Metrics::new(metrics::parse_buckets(
+        &serve.buckets,
+    )?)));
+    let server = metrics::serve(&serve.listen, Arc::clone(&metrics))?;
+
+    let observe = |line: &str| {
+        if let Some(c) = parser.parse(line).filter(|c| filter.matches(c)) {
+            metrics.lock().unwrap().observe(
+                &enrichment.request_path(&c),
+                c.get("status"),
+                c.get("body_bytes_sent"),
+                request_time(&c).as_deref(),
+            );
+        }
+    };
+
+    let mut names = vec![];
+    let mut sources: Vec<Box<dyn LineSource>> = vec![];
+    for access_log in &access_logs {
+        if should_follow(opts, access_log)? {
+            sources.push(follow(access_log)?);
+            names.push(access_log.clone());
+        } else {
+            for line in lossy_lines(input_source(access_log)?) {
+                observe(&line?);
+            }
+        }
+    }
+    open_other_inputs(opts, &mut names, &mut sources, |input| {
+        for line in lossy_lines(input) {
+            observe(&line?);
+        }
+        Ok(())
+    })?;
+
+    // Once everything has been read there is nothing left to do but keep serving it.
+    if sources.is_empty() {
+        server
+            .join()
+            .map_err(|_| anyhow!("metrics server panicked"))?;
+        return Ok(());
+    }
+
+    debug!("following {}", names.join(", "));
+    loop {
+        for source in &mut sources {
+            for line in source.read_lines()? {
+                observe(&line);
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn shell_subcommand(opts: &Options) -> Result<()> {
+    // Store every field so that anything can be asked of the table.
+    let fields = match queryable_fields(opts)? {
+        Some(f) => f,
+        None => default_fields(opts, false),
+    };
+    let processor = generate_processor(opts, Some(fields), Some(vec![]))?;
+
+    let mut log_on_stdin = false;
+    if !processor.existing() {
+        let parser = line_parser(opts)?;
+        let filter = RecordFilter::new(opts)?;
+        let enrichment = Enrichment::new(opts)?;
+        let unparsed = Unparsed::new(opts);
+        for access_log in access_logs(opts)? {
+            log_on_stdin |= access_log == STDIN;
+            let input = input_source(&access_log)?;
+            parse_input(
+                input,
+                &parser,
+                &filter,
+                &enrichment,
+                &unparsed,
+                &processor,
+                jobs(opts),
+            )?;
+        }
+        report_unparsed(opts, &unparsed);
+    }
+
+    shell::run(&processor, opts.output, log_on_stdin)
+}
+
+fn sizes_subcommand(opts: &Options) -> Result<()> {
+    require_fields(opts, "sizes", &[REQUEST_LENGTH, BYTES_SENT])?;
+    let fields = [REQUEST_LENGTH, BYTES_SENT, REQUEST_PATH]
+        .iter()
+        .map(|f| f.to_string())
+        .collect();
+    let queries = sizes_report(opts)?;
+    debug!("sizes sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
+
+fn slow_subcommand(opts: &Options, slow: &Slow) -> Result<()> {
+    require_fields(opts, "slow", &[REQUEST_TIME])?;
+    let fields = vec![REQUEST_TIME.to_string(), REQUEST_PATH.to_string()];
+    let query = slow_query(opts, slow.min_requests)?;
+    debug!("slow sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn stats_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
+    let mut selections = vec![];
+    for f in &fields {
+        // Values that were not logged as numbers, such as "-", are left out and counted as missing.
+        let v = format!("CASE WHEN {} THEN {} END", is_numeric(f), f);
+        selections.push(format!(
+            "SELECT '{f}' AS field, COUNT({v}) AS count, COUNT(1) - COUNT({v}) AS missing, \
+            AVG({v}) AS mean, median({v}) AS median, stddev({v}) AS stddev, MIN({v}) AS min, \
+            MAX({v}) AS max FROM {table} {where_clause}",
+            f = f,
+            v = v,
+            table = table(opts),
+            where_clause = where_clause(opts)
+        ));
+    }
+
+    let query = selections.join(" UNION ALL ");
+    debug!("stats sub command query: {}", query);
     run(opts, Some(fields), Some(vec![query]))
 }
 
 fn sum_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
     let sum_fields: Vec<String> = fields.iter().map(|f| format!("SUM({f})", f = f)).collect();
     let selections = sum_fields.join(", ");
-    let query = format!("SELECT {selections} FROM log", selections = selections);
+    let query = format!(
+        "SELECT {selections} FROM {table} {where_clause}",
+        selections = selections,
+        table = table(opts),
+        where_clause = where_clause(opts)
+    );
     debug!("sum sub command query: {}", query);
     run(opts, Some(fields), Some(vec![query]))
 }
 
-fn top_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
-    let mut queries = Vec::with_capacity(fields.len());
+fn timeseries_subcommand(opts: &Options, timeseries: &Timeseries) -> Result<()> {
+    let time_field = time_field(opts, "timeseries")?;
+    let query = timeseries_query(opts, time_field, &timeseries.granularity)?;
+    let mut fields = default_fields(opts, false);
+    fields.push(time_field.to_string());
+    debug!("timeseries sub command query: {}", query);
+    run(opts, Some(fields), Some(vec![query]))
+}
+
+fn tls_subcommand(opts: &Options) -> Result<()> {
+    let available = require_fields(opts, "tls", &[SSL_PROTOCOL])?;
+    let mut fields = vec![SSL_PROTOCOL.to_string()];
+    for field in &[SSL_CIPHER, "remote_addr"] {
+        if available.has(field) {
+            fields.push(field.to_string());
+        }
+    }
+    let queries = tls_report(
+        opts,
+        available.has(SSL_CIPHER),
+        available.has("remote_addr"),
+    )?;
+    debug!("tls sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
 
-    for f in &fields {
+fn top_subcommand(opts: &Options, top: &Top) -> Result<()> {
+    // What the values are ranked by: the requests, or the total of a field with --by.
+    let (weight, weight_column) = match &top.by {
+        Some(by) => {
+            let available = queryable_fields(opts)?;
+            if !available.is_none_or(|a| a.iter().any(|f| f == by)) {
+                return Err(anyhow!("--by needs ${} in the log format", by));
+            }
+            (format!("TOTAL({})", by), format!("sum_{}", by))
+        }
+        None => (String::from("COUNT(1)"), String::from("count")),
+    };
+
+    let mut fields = top.fields.clone();
+    let mut columns = String::from("COUNT(1) AS count");
+    if top.chart {
+        // Scaled so the largest value gets a bar 40 wide.
+        columns.push_str(&format!(
+            ", replace(hex(zeroblob(CAST(40.0 * {weight} / MAX({weight}) OVER () AS INTEGER))), \
+            '00', '#') AS chart",
+            weight = weight
+        ));
+    }
+    let mut selected = vec![String::from("count")];
+    let mut rest = String::from(if top.chart { ", chart" } else { "" });
+    let mut add_metric = |field: &str, column: &str, name: &str| {
+        if !fields.iter().any(|f| f == field) {
+            fields.push(field.to_string());
+        }
+        if selected.iter().any(|s| s == name) {
+            return;
+        }
+        columns.push_str(&format!(", {} AS {}", column, name));
+        rest.push_str(&format!(", {}", name));
+        selected.push(name.to_string());
+    };
+
+    if let Some(by) = &top.by {
+        add_metric(by, &format!("SUM({})", by), &weight_column);
+    }
+
+    if (top.all || top.bytes || top.errors || top.time) && opts.error_log {
+        return Err(anyhow!("the top metrics only work with access logs"));
+    }
+    if top.all || top.bytes {
+        add_metric(BYTES_SENT, "SUM(bytes_sent)", "sum_bytes_sent");
+    }
+    if top.all || top.time {
+        let has_request_time = queryable_fields(opts)?
+            .is_none_or(|available| available.iter().any(|v| v == REQUEST_TIME));
+        if has_request_time {
+            add_metric(REQUEST_TIME, "AVG(request_time)", "avg_request_time");
+        } else if top.time {
+            return Err(anyhow!("--time needs $request_time in the log format"));
+        }
+    }
+    if top.all || top.errors {
+        for class in &[4, 5] {
+            add_metric(
+                STATUS_TYPE,
+                &format!(
+                    "ROUND(100.0 * COUNT(CASE WHEN status_type = {} THEN 1 END) / COUNT(1), 2)",
+                    class
+                ),
+                &format!("pct_{}xx", class),
+            );
+        }
+    }
+
+    // The percentages are of the total weight. The cumulative percentage runs down the rows in the
+    // order they are shown, so the grouped counts are ordered again outside of it with the value
+    // breaking ties.
+    let mut queries = Vec::with_capacity(top.fields.len());
+    for f in &top.fields {
+        let order_by = format!(
+            "{}, {}",
+            order_by(
+                opts,
+                &[
+                    vec![f.clone()],
+                    selected.clone(),
+                    vec![String::from("percent")]
+                ]
+                .concat()
+            )?,
+            f
+        );
         let query = format!(
-            "SELECT {field}, COUNT(1) AS count FROM log \
-            GROUP BY {field} ORDER BY COUNT DESC LIMIT {limit}",
+            "SELECT {field}, count, percent, \
+            ROUND(100.0 * TOTAL({weight_column}) OVER (ORDER BY {order_by} ROWS UNBOUNDED PRECEDING) \
+            / TOTAL({weight_column}) OVER (), 2) AS cumulative_percent{rest} \
+            FROM (SELECT {field}, {columns}, \
+            ROUND(100.0 * {weight} / SUM({weight}) OVER (), 2) AS percent \
+            FROM {table} {where_clause} GROUP BY {field}) \
+            ORDER BY {order_by} LIMIT {limit}",
             field = f,
+            weight = weight,
+            weight_column = weight_column,
+            rest = rest,
+            columns = columns,
+            table = table(opts),
+            where_clause = where_clause(opts),
+            order_by = order_by,
             limit = opts.limit
         );
         debug!("top sub command query: {}", query);
@@ -241,20 +1102,103 @@ fn top_subcommand(opts: &Options, fields: Vec<String>) -> Result<()> {
     run(opts, Some(fields), Some(queries))
 }
 
+fn upstream_subcommand(opts: &Options) -> Result<()> {
+    let available = require_fields(opts, "upstream", &[UPSTREAM_RESPONSE_TIME])?;
+    let mut fields = vec![UPSTREAM_RESPONSE_TIME.to_string()];
+    for field in &[UPSTREAM_CONNECT_TIME, UPSTREAM_HEADER_TIME, UPSTREAM_ADDR] {
+        if available.has(field) {
+            fields.push(field.to_string());
+        }
+    }
+    let queries = upstream_report(
+        opts,
+        available.has(UPSTREAM_CONNECT_TIME),
+        available.has(UPSTREAM_HEADER_TIME),
+        available.has(UPSTREAM_ADDR),
+    )?;
+    debug!("upstream sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
+
+fn useragents_subcommand(opts: &Options) -> Result<()> {
+    require_fields(opts, "useragents", &[UA_BROWSER])?;
+    let fields = [UA_BROWSER, UA_OS, UA_IS_BOT]
+        .iter()
+        .map(|f| f.to_string())
+        .collect();
+    let queries = useragents_report(opts)?;
+    debug!("useragents sub command queries: {:?}", queries);
+    run(opts, Some(fields), Some(queries))
+}
+
 fn main() -> Result<()> {
     env_logger::init();
 
-    let opts = Options::from_args();
+    let matches = Options::clap().get_matches();
+    let mut opts = Options::from_clap(&matches);
+    let config = Config::load(opts.config.as_deref())?;
+    config.apply(&mut opts, &matches)?;
+    apply_nginx_config(&mut opts)?;
+    // Ban lists are fed to other programs, so they are plain lines unless asked otherwise.
+    let ban = matches!(opts.subcommand, Some(SubCommand::Ban(_)));
+    if ban && matches.occurrences_of("output") == 0 && opts.output == OutputFormat::Table {
+        opts.output = OutputFormat::Plain;
+    }
+    // Nor do they get a hostname column or a total row, which would not be rules.
+    if ban {
+        opts.resolve = false;
+        opts.totals = false;
+    }
+    // The slowest or heaviest values come first unless another order is asked for.
+    let ranked_by = match &opts.subcommand {
+        Some(SubCommand::Slow(_)) => Some(String::from("p95_request_time")),
+        Some(SubCommand::Heavy) => Some(String::from("sum_bytes_sent")),
+        Some(SubCommand::Top(top)) => top.by.as_ref().map(|by| format!("sum_{}", by)),
+        _ => None,
+    };
+    if let Some(column) = ranked_by {
+        if matches.occurrences_of("order-by") == 0 && opts.order_by == "count" {
+            opts.order_by = column;
+        }
+    }
     debug!("options: {:?}", opts);
 
     if let Some(sc) = &opts.subcommand {
         match sc {
+            SubCommand::Aborts => aborts_subcommand(&opts)?,
+            SubCommand::Apdex(a) => apdex_subcommand(&opts, a)?,
             SubCommand::Avg(f) => avg_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Backends => backends_subcommand(&opts)?,
+            SubCommand::Ban(b) => ban_subcommand(&opts, b)?,
+            SubCommand::Cache => cache_subcommand(&opts)?,
+            SubCommand::Compression => compression_subcommand(&opts)?,
+            SubCommand::Heavy => heavy_subcommand(&opts)?,
+            SubCommand::Hist(h) => hist_subcommand(&opts, h)?,
             SubCommand::Info => info_subcommand(&opts)?,
+            SubCommand::Keepalive => keepalive_subcommand(&opts)?,
+            SubCommand::Max(f) => max_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Methods => methods_subcommand(&opts)?,
+            SubCommand::Min(f) => min_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Percentile(p) => {
+                percentile_subcommand(&opts, p.fields.clone(), &p.percentiles)?
+            }
             SubCommand::Print(f) => print_subcommand(&opts, f.fields.clone())?,
-            SubCommand::Query(q) => query_subcommand(&opts, q.fields.clone(), q.query.clone())?,
+            SubCommand::Profile => profile_subcommand(&opts)?,
+            SubCommand::Query(q) => query_subcommand(&opts, &config, q)?,
+            SubCommand::Rate(r) => rate_subcommand(&opts, r)?,
+            SubCommand::Referrers(r) => referrers_subcommand(&opts, r)?,
+            SubCommand::Security(s) => security_subcommand(&opts, s)?,
+            SubCommand::Serve(s) => serve_subcommand(&opts, s)?,
+            SubCommand::Shell => shell_subcommand(&opts)?,
+            SubCommand::Sizes => sizes_subcommand(&opts)?,
+            SubCommand::Slow(slow) => slow_subcommand(&opts, slow)?,
+            SubCommand::Stats(f) => stats_subcommand(&opts, f.fields.clone())?,
             SubCommand::Sum(f) => sum_subcommand(&opts, f.fields.clone())?,
-            SubCommand::Top(f) => top_subcommand(&opts, f.fields.clone())?,
+            SubCommand::Timeseries(t) => timeseries_subcommand(&opts, t)?,
+            SubCommand::Tls => tls_subcommand(&opts)?,
+            SubCommand::Top(t) => top_subcommand(&opts, t)?,
+            SubCommand::Upstream => upstream_subcommand(&opts)?,
+            SubCommand::Useragents => useragents_subcommand(&opts)?,
         }
         return Ok(());
     }
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+
+use super::paths;
+
+/// The default request_time histogram buckets, in seconds.
+pub const DEFAULT_BUCKETS: &str = "0.005,0.01,0.025,0.05,0.1,0.25,0.5,1,2.5,5,10";
+
+// How long a scrape has to send its request, so that an idle connection does not hold up the
+// others, which are served one at a time.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parse a comma separated list of histogram buckets, which have to be increasing.
+pub fn parse_buckets(buckets: &str) -> Result<Vec<f64>> {
+    let mut parsed = vec![];
+    for b in buckets.split(',').map(str::trim) {
+        let bound = b
+            .parse::<f64>()
+            .ok()
+            .filter(|b| b.is_finite())
+            .ok_or_else(|| anyhow!("invalid bucket: {}", b))?;
+        if parsed.last().is_some_and(|&last| bound <= last) {
+            return Err(anyhow!("buckets must be in increasing order"));
+        }
+        parsed.push(bound);
+    }
+    Ok(parsed)
+}
+
+/// Counters and histograms aggregated from the access log in the Prometheus data model.
+pub struct Metrics {
+    buckets: Vec<f64>,
+    requests: BTreeMap<String, u64>,
+    bytes_sent: BTreeMap<String, u64>,
+    request_time: BTreeMap<String, Histogram>,
+}
+
+struct Histogram {
+    // Non cumulative counts for each bucket with the last one being +Inf.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Metrics {
+    pub fn new(buckets: Vec<f64>) -> Metrics {
+        Metrics {
+            buckets,
+            requests: BTreeMap::new(),
+            bytes_sent: BTreeMap::new(),
+            request_time: BTreeMap::new(),
+        }
+    }
+
+    /// Record a single request. Values that are missing or not numbers are not counted. The path is
+    /// labeled without its query string, which would make a series for every query.
+    pub fn observe(
+        &mut self,
+        path: &str,
+        status: Option<&str>,
+        bytes_sent: Option<&str>,
+        request_time: Option<&str>,
+    ) {
+        let status_class = match status.and_then(|s| s.parse::<u16>().ok()) {
+            Some(s) => format!("{}xx", s / 100),
+            None => String::from("unknown"),
+        };
+        *self.requests.entry(status_class.clone()).or_insert(0) += 1;
+
+        if let Some(bytes) = bytes_sent.and_then(|b| b.parse::<u64>().ok()) {
+            *self.bytes_sent.entry(status_class).or_insert(0) += bytes;
+        }
+
+        if let Some(t) = request_time.and_then(|t| t.parse::<f64>().ok()) {
+            let buckets = &self.buckets;
+            let histogram = self
+                .request_time
+                .entry(paths::strip_query(path).to_string())
+                .or_insert_with(|| Histogram {
+                    counts: vec![0; buckets.len() + 1],
+                    sum: 0.0,
+                });
+            let i = buckets
+                .iter()
+                .position(|&b| t <= b)
+                .unwrap_or(buckets.len());
+            histogram.counts[i] += 1;
+            histogram.sum += t;
+        }
+    }
+
+    /// Render every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP topngx_requests_total Requests by status class.\n");
+        out.push_str("# TYPE topngx_requests_total counter\n");
+        for (class, count) in &self.requests {
+            let _ = writeln!(
+                out,
+                "topngx_requests_total{{status=\"{}\"}} {}",
+                class, count
+            );
+        }
+
+        out.push_str("# HELP topngx_bytes_sent_total Response body bytes by status class.\n");
+        out.push_str("# TYPE topngx_bytes_sent_total counter\n");
+        for (class, bytes) in &self.bytes_sent {
+            let _ = writeln!(
+                out,
+                "topngx_bytes_sent_total{{status=\"{}\"}} {}",
+                class, bytes
+            );
+        }
+
+        out.push_str("# HELP topngx_request_time_seconds Request processing time by path.\n");
+        out.push_str("# TYPE topngx_request_time_seconds histogram\n");
+        for (path, h) in &self.request_time {
+            let path = escape_label(path);
+            let mut cumulative = 0;
+            for (i, count) in h.counts.iter().enumerate() {
+                cumulative += count;
+                let le = match self.buckets.get(i) {
+                    Some(b) => b.to_string(),
+                    None => String::from("+Inf"),
+                };
+                let _ = writeln!(
+                    out,
+                    "topngx_request_time_seconds_bucket{{path=\"{}\",le=\"{}\"}} {}",
+                    path, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "topngx_request_time_seconds_sum{{path=\"{}\"}} {}",
+                path, h.sum
+            );
+            let _ = writeln!(
+                out,
+                "topngx_request_time_seconds_count{{path=\"{}\"}} {}",
+                path, cumulative
+            );
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve the metrics on `/metrics` from a background thread.
+pub fn serve(addr: &str, metrics: Arc<Mutex<Metrics>>) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|s| handle(s, &metrics));
+            if let Err(e) = result {
+                warn!("failed to serve metrics request: {}", e);
+            }
+        }
+    }))
+}
+
+fn handle(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request_line)?;
+    debug!("metrics request: {}", request_line.trim_end());
+
+    // Drain the headers, we do not need any of them.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.lock().unwrap().render()),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_histograms() {
+        let mut metrics = Metrics::new(parse_buckets("0.1,1").unwrap());
+        metrics.observe("/a?q=1", Some("200"), Some("10"), Some("0.05"));
+        metrics.observe("/a", Some("503"), Some("-"), Some("2"));
+        metrics.observe("/b\"", Some("200"), Some("5"), None);
+
+        let out = metrics.render();
+        assert!(out.contains("topngx_requests_total{status=\"2xx\"} 2\n"));
+        assert!(out.contains("topngx_requests_total{status=\"5xx\"} 1\n"));
+        assert!(out.contains("topngx_bytes_sent_total{status=\"2xx\"} 15\n"));
+        assert!(out.contains("topngx_request_time_seconds_bucket{path=\"/a\",le=\"0.1\"} 1\n"));
+        assert!(out.contains("topngx_request_time_seconds_bucket{path=\"/a\",le=\"1\"} 1\n"));
+        assert!(out.contains("topngx_request_time_seconds_bucket{path=\"/a\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("topngx_request_time_seconds_sum{path=\"/a\"} 2.05\n"));
+        assert!(!out.contains("/b"));
+
+        assert!(parse_buckets("1,0.5").is_err());
+    }
+}
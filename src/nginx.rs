@@ -1,8 +1,19 @@
-use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info};
 use once_cell::sync::Lazy;
-use regex::Regex;
+use regex::{Captures, Regex};
+use rusqlite::types::Value;
+
+use super::{apache, glob, json};
 
-const COMBINED: &str = "combined";
+pub const COMBINED: &str = "combined";
+pub const JSON: &str = "json";
+pub const AWS_ALB: &str = "aws-alb";
+pub const HAPROXY: &str = "haproxy";
 const LOG_FORMAT_COMBINED: &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
 
 // We know that these patterns will compile.
@@ -10,11 +21,146 @@ static NGINX_VARIABLE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\$([a-zA-Z0
 static SPECIAL_CHARS_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"([\.\*\+\?\|\(\)\{\}\[\]])").unwrap());
 
-pub(crate) fn format_to_pattern(mut format: &str) -> Result<Regex> {
-    if format == COMBINED {
-        format = LOG_FORMAT_COMBINED;
+/// Whether the format is one we know about without needing to look it up.
+pub fn is_builtin_format(format: &str) -> bool {
+    format == COMBINED
+        || format == JSON
+        || format == AWS_ALB
+        || format == HAPROXY
+        || format.contains('$')
+        || apache::is_apache_format(format)
+}
+
+/// How each line of the access log is split up into its variables.
+pub enum LineParser {
+    /// A regular expression built from an NGINX log format.
+    Pattern(Regex),
+    /// One JSON object per line where each key is a variable.
+    Json,
+}
+
+impl LineParser {
+    pub fn new(format: &str) -> Result<LineParser> {
+        if format == JSON {
+            Ok(LineParser::Json)
+        } else {
+            Ok(LineParser::Pattern(format_to_pattern(format)?))
+        }
+    }
+
+    /// A parser for the NGINX error log rather than an access log.
+    pub fn error_log() -> LineParser {
+        LineParser::Pattern(ERROR_LOG_REGEX.clone())
+    }
+
+    /// Split a line into its variables, returning None if it does not match the format.
+    pub fn parse<'t>(&self, line: &'t str) -> Option<Variables<'t>> {
+        match self {
+            LineParser::Pattern(p) => p.captures(line).map(Variables::Captures),
+            LineParser::Json => json::parse_object(line).map(Variables::Json),
+        }
+    }
+}
+
+// Error log lines such as:
+// 2020/06/06 23:16:43 [error] 29#29: *1 open() "/x" failed (2: No such file or directory),
+// client: 172.17.0.1, server: localhost, request: "GET /x HTTP/1.1", host: "localhost"
+// where the context after the message is only there when the error is for a request.
+static ERROR_LOG_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(concat!(
+        r"^(?P<time>\d{4}/\d{2}/\d{2} \d{2}:\d{2}:\d{2}) \[(?P<level>\w+)\] ",
+        r"(?P<pid>\d+)#(?P<tid>\d+): (?:\*(?P<connection>\d+) )?(?P<message>.*?)",
+        r"(?:, client: (?P<client>[^,]*))?",
+        r"(?:, server: (?P<server>[^,]*))?",
+        r#"(?:, request: "(?P<request>[^"]*)")?"#,
+        r#"(?:, subrequest: "(?P<subrequest>[^"]*)")?"#,
+        r#"(?:, upstream: "(?P<upstream>[^"]*)")?"#,
+        r#"(?:, host: "(?P<host>[^"]*)")?"#,
+        r#"(?:, referrer: "(?P<referrer>[^"]*)")?$"#,
+    ))
+    .unwrap()
+});
+
+// AWS Application Load Balancer access logs such as:
+// http 2018-07-02T22:23:00.186641Z app/my-lb/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80
+// 0.000 0.001 0.000 200 200 34 366 "GET http://www.example.com:80/ HTTP/1.1" "curl/7.46.0" - -
+// arn:aws:...:targetgroup/my-targets/73e2d6bc24d8a067 "Root=1-58337262-36d228ad5d99923122bbe354"
+// "-" "-" 0 2018-07-02T22:22:48.364000Z "forward" "-" "-" "10.0.0.1:80" "200" "-" "-"
+// Fields are named after the NGINX variable with the same meaning where there is one. Newer
+// fields are optional and anything AWS appends later is ignored.
+static AWS_ALB_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(concat!(
+        r"^(?P<type>\S+) (?P<time_iso8601>\S+) (?P<elb>\S+) ",
+        r"(?P<remote_addr>\S+):(?P<remote_port>\d+) (?P<upstream_addr>\S+) ",
+        r"(?P<request_processing_time>\S+) (?P<upstream_response_time>\S+) ",
+        r"(?P<response_processing_time>\S+) (?P<status>\S+) (?P<upstream_status>\S+) ",
+        r"(?P<request_length>\S+) (?P<body_bytes_sent>\S+) ",
+        r#""(?P<request>[^"]*)" "(?P<http_user_agent>[^"]*)" "#,
+        r"(?P<ssl_cipher>\S+) (?P<ssl_protocol>\S+) (?P<target_group_arn>\S+) ",
+        r#""(?P<trace_id>[^"]*)" "(?P<ssl_server_name>[^"]*)" "(?P<chosen_cert_arn>[^"]*)" "#,
+        r"(?P<matched_rule_priority>\S+) (?P<request_creation_time>\S+) ",
+        r#""(?P<actions_executed>[^"]*)" "(?P<redirect_url>[^"]*)" "(?P<error_reason>[^"]*)""#,
+        r#"(?: "(?P<target_port_list>[^"]*)" "(?P<target_status_code_list>[^"]*)")?"#,
+        r#"(?: "(?P<classification>[^"]*)" "(?P<classification_reason>[^"]*)")?"#,
+    ))
+    .unwrap()
+});
+
+// HAProxy logs written with "option httplog", optionally still behind their syslog header:
+// Feb  6 12:14:14 localhost haproxy[14389]: 10.0.1.2:33317 [06/Feb/2009:12:14:14.655] http-in
+// static/srv1 10/0/30/69/109 200 2750 - - ---- 1/1/1/1/0 0/0 {1wt.eu} {} "GET /index.html HTTP/1.1"
+// The accept date is captured as $time_local. The timers are in milliseconds and the captured
+// headers are only there when configured.
+static HAPROXY_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(concat!(
+        r"^(?:.*?\]: )?(?P<remote_addr>\S+):(?P<remote_port>\d+) \[(?P<time_local>[^\]]+)\] ",
+        r"(?P<frontend_name>\S+) (?P<backend_name>[^/ ]+)/(?P<backend_server>\S+) ",
+        r"(?P<tq>-?\d+)/(?P<tw>-?\d+)/(?P<tc>-?\d+)/(?P<tr>-?\d+)/(?P<tt>\+?\d+) ",
+        r"(?P<status>-?\d+) (?P<body_bytes_sent>\+?\d+) ",
+        r"(?P<captured_request_cookie>\S+) (?P<captured_response_cookie>\S+) ",
+        r"(?P<termination_state>\S+) ",
+        r"(?P<actconn>\d+)/(?P<feconn>\d+)/(?P<beconn>\d+)/(?P<srv_conn>\d+)/(?P<retries>\+?\d+) ",
+        r"(?P<srv_queue>\d+)/(?P<backend_queue>\d+) ",
+        r"(?:\{(?P<captured_request_headers>[^}]*)\} )?",
+        r"(?:\{(?P<captured_response_headers>[^}]*)\} )?",
+        r#""(?P<request>[^"]*)""#,
+    ))
+    .unwrap()
+});
+
+/// The variables from a single parsed log line.
+pub enum Variables<'t> {
+    Captures(Captures<'t>),
+    Json(HashMap<String, String>),
+}
+
+impl Variables<'_> {
+    /// Look up the value of the given variable if it was present in the line.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match self {
+            Variables::Captures(c) => c.name(name).map(|m| m.as_str()),
+            Variables::Json(o) => o.get(name).map(String::as_str),
+        }
+    }
+}
+
+pub fn format_to_pattern(format: &str) -> Result<Regex> {
+    match format {
+        AWS_ALB => return Ok(AWS_ALB_REGEX.clone()),
+        HAPROXY => return Ok(HAPROXY_REGEX.clone()),
+        _ => {}
     }
 
+    let translated;
+    let format = if format == COMBINED {
+        LOG_FORMAT_COMBINED
+    } else if apache::is_apache_format(format) {
+        translated = apache::to_nginx_format(format)?;
+        &translated
+    } else {
+        format
+    };
+
     // Escape all of the existing special characters.
     let pattern = SPECIAL_CHARS_REGEX.replace_all(format, r"\$1");
 
@@ -23,26 +169,389 @@ pub(crate) fn format_to_pattern(mut format: &str) -> Result<Regex> {
     Ok(Regex::new(&captures)?)
 }
 
+/// The locations NGINX is usually installed with, checked in order when no configuration is given.
+const DEFAULT_CONFIG_PATHS: &[&str] = &[
+    "/etc/nginx/nginx.conf",
+    "/usr/local/etc/nginx/nginx.conf",
+    "/usr/local/nginx/conf/nginx.conf",
+];
+
+/// The parts of an NGINX configuration that describe logging.
+#[derive(Debug, Default)]
+pub struct NginxConfig {
+    /// Named formats declared with `log_format`.
+    pub log_formats: HashMap<String, String>,
+    /// Each `access_log` path along with the name of the format it is written in.
+    pub access_logs: Vec<(String, String)>,
+    /// Each `error_log` written to a file.
+    pub error_logs: Vec<String>,
+}
+
+impl NginxConfig {
+    /// Parse the configuration file at the given path, following any `include` directives.
+    pub fn parse<P: AsRef<Path>>(path: P) -> Result<NginxConfig> {
+        let mut config = NginxConfig::default();
+        config.parse_file(path.as_ref())?;
+        Ok(config)
+    }
+
+    /// Return the first configuration file found in the usual install locations.
+    pub fn find_default() -> Option<PathBuf> {
+        DEFAULT_CONFIG_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .find(|p| p.is_file())
+    }
+
+    fn parse_file(&mut self, path: &Path) -> Result<()> {
+        debug!("parsing nginx config: {}", path.display());
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read {}: {}", path.display(), e))?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        self.parse_str(&contents, base)
+    }
+
+    fn parse_str(&mut self, contents: &str, base: &Path) -> Result<()> {
+        for directive in directives(contents) {
+            match directive[0].as_str() {
+                "log_format" if directive.len() > 2 => {
+                    // Skip any parameters such as escape=json before the format strings.
+                    let format = directive[2..]
+                        .iter()
+                        .filter(|a| !a.starts_with("escape="))
+                        .map(String::as_str)
+                        .collect::<String>();
+                    // Formats that write JSON objects are parsed by their keys instead.
+                    let format = if format.trim_start().starts_with('{') {
+                        String::from(JSON)
+                    } else {
+                        format
+                    };
+                    self.log_formats.insert(directive[1].clone(), format);
+                }
+                "access_log" if directive.len() > 1 => {
+                    let path = &directive[1];
+                    if path == "off" || path.starts_with("syslog:") || path.contains('$') {
+                        continue;
+                    }
+                    let format = directive
+                        .get(2)
+                        .filter(|f| !f.contains('='))
+                        .map_or(COMBINED, |f| f.as_str());
+                    self.access_logs.push((path.clone(), format.to_string()));
+                }
+                "error_log" if directive.len() > 1 => {
+                    let path = &directive[1];
+                    if path == "stderr"
+                        || path.starts_with("syslog:")
+                        || path.starts_with("memory:")
+                    {
+                        continue;
+                    }
+                    self.error_logs.push(path.clone());
+                }
+                "include" if directive.len() > 1 => {
+                    for include in expand_include(base, &directive[1]) {
+                        self.parse_file(&include)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Map a format name to its definition. Built in and literal formats are returned untouched.
+    pub fn resolve_format(&self, format: &str) -> Result<String> {
+        if is_builtin_format(format) {
+            return Ok(format.to_string());
+        }
+
+        match self.log_formats.get(format) {
+            Some(f) => {
+                info!("using log format {} from nginx config", format);
+                Ok(f.clone())
+            }
+            None => Err(anyhow!("log format {} was not found", format)),
+        }
+    }
+}
+
+// Split the configuration into directives, each being a list of its name and arguments. Blocks are
+// flattened since we only care about where a directive appears, not which context it is in.
+fn directives(contents: &str) -> Vec<Vec<String>> {
+    let mut directives = vec![];
+    let mut current: Vec<String> = vec![];
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            ';' | '{' | '}' => {
+                if !current.is_empty() {
+                    directives.push(std::mem::take(&mut current));
+                }
+            }
+            '\'' | '"' => {
+                let mut token = String::new();
+                while let Some(n) = chars.next() {
+                    if n == c {
+                        break;
+                    } else if n == '\\' {
+                        if let Some(escaped) = chars.next() {
+                            token.push(escaped);
+                        }
+                    } else {
+                        token.push(n);
+                    }
+                }
+                current.push(token);
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut token = c.to_string();
+                while let Some(&n) = chars.peek() {
+                    if n.is_whitespace() || n == ';' || n == '{' || n == '}' {
+                        break;
+                    }
+                    token.push(n);
+                    chars.next();
+                }
+                current.push(token);
+            }
+        }
+    }
+
+    directives
+}
+
+// Resolve an include relative to the including file, expanding any wildcards in it. Like NGINX, a
+// wildcard that does not match anything is not an error.
+fn expand_include(base: &Path, include: &str) -> Vec<PathBuf> {
+    let path = base.join(include);
+    glob::expand(&path.to_string_lossy()).unwrap_or_default()
+}
+
+/// Split a request line such as "GET /index.html HTTP/1.1" into its method, path and protocol.
+/// Anything that does not look like a request line is returned as the path.
+pub fn split_request(request: &str) -> (&str, &str, &str) {
+    let mut parts = request.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), protocol) => (method, path, protocol.unwrap_or("")),
+        _ => ("", request, ""),
+    }
+}
+
 // List the available variables based on the supplied log format.
-pub(crate) fn available_variables(format: &str) -> Result<String> {
-    Ok(format_to_pattern(format)?
-        .capture_names()
-        .filter_map(|c| match c {
-            Some(n) => {
-                // Make some adjustments based on the schema.
-                if n == "status" {
-                    return Some(String::from(super::STATUS_TYPE));
-                } else if n == "body_bytes_sent" {
-                    return Some(String::from(super::BYTES_SENT));
-                } else if n == "request" {
-                    return Some(String::from(super::REQUEST_PATH));
-                }
-                Some(n.to_string())
-            }
-            None => None,
-        })
-        .collect::<Vec<String>>()
-        .join(", "))
+pub fn available_variables(format: &str) -> Result<String> {
+    if format == JSON {
+        return Ok(format!(
+            "any key in the JSON objects, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}",
+            super::REQUEST_METHOD,
+            super::REQUEST_PATH,
+            super::REQUEST_PROTOCOL,
+            super::UA_BROWSER,
+            super::UA_OS,
+            super::UA_IS_BOT,
+            super::STATUS_TYPE,
+            super::BYTES_SENT,
+            super::VHOST,
+            super::TIMESTAMP,
+            super::REFERER_DOMAIN,
+            super::UPSTREAM_ATTEMPTS
+        ));
+    }
+
+    // Both the raw variables and the ones derived from them can be queried.
+    Ok(queryable_variables(format)?.unwrap_or_default().join(", "))
+}
+
+/// List every variable that can be stored for the format, which includes both the raw variables
+/// and the fields derived from them. JSON logs can have any key so None is returned for them.
+pub fn queryable_variables(format: &str) -> Result<Option<Vec<String>>> {
+    if format == JSON {
+        return Ok(None);
+    }
+
+    let mut variables = vec![];
+    for name in format_to_pattern(format)?.capture_names().flatten() {
+        variables.push(name.to_string());
+        match name {
+            "status" => variables.push(String::from(super::STATUS_TYPE)),
+            "body_bytes_sent" => variables.push(String::from(super::BYTES_SENT)),
+            "request" => variables.extend(
+                [
+                    super::REQUEST_METHOD,
+                    super::REQUEST_PATH,
+                    super::REQUEST_PROTOCOL,
+                ]
+                .iter()
+                .map(|v| v.to_string()),
+            ),
+            "request_uri" => variables.push(String::from(super::REQUEST_PATH)),
+            "request_time_us" => variables.push(String::from("request_time")),
+            "server_protocol" => variables.push(String::from(super::REQUEST_PROTOCOL)),
+            "http_user_agent" => variables.extend(
+                [super::UA_BROWSER, super::UA_OS, super::UA_IS_BOT]
+                    .iter()
+                    .map(|v| v.to_string()),
+            ),
+            "host" | "server_name" | "http_host" => variables.push(String::from(super::VHOST)),
+            "http_referer" => variables.push(String::from(super::REFERER_DOMAIN)),
+            "upstream_addr" | "upstream_status" | "upstream_response_time" => {
+                variables.push(String::from(super::UPSTREAM_ATTEMPTS))
+            }
+            "time_local" | "time_iso8601" | "msec" => {
+                variables.push(String::from(super::TIMESTAMP))
+            }
+            _ => {}
+        }
+    }
+    // Keep the first occurrence of each since a derived field can also be logged directly.
+    let mut seen = HashSet::new();
+    variables.retain(|v| seen.insert(v.clone()));
+
+    Ok(Some(variables))
+}
+
+/// List the variables that can be queried in error logs, including the fields derived from the
+/// request.
+pub fn error_log_variables() -> Vec<String> {
+    let mut variables = vec![];
+    for name in ERROR_LOG_REGEX.capture_names().flatten() {
+        variables.push(name.to_string());
+        if name == super::ERROR_TIME {
+            variables.push(String::from(super::TIMESTAMP));
+        }
+        if name == "referrer" {
+            variables.push(String::from(super::REFERER_DOMAIN));
+        }
+        if name == "request" {
+            variables.extend(
+                [
+                    super::REQUEST_METHOD,
+                    super::REQUEST_PATH,
+                    super::REQUEST_PROTOCOL,
+                ]
+                .iter()
+                .map(|v| v.to_string()),
+            );
+        }
+    }
+    variables
+}
+
+/// The values of an upstream variable for each upstream tried. NGINX separates the servers of a
+/// group with commas and the groups of internal redirects with colons.
+pub fn upstream_values(raw: &str) -> impl Iterator<Item = &str> {
+    raw.split(", ").flat_map(|v| v.split(" : ")).map(str::trim)
+}
+
+/// The SQLite type a variable is stored as so that it sorts and aggregates numerically.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Integer,
+    Real,
+    Text,
+}
+
+impl ColumnType {
+    /// The type of the given variable, falling back to text for anything that is not known.
+    pub fn of(field: &str) -> ColumnType {
+        match field {
+            "status"
+            | "body_bytes_sent"
+            | "bytes_sent"
+            | "request_length"
+            | "connection"
+            | "connection_requests"
+            | "content_length"
+            | "remote_port"
+            | "server_port"
+            | "pid"
+            | "tid"
+            | "status_type"
+            | "upstream_status"
+            | "tq"
+            | "tw"
+            | "tc"
+            | "tr"
+            | "tt"
+            | "actconn"
+            | "feconn"
+            | "beconn"
+            | "srv_conn"
+            | "retries"
+            | "srv_queue"
+            | "backend_queue"
+            | "ua_is_bot"
+            | "asn"
+            | "upstream_attempts"
+            | "request_time_us" => ColumnType::Integer,
+            "request_time"
+            | "request_processing_time"
+            | "response_processing_time"
+            | "upstream_response_time"
+            | "upstream_connect_time"
+            | "upstream_header_time"
+            | "msec"
+            | "gzip_ratio" => ColumnType::Real,
+            _ => ColumnType::Text,
+        }
+    }
+
+    /// The declared type used when creating the table.
+    pub fn sql(self) -> &'static str {
+        match self {
+            ColumnType::Integer => "INTEGER",
+            ColumnType::Real => "REAL",
+            ColumnType::Text => "TEXT",
+        }
+    }
+
+    /// Convert a logged value to this type. Numbers that were not logged, which NGINX writes as
+    /// "-", are NULL so that aggregates skip them. Other values that do not parse are kept as text.
+    /// The upstream variables hold a value for each upstream tried, such as "0.004, 0.132 : 0.245",
+    /// which add up to the total time while the last status is the one that counted.
+    pub fn value(self, raw: &str) -> Value {
+        if self != ColumnType::Text && (raw == "-" || raw.is_empty()) {
+            return Value::Null;
+        }
+        if self != ColumnType::Text && (raw.contains(", ") || raw.contains(" : ")) {
+            let values: Vec<Value> = upstream_values(raw)
+                .map(|v| self.value(v))
+                .filter(|v| *v != Value::Null)
+                .collect();
+            return match self {
+                ColumnType::Real => match values.iter().try_fold(0.0, |sum, v| match v {
+                    Value::Real(r) => Some(sum + r),
+                    _ => None,
+                }) {
+                    Some(sum) if !values.is_empty() => Value::Real(sum),
+                    _ => Value::Text(raw.to_string()),
+                },
+                _ => values
+                    .into_iter()
+                    .last()
+                    .unwrap_or_else(|| Value::Text(raw.to_string())),
+            };
+        }
+        let parsed = match self {
+            ColumnType::Integer => raw.parse::<i64>().ok().map(Value::Integer),
+            ColumnType::Real => raw
+                .parse::<f64>()
+                .ok()
+                .filter(|f| f.is_finite())
+                .map(Value::Real),
+            ColumnType::Text => None,
+        };
+        parsed.unwrap_or_else(|| Value::Text(raw.to_string()))
+    }
 }
 
 #[cfg(test)]
@@ -55,4 +564,173 @@ mod tests {
         let pattern = format_to_pattern(LOG_FORMAT_COMBINED).unwrap();
         assert!(pattern.captures(line).is_some());
     }
+
+    #[test]
+    fn error_log_lines() {
+        let parser = LineParser::error_log();
+        let line = r#"2020/06/06 23:16:43 [error] 29#29: *1 open() "/usr/share/nginx/html/x" failed (2: No such file or directory), client: 172.17.0.1, server: localhost, request: "GET /x HTTP/1.1", host: "localhost:8080""#;
+        let c = parser.parse(line).unwrap();
+        assert_eq!(c.get("level"), Some("error"));
+        assert_eq!(c.get("connection"), Some("1"));
+        assert_eq!(
+            c.get("message"),
+            Some(r#"open() "/usr/share/nginx/html/x" failed (2: No such file or directory)"#)
+        );
+        assert_eq!(c.get("client"), Some("172.17.0.1"));
+        assert_eq!(c.get("request"), Some("GET /x HTTP/1.1"));
+        assert_eq!(c.get("host"), Some("localhost:8080"));
+
+        let c = parser
+            .parse("2020/06/06 23:16:40 [notice] 1#1: start worker processes")
+            .unwrap();
+        assert_eq!(c.get("message"), Some("start worker processes"));
+        assert_eq!(c.get("client"), None);
+    }
+
+    #[test]
+    fn aws_alb_lines() {
+        let parser = LineParser::new(AWS_ALB).unwrap();
+        let line = r#"https 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.086 0.048 0.037 200 200 0 57 "GET https://www.example.com:443/a?b=c HTTP/1.1" "curl/7.46.0" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 "Root=1-58337281-1d84f3d73c47ec4e58577259" "www.example.com" "arn:aws:acm:us-east-2:123456789012:certificate/12345678-1234-1234-1234-123456789012" 1 2018-07-02T22:22:48.364000Z "authenticate,forward" "-" "-" "10.0.0.1:80" "200" "-" "-" TID_1234"#;
+        let c = parser.parse(line).unwrap();
+        assert_eq!(c.get("remote_addr"), Some("192.168.131.39"));
+        assert_eq!(c.get("remote_port"), Some("2817"));
+        assert_eq!(c.get("status"), Some("200"));
+        assert_eq!(c.get("body_bytes_sent"), Some("57"));
+        assert_eq!(
+            c.get("request"),
+            Some("GET https://www.example.com:443/a?b=c HTTP/1.1")
+        );
+        assert_eq!(c.get("http_user_agent"), Some("curl/7.46.0"));
+        assert_eq!(c.get("ssl_protocol"), Some("TLSv1.2"));
+        assert_eq!(c.get("classification"), Some("-"));
+
+        // Older logs end after the error reason.
+        let line = r#"http 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 - -1 -1 -1 460 - 34 0 "GET http://www.example.com:80/ HTTP/1.1" "-" - - - "-" "-" "-" 0 2018-07-02T22:22:48.364000Z "forward" "-" "-""#;
+        let c = parser.parse(line).unwrap();
+        assert_eq!(c.get("status"), Some("460"));
+        assert_eq!(c.get("upstream_addr"), Some("-"));
+        assert_eq!(c.get("target_port_list"), None);
+    }
+
+    #[test]
+    fn haproxy_lines() {
+        let parser = LineParser::new(HAPROXY).unwrap();
+        let line = r#"Feb  6 12:14:14 localhost haproxy[14389]: 10.0.1.2:33317 [06/Feb/2009:12:14:14.655] http-in static/srv1 10/0/30/69/109 200 2750 - - ---- 1/1/1/1/0 0/0 {1wt.eu} {} "GET /index.html HTTP/1.1""#;
+        let c = parser.parse(line).unwrap();
+        assert_eq!(c.get("remote_addr"), Some("10.0.1.2"));
+        assert_eq!(c.get("time_local"), Some("06/Feb/2009:12:14:14.655"));
+        assert_eq!(c.get("backend_name"), Some("static"));
+        assert_eq!(c.get("backend_server"), Some("srv1"));
+        // The backend server is not the virtual host.
+        let variables = queryable_variables(HAPROXY).unwrap().unwrap();
+        assert!(!variables.iter().any(|v| v == crate::VHOST));
+        // The accept date is the time of the request.
+        assert!(variables.iter().any(|v| v == crate::TIMESTAMP));
+        assert_eq!(c.get("tr"), Some("69"));
+        assert_eq!(c.get("tt"), Some("109"));
+        assert_eq!(c.get("termination_state"), Some("----"));
+        assert_eq!(c.get("captured_request_headers"), Some("1wt.eu"));
+        assert_eq!(c.get("request"), Some("GET /index.html HTTP/1.1"));
+
+        let line = r#"10.0.1.2:33318 [06/Feb/2009:12:14:15.001] http-in~ www/<NOSRV> -1/-1/-1/-1/+5 503 212 - - SC-- 0/0/0/0/0 0/0 "GET / HTTP/1.1""#;
+        let c = parser.parse(line).unwrap();
+        assert_eq!(c.get("status"), Some("503"));
+        assert_eq!(c.get("tt"), Some("+5"));
+        assert_eq!(c.get("captured_request_headers"), None);
+        assert_eq!(ColumnType::of("tt").value("+5"), Value::Integer(5));
+    }
+
+    #[test]
+    fn request_lines() {
+        assert_eq!(
+            split_request("GET /a?b=c HTTP/1.1"),
+            ("GET", "/a?b=c", "HTTP/1.1")
+        );
+        assert_eq!(split_request("GET /"), ("GET", "/", ""));
+        assert_eq!(split_request("-"), ("", "-", ""));
+    }
+
+    #[test]
+    fn column_types() {
+        assert_eq!(ColumnType::of("status").value("404"), Value::Integer(404));
+        assert_eq!(
+            ColumnType::of("request_length").value("512"),
+            Value::Integer(512)
+        );
+        assert_eq!(
+            ColumnType::of("request_time").value("0.25"),
+            Value::Real(0.25)
+        );
+        assert_eq!(
+            ColumnType::of("upstream_response_time").value("-"),
+            Value::Null
+        );
+        assert_eq!(
+            ColumnType::of("upstream_response_time").value("0.004, 0.132 : 0.25"),
+            Value::Real(0.386)
+        );
+        assert_eq!(
+            ColumnType::of("upstream_connect_time").value("-, 0.5"),
+            Value::Real(0.5)
+        );
+        assert_eq!(
+            ColumnType::of("upstream_status").value("502, 200"),
+            Value::Integer(200)
+        );
+        assert_eq!(
+            upstream_values("10.0.0.1:80, 10.0.0.2:80 : 10.0.0.3:80").count(),
+            3
+        );
+        assert_eq!(
+            ColumnType::of("http_referer").value("-"),
+            Value::Text(String::from("-"))
+        );
+        assert_eq!(
+            ColumnType::of("remote_addr").value("10.0.0.1"),
+            Value::Text(String::from("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn config_formats_and_logs() {
+        let conf = r#"
+error_log /var/log/nginx/error.log warn;
+error_log stderr;
+
+http {
+    # A comment with a ; in it.
+    log_format  main  '$remote_addr - $remote_user [$time_local] "$request" '
+                      '$status $body_bytes_sent';
+    log_format structured escape=json '{"status":"$status"}';
+
+    server {
+        access_log /var/log/nginx/main.log main;
+        access_log /var/log/nginx/other.log;
+        access_log off;
+    }
+}"#;
+        let mut config = NginxConfig::default();
+        config.parse_str(conf, Path::new(".")).unwrap();
+
+        assert_eq!(
+            config.resolve_format("main").unwrap(),
+            r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent"#
+        );
+        assert_eq!(config.resolve_format("structured").unwrap(), JSON);
+        assert_eq!(config.error_logs, vec!["/var/log/nginx/error.log"]);
+        assert!(config.resolve_format("missing").is_err());
+        assert_eq!(
+            config.access_logs,
+            vec![
+                (
+                    String::from("/var/log/nginx/main.log"),
+                    String::from("main")
+                ),
+                (
+                    String::from("/var/log/nginx/other.log"),
+                    String::from(COMBINED)
+                ),
+            ]
+        );
+    }
 }
@@ -0,0 +1,602 @@
+use structopt::StructOpt;
+
+use super::metrics;
+use super::output::OutputFormat;
+
+/// The command line options, which also configure the default report, filters and output. A
+/// library user can build them from arguments with `Options::from_iter`.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    author,
+    name = "topngx",
+    about = "top for NGINX",
+    rename_all = "kebab-case"
+)]
+pub struct Options {
+    /// The access log to parse. This can be given multiple times to combine several logs, such as
+    /// one along with its rotated siblings, and may be a glob pattern like "/var/log/nginx/*.log".
+    /// Logs on other machines are read over SSH when given as ssh://user@host:/path, with an IPv6
+    /// host in brackets as in ssh://user@[::1]:/path. Their paths can not be glob patterns.
+    #[structopt(short, long, number_of_values = 1)]
+    pub access_log: Vec<String>,
+
+    /// Anonymize client addresses before they are stored, so reports can be shared. By default the
+    /// last octet of IPv4 addresses and the last 80 bits of IPv6 ones are zeroed. With
+    /// --anonymize-ips=hash they are replaced with a hash that changes on every run instead.
+    #[structopt(long, min_values = 0, require_equals = true, possible_values = &["truncate", "hash"])]
+    pub anonymize_ips: Option<Option<String>>,
+
+    /// A MaxMind ASN database such as GeoLite2-ASN.mmdb to look up the network of $remote_addr with,
+    /// which can then be queried as the asn and as_org variables to group clients by provider.
+    #[structopt(long)]
+    pub asn_db: Option<String>,
+
+    /// The comma separated file extensions of the static assets left out by --ignore-assets.
+    #[structopt(
+        long,
+        default_value = "css,js,map,png,jpg,jpeg,gif,svg,ico,webp,avif,woff,woff2,ttf,otf,eot"
+    )]
+    pub asset_extensions: String,
+
+    /// A file of extra User-Agent patterns that --exclude-bots and --only-bots treat as bots, one
+    /// per line. A User-Agent containing a pattern, ignoring case, is a bot.
+    #[structopt(long)]
+    pub bot_patterns: Option<String>,
+
+    /// Group client addresses into networks with this prefix length, so that remote_addr is
+    /// 192.0.2.0/24 with --cidr 24. A second length after a comma, such as 24,48, is used for IPv6
+    /// addresses, which are grouped by /64 otherwise.
+    #[structopt(long)]
+    pub cidr: Option<String>,
+
+    /// The configuration file with defaults for these options. Defaults to
+    /// ~/.config/topngx/config.toml when it exists.
+    #[structopt(long)]
+    pub config: Option<String>,
+
+    /// Keep the parsed records in this SQLite database file. When it already holds them from an
+    /// earlier run the logs are not read again, so delete the file to start over. The options that
+    /// change how lines are parsed, such as --status or --strip-query, are kept with it and can
+    /// only be given again with the same values.
+    #[structopt(long)]
+    pub db: Option<String>,
+
+    /// Only report the lines added to the logs since the previous run, for a report of what
+    /// happened lately from cron. Where each log was read up to is kept in the --state file, or
+    /// ~/.cache/topngx/state.json, and the first run only notes where the logs end.
+    #[structopt(long, conflicts_with = "db")]
+    pub delta: bool,
+
+    /// Parse NGINX error logs instead of access logs. The entries are stored in the errors table
+    /// with variables such as level, message, client and request.
+    #[structopt(long)]
+    pub error_log: bool,
+
+    /// Highlight the 5XX counts and percentages in red in table output when more than this percentage
+    /// of the requests failed.
+    #[structopt(long, default_value = "5")]
+    pub errors_threshold: f64,
+
+    /// Skip requests from crawlers, monitoring services and command line tools, going by the
+    /// User-Agent.
+    #[structopt(long, conflicts_with = "only-bots")]
+    pub exclude_bots: bool,
+
+    /// Skip requests from this IP address or CIDR block, such as 10.0.0.0/8. This can be given
+    /// multiple times.
+    #[structopt(long, number_of_values = 1)]
+    pub exclude_ip: Vec<String>,
+
+    /// Skip requests with these statuses, given as a comma separated list of codes or classes such
+    /// as "2xx,304".
+    #[structopt(long)]
+    pub exclude_status: Option<String>,
+
+    /// Only include records matching this SQL expression, such as "status_type = 5". It applies to
+    /// the default report and the built in subcommands but not to custom queries.
+    #[structopt(long = "where")]
+    pub filter: Option<String>,
+
+    /// The specific log format with which to parse. Use "json" for logs with one JSON object per
+    /// line, "apache-common" or "apache-combined" for Apache logs, "aws-alb" for AWS Application
+    /// Load Balancer logs, "haproxy" for HAProxy HTTP logs, an Apache LogFormat using % directives,
+    /// or the name of a format defined in the config file or a log_format from the NGINX
+    /// configuration.
+    #[structopt(short, long, default_value = "combined")]
+    pub format: String,
+
+    /// A MaxMind database such as GeoLite2-City.mmdb to look up the country and city of
+    /// $remote_addr with, which can then be queried as the country and city variables.
+    #[structopt(long)]
+    pub geoip_db: Option<String>,
+
+    /// Group by this variable, or a comma separated list of them such as
+    /// request_path,status_type.
+    #[structopt(short, long, default_value = "request_path")]
+    pub group_by: String,
+
+    /// Only show the groups for which this SQL expression holds, such as
+    /// "count > 100 AND avg_request_time > 0.5". A number on its own is the minimum count.
+    #[structopt(short = "w", long, default_value = "1")]
+    pub having: String,
+
+    /// Show sizes and times in the table, Markdown and HTML output in units, such as 1.4 GiB and
+    /// 230 ms, rather than as raw numbers of bytes and seconds.
+    #[structopt(long)]
+    pub human: bool,
+
+    /// Skip requests for static assets such as stylesheets, scripts, images and fonts, so the
+    /// statistics are about the dynamic endpoints. See --asset-extensions for which are skipped.
+    #[structopt(long)]
+    pub ignore_assets: bool,
+
+    /// Refresh the statistics using this interval which is given in seconds.
+    #[structopt(short = "t", long, conflicts_with = "no_follow", default_value = "2")]
+    pub interval: u64,
+
+    /// The number of threads used to parse logs that are read in full. Defaults to the number of
+    /// CPUs.
+    #[structopt(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Read the log from the systemd journal rather than a file.
+    #[structopt(long)]
+    pub journal: bool,
+
+    /// The number of records to limit for each query.
+    #[structopt(short, long, default_value = "10")]
+    pub limit: u64,
+
+    /// Receive access log lines from NGINX over syslog on this address, such as 0.0.0.0:514, for
+    /// `access_log syslog:server=...`. Messages are accepted over both UDP and TCP.
+    #[structopt(long, conflicts_with = "no-follow")]
+    pub listen_syslog: Option<String>,
+
+    /// Keep at most this many of the newest requests, dropping the oldest at each refresh, so that
+    /// following a busy log does not use ever more memory. A database given with --db is trimmed
+    /// too.
+    #[structopt(long, conflicts_with = "stream")]
+    pub max_records: Option<u64>,
+
+    /// The NGINX configuration to read log formats and access logs from. The usual install locations
+    /// are checked when this is not given.
+    #[structopt(short = "c", long)]
+    pub nginx_config: Option<String>,
+
+    /// Do not highlight problems in the table output with colors. Colors are also left out when
+    /// the output is not a terminal or NO_COLOR is set.
+    #[structopt(long)]
+    pub no_color: bool,
+
+    /// Do not tail the log file and only report what is currently there.
+    #[structopt(short, long)]
+    pub no_follow: bool,
+
+    /// Replace the numbers and UUIDs in request paths with ":id", so that /users/12345 and
+    /// /users/678 are both counted as /users/:id.
+    #[structopt(long)]
+    pub normalize_paths: bool,
+
+    /// Only include requests from crawlers, monitoring services and command line tools.
+    #[structopt(long)]
+    pub only_bots: bool,
+
+    /// Only include requests from this IP address or CIDR block. This can be given multiple times
+    /// to include several.
+    #[structopt(long, number_of_values = 1)]
+    pub only_ip: Vec<String>,
+
+    /// Order of output for the default queries, as a comma separated list of columns each
+    /// optionally followed by ASC or DESC, such as "avg_request_time DESC, count ASC". Columns are
+    /// sorted in descending order by default.
+    #[structopt(short, long, default_value = "count")]
+    pub order_by: String,
+
+    /// The format to write results in.
+    #[structopt(long, default_value = "table", possible_values = OutputFormat::VARIANTS)]
+    pub output: OutputFormat,
+
+    /// Write the results to this file instead of standard output. The file is replaced as a whole
+    /// so it never holds a partial report, including on each refresh when following.
+    #[structopt(long)]
+    pub output_file: Option<String>,
+
+    /// Scrub secrets such as API keys from a field before it is stored, given as the field and a
+    /// regex such as "request_path=token=([^&]+)". Only the groups of the regex are replaced when
+    /// it has any, otherwise the whole match is. This can be given multiple times. Rules for
+    /// request_path also scrub $request_uri and the path in $request, and lines shown by
+    /// --show-unparsed have every pattern applied to them.
+    #[structopt(long, number_of_values = 1)]
+    pub redact: Vec<String>,
+
+    /// Look up the hostnames of the client addresses in the report with reverse DNS and show them
+    /// in a hostname column. A few lookups run at a time and each address is looked up once.
+    #[structopt(long)]
+    pub resolve: bool,
+
+    /// Only keep the requests logged within this long of the newest one, such as "1h" or "7d",
+    /// dropping the older ones at each refresh. A database given with --db is trimmed too.
+    #[structopt(long, conflicts_with = "stream")]
+    pub retention: Option<String>,
+
+    /// A YAML file mapping regexes to route names, such as `'^/api/v1/users/\d+$': api.user.get`,
+    /// that adds a route variable with the name of the first regex the request path matches.
+    #[structopt(long)]
+    pub routes: Option<String>,
+
+    /// Only parse this fraction of the lines, such as 0.01 or 1/100, to get a quick idea of a very
+    /// large log. Lines are picked by a hash of their contents so runs give the same results, and
+    /// the counts and totals in the reports are scaled up to estimate the whole log.
+    #[structopt(long)]
+    pub sample: Option<String>,
+
+    /// Print the first lines that did not match the log format along with how many there were.
+    #[structopt(long)]
+    pub show_unparsed: bool,
+
+    /// Only include requests logged at or after this time, such as "2024-01-01 13:00". Without a UTC
+    /// offset like "+02:00" the time is compared against the local time written in the log.
+    #[structopt(long)]
+    pub since: Option<String>,
+
+    /// Highlight request times in yellow in table output when they are slower than this many
+    /// seconds.
+    #[structopt(long, default_value = "1")]
+    pub slow_threshold: f64,
+
+    /// Remember in this file how far each log was read, such as ~/.cache/topngx/state.json, so
+    /// that the next run only reads the lines added since, as when running from cron. Logs are
+    /// not followed. With --db the new lines are added to the database, which keeps the totals of
+    /// every run, and the state is only saved once the report was written.
+    #[structopt(long)]
+    pub state: Option<String>,
+
+    /// Only include requests with these statuses, given as a comma separated list of codes or
+    /// classes such as "5xx" or "404,410".
+    #[structopt(long)]
+    pub status: Option<String>,
+
+    /// Stop with an error at the first line that does not match the log format rather than
+    /// skipping it.
+    #[structopt(long)]
+    pub strict: bool,
+
+    /// Keep the default report as running totals and a bounded summary of the top groups rather
+    /// than storing every record, so that following a busy log for days uses constant memory. The
+    /// counts of groups are estimates that are at most error_bound over, the unique clients and
+    /// paths are estimated within a few percent and the percentiles of request_time within 1%.
+    #[structopt(long, conflicts_with = "db")]
+    pub stream: bool,
+
+    /// Leave the query string out of request paths, so that /search?q=foo and /search?q=bar are
+    /// both counted as /search.
+    #[structopt(long)]
+    pub strip_query: bool,
+
+    /// Show times and bucket timeseries in this timezone, such as Europe/Berlin, rather than the
+    /// one the server logged. Times given to --since and --until without an offset are in it too.
+    #[structopt(long)]
+    pub timezone: Option<String>,
+
+    /// Append a row with the totals, or averages where adding up makes no sense, of the rows shown
+    /// in each report.
+    #[structopt(long)]
+    pub totals: bool,
+
+    /// The systemd unit to read from the journal [default: nginx.service].
+    #[structopt(long, requires = "journal")]
+    pub unit: Option<String>,
+
+    /// Only include requests logged before this time, given in the same way as --since.
+    #[structopt(long)]
+    pub until: Option<String>,
+
+    /// Show times and bucket timeseries in UTC, the same as --timezone UTC.
+    #[structopt(long, conflicts_with = "timezone")]
+    pub utc: bool,
+
+    /// Only include requests for this virtual host, going by $host, $server_name or the Host
+    /// header. A leading "*." matches any subdomain. This can be given multiple times.
+    #[structopt(long, number_of_values = 1)]
+    pub vhost: Vec<String>,
+
+    /// When following, only show the requests read in this last while, such as "5m", "2h" or "1d",
+    /// rather than everything since starting. Older requests are dropped at each refresh.
+    #[structopt(long, conflicts_with_all = &["db", "stream"])]
+    pub window: Option<String>,
+
+    #[structopt(subcommand)]
+    pub subcommand: Option<SubCommand>,
+}
+
+// The list of subcommands available to use.
+#[derive(Debug, StructOpt)]
+pub enum SubCommand {
+    /// Show the requests clients abandoned before the response was sent, logged with status 499,
+    /// overall and for the paths abandoned most, along with how long they had waited.
+    Aborts,
+
+    /// Compute the Apdex score of the request times overall and for the top values of the group by
+    /// variable.
+    Apdex(Apdex),
+
+    /// Print the average of the given fields.
+    Avg(Fields),
+
+    /// Compare the upstream servers from $upstream_addr by their requests, errors and latency.
+    Backends,
+
+    /// List the clients with too many requests, such as too many 4XX with --status 4xx, as a plain
+    /// list of addresses or as rules for fail2ban, iptables or NGINX to block them with.
+    Ban(Ban),
+
+    /// Show how often proxy_cache served requests going by $upstream_cache_status, overall and for
+    /// the top paths.
+    Cache,
+
+    /// Show how well responses compress going by $gzip_ratio, with the bytes saved, overall and for
+    /// the top paths.
+    Compression,
+
+    /// Rank the paths by the bytes they sent, with their share of the total, and list the largest
+    /// responses.
+    Heavy,
+
+    /// Count how many values of a numeric field fall into each bucket.
+    Hist(Hist),
+
+    /// List the available fields as well as the access log and format being used.
+    Info,
+
+    /// Show how many requests each connection served going by $connection, and how many were
+    /// pipelined with $pipe, to tune keepalive and find the clients opening a new connection for
+    /// every request.
+    Keepalive,
+
+    /// Print the largest value of the given fields.
+    Max(Fields),
+
+    /// Count the requests of each HTTP method with their error rates and average latency.
+    Methods,
+
+    /// Print the smallest value of the given fields.
+    Min(Fields),
+
+    /// Compute percentiles of the given fields.
+    Percentile(Percentiles),
+
+    /// Print out the supplied fields with the given limit.
+    Print(Fields),
+
+    /// Count the requests and errors by hour of the day and by day of the week.
+    Profile,
+
+    /// Supply a custom query.
+    Query(Query),
+
+    /// Compute the requests per second overall and for the top values of the group by variable.
+    Rate(Rate),
+
+    /// Find the top domains referring requests from other sites, along with their share of them.
+    Referrers(Referrers),
+
+    /// Look for scanners: clients mostly refused with 403 or 404, requests for paths that scanners
+    /// probe for such as /wp-login.php or /.env, and unusual request methods.
+    Security(Security),
+
+    /// Follow the access logs and serve aggregated metrics for Prometheus on /metrics.
+    Serve(Serve),
+
+    /// Parse the logs once and then run SQL statements against the log table at a prompt.
+    Shell,
+
+    /// Compare the bytes received, going by $request_length, with the bytes sent, overall and for
+    /// the top paths.
+    Sizes,
+
+    /// Rank the paths by their 95th percentile request time, slowest first.
+    Slow(Slow),
+
+    /// Print the count, mean, median, standard deviation, minimum and maximum of the numeric values
+    /// of the given fields, along with how many were missing.
+    Stats(Fields),
+
+    /// Compute the sum of the given fields.
+    Sum(Fields),
+
+    /// Count the requests, bytes sent and errors in each period of time.
+    Timeseries(Timeseries),
+
+    /// Show which TLS protocols and ciphers are negotiated from $ssl_protocol and $ssl_cipher, to
+    /// see how many clients still use deprecated versions.
+    Tls,
+
+    /// Find the top values for the given fields.
+    Top(Top),
+
+    /// Break the upstream response time into connecting, waiting for the header and reading the
+    /// body, overall and for each upstream server.
+    Upstream,
+
+    /// Find the top browsers, operating systems and bots going by the User-Agent, along with their
+    /// share of the requests.
+    Useragents,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Apdex {
+    /// The request time in seconds up to which users are satisfied. Up to four times as long is
+    /// tolerated and anything slower is frustrating.
+    #[structopt(short, long, default_value = "0.5")]
+    pub threshold: f64,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Ban {
+    /// The number of requests from a client, after the filters, that gets it banned.
+    #[structopt(long, default_value = "100")]
+    pub min_count: u64,
+
+    /// Only count requests that fall within this long of each other, such as 10m, so that slow
+    /// but steady clients are not banned.
+    #[structopt(long)]
+    pub within: Option<String>,
+
+    /// How to write each client: plain, fail2ban, iptables or nginx.
+    #[structopt(long, default_value = "plain", possible_values = &["plain", "fail2ban", "iptables", "nginx"])]
+    pub format: String,
+
+    /// The fail2ban jail to ban the clients in.
+    #[structopt(long, default_value = "nginx")]
+    pub jail: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Fields {
+    /// A space Separated list of field names.
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Hist {
+    /// A comma separated list of the upper bounds of each bucket, in increasing order.
+    #[structopt(short, long, default_value = metrics::DEFAULT_BUCKETS)]
+    pub buckets: String,
+
+    /// Draw a bar for each bucket next to its count.
+    #[structopt(long)]
+    pub bars: bool,
+
+    /// The numeric field to bin, such as request_time.
+    pub field: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Percentiles {
+    /// A comma separated list of the percentiles to compute.
+    #[structopt(short, long, default_value = "50,95,99")]
+    pub percentiles: String,
+
+    /// A space separated list of field names.
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Rate {
+    /// When following, also show the rate over this many of the most recent seconds.
+    #[structopt(short, long, default_value = "60")]
+    pub window: u64,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Referrers {
+    /// A domain of the site itself, whose referrals are left out. The virtual host of each request
+    /// is left out already when it is logged. This can be given multiple times.
+    #[structopt(long, number_of_values = 1)]
+    pub host: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Security {
+    /// The number of requests a client needs before it is reported for being mostly refused.
+    #[structopt(long, default_value = "10")]
+    pub min_requests: u64,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Slow {
+    /// The number of requests a path needs before it is ranked.
+    #[structopt(long, default_value = "10")]
+    pub min_requests: u64,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Serve {
+    /// The address to listen on.
+    #[structopt(short, long, default_value = "0.0.0.0:9145")]
+    pub listen: String,
+
+    /// A comma separated list of the request_time histogram buckets in seconds.
+    #[structopt(short, long, default_value = metrics::DEFAULT_BUCKETS)]
+    pub buckets: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Timeseries {
+    /// The length of each period: second, minute, hour, day or a number followed by s, m, h or d
+    /// such as 15m.
+    #[structopt(short, long, default_value = "minute")]
+    pub granularity: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Top {
+    /// Include every secondary metric available in the log format.
+    #[structopt(long)]
+    pub all: bool,
+
+    /// Rank the values by the total of this numeric field rather than by their count, such as
+    /// bytes_sent or request_time. The percentages are then of the field's total too.
+    #[structopt(long)]
+    pub by: Option<String>,
+
+    /// Include the total bytes sent for each value.
+    #[structopt(long)]
+    pub bytes: bool,
+
+    /// Draw a bar of # proportional to the count next to it.
+    #[structopt(long)]
+    pub chart: bool,
+
+    /// Include the percentages of requests with a 4XX and a 5XX status for each value.
+    #[structopt(long)]
+    pub errors: bool,
+
+    /// Include the average request time for each value.
+    #[structopt(long)]
+    pub time: bool,
+
+    /// A space separated list of field names.
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Query {
+    /// A space separated list of field names. Defaults to the fields the query refers to.
+    #[structopt(short, long)]
+    pub fields: Vec<String>,
+
+    /// Run a query saved under this name in the config file.
+    #[structopt(long, required_unless = "query", conflicts_with = "query")]
+    pub name: Option<String>,
+
+    /// A value for a placeholder in the query given as name=value, such as -p path=/api for {path}.
+    #[structopt(short, long = "param", number_of_values = 1, parse(try_from_str = parse_param))]
+    pub params: Vec<(String, String)>,
+
+    /// The supplied query. You typically will want to use your shell to quote it.
+    #[structopt(short, long)]
+    pub query: Option<String>,
+}
+
+fn parse_param(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) => Ok((name.to_string(), value.to_string())),
+        None => Err(format!("expected name=value, got {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflicts() {
+        let parse = |args: &[&str]| {
+            Options::from_iter_safe(std::iter::once("topngx").chain(args.iter().copied()))
+        };
+        let err = parse(&["-n", "--listen-syslog", "127.0.0.1:5514"]).unwrap_err();
+        assert_eq!(err.kind, structopt::clap::ErrorKind::ArgumentConflict);
+        assert!(parse(&["--listen-syslog", "127.0.0.1:5514"]).is_ok());
+    }
+}
@@ -0,0 +1,683 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use rusqlite::types::Value;
+use tabwriter::TabWriter;
+
+use super::json;
+
+/// The ways a query result can be written out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+    Markdown,
+    Html,
+    Plain,
+}
+
+impl OutputFormat {
+    pub const VARIANTS: &'static [&'static str] =
+        &["table", "json", "csv", "markdown", "html", "plain"];
+
+    /// Whether the format is meant to be read by people rather than programs, so values can be
+    /// shown in units.
+    pub fn is_readable(self) -> bool {
+        matches!(
+            self,
+            OutputFormat::Table | OutputFormat::Markdown | OutputFormat::Html
+        )
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            "markdown" => Ok(OutputFormat::Markdown),
+            "html" => Ok(OutputFormat::Html),
+            "plain" => Ok(OutputFormat::Plain),
+            _ => Err(anyhow!("unknown output format: {}", s)),
+        }
+    }
+}
+
+/// The limits beyond which cells are highlighted in table output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// The percentage of requests with a 5XX status.
+    pub errors: f64,
+    /// The request time in seconds.
+    pub slow: f64,
+}
+
+/// The colors cells are highlighted with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    Red,
+    Yellow,
+}
+
+impl Color {
+    fn paint(self, text: &str) -> String {
+        let code = match self {
+            Color::Red => 31,
+            Color::Yellow => 33,
+        };
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    }
+}
+
+/// The column names and rows returned by a single query.
+#[derive(Debug)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl QueryResult {
+    /// Find the cells that are over the thresholds, as their row, column and color. The 5XX
+    /// percentage is either a pct_5xx column or the 5XX column compared against the count.
+    pub fn highlights(&self, thresholds: &Thresholds) -> Vec<(usize, usize, Color)> {
+        let position = |name: &str| {
+            self.columns
+                .iter()
+                .position(|c| c.eq_ignore_ascii_case(name))
+        };
+        let (count, errors, pct_errors) = (position("count"), position("5XX"), position("pct_5xx"));
+
+        let mut cells = vec![];
+        for (i, row) in self.rows.iter().enumerate() {
+            let value = |j: usize| match row.get(j) {
+                Some(Value::Integer(n)) => Some(*n as f64),
+                Some(Value::Real(r)) => Some(*r),
+                _ => None,
+            };
+
+            if let Some(j) = pct_errors {
+                if value(j).is_some_and(|p| p > thresholds.errors) {
+                    cells.push((i, j, Color::Red));
+                }
+            }
+            if let (Some(c), Some(j)) = (count, errors) {
+                if let (Some(count), Some(errors)) = (value(c), value(j)) {
+                    if count > 0.0 && 100.0 * errors / count > thresholds.errors {
+                        cells.push((i, j, Color::Red));
+                    }
+                }
+            }
+            for (j, column) in self.columns.iter().enumerate() {
+                if is_duration(column) && value(j).is_some_and(|t| t > thresholds.slow) {
+                    cells.push((i, j, Color::Yellow));
+                }
+            }
+        }
+        cells
+    }
+
+    /// Color the given cells, turning them into text.
+    pub fn paint(&mut self, cells: &[(usize, usize, Color)]) {
+        for &(i, j, color) in cells {
+            if let Some(cell) = self.rows.get_mut(i).and_then(|r| r.get_mut(j)) {
+                *cell = Value::Text(color.paint(&display(cell)));
+            }
+        }
+    }
+
+    /// Multiply the counts and totals, such as the count and 5XX columns, to estimate them for the
+    /// whole log from a sample of it. Which columns hold them is judged by their names.
+    pub fn scale(&mut self, factor: f64) {
+        for (i, column) in self.columns.iter().enumerate() {
+            if !is_total(column) {
+                continue;
+            }
+            for row in &mut self.rows {
+                match row.get(i) {
+                    Some(Value::Integer(n)) => {
+                        row[i] = Value::Integer((*n as f64 * factor).round() as i64)
+                    }
+                    Some(Value::Real(r)) => row[i] = Value::Real(r * factor),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Append a row totalling the rows above it, unless there are fewer than two. Counts, sums and
+    /// shares are added up, averages and percentages are averaged weighted by the count when there
+    /// is one, and the largest and smallest values are carried over. Anything else, such as a
+    /// percentile, cannot be worked out from the rows and is left empty.
+    pub fn add_totals(&mut self) {
+        if self.rows.len() < 2 {
+            return;
+        }
+        let number = |v: Option<&Value>| match v {
+            Some(Value::Integer(n)) => Some(*n as f64),
+            Some(Value::Real(r)) => Some(*r),
+            _ => None,
+        };
+        let count = self
+            .columns
+            .iter()
+            .position(|c| c.eq_ignore_ascii_case("count"));
+
+        let mut totals = Vec::with_capacity(self.columns.len());
+        for (i, column) in self.columns.iter().enumerate() {
+            let column = column.to_ascii_lowercase();
+            let values: Vec<(f64, f64)> = self
+                .rows
+                .iter()
+                .filter_map(|row| {
+                    let weight = count.and_then(|c| number(row.get(c))).unwrap_or(1.0);
+                    Some((number(row.get(i))?, weight))
+                })
+                .collect();
+            // Integers stay integers where adding them up or picking one keeps them whole.
+            let integers = self
+                .rows
+                .iter()
+                .all(|row| !matches!(row.get(i), Some(Value::Real(_))));
+            let whole = |v: f64| {
+                if integers {
+                    Value::Integer(v as i64)
+                } else {
+                    Value::Real(v)
+                }
+            };
+            let total = if values.is_empty() {
+                Value::Null
+            } else if is_total(&column) || is_share(&column) {
+                whole(values.iter().map(|(v, _)| v).sum())
+            } else if column.starts_with("avg") || column.starts_with("pct") {
+                let weights: f64 = values.iter().map(|(_, w)| w).sum();
+                let sum: f64 = values.iter().map(|(v, w)| v * w).sum();
+                if weights > 0.0 {
+                    Value::Real(sum / weights)
+                } else {
+                    Value::Null
+                }
+            } else if column.starts_with("max") {
+                whole(values.iter().map(|(v, _)| *v).fold(f64::MIN, f64::max))
+            } else if column.starts_with("min") {
+                whole(values.iter().map(|(v, _)| *v).fold(f64::MAX, f64::min))
+            } else {
+                Value::Null
+            };
+            // Rounded to two places like the columns of the reports, so that adding up fractions
+            // does not show floating point error.
+            totals.push(match total {
+                Value::Real(r) => Value::Real((r * 100.0).round() / 100.0),
+                t => t,
+            });
+        }
+        // The first column usually names the group, so it labels the row.
+        if let Some(first @ Value::Null) = totals.first_mut() {
+            *first = Value::Text(String::from("total"));
+        }
+        self.rows.push(totals);
+    }
+
+    /// Replace the numbers in columns of sizes and times with text in units such as 1.4 GiB and
+    /// 230 ms. Which columns hold them is judged by their names, with times taken to be in
+    /// seconds as NGINX logs them, and percentages such as pct_bytes_sent are left as they are.
+    pub fn humanize(&mut self) {
+        for (i, column) in self.columns.iter().enumerate() {
+            let lowercase = column.to_ascii_lowercase();
+            let format: fn(f64) -> String = if lowercase.starts_with("pct_") || is_share(column) {
+                continue;
+            } else if lowercase.contains("bytes") {
+                format_bytes
+            } else if is_duration(column) {
+                format_duration
+            } else {
+                continue;
+            };
+
+            for row in &mut self.rows {
+                let value = match row.get(i) {
+                    Some(Value::Integer(n)) => *n as f64,
+                    Some(Value::Real(r)) if r.is_finite() => *r,
+                    _ => continue,
+                };
+                row[i] = Value::Text(format(value));
+            }
+        }
+    }
+
+    /// Write the result in the given format.
+    pub fn write<W: Write>(&self, format: OutputFormat, w: W) -> Result<()> {
+        match format {
+            OutputFormat::Table => self.write_table(w),
+            OutputFormat::Json => self.write_json(w),
+            OutputFormat::Csv => self.write_csv(w),
+            OutputFormat::Markdown => self.write_markdown(w),
+            OutputFormat::Html => self.write_html(w),
+            OutputFormat::Plain => self.write_plain(w),
+        }
+    }
+
+    // An aligned table. Nothing is written at all when there are no rows.
+    fn write_table<W: Write>(&self, w: W) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut tw = TabWriter::new(w);
+        writeln!(&mut tw, "{}", self.columns.join("\t"))?;
+        for row in &self.rows {
+            for val in row {
+                match val {
+                    Value::Null => write!(&mut tw, "null\t")?,
+                    Value::Integer(i) => write!(&mut tw, "{}\t", i)?,
+                    Value::Real(r) => write!(&mut tw, "{}\t", r)?,
+                    Value::Text(t) => write!(&mut tw, "{}\t", t)?,
+                    Value::Blob(b) => write!(&mut tw, "{}\t", String::from_utf8_lossy(b))?,
+                }
+            }
+            writeln!(&mut tw)?;
+        }
+        tw.flush()?;
+
+        Ok(())
+    }
+
+    // An array of objects keyed by column name, written on a single line.
+    fn write_json<W: Write>(&self, mut w: W) -> Result<()> {
+        write!(w, "[")?;
+        for (i, row) in self.rows.iter().enumerate() {
+            if i > 0 {
+                write!(w, ",")?;
+            }
+            write!(w, "{{")?;
+            for (j, (column, val)) in self.columns.iter().zip(row).enumerate() {
+                if j > 0 {
+                    write!(w, ",")?;
+                }
+                write!(w, "{}:", json::quote(column))?;
+                match val {
+                    Value::Null => write!(w, "null")?,
+                    Value::Integer(i) => write!(w, "{}", i)?,
+                    Value::Real(r) if r.is_finite() => write!(w, "{}", r)?,
+                    Value::Real(_) => write!(w, "null")?,
+                    Value::Text(t) => write!(w, "{}", json::quote(t))?,
+                    Value::Blob(b) => write!(w, "{}", json::quote(&String::from_utf8_lossy(b)))?,
+                }
+            }
+            write!(w, "}}")?;
+        }
+        writeln!(w, "]")?;
+
+        Ok(())
+    }
+
+    // Comma separated values with a header row, quoting fields only when needed.
+    fn write_csv<W: Write>(&self, mut w: W) -> Result<()> {
+        let header = self
+            .columns
+            .iter()
+            .map(|c| csv_field(c))
+            .collect::<Vec<String>>();
+        writeln!(w, "{}", header.join(","))?;
+
+        for row in &self.rows {
+            let fields = row
+                .iter()
+                .map(|val| match val {
+                    Value::Null => String::new(),
+                    Value::Integer(i) => i.to_string(),
+                    Value::Real(r) => r.to_string(),
+                    Value::Text(t) => csv_field(t),
+                    Value::Blob(b) => csv_field(&String::from_utf8_lossy(b)),
+                })
+                .collect::<Vec<String>>();
+            writeln!(w, "{}", fields.join(","))?;
+        }
+
+        Ok(())
+    }
+
+    // Just the values, separated by tabs, for feeding into other programs one line at a time.
+    fn write_plain<W: Write>(&self, mut w: W) -> Result<()> {
+        for row in &self.rows {
+            let values = row
+                .iter()
+                .map(|val| match val {
+                    Value::Null => String::new(),
+                    val => display(val),
+                })
+                .collect::<Vec<String>>();
+            writeln!(w, "{}", values.join("\t"))?;
+        }
+
+        Ok(())
+    }
+
+    // A GitHub flavored Markdown table.
+    fn write_markdown<W: Write>(&self, mut w: W) -> Result<()> {
+        let header = self
+            .columns
+            .iter()
+            .map(|c| markdown_cell(c))
+            .collect::<Vec<String>>();
+        writeln!(w, "| {} |", header.join(" | "))?;
+        writeln!(w, "|{}", " --- |".repeat(self.columns.len()))?;
+
+        for row in &self.rows {
+            let cells = row
+                .iter()
+                .map(|val| markdown_cell(&display(val)))
+                .collect::<Vec<String>>();
+            writeln!(w, "| {} |", cells.join(" | "))?;
+        }
+
+        Ok(())
+    }
+
+    // An HTML table with the column names in its head.
+    fn write_html<W: Write>(&self, mut w: W) -> Result<()> {
+        writeln!(w, "<table>")?;
+        writeln!(w, "<thead>")?;
+        write!(w, "<tr>")?;
+        for column in &self.columns {
+            write!(w, "<th>{}</th>", html_escape(column))?;
+        }
+        writeln!(w, "</tr>")?;
+        writeln!(w, "</thead>")?;
+
+        writeln!(w, "<tbody>")?;
+        for row in &self.rows {
+            write!(w, "<tr>")?;
+            for val in row {
+                write!(w, "<td>{}</td>", html_escape(&display(val)))?;
+            }
+            writeln!(w, "</tr>")?;
+        }
+        writeln!(w, "</tbody>")?;
+        writeln!(w, "</table>")?;
+
+        Ok(())
+    }
+}
+
+// The columns of the built in reports that hold counts of requests but are not named for it.
+const TOTALS: &[&str] = &[
+    "missing",
+    "2xx",
+    "3xx",
+    "4xx",
+    "5xx",
+    "satisfied",
+    "tolerating",
+    "frustrated",
+    "rps",
+    "recent_rps",
+    "critical",
+    "error",
+    "warn",
+    "info",
+];
+
+// Whether the column holds a count or total that grows with the number of requests, judging by its
+// name.
+fn is_total(column: &str) -> bool {
+    let column = column.to_ascii_lowercase();
+    column.starts_with("count") || column.starts_with("sum") || TOTALS.contains(&column.as_str())
+}
+
+// Whether the column holds a share of the whole that adds up to 100 across the rows, judging by its
+// name.
+fn is_share(column: &str) -> bool {
+    column == "percent" || column == "pct_bytes_sent"
+}
+
+// Whether the column holds times in seconds, judging by its name.
+fn is_duration(column: &str) -> bool {
+    let column = column.to_ascii_lowercase();
+    column.contains("time") && !column.contains("_us")
+}
+
+// A size in bytes with binary prefixes, such as 1.4 GiB.
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes.abs() < 1024.0 {
+        return format!("{} B", bytes.round());
+    }
+    let mut value = bytes / 1024.0;
+    let mut unit = 0;
+    while value.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+// A duration given in seconds, such as 230 ms.
+fn format_duration(seconds: f64) -> String {
+    match seconds.abs() {
+        0.0 => String::from("0 s"),
+        s if s < 0.001 => format!("{:.0} µs", seconds * 1_000_000.0),
+        s if s < 1.0 => format!("{:.0} ms", seconds * 1000.0),
+        s if s < 60.0 => format!("{:.2} s", seconds),
+        s if s < 3600.0 => format!("{:.1} min", seconds / 60.0),
+        _ => format!("{:.1} h", seconds / 3600.0),
+    }
+}
+
+// The value as it is shown in tables.
+fn display(val: &Value) -> String {
+    match val {
+        Value::Null => String::from("null"),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(r) => r.to_string(),
+        Value::Text(t) => t.clone(),
+        Value::Blob(b) => String::from_utf8_lossy(b).into_owned(),
+    }
+}
+
+// Pipes would end the cell and newlines the row.
+fn markdown_cell(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace(['\r', '\n'], " ")
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result() -> QueryResult {
+        QueryResult {
+            columns: vec![String::from("request_path"), String::from("count")],
+            rows: vec![
+                vec![
+                    Value::Text(String::from("GET /a,b HTTP/1.1")),
+                    Value::Integer(2),
+                ],
+                vec![Value::Text(String::from("say \"hi\"")), Value::Null],
+            ],
+        }
+    }
+
+    #[test]
+    fn human_readable() {
+        let mut result = QueryResult {
+            columns: vec![
+                String::from("request_path"),
+                String::from("sum_bytes_sent"),
+                String::from("avg_request_time"),
+            ],
+            rows: vec![
+                vec![
+                    Value::Text(String::from("/")),
+                    Value::Integer(1_503_238_554),
+                    Value::Real(0.23),
+                ],
+                vec![Value::Integer(7), Value::Integer(512), Value::Null],
+            ],
+        };
+        result.humanize();
+        assert_eq!(
+            result.rows[0],
+            vec![
+                Value::Text(String::from("/")),
+                Value::Text(String::from("1.4 GiB")),
+                Value::Text(String::from("230 ms")),
+            ]
+        );
+        assert_eq!(
+            result.rows[1],
+            vec![
+                Value::Integer(7),
+                Value::Text(String::from("512 B")),
+                Value::Null
+            ]
+        );
+        assert_eq!(format_duration(2.5), "2.50 s");
+        assert_eq!(format_duration(0.0004), "400 µs");
+    }
+
+    #[test]
+    fn totals() {
+        let mut result = QueryResult {
+            columns: vec![
+                String::from("request_path"),
+                String::from("count"),
+                String::from("percent"),
+                String::from("avg_request_time"),
+                String::from("p95_request_time"),
+                String::from("max_bytes_sent"),
+            ],
+            rows: vec![
+                vec![
+                    Value::Text(String::from("/a")),
+                    Value::Integer(3),
+                    Value::Real(75.1),
+                    Value::Real(1.0),
+                    Value::Real(2.0),
+                    Value::Integer(10),
+                ],
+                vec![
+                    Value::Text(String::from("/b")),
+                    Value::Integer(1),
+                    Value::Real(24.2),
+                    Value::Real(1.35),
+                    Value::Real(6.0),
+                    Value::Integer(40),
+                ],
+            ],
+        };
+        result.add_totals();
+        assert_eq!(
+            result.rows[2],
+            vec![
+                Value::Text(String::from("total")),
+                Value::Integer(4),
+                Value::Real(99.3),
+                Value::Real(1.09),
+                Value::Null,
+                Value::Integer(40),
+            ]
+        );
+
+        result.rows.truncate(1);
+        result.add_totals();
+        assert_eq!(result.rows.len(), 1);
+    }
+
+    #[test]
+    fn highlights() {
+        let result = QueryResult {
+            columns: vec![
+                String::from("count"),
+                String::from("avg_request_time"),
+                String::from("5XX"),
+            ],
+            rows: vec![
+                vec![Value::Integer(100), Value::Real(0.2), Value::Integer(10)],
+                vec![Value::Integer(100), Value::Real(1.5), Value::Integer(5)],
+            ],
+        };
+        let thresholds = Thresholds {
+            errors: 5.0,
+            slow: 1.0,
+        };
+        assert_eq!(
+            result.highlights(&thresholds),
+            vec![(0, 2, Color::Red), (1, 1, Color::Yellow)]
+        );
+    }
+
+    #[test]
+    fn json_output() {
+        let mut out = vec![];
+        result().write(OutputFormat::Json, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[{\"request_path\":\"GET /a,b HTTP/1.1\",\"count\":2},\
+            {\"request_path\":\"say \\\"hi\\\"\",\"count\":null}]\n"
+        );
+    }
+
+    #[test]
+    fn markdown_output() {
+        let mut out = vec![];
+        result().write(OutputFormat::Markdown, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "| request_path | count |\n| --- | --- |\n| GET /a,b HTTP/1.1 | 2 |\n| say \"hi\" | null |\n"
+        );
+    }
+
+    #[test]
+    fn html_output() {
+        let mut out = vec![];
+        result().write(OutputFormat::Html, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<table>\n<thead>\n<tr><th>request_path</th><th>count</th></tr>\n</thead>\n<tbody>\n\
+            <tr><td>GET /a,b HTTP/1.1</td><td>2</td></tr>\n\
+            <tr><td>say &quot;hi&quot;</td><td>null</td></tr>\n</tbody>\n</table>\n"
+        );
+    }
+
+    #[test]
+    fn plain_output() {
+        let mut out = vec![];
+        result().write(OutputFormat::Plain, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "GET /a,b HTTP/1.1\t2\nsay \"hi\"\t\n"
+        );
+    }
+
+    #[test]
+    fn csv_output() {
+        let mut out = vec![];
+        result().write(OutputFormat::Csv, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "request_path,count\n\"GET /a,b HTTP/1.1\",2\n\"say \"\"hi\"\"\",\n"
+        );
+    }
+}
@@ -0,0 +1,538 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use rusqlite::types::ToSql;
+
+use super::enrich::{enriched_variables, Enrichment};
+use super::filter::RecordFilter;
+use super::nginx::{
+    error_log_variables, queryable_variables, split_request, upstream_values, ColumnType,
+    LineParser, Variables,
+};
+use super::options::Options;
+use super::processor::{Processor, Record};
+use super::queries::REQUEST_TIME;
+use super::timestamp::{self, Timestamp};
+use super::ua;
+use super::{
+    BYTES_SENT, ERROR_TIME, MSEC, REFERER_DOMAIN, REQUEST_METHOD, REQUEST_PATH, REQUEST_PROTOCOL,
+    STATUS_TYPE, TIMESTAMP, TIME_ISO8601, TIME_LOCAL, UA_BROWSER, UA_IS_BOT, UA_OS,
+    UPSTREAM_ATTEMPTS, VHOST,
+};
+
+// The number of records inserted in each transaction so memory use stays flat for large logs.
+const BATCH_SIZE: usize = 10_000;
+// How many of the lines that did not parse are kept to show.
+const UNPARSED_SAMPLES: usize = 5;
+
+/// Counts the lines that did not match the log format and keeps the first few of them, so that a
+/// wrong format is noticed instead of giving a report on part of the log.
+#[derive(Debug, Default)]
+pub struct Unparsed {
+    count: AtomicUsize,
+    samples: Mutex<Vec<String>>,
+    strict: bool,
+}
+
+impl Unparsed {
+    pub fn new(opts: &Options) -> Unparsed {
+        Unparsed {
+            strict: opts.strict,
+            ..Unparsed::default()
+        }
+    }
+
+    fn add(&self, line: &str) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() < UNPARSED_SAMPLES {
+            samples.push(line.to_string());
+        }
+    }
+
+    /// How many lines did not parse.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The first lines that did not parse.
+    pub fn samples(&self) -> Vec<String> {
+        self.samples.lock().unwrap().clone()
+    }
+
+    /// In strict mode, fail once any line has not parsed.
+    pub fn check(&self) -> Result<()> {
+        if !self.strict {
+            return Ok(());
+        }
+        match self.samples.lock().unwrap().first() {
+            Some(line) => Err(anyhow!("line does not match the log format: {}", line)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The parser for the kind of log the options are for.
+pub fn line_parser(opts: &Options) -> Result<LineParser> {
+    if opts.error_log {
+        Ok(LineParser::error_log())
+    } else {
+        LineParser::new(&opts.format)
+    }
+}
+
+/// Every field that can be queried with the options, or None when any field could be, as with JSON
+/// logs.
+pub fn queryable_fields(opts: &Options) -> Result<Option<Vec<String>>> {
+    let available = if opts.error_log {
+        Some(error_log_variables())
+    } else {
+        queryable_variables(&opts.format)?
+    };
+    Ok(available.map(|mut a| {
+        a.extend(enriched_variables(opts));
+        a
+    }))
+}
+
+/// Split the input into lines like [`BufRead::lines`], but replace bytes that are not valid UTF-8
+/// rather than failing, so that the junk scanners send can not stop a whole log from being read.
+pub fn lossy_lines<R: BufRead>(input: R) -> LossyLines<R> {
+    LossyLines { input }
+}
+
+/// The iterator returned by [`lossy_lines`].
+pub struct LossyLines<R> {
+    input: R,
+}
+
+impl<R: BufRead> Iterator for LossyLines<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        let mut buf = vec![];
+        match self.input.read_until(b'\n', &mut buf) {
+            Ok(0) => None,
+            Ok(_) => {
+                if buf.last() == Some(&b'\n') {
+                    buf.pop();
+                    if buf.last() == Some(&b'\r') {
+                        buf.pop();
+                    }
+                }
+                Some(Ok(to_string_lossy(buf)))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Turn bytes into a string, replacing anything that is not valid UTF-8 and only copying them when
+/// there is.
+pub fn to_string_lossy(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+/// Parse every line of the input and insert the records into the processor in batches, using
+/// worker threads to parse when `jobs` is more than one.
+pub fn parse_input(
+    input: Box<dyn BufRead>,
+    parser: &LineParser,
+    filter: &RecordFilter,
+    enrichment: &Enrichment,
+    unparsed: &Unparsed,
+    processor: &Processor,
+    jobs: usize,
+) -> Result<()> {
+    if jobs > 1 {
+        return parse_input_parallel(input, parser, filter, enrichment, unparsed, processor, jobs);
+    }
+
+    let mut records = Vec::with_capacity(BATCH_SIZE);
+
+    for line in lossy_lines(input) {
+        let line = line?;
+        match parse_line(
+            &line,
+            parser,
+            filter,
+            enrichment,
+            unparsed,
+            &processor.fields,
+        ) {
+            Some(record) => records.push(record),
+            None => unparsed.check()?,
+        }
+        if records.len() == BATCH_SIZE {
+            processor.process(std::mem::replace(
+                &mut records,
+                Vec::with_capacity(BATCH_SIZE),
+            ))?;
+        }
+    }
+
+    processor.process(records)
+}
+
+// Read chunks of lines and hand them to worker threads to parse. The parsed chunks are inserted
+// from this thread in the order they were read so the results match parsing on a single thread.
+fn parse_input_parallel(
+    input: Box<dyn BufRead>,
+    parser: &LineParser,
+    filter: &RecordFilter,
+    enrichment: &Enrichment,
+    unparsed: &Unparsed,
+    processor: &Processor,
+    jobs: usize,
+) -> Result<()> {
+    let fields = &processor.fields;
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel::<(usize, Vec<String>)>(jobs * 2);
+    let chunk_rx = Mutex::new(chunk_rx);
+    let (record_tx, record_rx) = mpsc::channel::<(usize, Vec<Record>)>();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            let chunk_rx = &chunk_rx;
+            let record_tx = record_tx.clone();
+            scope.spawn(move || loop {
+                let next = chunk_rx.lock().unwrap().recv();
+                let (seq, lines) = match next {
+                    Ok(chunk) => chunk,
+                    Err(_) => break,
+                };
+                let records = lines
+                    .iter()
+                    .filter_map(|l| parse_line(l, parser, filter, enrichment, unparsed, fields))
+                    .collect();
+                if record_tx.send((seq, records)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(record_tx);
+
+        let mut pending = BTreeMap::new();
+        let mut next_seq = 0;
+        let mut insert_ready = |pending: &mut BTreeMap<usize, Vec<Record>>| -> Result<()> {
+            while let Some(records) = pending.remove(&next_seq) {
+                processor.process(records)?;
+                next_seq += 1;
+            }
+            Ok(())
+        };
+
+        let read = (|| -> Result<()> {
+            let mut lines = lossy_lines(input);
+            let mut seq = 0;
+            loop {
+                let chunk = lines
+                    .by_ref()
+                    .take(BATCH_SIZE)
+                    .collect::<io::Result<Vec<String>>>()?;
+                if chunk.is_empty() {
+                    return Ok(());
+                }
+                chunk_tx.send((seq, chunk))?;
+                seq += 1;
+
+                for (seq, records) in record_rx.try_iter() {
+                    pending.insert(seq, records);
+                }
+                insert_ready(&mut pending)?;
+                unparsed.check()?;
+            }
+        })();
+        // Closing the channel lets the workers finish once they are done with what was sent.
+        drop(chunk_tx);
+        read?;
+
+        for (seq, records) in record_rx.iter() {
+            pending.insert(seq, records);
+            insert_ready(&mut pending)?;
+        }
+
+        unparsed.check()
+    })
+}
+
+/// Turn a single log line into a record holding the given fields, skipping it if it does not parse
+/// or pass the filters. Lines that do not parse, other than blank ones, are counted in `unparsed`.
+pub fn parse_line(
+    line: &str,
+    parser: &LineParser,
+    filter: &RecordFilter,
+    enrichment: &Enrichment,
+    unparsed: &Unparsed,
+    fields: &[String],
+) -> Option<Record> {
+    if !filter.sampled(line) {
+        return None;
+    }
+    let c = match parser.parse(line) {
+        Some(c) => c,
+        None => {
+            if !line.trim().is_empty() {
+                unparsed.add(&enrichment.redact_line(line));
+            }
+            return None;
+        }
+    };
+    if !filter.matches(&c) {
+        return None;
+    }
+    let mut record: Record = vec![];
+    let mut user_agent = None;
+
+    for field in fields {
+        if field == STATUS_TYPE {
+            let status = c.get("status").unwrap_or("");
+            let status_type = status.parse::<u16>().unwrap_or(0) / 100;
+            record.push((format!(":{}", field), Box::new(status_type)));
+        } else if field == BYTES_SENT {
+            // NULL rather than 0 when nothing was logged, so it does not drag the average down.
+            let bytes_sent = c.get("body_bytes_sent").unwrap_or("");
+            let bytes_sent = bytes_sent.parse::<i64>().ok();
+            record.push((format!(":{}", field), Box::new(bytes_sent)));
+        } else if field == REQUEST_METHOD {
+            let method = c
+                .get(REQUEST_METHOD)
+                .unwrap_or_else(|| split_request(c.get("request").unwrap_or("")).0);
+            record.push((format!(":{}", field), Box::new(method.to_string())));
+        } else if field == REQUEST_PATH {
+            record.push((
+                format!(":{}", field),
+                Box::new(
+                    enrichment
+                        .redact(field, enrichment.request_path(&c))
+                        .into_owned(),
+                ),
+            ));
+        } else if field == REQUEST_PROTOCOL {
+            let protocol = c
+                .get("server_protocol")
+                .unwrap_or_else(|| split_request(c.get("request").unwrap_or("")).2);
+            record.push((format!(":{}", field), Box::new(protocol.to_string())));
+        } else if field == REQUEST_TIME {
+            let value = ColumnType::of(field).value(&request_time(&c).unwrap_or_default());
+            record.push((format!(":{}", field), Box::new(value)));
+        } else if field == TIMESTAMP {
+            let value = logged_time(&c).map(|t| t.format_utc());
+            record.push((format!(":{}", field), Box::new(value)));
+        } else if field == VHOST {
+            let value = vhost(&c).map(str::to_ascii_lowercase);
+            record.push((format!(":{}", field), Box::new(value)));
+        } else if field == UPSTREAM_ATTEMPTS {
+            let value = ["upstream_addr", "upstream_status", "upstream_response_time"]
+                .iter()
+                .filter_map(|v| c.get(v))
+                .find(|v| !v.is_empty() && *v != "-")
+                .map(|v| upstream_values(v).count() as i64);
+            record.push((format!(":{}", field), Box::new(value)));
+        } else if field == REFERER_DOMAIN {
+            let value = c
+                .get("http_referer")
+                .or_else(|| c.get("referrer"))
+                .and_then(referer_domain)
+                .map(str::to_ascii_lowercase);
+            record.push((format!(":{}", field), Box::new(value)));
+        } else if field == UA_BROWSER || field == UA_OS || field == UA_IS_BOT {
+            // Parse the User-Agent once no matter how many of its fields are used.
+            let ua =
+                user_agent.get_or_insert_with(|| ua::parse(c.get("http_user_agent").unwrap_or("")));
+            let value: Box<dyn ToSql + Send> = if field == UA_BROWSER {
+                Box::new(ua.browser.clone())
+            } else if field == UA_OS {
+                Box::new(ua.os.clone())
+            } else {
+                Box::new(ua.is_bot)
+            };
+            record.push((format!(":{}", field), value));
+        } else if let Some(value) = enrichment.value(field, &c) {
+            record.push((format!(":{}", field), Box::new(value)));
+        } else {
+            let value = enrichment.redact(field, Cow::Borrowed(c.get(field).unwrap_or("")));
+            let value = ColumnType::of(field).value(&value);
+            record.push((format!(":{}", field), Box::new(value)));
+        }
+    }
+
+    Some(record)
+}
+
+/// Prefer the request URI when it is logged and otherwise fall back to the path in the request
+/// line. Absolute URIs, which load balancers and proxies log, are cut down to their path.
+pub fn request_path<'a>(c: &'a Variables) -> &'a str {
+    let path = c
+        .get("request_uri")
+        .unwrap_or_else(|| split_request(c.get("request").unwrap_or("")).1);
+    match path
+        .strip_prefix("http://")
+        .or_else(|| path.strip_prefix("https://"))
+    {
+        Some(rest) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => path,
+    }
+}
+
+/// The time taken by the request in seconds from $request_time, or else from the microseconds
+/// that Apache logs with %D.
+pub fn request_time<'a>(c: &'a Variables) -> Option<Cow<'a, str>> {
+    c.get(REQUEST_TIME).map(Cow::Borrowed).or_else(|| {
+        let microseconds = c.get("request_time_us")?.parse::<f64>().ok()?;
+        Some(Cow::Owned((microseconds / 1e6).to_string()))
+    })
+}
+
+// When the request was logged, from whichever of the time variables the log has.
+fn logged_time(c: &Variables) -> Option<Timestamp> {
+    c.get(TIME_LOCAL)
+        .and_then(timestamp::parse_time_local)
+        .or_else(|| c.get(TIME_ISO8601).and_then(timestamp::parse_time_iso8601))
+        .or_else(|| c.get(ERROR_TIME).and_then(timestamp::parse_error_time))
+        .or_else(|| c.get(MSEC).and_then(timestamp::parse_msec))
+}
+
+/// The virtual host the request was for, preferring $host, then $server_name and then the Host
+/// header. Any port is left out but the case is kept.
+pub fn vhost<'a>(c: &'a Variables) -> Option<&'a str> {
+    let host = ["host", "server_name", "http_host"]
+        .iter()
+        .filter_map(|v| c.get(v))
+        .find(|h| !h.is_empty() && *h != "-")?;
+    // Leave the brackets of IPv6 addresses such as [::1]:8080 alone.
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') && host[..i].rfind(':').is_none() => Some(&host[..i]),
+        Some(i) if host.starts_with('[') && host[..i].ends_with(']') => Some(&host[..i]),
+        _ => Some(host),
+    }
+}
+
+/// The host of a Referer such as https://www.example.com:8443/page?q=1, which is www.example.com,
+/// keeping its case. Referers that are not absolute URLs, such as "-", have none.
+pub fn referer_domain(referer: &str) -> Option<&str> {
+    let rest = match referer.find("://") {
+        Some(i) => &referer[i + 3..],
+        None => referer.strip_prefix("//")?,
+    };
+    let authority = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    Some(host).filter(|h| !h.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::{ToSqlOutput, Value, ValueRef};
+    use structopt::StructOpt;
+
+    #[test]
+    fn invalid_utf8() {
+        let input: &[u8] = b"GET /caf\xe9 HTTP/1.1\r\n\x16\x03\x01\x02\nlast";
+        let lines = lossy_lines(input)
+            .collect::<io::Result<Vec<String>>>()
+            .unwrap();
+        assert_eq!(
+            lines,
+            vec!["GET /caf\u{fffd} HTTP/1.1", "\u{16}\u{3}\u{1}\u{2}", "last"]
+        );
+    }
+
+    #[test]
+    fn redacted_records() {
+        let opts = Options::from_iter(&[
+            "topngx",
+            "--format",
+            "$remote_addr \"$request\" $request_uri $status",
+            "--redact",
+            "request_path=token=[^&]+",
+        ]);
+        let fields = queryable_fields(&opts).unwrap().unwrap();
+        let record = parse_line(
+            "10.0.0.1 \"GET /a?token=SECRET&x=1 HTTP/1.1\" /a?token=SECRET&x=1 200",
+            &LineParser::new(&opts.format).unwrap(),
+            &RecordFilter::new(&opts).unwrap(),
+            &Enrichment::new(&opts).unwrap(),
+            &Unparsed::new(&opts),
+            &fields,
+        )
+        .unwrap();
+
+        let stored: Vec<(String, String)> = record
+            .iter()
+            .map(|(name, value)| {
+                let value = match value.to_sql().unwrap() {
+                    ToSqlOutput::Borrowed(ValueRef::Text(t)) => String::from_utf8_lossy(t).into(),
+                    ToSqlOutput::Owned(Value::Text(t)) => t,
+                    other => format!("{:?}", other),
+                };
+                (name.clone(), value)
+            })
+            .collect();
+        assert!(
+            stored.iter().all(|(_, v)| !v.contains("SECRET")),
+            "{:?}",
+            stored
+        );
+        let value = |name: &str| &stored.iter().find(|(n, _)| n == name).unwrap().1;
+        assert_eq!(value(":request"), "GET /a?REDACTED&x=1 HTTP/1.1");
+        assert_eq!(value(":request_uri"), "/a?REDACTED&x=1");
+        assert_eq!(value(":request_path"), "/a?REDACTED&x=1");
+    }
+
+    #[test]
+    fn virtual_hosts() {
+        let parser = LineParser::new("$host $http_host").unwrap();
+        let host = |line| vhost(&parser.parse(line).unwrap()).map(String::from);
+        assert_eq!(
+            host("Example.com:8080 x"),
+            Some(String::from("Example.com"))
+        );
+        assert_eq!(host("[::1]:8080 x"), Some(String::from("[::1]")));
+        assert_eq!(host("::1 x"), Some(String::from("::1")));
+        assert_eq!(host("- b.example.com"), Some(String::from("b.example.com")));
+        assert_eq!(host("- -"), None);
+    }
+
+    #[test]
+    fn request_times() {
+        let parser = LineParser::new("%h %D").unwrap();
+        let c = parser.parse("10.0.0.1 250000").unwrap();
+        assert_eq!(request_time(&c).as_deref(), Some("0.25"));
+        let fields = queryable_fields(&Options::from_iter(&["topngx", "-f", "%h %D"]));
+        assert!(fields.unwrap().unwrap().iter().any(|f| f == REQUEST_TIME));
+
+        let parser = LineParser::new("$remote_addr $request_time").unwrap();
+        let c = parser.parse("10.0.0.1 0.005").unwrap();
+        assert_eq!(request_time(&c).as_deref(), Some("0.005"));
+        assert_eq!(
+            request_time(&parser.parse("10.0.0.1 -").unwrap()).as_deref(),
+            Some("-")
+        );
+    }
+
+    #[test]
+    fn referer_domains() {
+        assert_eq!(
+            referer_domain("https://www.Example.com:8443/page?q=1"),
+            Some("www.Example.com")
+        );
+        assert_eq!(referer_domain("http://user@[::1]:80/"), Some("[::1]"));
+        assert_eq!(referer_domain("//cdn.example.com"), Some("cdn.example.com"));
+        assert_eq!(
+            referer_domain("android-app://com.google.android.gm"),
+            Some("com.google.android.gm")
+        );
+        assert_eq!(referer_domain("-"), None);
+        assert_eq!(referer_domain("/relative/path"), None);
+        assert_eq!(referer_domain("https:///"), None);
+    }
+}
@@ -0,0 +1,170 @@
+use std::borrow::Cow;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+
+// What the segments of a path that identify a resource are replaced with.
+const ID: &str = ":id";
+
+/// Replace the segments of the path that identify a resource, which are numbers and UUIDs, with
+/// ":id" so that /users/12345/orders/987 becomes /users/:id/orders/:id. Any query string is kept.
+pub(crate) fn normalize(path: &str) -> Cow<'_, str> {
+    let (path_only, query) = path.split_at(path.find('?').unwrap_or(path.len()));
+    if !path_only.split('/').any(is_id) {
+        return Cow::Borrowed(path);
+    }
+
+    let segments: Vec<&str> = path_only
+        .split('/')
+        .map(|s| if is_id(s) { ID } else { s })
+        .collect();
+    Cow::Owned(segments.join("/") + query)
+}
+
+/// The path without its query string, so /search?q=foo is /search.
+pub(crate) fn strip_query(path: &str) -> &str {
+    path.split('?').next().unwrap_or(path)
+}
+
+/// Named endpoints for the paths matching each regex, read from a YAML mapping of regex to name
+/// such as `'^/api/v1/users/\d+$': api.user.get`. Only flat mappings like this are supported.
+#[derive(Debug)]
+pub(crate) struct Routes(Vec<(Regex, String)>);
+
+impl Routes {
+    pub(crate) fn load(path: &Path) -> Result<Routes> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read routes {}", path.display()))?;
+        Routes::parse(&contents).with_context(|| format!("invalid routes {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Routes> {
+        let mut routes = vec![];
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line == "---" {
+                continue;
+            }
+            let (pattern, name) =
+                split_route(line).ok_or_else(|| anyhow!("line {}: expected regex: name", n + 1))?;
+            let regex = Regex::new(&pattern).with_context(|| format!("line {}", n + 1))?;
+            routes.push((regex, name));
+        }
+        Ok(Routes(routes))
+    }
+
+    /// The name of the first route the path matches.
+    pub(crate) fn find(&self, path: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(regex, _)| regex.is_match(path))
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+// Split a "key: value" line, where the key may be quoted so that it can contain ": " or start with
+// characters YAML treats specially.
+fn split_route(line: &str) -> Option<(String, String)> {
+    let (key, rest) = match line.chars().next()? {
+        '\'' | '"' => unquote(line)?,
+        _ => {
+            let i = line.rfind(": ")?;
+            (line[..i].trim_end().to_string(), &line[i..])
+        }
+    };
+    let value = rest.trim_start().strip_prefix(':')?;
+    let value = match value.find(" #") {
+        Some(i) => &value[..i],
+        None => value,
+    }
+    .trim();
+    let value = match value.chars().next() {
+        Some('\'') | Some('"') => unquote(value).filter(|(_, r)| r.trim().is_empty())?.0,
+        _ => value.to_string(),
+    };
+    if key.is_empty() || value.is_empty() {
+        return None;
+    }
+    Some((key, value))
+}
+
+// Read a quoted YAML string from the start of the text, returning it and what follows. In single
+// quotes '' is a quote, while in double quotes a backslash escapes a quote or backslash.
+fn unquote(text: &str) -> Option<(String, &str)> {
+    let quote = text.chars().next()?;
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        match (c, chars.peek().map(|&(_, next)| next)) {
+            ('\'', Some('\'')) if quote == '\'' => {
+                value.push('\'');
+                chars.next();
+            }
+            ('\\', Some(next @ ('"' | '\\'))) if quote == '"' => {
+                value.push(next);
+                chars.next();
+            }
+            (c, _) if c == quote => return Some((value, &text[i + 1..])),
+            (c, _) => value.push(c),
+        }
+    }
+    None
+}
+
+fn is_id(segment: &str) -> bool {
+    (!segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit())) || is_uuid(segment)
+}
+
+// A UUID in its usual form, such as 123e4567-e89b-12d3-a456-426614174000.
+fn is_uuid(segment: &str) -> bool {
+    segment.len() == 36
+        && segment.bytes().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_paths() {
+        assert_eq!(
+            normalize("/users/12345/orders/987"),
+            "/users/:id/orders/:id"
+        );
+        assert_eq!(
+            normalize("/files/123E4567-e89b-12d3-a456-426614174000/raw?v=2"),
+            "/files/:id/raw?v=2"
+        );
+        assert_eq!(normalize("/search?page=2"), "/search?page=2");
+        assert_eq!(normalize("/v2/item-7/"), "/v2/item-7/");
+        assert!(matches!(normalize("/about"), Cow::Borrowed(_)));
+
+        assert_eq!(strip_query("/search?q=foo"), "/search");
+        assert_eq!(strip_query("/search"), "/search");
+    }
+
+    #[test]
+    fn routes() {
+        let routes = Routes::parse(
+            r#"
+# Checked in order.
+'^/api/v1/users/\d+$': api.user.get
+"^/api/v1/users/?$": "api.user.list"  # all of them
+^/(?:css|js)/: static
+"#,
+        )
+        .unwrap();
+        assert_eq!(routes.find("/api/v1/users/12"), Some("api.user.get"));
+        assert_eq!(routes.find("/api/v1/users"), Some("api.user.list"));
+        assert_eq!(routes.find("/js/app.js"), Some("static"));
+        assert_eq!(routes.find("/api/v1/users/12/orders"), None);
+
+        assert!(Routes::parse("^/a$ api").is_err());
+        assert!(Routes::parse("'^/a(': api").is_err());
+    }
+}
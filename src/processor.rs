@@ -1,48 +1,312 @@
-use std::fmt::Debug;
-use std::io::{self, Write};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use log::debug;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+use once_cell::sync::Lazy;
+use regex::Regex;
 use rusqlite::types::{ToSql, Value};
 use rusqlite::{params, Connection};
-use tabwriter::TabWriter;
 
-use super::Options;
+use super::dns::Resolver;
+use super::filter::sample_fraction;
+use super::nginx::ColumnType;
+use super::options::Options;
+use super::output::{OutputFormat, QueryResult, Thresholds};
+use super::parse::queryable_fields;
+use super::stream::{Stream, DISTINCT_FIELDS};
+use super::timestamp::{self, Zone};
+use super::trend::Trends;
+use super::{aggregates, queries, VHOST};
+
+// Matches string literals so they can be removed before looking for column names.
+static SQL_STRING_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"'(?:[^']|'')*'").unwrap());
+// Matches identifiers that are not immediately followed by a function call.
+static SQL_IDENTIFIER_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b([a-zA-Z_][a-zA-Z0-9_]*)\b\s*(\()?").unwrap());
+
+// Words that can appear in an expression without referring to a column.
+const SQL_KEYWORDS: &[&str] = &[
+    "and", "as", "between", "case", "collate", "else", "end", "escape", "glob", "in", "is", "like",
+    "match", "not", "null", "or", "regexp", "then", "when",
+];
+
+/// A single parsed line as named parameters for the insert statement.
+pub type Record = Vec<(String, Box<dyn ToSql + Send>)>;
 
 /// The main processing engine for all of the statistics.
-pub(crate) struct Processor {
+pub struct Processor {
+    // When each batch of records was inserted and its last row, for expiring them from the window.
+    batches: RefCell<VecDeque<(Instant, i64)>>,
+    colors: Option<Thresholds>,
     columns: String,
     conn: Connection,
-    pub(crate) fields: Vec<String>,
+    existing: bool,
+    pub fields: Vec<String>,
+    // Whether the indexed fields have been indexed yet, which waits for the first report.
+    has_indexes: Cell<bool>,
+    human: bool,
+    indexed: usize,
+    // The most records kept, dropping the oldest.
+    max_records: Option<u64>,
+    output: OutputFormat,
+    output_file: Option<PathBuf>,
     placeholders: String,
     queries: Vec<String>,
+    resolver: Option<Resolver>,
+    // The time variable and how long before the newest time logged records are kept.
+    retention: Option<(String, Duration)>,
+    // What the counts are multiplied by when only a sample of the lines was parsed.
+    scale: f64,
+    stream: Option<RefCell<Stream>>,
+    table: String,
+    totals: bool,
+    trends: Option<RefCell<Trends>>,
+    // How long records are kept, if not for good.
+    window: Option<Duration>,
 }
 
 impl Processor {
-    /// Given the fields to keep track of and the respective queries, return a new Processor.
-    fn new(fields: Vec<String>, queries: Vec<String>) -> Result<Processor> {
-        Ok(Processor {
+    /// Given the table, the fields to keep track of and the respective queries, return a new
+    /// Processor with its table ready for records.
+    pub fn new(
+        table: &str,
+        fields: Vec<String>,
+        queries: Vec<String>,
+        output: OutputFormat,
+    ) -> Result<Processor> {
+        let indexed = fields.len();
+        let conn = Connection::open_in_memory()?;
+        Processor::with_connection(conn, table, fields, indexed, queries, output)
+    }
+
+    /// Like `new` but keeps the records in a database file, along with the extra fields which are
+    /// not indexed. When the table is already there from an earlier run it is used as it is, with
+    /// its own columns, and nothing needs to be inserted.
+    pub fn open(
+        path: &str,
+        table: &str,
+        mut fields: Vec<String>,
+        extra_fields: Vec<String>,
+        queries: Vec<String>,
+        output: OutputFormat,
+    ) -> Result<Processor> {
+        let indexed = fields.len();
+        for field in extra_fields {
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+
+        let conn = Connection::open(path)?;
+        // The database can always be built again from the logs, so favor speed over durability.
+        conn.execute_batch("PRAGMA synchronous = OFF; PRAGMA journal_mode = MEMORY;")?;
+        Processor::with_connection(conn, table, fields, indexed, queries, output)
+    }
+
+    fn with_connection(
+        conn: Connection,
+        table: &str,
+        fields: Vec<String>,
+        indexed: usize,
+        queries: Vec<String>,
+        output: OutputFormat,
+    ) -> Result<Processor> {
+        aggregates::register(&conn)?;
+
+        let columns = table_columns(&conn, table)?;
+        let existing = !columns.is_empty();
+        let fields = if existing {
+            info!("using the existing {} table", table);
+            columns
+        } else {
+            fields
+        };
+
+        let p = Processor {
+            batches: RefCell::new(VecDeque::new()),
+            colors: None,
             columns: fields.join(", "),
-            conn: Connection::open_in_memory()?,
+            conn,
+            existing,
             fields: fields.clone(),
+            has_indexes: Cell::new(existing),
+            human: false,
+            indexed,
+            max_records: None,
+            output,
+            output_file: None,
             placeholders: fields
                 .iter()
                 .map(|f| format!(":{}", f))
                 .collect::<Vec<String>>()
                 .join(", "),
             queries,
-        })
+            resolver: None,
+            retention: None,
+            scale: 1.0,
+            table: table.to_string(),
+            stream: None,
+            totals: false,
+            trends: None,
+            window: None,
+        };
+        if !existing {
+            p.initialize()?;
+        }
+        p.validate()?;
+
+        Ok(p)
+    }
+
+    /// Show sizes and times in units in the table output of the report.
+    pub fn set_human(&mut self, human: bool) {
+        self.human = human;
+    }
+
+    /// Summarize the records for the default report as they come instead of storing them.
+    pub(crate) fn set_stream(&mut self, stream: Stream) {
+        self.stream = Some(RefCell::new(stream));
+    }
+
+    /// Append a row totalling the rows of each result of the report.
+    pub fn set_totals(&mut self, totals: bool) {
+        self.totals = totals;
+    }
+
+    /// Look up the hostnames of the client addresses in the report with reverse DNS.
+    pub fn set_resolve(&mut self, resolve: bool) {
+        self.resolver = if resolve {
+            Some(Resolver::default())
+        } else {
+            None
+        };
+    }
+
+    /// Bucket times in the given timezone rather than the one they were logged in.
+    pub(crate) fn set_timezone(&mut self, zone: Zone) -> Result<()> {
+        aggregates::register_time_bucket(&self.conn, zone)?;
+        Ok(())
+    }
+
+    /// Scale the counts and totals in the report up from a sample of the given fraction of lines.
+    pub fn set_sample(&mut self, fraction: f64) {
+        self.scale = 1.0 / fraction;
+    }
+
+    /// Highlight the cells over the thresholds in the table output of the report.
+    pub fn set_colors(&mut self, thresholds: Option<Thresholds>) {
+        self.colors = thresholds;
+    }
+
+    /// Write the report to this file rather than standard output. Each report replaces the file
+    /// at once so readers never see a partial one.
+    pub fn set_output_file(&mut self, path: Option<PathBuf>) {
+        self.output_file = path;
+    }
+
+    /// Add a column to the table output with a sparkline of how each count grew between reports,
+    /// for when the report is refreshed while following.
+    pub fn set_trends(&mut self, trends: bool) {
+        self.trends = if trends {
+            Some(RefCell::new(Trends::default()))
+        } else {
+            None
+        };
+    }
+
+    /// Drop the records inserted longer ago than the duration before each report, so that it only
+    /// covers the recent requests.
+    pub fn set_window(&mut self, window: Option<Duration>) {
+        self.window = window;
+    }
+
+    /// Keep at most this many records, dropping the oldest before each report.
+    pub fn set_max_records(&mut self, max_records: Option<u64>) {
+        self.max_records = max_records;
+    }
+
+    /// Drop the records logged longer than the duration before the newest one before each report,
+    /// going by the given time variable.
+    pub fn set_retention(&mut self, retention: Option<(String, Duration)>) {
+        self.retention = retention;
+    }
+
+    /// Whether the report goes to a file rather than standard output.
+    pub fn has_output_file(&self) -> bool {
+        self.output_file.is_some()
+    }
+
+    /// Whether the table was already in the database, in which case there is nothing to insert.
+    pub fn existing(&self) -> bool {
+        self.existing
+    }
+
+    // The parsing options the database was built with, which are none for databases from before
+    // they were kept.
+    fn stored_options(&self) -> Result<Vec<(String, String)>> {
+        let table = self.options_table();
+        if table_columns(&self.conn, &table)?.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT name, value FROM {}", table))?;
+        let options = stmt
+            .query_map(params![], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+        Ok(options)
+    }
+
+    // The table of a database file holding the parsing options its table of records was built with.
+    fn options_table(&self) -> String {
+        format!("{}_options", self.table)
+    }
+
+    // Keep the parsing options along with the records so that later runs can check theirs.
+    fn store_options(&self, options: &[(String, String)]) -> Result<()> {
+        let table = self.options_table();
+        self.conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {table} (name TEXT, value TEXT); DELETE FROM {table};",
+            table = table
+        ))?;
+        let insert_stmt = format!("INSERT INTO {} VALUES (?, ?)", table);
+        for (name, value) in options {
+            self.conn.execute(&insert_stmt, params![name, value])?;
+        }
+        Ok(())
     }
 
     /// After establishing a new connection, create the table and indexes we need.
     fn initialize(&self) -> Result<()> {
-        let create_stmt = format!("CREATE TABLE log ({})", self.columns);
+        let definitions = self
+            .fields
+            .iter()
+            .map(|f| format!("{} {}", f, ColumnType::of(f).sql()))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let create_stmt = format!("CREATE TABLE {} ({})", self.table, definitions);
         debug!("create table statement: {}", create_stmt);
         self.conn.execute(&create_stmt, params![])?;
+        Ok(())
+    }
 
-        for (i, field) in self.fields.iter().enumerate() {
+    // Index the fields the report uses. Building the indexes once the records are in is quicker
+    // than keeping them up to date while inserting, and statements run at the shell do without.
+    fn create_indexes(&self) -> Result<()> {
+        if self.has_indexes.replace(true) {
+            return Ok(());
+        }
+        for (i, field) in self.fields.iter().take(self.indexed).enumerate() {
             let index_stmt = format!(
-                "CREATE INDEX log_idx{i} on log ({field})",
+                "CREATE INDEX {table}_idx{i} on {table} ({field})",
+                table = self.table,
                 i = i,
                 field = field
             );
@@ -53,40 +317,208 @@ impl Processor {
         Ok(())
     }
 
-    /// Insert all of the given records into the database.
-    pub(crate) fn process(&self, records: Vec<Vec<(String, Box<dyn ToSql>)>>) -> Result<()> {
+    // Compile the queries up front so that mistakes in them, such as in the --where and --having
+    // expressions, are found before the logs are read rather than after.
+    fn validate(&self) -> Result<()> {
+        for query in &self.queries {
+            self.conn
+                .prepare_cached(query)
+                .with_context(|| format!("invalid query: {}", query.trim()))?;
+        }
+        Ok(())
+    }
+
+    /// Insert a batch of records into the database within a single transaction.
+    pub fn process(&self, records: Vec<Record>) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        if let Some(stream) = &self.stream {
+            let mut stream = stream.borrow_mut();
+            for record in &records {
+                stream.observe(record);
+            }
+            return Ok(());
+        }
         let insert_stmt = format!(
-            "INSERT INTO LOG ({columns}) VALUES ({placeholders})",
+            "INSERT INTO {table} ({columns}) VALUES ({placeholders})",
+            table = self.table,
             columns = self.columns,
             placeholders = self.placeholders
         );
         debug!("insert records statement: {}", insert_stmt);
 
-        let mut stmt = self.conn.prepare_cached(&insert_stmt)?;
-        for record in records {
-            stmt.execute_named(
-                &record
-                    .iter()
-                    .map(|r| (r.0.as_str(), &r.1 as &dyn ToSql))
-                    .collect::<Vec<(&str, &dyn ToSql)>>(),
-            )?;
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(&insert_stmt)?;
+            for record in records {
+                stmt.execute_named(
+                    &record
+                        .iter()
+                        .map(|r| (r.0.as_str(), &r.1 as &dyn ToSql))
+                        .collect::<Vec<(&str, &dyn ToSql)>>(),
+                )?;
+            }
+        }
+        tx.commit()?;
+        if self.window.is_some() {
+            self.batches
+                .borrow_mut()
+                .push_back((Instant::now(), self.conn.last_insert_rowid()));
+        }
+
+        Ok(())
+    }
+
+    // Delete the records that have fallen out of the window or the retention period, and the
+    // oldest ones beyond the most that are kept. A database from an earlier run is trimmed too.
+    fn expire(&self) -> Result<()> {
+        if let Some(window) = self.window {
+            let mut batches = self.batches.borrow_mut();
+            let mut last_row = None;
+            while let Some(&(inserted, row)) = batches.front() {
+                if inserted.elapsed() < window {
+                    break;
+                }
+                last_row = Some(row);
+                batches.pop_front();
+            }
+
+            if let Some(row) = last_row {
+                let delete_stmt = format!("DELETE FROM {} WHERE rowid <= ?", self.table);
+                let deleted = self.conn.execute(&delete_stmt, params![row])?;
+                debug!("expired {} records out of the window", deleted);
+            }
+        }
+
+        if let Some((field, retention)) = &self.retention {
+            // Going by the newest time logged rather than the clock, so that older logs are
+            // trimmed the same way as a live one.
+            let delete_stmt = format!(
+                "DELETE FROM {table} WHERE unix_time({field}) < \
+                (SELECT MAX(unix_time({field})) FROM {table}) - ?",
+                table = self.table,
+                field = field
+            );
+            let deleted = self
+                .conn
+                .execute(&delete_stmt, params![retention.as_secs() as i64])?;
+            debug!("expired {} records past the retention period", deleted);
+        }
+
+        if let Some(max_records) = self.max_records {
+            // The newest records have the highest rowids, which the retention period can leave
+            // gaps in.
+            let delete_stmt = format!(
+                "DELETE FROM {table} WHERE rowid IN \
+                (SELECT rowid FROM {table} ORDER BY rowid DESC LIMIT -1 OFFSET ?)",
+                table = self.table
+            );
+            let deleted = self
+                .conn
+                .execute(&delete_stmt, params![max_records as i64])?;
+            debug!("expired {} records over the most kept", deleted);
+        }
+        Ok(())
+    }
+
+    /// The last row in the table, for removing the rows inserted after it with `rollback_to`.
+    pub fn checkpoint(&self) -> Result<i64> {
+        let query = format!("SELECT COALESCE(MAX(rowid), 0) FROM {}", self.table);
+        Ok(self.conn.query_row(&query, params![], |r| r.get(0))?)
+    }
+
+    /// Remove the rows inserted after the checkpoint.
+    pub fn rollback_to(&self, checkpoint: i64) -> Result<()> {
+        let delete_stmt = format!("DELETE FROM {} WHERE rowid > ?", self.table);
+        self.conn.execute(&delete_stmt, params![checkpoint])?;
+        Ok(())
+    }
+
+    /// The number of records inserted so far.
+    pub fn count(&self) -> Result<i64> {
+        if let Some(stream) = &self.stream {
+            return Ok(stream.borrow().count() as i64);
+        }
+        self.expire()?;
+        let query = format!("SELECT COUNT(1) FROM {}", self.table);
+        Ok(self.conn.query_row(&query, params![], |r| r.get(0))?)
+    }
+
+    /// Run the queries as specified by the user and print them to standard output.
+    pub fn report(&self) -> Result<()> {
+        match &self.output_file {
+            Some(path) => write_atomically(path, |f| self.report_to(f)),
+            None => {
+                let stdout = io::stdout();
+                self.report_to(stdout.lock())
+            }
+        }
+    }
+
+    /// Run the queries as specified by the user, writing the results to the given writer.
+    pub fn report_to<W: Write>(&self, mut w: W) -> Result<()> {
+        for (i, mut result) in self.results()?.into_iter().enumerate() {
+            // Separate each CSV document and Markdown table so they can be told apart.
+            if i > 0 && matches!(self.output, OutputFormat::Csv | OutputFormat::Markdown) {
+                writeln!(w)?;
+            }
+            if self.scale != 1.0 {
+                result.scale(self.scale);
+            }
+            if self.totals {
+                result.add_totals();
+            }
+            if let Some(resolver) = &self.resolver {
+                resolver.annotate(&mut result);
+            }
+            // The highlights are found before humanizing turns the numbers into text.
+            let cells = match self.colors {
+                Some(t) if self.output == OutputFormat::Table => Some(result.highlights(&t)),
+                _ => None,
+            };
+            if self.human && self.output.is_readable() {
+                result.humanize();
+            }
+            if let Some(trends) = self.trends.as_ref().filter(|_| self.output.is_readable()) {
+                trends.borrow_mut().update(i, &mut result);
+            }
+            if let Some(cells) = cells {
+                result.paint(&cells);
+            }
+            result.write(self.output, &mut w)?;
         }
 
         Ok(())
     }
 
-    /// Run the queries as specified by the user.
-    pub(crate) fn report(&self) -> Result<()> {
+    /// Run the queries as specified by the user and return their results.
+    pub fn results(&self) -> Result<Vec<QueryResult>> {
+        if let Some(stream) = &self.stream {
+            return Ok(stream.borrow().results());
+        }
+        self.expire()?;
+        self.create_indexes()?;
+        let mut results = Vec::with_capacity(self.queries.len());
+
         for query in &self.queries {
             debug!("report query: {}", query);
+            results.push(self.query(query)?);
+        }
 
-            let mut stmt = self.conn.prepare_cached(&query)?;
-            let rows = stmt.query_map(params![], |r| {
-                let columns = r
-                    .column_names()
-                    .iter()
-                    .map(|c| c.to_string())
-                    .collect::<Vec<String>>();
+        Ok(results)
+    }
+
+    /// Run any SQL statement against the records and return what it selects.
+    pub fn query(&self, query: &str) -> Result<QueryResult> {
+        let mut stmt = self.conn.prepare_cached(query)?;
+        let columns = stmt
+            .column_names()
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<String>>();
+        let rows = stmt
+            .query_map(params![], |r| {
                 let col_count = r.column_count();
                 let mut row = Vec::with_capacity(col_count);
 
@@ -94,104 +526,460 @@ impl Processor {
                     row.push(r.get_raw_checked(i)?.into());
                 }
 
-                Ok(QueryResult { columns, row })
-            })?;
+                Ok(row)
+            })?
+            .collect::<rusqlite::Result<Vec<Vec<Value>>>>()?;
 
-            let stdout = io::stdout();
-            let mut tw = TabWriter::new(stdout.lock());
-            let mut wrote_headers = false;
-            for r in rows {
-                let r = r?;
+        Ok(QueryResult { columns, rows })
+    }
+}
 
-                if !wrote_headers {
-                    writeln!(&mut tw, "{}", r.columns.join("\t"))?;
-                    wrote_headers = true;
-                }
+// Write to a temporary file next to the path and then rename it over the path, which replaces it in
+// one step.
+pub(crate) fn write_atomically<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut BufWriter<&File>) -> Result<()>,
+{
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("invalid output file {}", path.display()))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp = path.with_file_name(temp_name);
 
-                for val in r.row {
-                    match val {
-                        Value::Null => write!(&mut tw, "null\t")?,
-                        Value::Integer(i) => write!(&mut tw, "{}\t", i)?,
-                        Value::Real(r) => write!(&mut tw, "{}\t", r)?,
-                        Value::Text(t) => write!(&mut tw, "{}\t", t)?,
-                        Value::Blob(b) => write!(&mut tw, "{}\t", String::from_utf8(b)?)?,
-                    }
-                }
-                writeln!(&mut tw)?;
-            }
-            tw.flush()?;
+    let result = File::create(&temp)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| {
+            let mut w = BufWriter::new(&file);
+            write(&mut w)?;
+            w.flush()?;
+            drop(w);
+            file.sync_all()?;
+            Ok(())
+        })
+        .and_then(|_| Ok(fs::rename(&temp, path)?))
+        .with_context(|| format!("failed to write {}", path.display()));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+// The columns of the table, which is empty when it does not exist.
+fn table_columns(conn: &Connection, table: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt
+        .query_map(params![], |r| r.get(1))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(columns)
+}
+
+// The options that change the records as they are parsed, by name and value, sorted so that two
+// runs can be compared. Options given several times appear once for each value.
+fn parsing_options(opts: &Options) -> Vec<(String, String)> {
+    let mut options: Vec<(&str, String)> = vec![];
+    let mut flag = |name, set: bool| {
+        if set {
+            options.push((name, String::new()));
         }
+    };
+    flag("exclude-bots", opts.exclude_bots);
+    flag("ignore-assets", opts.ignore_assets);
+    flag("normalize-paths", opts.normalize_paths);
+    flag("only-bots", opts.only_bots);
+    flag("strip-query", opts.strip_query);
 
-        Ok(())
+    if let Some(mode) = &opts.anonymize_ips {
+        options.push((
+            "anonymize-ips",
+            mode.as_deref().unwrap_or("truncate").to_string(),
+        ));
     }
+    if opts.ignore_assets {
+        options.push(("asset-extensions", opts.asset_extensions.clone()));
+    }
+    let values = [
+        ("asn-db", &opts.asn_db),
+        ("bot-patterns", &opts.bot_patterns),
+        ("cidr", &opts.cidr),
+        ("exclude-status", &opts.exclude_status),
+        ("geoip-db", &opts.geoip_db),
+        ("routes", &opts.routes),
+        ("sample", &opts.sample),
+        ("status", &opts.status),
+    ];
+    for (name, value) in values.iter() {
+        if let Some(v) = value {
+            options.push((name, v.clone()));
+        }
+    }
+    for (name, values) in [
+        ("exclude-ip", &opts.exclude_ip),
+        ("only-ip", &opts.only_ip),
+        ("redact", &opts.redact),
+        ("vhost", &opts.vhost),
+    ]
+    .iter()
+    {
+        options.extend(values.iter().map(|v| (*name, v.clone())));
+    }
+
+    let mut options: Vec<(String, String)> = options
+        .into_iter()
+        .map(|(n, v)| (n.to_string(), v))
+        .collect();
+    options.sort();
+    options
 }
 
-/// This represents a generic query result with column names and a row as a result.
-#[derive(Debug)]
-pub(crate) struct QueryResult {
-    columns: Vec<String>,
-    row: Vec<Value>,
+// The options as they would be given on the command line.
+fn describe_options(options: &[(String, String)]) -> String {
+    if options.is_empty() {
+        return String::from("no parsing options");
+    }
+    options
+        .iter()
+        .map(|(n, v)| {
+            if v.is_empty() {
+                format!("--{}", n)
+            } else {
+                format!("--{}={}", n, v)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
 }
 
-pub(crate) fn generate_processor(
+/// Build a query that counts the numeric values of the field falling into each bucket, where every
+/// bucket holds the values up to its upper bound and a final one holds everything above them. Empty
+/// buckets are included. With bars a column of # proportional to the largest count is added. The
+/// where clause is expected to leave out the values that are not numbers, as from `numeric_where`.
+pub fn histogram_query(
+    table: &str,
+    field: &str,
+    buckets: &[f64],
+    where_clause: &str,
+    bars: bool,
+) -> String {
+    let mut cases = String::new();
+    let mut labels = vec![];
+    for (i, bound) in buckets.iter().enumerate() {
+        cases.push_str(&format!("WHEN {} <= {} THEN {} ", field, bound, i));
+        let label = match i {
+            0 => format!("<= {}", bound),
+            _ => format!("{} - {}", buckets[i - 1], bound),
+        };
+        labels.push(format!("({}, '{}')", i, label));
+    }
+    if let Some(last) = buckets.last() {
+        labels.push(format!("({}, '> {}')", buckets.len(), last));
+    }
+
+    let bar = if bars {
+        ", replace(hex(zeroblob(CAST(40.0 * COALESCE(count, 0) / \
+        (SELECT MAX(count) FROM counts) AS INTEGER))), '00', '#') AS bar"
+    } else {
+        ""
+    };
+
+    format!(
+        "WITH buckets(i, label) AS (VALUES {labels}), \
+        counts AS (SELECT CASE {cases}ELSE {last} END AS i, COUNT(1) AS count \
+        FROM {table} {where_clause} GROUP BY i) \
+        SELECT label AS {field}, COALESCE(count, 0) AS count{bar} \
+        FROM buckets LEFT JOIN counts USING (i) ORDER BY i",
+        labels = labels.join(", "),
+        cases = cases,
+        last = buckets.len(),
+        table = table,
+        where_clause = where_clause,
+        field = field,
+        bar = bar
+    )
+}
+
+/// Find the variables a SQL expression refers to so they can be stored along with the requested
+/// fields. When the available variables are not known, any identifier that is not a keyword is
+/// assumed to be one.
+pub fn referenced_fields(expr: &str, available: Option<&[String]>) -> Vec<String> {
+    let expr = SQL_STRING_REGEX.replace_all(expr, "''");
+    let mut fields: Vec<String> = vec![];
+
+    for c in SQL_IDENTIFIER_REGEX.captures_iter(&expr) {
+        if c.get(2).is_some() {
+            continue;
+        }
+
+        let name = &c[1];
+        let known = match available {
+            Some(a) => a.iter().any(|v| v == name),
+            None => !SQL_KEYWORDS.contains(&name.to_lowercase().as_str()),
+        };
+        if known && !fields.iter().any(|f| f == name) {
+            fields.push(name.to_string());
+        }
+    }
+
+    fields
+}
+
+/// Build a processor for the options, using the default report unless fields and queries are
+/// given. Any fields the filter refers to are stored as well.
+pub fn generate_processor(
     opts: &Options,
     fields: Option<Vec<String>>,
     queries: Option<Vec<String>>,
 ) -> Result<Processor> {
-    let mut log_fields;
-    match fields {
-        Some(f) => log_fields = f,
-        None => {
-            log_fields = vec![
-                String::from(super::STATUS_TYPE),
-                String::from(super::BYTES_SENT),
-            ];
-            if !log_fields.contains(&opts.group_by) {
-                log_fields.push(opts.group_by.clone());
-            }
-        }
-    }
-
-    let default_summary_query = format!(
-        "SELECT count(1) AS count,
-AVG(bytes_sent) as avg_bytes_sent,
-COUNT(CASE WHEN status_type = 2 THEN 1 END) AS '2XX',
-COUNT(CASE WHEN status_type = 3 THEN 1 END) AS '3XX',
-COUNT(CASE WHEN status_type = 4 THEN 1 END) AS '4XX',
-COUNT(CASE WHEN status_type = 5 THEN 1 END) AS '5XX'
-FROM log
-ORDER BY {order_by} DESC
-LIMIT {limit};",
-        order_by = opts.order_by,
-        limit = opts.limit
-    );
+    let available = queryable_fields(opts)?;
+    let has = |variable: &str| {
+        available
+            .as_ref()
+            .is_some_and(|a| a.iter().any(|v| v == variable))
+    };
+    let has_request_time = has(queries::REQUEST_TIME);
+    // Multi-site servers get a breakdown per virtual host in the default report.
+    let has_vhost = !opts.error_log && has(VHOST);
 
-    let default_detailed_query = format!(
-        "SELECT {group_by},
-COUNT(1) AS count,
-AVG(bytes_sent) AS avg_bytes_sent,
-COUNT(CASE WHEN status_type = 2 THEN 1 END) AS '2XX',
-COUNT(CASE WHEN status_type = 3 THEN 1 END) AS '3XX',
-COUNT(CASE WHEN status_type = 4 THEN 1 END) AS '4XX',
-COUNT(CASE WHEN status_type = 5 THEN 1 END) AS '5XX'
-FROM log
-GROUP BY {group_by}
-HAVING {having_opt}
-ORDER BY {order_by} DESC
-LIMIT {limit};",
-        group_by = opts.group_by,
-        having_opt = opts.having,
-        order_by = opts.order_by,
-        limit = opts.limit
-    );
+    let default = fields.is_none();
+    let mut log_fields = match fields {
+        Some(f) => f,
+        None => queries::default_fields(opts, has_request_time),
+    };
+    if default && has_vhost && !log_fields.iter().any(|f| f == VHOST) {
+        log_fields.push(String::from(VHOST));
+    }
+    // The summary of --stream counts the distinct values of these rather than storing them.
+    let distinct: Vec<String> = DISTINCT_FIELDS
+        .iter()
+        .filter(|&&f| opts.stream && !opts.error_log && has(f))
+        .map(|&f| String::from(f))
+        .collect();
+    for field in &distinct {
+        if default && !log_fields.contains(field) {
+            log_fields.push(field.clone());
+        }
+    }
+    let retention = match &opts.retention {
+        Some(r) => {
+            let field = queries::time_field(opts, "--retention")?;
+            if !log_fields.iter().any(|f| f == field) {
+                log_fields.push(String::from(field));
+            }
+            Some((String::from(field), timestamp::parse_duration(r)?))
+        }
+        None => None,
+    };
+
+    if let Some(filter) = &opts.filter {
+        for field in referenced_fields(filter, available.as_deref()) {
+            if !log_fields.contains(&field) {
+                log_fields.push(field);
+            }
+        }
+    }
 
     let log_queries = match queries {
         Some(q) => q,
-        None => vec![default_summary_query, default_detailed_query],
+        None => queries::default_report(opts, has_request_time, has_vhost)?,
     };
 
-    let p = Processor::new(log_fields, log_queries)?;
-    p.initialize()?;
+    let mut processor = match &opts.db {
+        // Store everything that could be queried so later runs can ask anything of the database.
+        Some(path) => Processor::open(
+            path,
+            queries::table(opts),
+            log_fields,
+            available.unwrap_or_default(),
+            log_queries,
+            opts.output,
+        ),
+        None => Processor::new(queries::table(opts), log_fields, log_queries, opts.output),
+    }?;
+    processor.set_human(opts.human);
+    processor.set_resolve(opts.resolve);
+    processor.set_totals(opts.totals);
+    processor.set_max_records(opts.max_records);
+    processor.set_retention(retention);
+    processor.set_window(
+        opts.window
+            .as_deref()
+            .map(timestamp::parse_duration)
+            .transpose()?,
+    );
+    if opts.stream {
+        if !default || opts.error_log {
+            return Err(anyhow!(
+                "--stream only works with the default report of access logs"
+            ));
+        }
+        if opts.filter.is_some() {
+            return Err(anyhow!(
+                "--where cannot be used with --stream, use options such as --status instead"
+            ));
+        }
+        if opts.order_by != "count" {
+            return Err(anyhow!("--stream only orders by count"));
+        }
+        let min_count = opts
+            .having
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| anyhow!("--stream only takes a minimum count for --having"))?;
+        processor.set_stream(Stream::new(
+            queries::group_by(opts),
+            distinct,
+            has_request_time,
+            opts.limit as usize,
+            min_count,
+        ));
+    }
+    processor.set_timezone(Zone::from_options(opts)?)?;
+    let parsing = parsing_options(opts);
+    if processor.existing() {
+        // A report of the database can leave the options out since its records already went
+        // through them, but any that are given, or that new lines are read with, have to match.
+        let stored = processor.stored_options()?;
+        let reading = opts.state.is_some() || opts.delta;
+        if (reading || !parsing.is_empty()) && parsing != stored {
+            return Err(anyhow!(
+                "the database was parsed with {} rather than {}, give the same parsing options or \
+                delete it to start over",
+                describe_options(&stored),
+                describe_options(&parsing)
+            ));
+        }
+    } else {
+        if opts.db.is_some() {
+            processor.store_options(&parsing)?;
+        }
+        if let Some(sample) = &opts.sample {
+            processor.set_sample(sample_fraction(sample)?);
+        }
+    }
+    processor.set_output_file(opts.output_file.as_ref().map(PathBuf::from));
+    let colors = !opts.no_color
+        && opts.output_file.is_none()
+        && env::var_os("NO_COLOR").is_none()
+        && atty::is(atty::Stream::Stdout);
+    if colors {
+        processor.set_colors(Some(Thresholds {
+            errors: opts.errors_threshold,
+            slow: opts.slow_threshold,
+        }));
+    }
+    Ok(processor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
 
-    Ok(p)
+    #[test]
+    fn stored_options() {
+        let opts = Options::from_iter(&["topngx", "--vhost", "b", "--strip-query", "--vhost", "a"]);
+        let parsing = parsing_options(&opts);
+        assert_eq!(
+            describe_options(&parsing),
+            "--strip-query --vhost=a --vhost=b"
+        );
+        assert_eq!(describe_options(&[]), "no parsing options");
+
+        let p = Processor::new(
+            "log",
+            vec![String::from("status")],
+            vec![],
+            OutputFormat::Table,
+        )
+        .unwrap();
+        assert!(p.stored_options().unwrap().is_empty());
+        p.store_options(&parsing).unwrap();
+        assert_eq!(p.stored_options().unwrap(), parsing);
+    }
+
+    #[test]
+    fn most_records_kept() {
+        let mut p = Processor::new(
+            "log",
+            vec![String::from("status")],
+            vec![String::from("SELECT status FROM log ORDER BY rowid")],
+            OutputFormat::Table,
+        )
+        .unwrap();
+        p.set_max_records(Some(2));
+        let records = (1..=5)
+            .map(|s| {
+                let value: Box<dyn ToSql + Send> = Box::new(s);
+                vec![(String::from(":status"), value)]
+            })
+            .collect();
+        p.process(records).unwrap();
+        // Deleting by time can leave gaps in the rows.
+        p.query("DELETE FROM log WHERE rowid = 4").unwrap();
+
+        let statuses: Vec<Value> = p.results().unwrap()[0]
+            .rows
+            .iter()
+            .map(|r| r[0].clone())
+            .collect();
+        assert_eq!(statuses, [Value::Integer(3), Value::Integer(5)]);
+    }
+
+    #[test]
+    fn histograms() {
+        let p = Processor::new(
+            "log",
+            vec![String::from("request_time")],
+            vec![histogram_query(
+                "log",
+                "request_time",
+                &[0.1, 1.0],
+                &format!("WHERE {}", queries::is_numeric("request_time")),
+                true,
+            )],
+            OutputFormat::Table,
+        )
+        .unwrap();
+        let records = ["0.05", "0.5", "0.7", "-", "3"]
+            .iter()
+            .map(|t| {
+                let value: Box<dyn ToSql + Send> = Box::new(ColumnType::Real.value(t));
+                vec![(String::from(":request_time"), value)]
+            })
+            .collect();
+        p.process(records).unwrap();
+
+        let results = p.results().unwrap();
+        let rows: Vec<(Value, Value, Value)> = results[0]
+            .rows
+            .iter()
+            .map(|r| (r[0].clone(), r[1].clone(), r[2].clone()))
+            .collect();
+        let text = |s: &str| Value::Text(String::from(s));
+        assert_eq!(
+            rows,
+            vec![
+                (text("<= 0.1"), Value::Integer(1), text(&"#".repeat(20))),
+                (text("0.1 - 1"), Value::Integer(2), text(&"#".repeat(40))),
+                (text("> 1"), Value::Integer(1), text(&"#".repeat(20))),
+            ]
+        );
+    }
+
+    #[test]
+    fn fields_in_expressions() {
+        let available = vec![String::from("status"), String::from("request_path")];
+        assert_eq!(
+            referenced_fields(
+                "status >= 500 AND lower(request_path) LIKE '%status%' OR remote_addr = 'x'",
+                Some(&available)
+            ),
+            vec!["status", "request_path"]
+        );
+        assert_eq!(
+            referenced_fields("upstream IS NOT NULL and length(code) = 3", None),
+            vec!["upstream", "code"]
+        );
+    }
 }
@@ -0,0 +1,154 @@
+use std::cell::Cell;
+use std::io::{self, BufRead, Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// How often the progress line is redrawn.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+const BAR_WIDTH: usize = 30;
+
+/// Shows on standard error how far through a file the parse is, along with the lines read per
+/// second, so long parses do not look hung. The line is cleared once the file has been read.
+pub(crate) struct Progress {
+    name: String,
+    total: u64,
+    read: Cell<u64>,
+    lines: Cell<u64>,
+    started: Instant,
+    drawn: Cell<Option<Instant>>,
+}
+
+impl Progress {
+    /// Wrap the file, which is of the given size, and the lines decompressed from it so that
+    /// reading them updates the progress.
+    pub(crate) fn wrap<F, D>(
+        name: &str,
+        total: u64,
+        file: F,
+        decompress: D,
+    ) -> io::Result<Box<dyn BufRead>>
+    where
+        F: Read + 'static,
+        D: FnOnce(Box<dyn BufRead>) -> io::Result<Box<dyn BufRead>>,
+    {
+        let progress = Rc::new(Progress {
+            name: name.to_string(),
+            total,
+            read: Cell::new(0),
+            lines: Cell::new(0),
+            started: Instant::now(),
+            drawn: Cell::new(None),
+        });
+        let file = io::BufReader::new(CountBytes {
+            inner: file,
+            progress: Rc::clone(&progress),
+        });
+        Ok(Box::new(CountLines {
+            inner: decompress(Box::new(file))?,
+            progress,
+        }))
+    }
+
+    fn update(&self) {
+        let now = Instant::now();
+        let due = match self.drawn.get() {
+            Some(drawn) => now.duration_since(drawn) >= REDRAW_INTERVAL,
+            // Files that are read quickly never show any progress.
+            None => now.duration_since(self.started) >= REDRAW_INTERVAL,
+        };
+        if !due {
+            return;
+        }
+        self.drawn.set(Some(now));
+
+        let fraction = if self.total == 0 {
+            1.0
+        } else {
+            (self.read.get() as f64 / self.total as f64).min(1.0)
+        };
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            self.lines.get() as f64 / elapsed
+        } else {
+            0.0
+        };
+        let _ = write!(
+            io::stderr(),
+            "\r\x1b[K{} [{}{}] {:3.0}% {} lines {:.0} lines/s",
+            self.name,
+            "#".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            fraction * 100.0,
+            self.lines.get(),
+            rate
+        );
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.drawn.get().is_some() {
+            let _ = write!(io::stderr(), "\r\x1b[K");
+        }
+    }
+}
+
+// Counts the bytes read from the file, before any decompression.
+struct CountBytes<R> {
+    inner: R,
+    progress: Rc<Progress>,
+}
+
+impl<R: Read> Read for CountBytes<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.read.set(self.progress.read.get() + n as u64);
+        Ok(n)
+    }
+}
+
+// Counts the lines as they are consumed and redraws the progress.
+struct CountLines {
+    inner: Box<dyn BufRead>,
+    progress: Rc<Progress>,
+}
+
+impl Read for CountLines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl BufRead for CountLines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // The buffer is already filled so this does not read anything more.
+        if let Ok(buf) = self.inner.fill_buf() {
+            let lines = buf[..amt.min(buf.len())]
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count();
+            self.progress
+                .lines
+                .set(self.progress.lines.get() + lines as u64);
+        }
+        self.inner.consume(amt);
+        self.progress.update();
+    }
+}
+
+impl CountLines {
+    fn count(&self, bytes: &[u8]) {
+        let lines = bytes.iter().filter(|&&b| b == b'\n').count();
+        self.progress
+            .lines
+            .set(self.progress.lines.get() + lines as u64);
+        self.progress.update();
+    }
+}
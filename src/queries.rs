@@ -0,0 +1,1939 @@
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+
+use super::options::{Ban, Options};
+use super::parse::queryable_fields;
+use super::timestamp;
+use super::{
+    ERROR_TIME, MSEC, REQUEST_METHOD, REQUEST_PATH, TIMESTAMP, TIME_ISO8601, TIME_LOCAL,
+    UA_BROWSER, UA_IS_BOT, UA_OS,
+};
+
+// The names given to the columns of the reports with AS.
+static ALIAS_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\bAS '?(\w+)'?").unwrap());
+
+// Placeholders in query templates such as {limit}.
+static PLACEHOLDER_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\{([a-zA-Z_][a-zA-Z0-9_]*)\}").unwrap());
+
+/// The variable NGINX logs the request processing time in.
+pub const REQUEST_TIME: &str = "request_time";
+/// The time spent receiving the response from the upstream server.
+pub const UPSTREAM_RESPONSE_TIME: &str = "upstream_response_time";
+/// The time spent connecting to the upstream server.
+pub const UPSTREAM_CONNECT_TIME: &str = "upstream_connect_time";
+/// The time until the upstream server sent the response header.
+pub const UPSTREAM_HEADER_TIME: &str = "upstream_header_time";
+/// The address of the upstream server that handled the request.
+pub const UPSTREAM_ADDR: &str = "upstream_addr";
+/// The status of the response from the upstream server.
+pub const UPSTREAM_STATUS: &str = "upstream_status";
+
+/// The table access logs are stored in.
+pub const ACCESS_LOG_TABLE: &str = "log";
+/// The table error logs are stored in.
+pub const ERROR_LOG_TABLE: &str = "errors";
+
+// The severity of error log entries.
+const LEVEL: &str = "level";
+
+// Paths that scanners probe for, matched with LIKE so that % is a wildcard and case is ignored.
+const PROBE_PATHS: &[&str] = &[
+    "/wp-login.php%",
+    "/wp-admin%",
+    "/wp-config.php%",
+    "/xmlrpc.php%",
+    "/.env%",
+    "/.git/%",
+    "/.aws/%",
+    "/.ds_store",
+    "/phpmyadmin%",
+    "/pma/%",
+    "/myadmin/%",
+    "/admin.php%",
+    "/config.php%",
+    "/cgi-bin/%",
+    "/vendor/phpunit/%",
+    "/boaform/%",
+    "/hnap1%",
+    "/actuator/%",
+    "/server-status%",
+    "/solr/%",
+];
+
+// The protocols browsers no longer support, which $ssl_protocol logs like this.
+const DEPRECATED_TLS_PROTOCOLS: &[&str] = &["SSLv2", "SSLv3", "TLSv1", "TLSv1.1"];
+
+// The buckets of requests per connection, each with its smallest and largest size.
+const KEEPALIVE_BUCKETS: &[(&str, i64, i64)] = &[
+    ("1", 1, 1),
+    ("2", 2, 2),
+    ("3-5", 3, 5),
+    ("6-10", 6, 10),
+    ("11-100", 11, 100),
+    (">100", 101, i64::MAX),
+];
+
+// The methods that ordinary clients and APIs use.
+const COMMON_METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+/// The table the records are stored in for the kind of log being read.
+pub fn table(opts: &Options) -> &'static str {
+    if opts.error_log {
+        ERROR_LOG_TABLE
+    } else {
+        ACCESS_LOG_TABLE
+    }
+}
+
+/// The WHERE clause for the filter given on the command line, if any.
+pub fn where_clause(opts: &Options) -> String {
+    match &opts.filter {
+        Some(f) => format!("WHERE ({})", f),
+        None => String::new(),
+    }
+}
+
+/// Whether the variable was logged as a number, rather than for example "-".
+pub fn is_numeric(field: &str) -> String {
+    format!("typeof({}) IN ('integer', 'real')", field)
+}
+
+/// The WHERE clause from the filter that also leaves out the rows where the variable was not logged
+/// as a number.
+pub fn numeric_where(opts: &Options, field: &str) -> String {
+    match &opts.filter {
+        Some(f) => format!("WHERE ({}) AND {}", f, is_numeric(field)),
+        None => format!("WHERE {}", is_numeric(field)),
+    }
+}
+
+/// Fill in the placeholders of a query template. {table}, {where}, {filter}, {limit}, {having},
+/// {group_by} and {order_by} come from the options and anything else from the given parameters.
+pub fn expand_template(
+    template: &str,
+    opts: &Options,
+    params: &[(String, String)],
+) -> Result<String> {
+    let mut missing = vec![];
+    let query = PLACEHOLDER_REGEX.replace_all(template, |c: &Captures| {
+        let name = &c[1];
+        if let Some((_, value)) = params.iter().find(|(k, _)| k == name) {
+            return value.clone();
+        }
+        match name {
+            "table" => table(opts).to_string(),
+            "where" => where_clause(opts),
+            "filter" => opts.filter.clone().unwrap_or_else(|| String::from("1")),
+            "limit" => opts.limit.to_string(),
+            // Templates need not name a count column, so a minimum count is counted here.
+            "having" => match opts.having.trim().parse::<u64>() {
+                Ok(n) => format!("COUNT(1) >= {}", n),
+                Err(_) => format!("({})", opts.having),
+            },
+            "group_by" => group_by(opts).join(", "),
+            "order_by" => opts.order_by.clone(),
+            _ => {
+                missing.push(name.to_string());
+                c[0].to_string()
+            }
+        }
+    });
+
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "no value for {} in the query, pass it with --param",
+            missing.join(", ")
+        ));
+    }
+    Ok(query.into_owned())
+}
+
+/// The fields needed by the default report.
+pub fn default_fields(opts: &Options, has_request_time: bool) -> Vec<String> {
+    let mut fields = if opts.error_log {
+        vec![String::from(LEVEL)]
+    } else {
+        vec![
+            String::from(super::STATUS_TYPE),
+            String::from(super::BYTES_SENT),
+        ]
+    };
+    if has_request_time {
+        fields.push(String::from(REQUEST_TIME));
+    }
+    for variable in group_by(opts) {
+        if !fields.contains(&variable) {
+            fields.push(variable);
+        }
+    }
+    fields
+}
+
+/// The condition the groups of the reports have to meet. A number on its own is the minimum count,
+/// otherwise it is an expression over the columns of the report.
+pub fn having(opts: &Options) -> String {
+    match opts.having.trim().parse::<u64>() {
+        Ok(n) => format!("count >= {}", n),
+        Err(_) => format!("({})", opts.having),
+    }
+}
+
+/// The ORDER BY clause for --order-by, a comma separated list of columns that are each sorted in
+/// descending order unless followed by ASC. Every column has to be one of those selected.
+pub fn order_by(opts: &Options, selected: &[String]) -> Result<String> {
+    regrouped_order_by(opts, selected, &[], &[])
+}
+
+// The ORDER BY clause of a report that is grouped differently from the one --order-by was written
+// for, where each column in `from` is read as the columns in `to` that it is grouped by instead.
+fn regrouped_order_by(
+    opts: &Options,
+    selected: &[String],
+    from: &[String],
+    to: &[String],
+) -> Result<String> {
+    let mut terms = vec![];
+    for term in opts.order_by.split(',') {
+        let words: Vec<&str> = term.split_whitespace().collect();
+        let (column, direction) = match words.as_slice() {
+            [c] => (*c, "DESC"),
+            [c, d] if d.eq_ignore_ascii_case("asc") => (*c, "ASC"),
+            [c, d] if d.eq_ignore_ascii_case("desc") => (*c, "DESC"),
+            _ => {
+                return Err(anyhow!(
+                    "invalid --order-by {}, expected a column optionally followed by ASC or DESC",
+                    term.trim()
+                ))
+            }
+        };
+        let column = column.trim_matches(['\'', '"']);
+        let columns = if from.iter().any(|f| f.eq_ignore_ascii_case(column)) {
+            to.iter().map(String::as_str).collect()
+        } else {
+            vec![column]
+        };
+        for column in columns {
+            let column = selected
+                .iter()
+                .find(|s| s.eq_ignore_ascii_case(column))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "cannot order by {}, the report has the columns {}",
+                        column,
+                        selected.join(", ")
+                    )
+                })?;
+            // Quoted so that columns such as 5XX are not read as numbers.
+            terms.push(format!("\"{}\" {}", column, direction));
+        }
+    }
+    Ok(terms.join(", "))
+}
+
+// The columns of a report, the leading ones it is grouped by followed by the names given to the
+// aggregates.
+fn selected_columns(mut leading: Vec<String>, columns: &str) -> Vec<String> {
+    leading.extend(ALIAS_REGEX.captures_iter(columns).map(|c| c[1].to_string()));
+    leading
+}
+
+/// The variables to group by, which are given as a comma separated list.
+pub fn group_by(opts: &Options) -> Vec<String> {
+    opts.group_by
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// The aggregates shown for every row of the default report.
+fn summary_columns(has_request_time: bool) -> String {
+    let mut columns = String::from(
+        "COUNT(1) AS count,
+AVG(bytes_sent) AS avg_bytes_sent,\n",
+    );
+    if has_request_time {
+        columns.push_str("AVG(request_time) AS avg_request_time,\n");
+    }
+    columns.push_str(
+        "COUNT(CASE WHEN status_type = 2 THEN 1 END) AS '2XX',
+COUNT(CASE WHEN status_type = 3 THEN 1 END) AS '3XX',
+COUNT(CASE WHEN status_type = 4 THEN 1 END) AS '4XX',
+COUNT(CASE WHEN status_type = 5 THEN 1 END) AS '5XX',
+ROUND(100.0 * COUNT(CASE WHEN status_type = 4 THEN 1 END) / COUNT(1), 2) AS pct_4xx,
+ROUND(100.0 * COUNT(CASE WHEN status_type = 5 THEN 1 END) / COUNT(1), 2) AS pct_5xx",
+    );
+    columns
+}
+
+// The aggregates shown for every row of the default error log report.
+fn error_summary_columns() -> String {
+    String::from(
+        "COUNT(1) AS count,
+COUNT(CASE WHEN level IN ('emerg', 'alert', 'crit') THEN 1 END) AS critical,
+COUNT(CASE WHEN level = 'error' THEN 1 END) AS error,
+COUNT(CASE WHEN level = 'warn' THEN 1 END) AS warn,
+COUNT(CASE WHEN level IN ('notice', 'info', 'debug') THEN 1 END) AS info",
+    )
+}
+
+/// The variable holding the time of each record: $time_local when the format has it, otherwise
+/// $time_iso8601 and then $msec. The name of what needs it goes into the error when there is none.
+pub fn time_field(opts: &Options, name: &str) -> Result<&'static str> {
+    if opts.error_log {
+        return Ok(ERROR_TIME);
+    }
+    let available = queryable_fields(opts)?;
+    let has = |v: &str| available.as_ref().is_none_or(|a| a.iter().any(|f| f == v));
+    if has(TIME_LOCAL) {
+        Ok(TIME_LOCAL)
+    } else if has(TIME_ISO8601) {
+        Ok(TIME_ISO8601)
+    } else if has(MSEC) {
+        Ok(MSEC)
+    } else {
+        Err(anyhow!(
+            "{} needs $time_local, $time_iso8601 or $msec in the log format",
+            name
+        ))
+    }
+}
+
+/// Count the records in each bucket of time, oldest first, along with the bytes sent and errors for
+/// access logs or the severities for error logs. The granularity is a bucket size such as "minute"
+/// or "15m".
+pub fn timeseries_query(opts: &Options, time_field: &str, granularity: &str) -> Result<String> {
+    let seconds = timestamp::parse_granularity(granularity)?;
+    let columns = if opts.error_log {
+        error_summary_columns()
+    } else {
+        String::from(
+            "COUNT(1) AS count,
+SUM(bytes_sent) AS sum_bytes_sent,
+COUNT(CASE WHEN status_type = 4 THEN 1 END) AS '4XX',
+COUNT(CASE WHEN status_type = 5 THEN 1 END) AS '5XX'",
+        )
+    };
+    // Records whose time could not be parsed are left out rather than counted in a NULL bucket.
+    let bucket = format!("time_bucket({}, {})", time_field, seconds);
+    let where_clause = match &opts.filter {
+        Some(f) => format!("WHERE ({}) AND {} IS NOT NULL", f, bucket),
+        None => format!("WHERE {} IS NOT NULL", bucket),
+    };
+
+    Ok(format!(
+        "SELECT {bucket} AS time,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY 1
+ORDER BY 1;",
+        bucket = bucket,
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause
+    ))
+}
+
+/// How requests were served by proxy_cache going by $upstream_cache_status: the share of each cache
+/// status overall and the hit, miss, bypass and expired percentages of the top paths. Requests that
+/// did not go through the cache are left out.
+pub fn cache_report(opts: &Options) -> Result<Vec<String>> {
+    let cached = "upstream_cache_status NOT IN ('', '-')";
+    let where_clause = match &opts.filter {
+        Some(f) => format!("WHERE ({}) AND {}", f, cached),
+        None => format!("WHERE {}", cached),
+    };
+    let pct = |status: &str| {
+        format!(
+            "ROUND(100.0 * COUNT(CASE WHEN upstream_cache_status = '{status}' THEN 1 END) / COUNT(1), 2) AS pct_{name}",
+            status = status,
+            name = status.to_ascii_lowercase()
+        )
+    };
+    let columns = format!(
+        "COUNT(1) AS count,
+{},
+{},
+{},
+{}",
+        pct("HIT"),
+        pct("MISS"),
+        pct("BYPASS"),
+        pct("EXPIRED")
+    );
+
+    let summary = format!(
+        "SELECT upstream_cache_status AS cache_status,
+COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / SUM(COUNT(1)) OVER (), 2) AS percent
+FROM {table}
+{where_clause}
+GROUP BY upstream_cache_status
+ORDER BY count DESC;",
+        table = table(opts),
+        where_clause = where_clause
+    );
+    let selected = selected_columns(vec![String::from(REQUEST_PATH)], &columns);
+    let detailed = format!(
+        "SELECT request_path,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY request_path
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause,
+        having = having(opts),
+        order_by = order_by(opts, &selected)?,
+        limit = opts.limit
+    );
+
+    Ok(vec![summary, detailed])
+}
+
+/// Compare the upstream servers by their request count, percentage of 5XX responses and latency,
+/// so a bad server in the pool stands out. The status and time are the upstream's own when it is
+/// logged, otherwise those of the request. Requests that did not go to an upstream are left out.
+pub fn backends_query(
+    opts: &Options,
+    has_upstream_status: bool,
+    time_field: Option<&str>,
+) -> Result<String> {
+    let status = if has_upstream_status {
+        "upstream_status"
+    } else {
+        "status"
+    };
+    let mut columns = format!(
+        "COUNT(1) AS count,
+ROUND(100.0 * COUNT(CASE WHEN {status} >= 500 THEN 1 END) / COUNT(1), 2) AS pct_5xx",
+        status = status
+    );
+    if let Some(time) = time_field {
+        columns.push_str(&format!(
+            ",
+AVG({time}) AS avg_{time},
+p95({time}) AS p95_{time}",
+            time = time
+        ));
+    }
+    let upstream = "upstream_addr NOT IN ('', '-')";
+    let where_clause = match &opts.filter {
+        Some(f) => format!("WHERE ({}) AND {}", f, upstream),
+        None => format!("WHERE {}", upstream),
+    };
+    let selected = selected_columns(vec![String::from(UPSTREAM_ADDR)], &columns);
+
+    Ok(format!(
+        "SELECT upstream_addr,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY upstream_addr
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause,
+        having = having(opts),
+        order_by = order_by(opts, &selected)?,
+        limit = opts.limit
+    ))
+}
+
+/// Count the requests of each HTTP method with their share of the total, their 4XX and 5XX
+/// percentages and, with $request_time, their average latency.
+pub fn methods_query(opts: &Options, has_request_time: bool) -> Result<String> {
+    let mut columns = String::from(
+        "COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / SUM(COUNT(1)) OVER (), 2) AS percent,
+ROUND(100.0 * COUNT(CASE WHEN status BETWEEN 400 AND 499 THEN 1 END) / COUNT(1), 2) AS pct_4xx,
+ROUND(100.0 * COUNT(CASE WHEN status >= 500 THEN 1 END) / COUNT(1), 2) AS pct_5xx",
+    );
+    if has_request_time {
+        columns.push_str(",\nAVG(request_time) AS avg_request_time");
+    }
+    let selected = selected_columns(vec![String::from(REQUEST_METHOD)], &columns);
+
+    Ok(format!(
+        "SELECT request_method,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY request_method
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause(opts),
+        having = having(opts),
+        order_by = order_by(opts, &selected)?,
+        limit = opts.limit
+    ))
+}
+
+/// Break the time spent on the upstream into connecting to it, waiting for the response header
+/// once connected and reading the body, overall and for each upstream server when $upstream_addr
+/// is logged. The phases need $upstream_connect_time and $upstream_header_time, and are left out
+/// without them.
+pub fn upstream_report(
+    opts: &Options,
+    has_connect: bool,
+    has_header: bool,
+    has_upstream_addr: bool,
+) -> Result<Vec<String>> {
+    let where_clause = numeric_where(opts, UPSTREAM_RESPONSE_TIME);
+    let mut columns = vec![String::from("COUNT(1) AS count")];
+    if has_connect {
+        columns.push(String::from(
+            "AVG(upstream_connect_time) AS avg_connect_time",
+        ));
+    }
+    if has_header {
+        let start = if has_connect {
+            "upstream_connect_time"
+        } else {
+            "0"
+        };
+        columns.push(format!(
+            "AVG(upstream_header_time - {}) AS avg_header_time",
+            start
+        ));
+        columns.push(String::from(
+            "AVG(upstream_response_time - upstream_header_time) AS avg_body_time",
+        ));
+    }
+    columns.push(String::from(
+        "AVG(upstream_response_time) AS avg_upstream_response_time",
+    ));
+    columns.push(String::from(
+        "p95(upstream_response_time) AS p95_upstream_response_time",
+    ));
+    let columns = columns.join(",\n");
+
+    let mut queries = vec![format!(
+        "SELECT {columns}
+FROM {table}
+{where_clause};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause
+    )];
+    if has_upstream_addr {
+        let selected = selected_columns(vec![String::from(UPSTREAM_ADDR)], &columns);
+        queries.push(format!(
+            "SELECT upstream_addr,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY upstream_addr
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+            columns = columns,
+            table = table(opts),
+            where_clause = where_clause,
+            having = having(opts),
+            order_by = order_by(opts, &selected)?,
+            limit = opts.limit
+        ));
+    }
+
+    Ok(queries)
+}
+
+/// How well responses compress going by $gzip_ratio, overall and for the top paths: the percentage
+/// of responses compressed, their average ratio and an estimate of the bytes saved, which is what
+/// the compressed bodies would have been uncompressed less what was sent.
+pub fn compression_report(opts: &Options) -> Result<Vec<String>> {
+    let where_clause = where_clause(opts);
+    let compressed = format!("{} AND gzip_ratio > 0", is_numeric("gzip_ratio"));
+    let columns = format!(
+        "COUNT(1) AS count,
+ROUND(100.0 * COUNT(CASE WHEN {compressed} THEN 1 END) / COUNT(1), 2) AS pct_compressed,
+ROUND(AVG(CASE WHEN {compressed} THEN gzip_ratio END), 2) AS avg_gzip_ratio,
+SUM(bytes_sent) AS sum_bytes_sent,
+CAST(SUM(CASE WHEN {compressed} THEN bytes_sent * (gzip_ratio - 1) END) AS INTEGER) AS sum_bytes_saved",
+        compressed = compressed
+    );
+
+    let summary = format!(
+        "SELECT {columns}
+FROM {table}
+{where_clause};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause
+    );
+    let selected = selected_columns(vec![String::from(REQUEST_PATH)], &columns);
+    let detailed = format!(
+        "SELECT request_path,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY request_path
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause,
+        having = having(opts),
+        order_by = order_by(opts, &selected)?,
+        limit = opts.limit
+    );
+
+    Ok(vec![summary, detailed])
+}
+
+/// Compare the bytes received, going by $request_length, with the bytes sent, overall and for the
+/// top paths, to find upload heavy endpoints. pct_inbound is the share of the traffic received.
+pub fn sizes_report(opts: &Options) -> Result<Vec<String>> {
+    let where_clause = where_clause(opts);
+    let columns = "COUNT(1) AS count,
+CAST(AVG(request_length) AS INTEGER) AS avg_request_length,
+CAST(AVG(bytes_sent) AS INTEGER) AS avg_bytes_sent,
+SUM(request_length) AS sum_request_length,
+SUM(bytes_sent) AS sum_bytes_sent,
+ROUND(100.0 * TOTAL(request_length) / NULLIF(TOTAL(request_length) + TOTAL(bytes_sent), 0), 2) AS pct_inbound";
+
+    let summary = format!(
+        "SELECT {columns}
+FROM {table}
+{where_clause};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause
+    );
+    let selected = selected_columns(vec![String::from(REQUEST_PATH)], columns);
+    let detailed = format!(
+        "SELECT request_path,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY request_path
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause,
+        having = having(opts),
+        order_by = order_by(opts, &selected)?,
+        limit = opts.limit
+    );
+
+    Ok(vec![summary, detailed])
+}
+
+/// The requests clients gave up on before the response was sent, which nginx logs with status 499,
+/// overall and for the paths abandoned most. count is the number of aborted requests and
+/// pct_aborted their share of all the requests, and with $request_time the time they were waited on
+/// is summarized too.
+pub fn aborts_report(opts: &Options, has_request_time: bool) -> Result<Vec<String>> {
+    let where_clause = where_clause(opts);
+    let mut columns = vec![
+        String::from("COUNT(CASE WHEN status = 499 THEN 1 END) AS count"),
+        String::from(
+            "ROUND(100.0 * COUNT(CASE WHEN status = 499 THEN 1 END) / COUNT(1), 2) AS pct_aborted",
+        ),
+    ];
+    if has_request_time {
+        for (function, alias) in &[
+            ("AVG", "avg"),
+            ("p50", "p50"),
+            ("p95", "p95"),
+            ("MAX", "max"),
+        ] {
+            columns.push(format!(
+                "{}(CASE WHEN status = 499 THEN request_time END) AS {}_request_time",
+                function, alias
+            ));
+        }
+    }
+    let columns = columns.join(",\n");
+
+    let summary = format!(
+        "SELECT {columns}
+FROM {table}
+{where_clause};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause
+    );
+    let selected = selected_columns(vec![String::from(REQUEST_PATH)], &columns);
+    let detailed = format!(
+        "SELECT request_path,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY request_path
+HAVING count > 0 AND {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause,
+        having = having(opts),
+        order_by = order_by(opts, &selected)?,
+        limit = opts.limit
+    );
+
+    Ok(vec![summary, detailed])
+}
+
+/// How well keepalive works, going by $connection: the number of connections, the average requests
+/// on each and the percentage used for a single request, then how many connections served each
+/// number of requests and, with $remote_addr, the requests and connections of each client to find
+/// those opening a new connection for every request. With $connection_requests, requests made on a
+/// connection before the log started are counted too, and with $pipe the pipelined requests are.
+pub fn keepalive_report(
+    opts: &Options,
+    has_connection_requests: bool,
+    has_remote_addr: bool,
+    has_pipe: bool,
+) -> Result<Vec<String>> {
+    let known = "connection IS NOT NULL";
+    let where_clause = match &opts.filter {
+        Some(f) => format!("WHERE ({}) AND {}", f, known),
+        None => format!("WHERE {}", known),
+    };
+    let requests = if has_connection_requests {
+        "MAX(MAX(connection_requests), COUNT(1))"
+    } else {
+        "COUNT(1)"
+    };
+    // $pipe is "p" for pipelined requests and "." otherwise.
+    let pipelined = "COUNT(CASE WHEN pipe = 'p' THEN 1 END) AS pipelined";
+    let connections = format!(
+        "WITH connections AS (SELECT {requests} AS served, COUNT(1) AS logged{pipelined} FROM {table} {where_clause} GROUP BY connection)",
+        requests = requests,
+        pipelined = if has_pipe {
+            format!(", {}", pipelined)
+        } else {
+            String::new()
+        },
+        table = table(opts),
+        where_clause = where_clause
+    );
+    let buckets = KEEPALIVE_BUCKETS
+        .iter()
+        .map(|(name, min, max)| {
+            format!(
+                "WHEN served BETWEEN {min} AND {max} THEN '{name}'",
+                min = min,
+                max = max,
+                name = name
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    let mut queries = vec![
+        format!(
+            "{connections}
+SELECT COUNT(1) AS connections,
+ROUND(AVG(served), 2) AS avg_requests,
+ROUND(100.0 * COUNT(CASE WHEN served = 1 THEN 1 END) / COUNT(1), 2) AS pct_single{pipelined}
+FROM connections;",
+            connections = connections,
+            pipelined = if has_pipe {
+                ",\nROUND(100.0 * SUM(pipelined) / SUM(logged), 2) AS pct_pipelined"
+            } else {
+                ""
+            }
+        ),
+        format!(
+            "{connections}
+SELECT CASE {buckets} END AS requests,
+COUNT(1) AS connections,
+ROUND(100.0 * COUNT(1) / SUM(COUNT(1)) OVER (), 2) AS percent
+FROM connections
+GROUP BY 1
+ORDER BY MIN(served);",
+            connections = connections,
+            buckets = buckets
+        ),
+    ];
+    if has_remote_addr {
+        let mut columns = String::from(
+            "COUNT(1) AS count,
+COUNT(DISTINCT connection) AS connections,
+ROUND(1.0 * COUNT(1) / COUNT(DISTINCT connection), 2) AS requests_per_connection",
+        );
+        if has_pipe {
+            columns.push_str(",\n");
+            columns.push_str(pipelined);
+        }
+        let selected = selected_columns(vec![String::from("remote_addr")], &columns);
+        queries.push(format!(
+            "SELECT remote_addr,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY remote_addr
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+            columns = columns,
+            table = table(opts),
+            where_clause = where_clause,
+            having = having(opts),
+            order_by = order_by(opts, &selected)?,
+            limit = opts.limit
+        ));
+    }
+    Ok(queries)
+}
+
+/// The slowest paths by their 95th percentile request time, with the average, maximum and total time
+/// spent on them. Paths with fewer than the given number of requests are left out so that a single
+/// slow request does not top the list.
+pub fn slow_query(opts: &Options, min_requests: u64) -> Result<String> {
+    let columns = "COUNT(1) AS count,
+AVG(request_time) AS avg_request_time,
+p95(request_time) AS p95_request_time,
+MAX(request_time) AS max_request_time,
+SUM(request_time) AS sum_request_time";
+    let selected = selected_columns(vec![String::from(REQUEST_PATH)], columns);
+
+    Ok(format!(
+        "SELECT request_path,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY request_path
+HAVING count >= {min_requests} AND {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause(opts),
+        min_requests = min_requests,
+        having = having(opts),
+        order_by = order_by(opts, &selected)?,
+        limit = opts.limit
+    ))
+}
+
+/// The paths sending the most bytes with their share of all the bytes sent, followed by the largest
+/// individual responses. The responses show whichever of the client, status and time are logged,
+/// with the time in the chosen timezone.
+pub fn heavy_report(opts: &Options, details: &[&str]) -> Result<Vec<String>> {
+    let columns = "COUNT(1) AS count,
+SUM(bytes_sent) AS sum_bytes_sent,
+ROUND(100.0 * TOTAL(bytes_sent) / SUM(TOTAL(bytes_sent)) OVER (), 2) AS pct_bytes_sent,
+CAST(AVG(bytes_sent) AS INTEGER) AS avg_bytes_sent,
+MAX(bytes_sent) AS max_bytes_sent";
+    let selected = selected_columns(vec![String::from(REQUEST_PATH)], columns);
+    let where_clause = where_clause(opts);
+
+    let paths = format!(
+        "SELECT request_path,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY request_path
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause,
+        having = having(opts),
+        order_by = order_by(opts, &selected)?,
+        limit = opts.limit
+    );
+    let responses = format!(
+        "SELECT {details}request_path, bytes_sent
+FROM {table}
+{where_clause}
+ORDER BY bytes_sent DESC
+LIMIT {limit};",
+        details = details
+            .iter()
+            .map(|&d| match d {
+                // The timestamp is stored in UTC, and one second buckets are the time shown in the
+                // timezone.
+                TIMESTAMP => format!("time_bucket({}, 1) AS {}, ", d, d),
+                _ => format!("{}, ", d),
+            })
+            .collect::<String>(),
+        table = table(opts),
+        where_clause = where_clause,
+        limit = opts.limit
+    );
+
+    Ok(vec![paths, responses])
+}
+
+/// Count the records by hour of the day and by day of the week, Monday first, along with the errors
+/// for access logs or the severities for error logs.
+pub fn profile_report(opts: &Options, time_field: &str) -> Vec<String> {
+    let columns = if opts.error_log {
+        error_summary_columns()
+    } else {
+        String::from(
+            "COUNT(1) AS count,
+COUNT(CASE WHEN status_type = 4 THEN 1 END) AS '4XX',
+COUNT(CASE WHEN status_type = 5 THEN 1 END) AS '5XX'",
+        )
+    };
+    // A one second bucket is simply the time as it is shown, in the chosen timezone.
+    let time = format!("time_bucket({}, 1)", time_field);
+    let where_clause = match &opts.filter {
+        Some(f) => format!("WHERE ({}) AND {} IS NOT NULL", f, time),
+        None => format!("WHERE {} IS NOT NULL", time),
+    };
+    // SQLite counts the days from Sunday.
+    let day = format!("((strftime('%w', {}) + 6) % 7)", time);
+
+    vec![
+        format!(
+            "SELECT strftime('%H', {time}) AS hour,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY 1
+ORDER BY 1;",
+            time = time,
+            columns = columns,
+            table = table(opts),
+            where_clause = where_clause
+        ),
+        format!(
+            "SELECT substr('MonTueWedThuFriSatSun', 1 + 3 * {day}, 3) AS weekday,
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY {day}
+ORDER BY {day};",
+            day = day,
+            columns = columns,
+            table = table(opts),
+            where_clause = where_clause
+        ),
+    ]
+}
+
+/// The top domains referring requests from other sites, with their share of all those referrals.
+/// Referrals from the virtual host of the request, when it is logged, or from one of the given
+/// hosts are the site's own and left out. A www. in front of either side is ignored.
+pub fn referrers_query(opts: &Options, hosts: &[String], has_vhost: bool) -> Result<String> {
+    let own = |host: &str| {
+        format!(
+            "referer_domain IN ({host}, 'www.' || {host}) OR 'www.' || referer_domain = {host}",
+            host = host
+        )
+    };
+    let mut conditions = vec![String::from("referer_domain IS NOT NULL")];
+    if let Some(f) = &opts.filter {
+        conditions.push(format!("({})", f));
+    }
+    if has_vhost {
+        conditions.push(format!("(vhost IS NULL OR NOT ({}))", own("vhost")));
+    }
+    for host in hosts {
+        let host = format!("'{}'", host.to_ascii_lowercase().replace('\'', "''"));
+        conditions.push(format!("NOT ({})", own(&host)));
+    }
+    let selected = [
+        String::from("referer_domain"),
+        String::from("count"),
+        String::from("percent"),
+    ];
+
+    Ok(format!(
+        "SELECT referer_domain,
+COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / SUM(COUNT(1)) OVER (), 2) AS percent
+FROM {table}
+WHERE {conditions}
+GROUP BY referer_domain
+ORDER BY {order_by}
+LIMIT {limit};",
+        table = table(opts),
+        conditions = conditions.join(" AND "),
+        order_by = order_by(opts, &selected)?,
+        limit = opts.limit
+    ))
+}
+
+/// The top browsers and operating systems of people and the top bots, each with their share of all
+/// requests.
+pub fn useragents_report(opts: &Options) -> Result<Vec<String>> {
+    let where_clause = where_clause(opts);
+    let total = format!(
+        "(SELECT COUNT(1) FROM {table} {where_clause})",
+        table = table(opts),
+        where_clause = where_clause
+    );
+    let mut queries = vec![];
+    for (column, field, is_bot) in [
+        ("browser", UA_BROWSER, false),
+        ("os", UA_OS, false),
+        ("bot", UA_BROWSER, true),
+    ] {
+        let selected = [
+            column.to_string(),
+            String::from("count"),
+            String::from("percent"),
+        ];
+        let condition = format!("{} = {}", UA_IS_BOT, if is_bot { 1 } else { 0 });
+        queries.push(format!(
+            "SELECT {field} AS {column},
+COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / {total}, 2) AS percent
+FROM {table}
+{where_clause}
+GROUP BY {field}
+ORDER BY {order_by}
+LIMIT {limit};",
+            field = field,
+            column = column,
+            total = total,
+            table = table(opts),
+            where_clause = match &opts.filter {
+                Some(f) => format!("WHERE ({}) AND {}", f, condition),
+                None => format!("WHERE {}", condition),
+            },
+            order_by = order_by(opts, &selected)?,
+            limit = opts.limit
+        ));
+    }
+    Ok(queries)
+}
+
+/// The TLS protocols negotiated, with the share of the HTTPS requests and the clients using each
+/// and whether it is deprecated, followed by the ciphers used with each protocol when $ssl_cipher
+/// is logged. Plain HTTP requests, which log "-", are left out.
+pub fn tls_report(opts: &Options, has_cipher: bool, has_remote_addr: bool) -> Result<Vec<String>> {
+    let tls = "ssl_protocol NOT IN ('', '-')";
+    let where_clause = match &opts.filter {
+        Some(f) => format!("WHERE ({}) AND {}", f, tls),
+        None => format!("WHERE {}", tls),
+    };
+    let mut columns = String::from(
+        "COUNT(1) AS count,
+ROUND(100.0 * COUNT(1) / SUM(COUNT(1)) OVER (), 2) AS percent",
+    );
+    if has_remote_addr {
+        columns.push_str(",\nCOUNT(DISTINCT remote_addr) AS clients");
+    }
+    let deprecated = DEPRECATED_TLS_PROTOCOLS
+        .iter()
+        .map(|p| format!("'{}'", p))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let mut queries = vec![];
+    let mut groups = vec![vec![String::from("ssl_protocol")]];
+    if has_cipher {
+        groups.push(vec![
+            String::from("ssl_protocol"),
+            String::from("ssl_cipher"),
+        ]);
+    }
+    for group in groups {
+        let mut selected = selected_columns(group.clone(), &columns);
+        selected.push(String::from("deprecated"));
+        queries.push(format!(
+            "SELECT {group},
+{columns},
+CASE WHEN ssl_protocol IN ({deprecated}) THEN 'yes' ELSE 'no' END AS deprecated
+FROM {table}
+{where_clause}
+GROUP BY {group}
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+            group = group.join(", "),
+            columns = columns,
+            deprecated = deprecated,
+            table = table(opts),
+            where_clause = where_clause,
+            having = having(opts),
+            order_by = order_by(opts, &selected)?,
+            limit = opts.limit
+        ));
+    }
+    Ok(queries)
+}
+
+/// The clients with at least the given number of requests, written as rules in the format: plain
+/// addresses, fail2ban-client commands banning them in the jail, iptables commands dropping them
+/// or NGINX deny directives. With a window, the requests are counted in buckets of time and the
+/// busiest bucket of each client is what counts. Values that are not addresses are left out.
+pub fn ban_query(opts: &Options, ban: &Ban) -> Result<String> {
+    let rule = match ban.format.as_str() {
+        "plain" => String::from("remote_addr"),
+        "fail2ban" => format!(
+            "'fail2ban-client set {} banip ' || remote_addr",
+            ban.jail.replace('\'', "''")
+        ),
+        "iptables" => String::from(
+            "CASE WHEN instr(remote_addr, ':') THEN 'ip6tables' ELSE 'iptables' END \
+            || ' -A INPUT -s ' || remote_addr || ' -j DROP'",
+        ),
+        "nginx" => String::from("'deny ' || remote_addr || ';'"),
+        f => return Err(anyhow!("unknown ban list format {}", f)),
+    };
+    let where_clause = match &opts.filter {
+        Some(f) => format!("WHERE ({}) AND is_address(remote_addr)", f),
+        None => String::from("WHERE is_address(remote_addr)"),
+    };
+    let counts = match &ban.within {
+        Some(within) => format!(
+            "SELECT remote_addr, MAX(n) AS count FROM (
+SELECT remote_addr, COUNT(1) AS n FROM {table} {where_clause}
+GROUP BY remote_addr, time_bucket({time_field}, {seconds}))
+GROUP BY remote_addr",
+            table = table(opts),
+            where_clause = where_clause,
+            time_field = time_field(opts, "ban --within")?,
+            seconds = timestamp::parse_granularity(within)?
+        ),
+        None => format!(
+            "SELECT remote_addr, COUNT(1) AS count FROM {table} {where_clause}
+GROUP BY remote_addr",
+            table = table(opts),
+            where_clause = where_clause
+        ),
+    };
+
+    Ok(format!(
+        "SELECT {rule} AS {column}
+FROM ({counts})
+WHERE count >= {min_count}
+ORDER BY count DESC, remote_addr;",
+        rule = rule,
+        column = if ban.format == "plain" {
+            "remote_addr"
+        } else {
+            "rule"
+        },
+        counts = counts,
+        min_count = ban.min_count
+    ))
+}
+
+/// Signs of scanners: the clients with at least the given number of requests that were mostly
+/// refused with 403 or 404, the requests for paths that scanners probe for and the requests with
+/// unusual methods.
+pub fn security_report(opts: &Options, min_requests: u64) -> Vec<String> {
+    let filter = |condition: String| match &opts.filter {
+        Some(f) => format!("WHERE ({}) AND {}", f, condition),
+        None => format!("WHERE {}", condition),
+    };
+    let probes = PROBE_PATHS
+        .iter()
+        .map(|p| format!("request_path LIKE '{}'", p))
+        .collect::<Vec<String>>()
+        .join(" OR ");
+    let methods = COMMON_METHODS
+        .iter()
+        .map(|m| format!("'{}'", m))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    vec![
+        format!(
+            "SELECT remote_addr,
+COUNT(1) AS count,
+COUNT(CASE WHEN status IN (403, 404) THEN 1 END) AS count_denied,
+ROUND(100.0 * COUNT(CASE WHEN status IN (403, 404) THEN 1 END) / COUNT(1), 2) AS pct_denied
+FROM {table}
+{where_clause}
+GROUP BY remote_addr
+HAVING count >= {min_requests} AND pct_denied >= 50
+ORDER BY pct_denied DESC, count DESC
+LIMIT {limit};",
+            table = table(opts),
+            where_clause = where_clause(opts),
+            min_requests = min_requests,
+            limit = opts.limit
+        ),
+        format!(
+            "SELECT request_path,
+COUNT(1) AS count,
+COUNT(DISTINCT remote_addr) AS clients
+FROM {table}
+{where_clause}
+GROUP BY request_path
+ORDER BY count DESC
+LIMIT {limit};",
+            table = table(opts),
+            where_clause = filter(format!("({})", probes)),
+            limit = opts.limit
+        ),
+        format!(
+            "SELECT request_method,
+COUNT(1) AS count,
+COUNT(DISTINCT remote_addr) AS clients
+FROM {table}
+{where_clause}
+GROUP BY request_method
+ORDER BY count DESC
+LIMIT {limit};",
+            table = table(opts),
+            where_clause = filter(format!("upper(request_method) NOT IN ({})", methods)),
+            limit = opts.limit
+        ),
+    ]
+}
+
+/// Requests per second over the time between the first and last record, overall and for the top
+/// values of the group by variable. With a window, the rate over that many seconds up to now is
+/// added, for when the logs are being followed.
+pub fn rate_report(opts: &Options, time_field: &str, window: Option<u64>) -> Result<Vec<String>> {
+    let where_clause = where_clause(opts);
+    let mut columns = String::from(
+        "COUNT(1) AS count,
+last - first AS seconds,
+ROUND(COUNT(1) * 1.0 / MAX(last - first, 1), 2) AS rps",
+    );
+    if let Some(w) = window {
+        columns.push_str(&format!(
+            ",
+ROUND(COUNT(CASE WHEN unix_time({time_field}) > strftime('%s', 'now') - {window} THEN 1 END) * 1.0 / {window}, 2) AS recent_rps",
+            time_field = time_field,
+            window = w
+        ));
+    }
+    let span = format!(
+        "WITH span AS (SELECT MIN(unix_time({time_field})) AS first, MAX(unix_time({time_field})) AS last FROM {table} {where_clause})",
+        time_field = time_field,
+        table = table(opts),
+        where_clause = where_clause
+    );
+
+    let summary = format!(
+        "{span}
+SELECT {columns}
+FROM {table}, span
+{where_clause};",
+        span = span,
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause
+    );
+
+    let detailed = format!(
+        "{span}
+SELECT {group_by},
+{columns}
+FROM {table}, span
+{where_clause}
+GROUP BY {group_by}
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+        span = span,
+        group_by = group_by(opts).join(", "),
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause,
+        having = having(opts),
+        order_by = order_by(opts, &selected_columns(group_by(opts), &columns))?,
+        limit = opts.limit
+    );
+
+    Ok(vec![summary, detailed])
+}
+
+/// The Apdex score of the request times given the threshold T in seconds, overall and for the top
+/// values of the group by variable. Requests up to T are satisfied, those up to 4T are tolerating
+/// and the rest are frustrated. Requests without a numeric request time are left out.
+pub fn apdex_report(opts: &Options, threshold: f64) -> Result<Vec<String>> {
+    let where_clause = numeric_where(opts, REQUEST_TIME);
+    let columns = format!(
+        "COUNT(1) AS count,
+COUNT(CASE WHEN request_time <= {t} THEN 1 END) AS satisfied,
+COUNT(CASE WHEN request_time > {t} AND request_time <= {t4} THEN 1 END) AS tolerating,
+COUNT(CASE WHEN request_time > {t4} THEN 1 END) AS frustrated,
+ROUND((COUNT(CASE WHEN request_time <= {t} THEN 1 END) + COUNT(CASE WHEN request_time > {t} AND request_time <= {t4} THEN 1 END) / 2.0) / COUNT(1), 2) AS apdex",
+        t = threshold,
+        t4 = threshold * 4.0
+    );
+
+    let summary = format!(
+        "SELECT {columns}
+FROM {table}
+{where_clause};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause
+    );
+
+    let detailed = format!(
+        "SELECT {group_by},
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY {group_by}
+HAVING {having}
+ORDER BY {order_by}
+LIMIT {limit};",
+        group_by = group_by(opts).join(", "),
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause,
+        having = having(opts),
+        order_by = order_by(opts, &selected_columns(group_by(opts), &columns))?,
+        limit = opts.limit
+    );
+
+    Ok(vec![summary, detailed])
+}
+
+/// The report shown when no subcommand is given, modeled after ngxtop. It has an overall summary
+/// followed by the same statistics for the top values of the group by variable, and for each
+/// virtual host when the log has them.
+pub fn default_report(
+    opts: &Options,
+    has_request_time: bool,
+    has_vhost: bool,
+) -> Result<Vec<String>> {
+    let where_clause = where_clause(opts);
+    let columns = if opts.error_log {
+        error_summary_columns()
+    } else {
+        summary_columns(has_request_time)
+    };
+    // --order-by the virtual host orders the detailed report by its own group by variables, and
+    // --order-by those orders the report for each virtual host by the host.
+    let vhost = vec![String::from(super::VHOST)];
+    let per_vhost = has_vhost && !group_by(opts).contains(&vhost[0]);
+
+    let summary = format!(
+        "SELECT {columns}
+FROM {table}
+{where_clause}
+LIMIT {limit};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause,
+        limit = opts.limit
+    );
+
+    let detailed = format!(
+        "SELECT {group_by},
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY {group_by}
+HAVING {having_opt}
+ORDER BY {order_by}
+LIMIT {limit};",
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause,
+        group_by = group_by(opts).join(", "),
+        having_opt = having(opts),
+        order_by = if per_vhost {
+            regrouped_order_by(
+                opts,
+                &selected_columns(group_by(opts), &columns),
+                &vhost,
+                &group_by(opts),
+            )?
+        } else {
+            order_by(opts, &selected_columns(group_by(opts), &columns))?
+        },
+        limit = opts.limit
+    );
+
+    if !per_vhost {
+        return Ok(vec![summary, detailed]);
+    }
+    let per_vhost = format!(
+        "SELECT {vhost},
+{columns}
+FROM {table}
+{where_clause}
+GROUP BY {vhost}
+HAVING {having_opt}
+ORDER BY {order_by}
+LIMIT {limit};",
+        vhost = super::VHOST,
+        columns = columns,
+        table = table(opts),
+        where_clause = where_clause,
+        having_opt = having(opts),
+        order_by = regrouped_order_by(
+            opts,
+            &selected_columns(vhost.clone(), &columns),
+            &group_by(opts),
+            &vhost,
+        )?,
+        limit = opts.limit
+    );
+
+    Ok(vec![summary, detailed, per_vhost])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregates;
+    use crate::nginx::ColumnType;
+    use rusqlite::types::Value;
+    use rusqlite::{params, Connection};
+    use structopt::StructOpt;
+
+    // Access log records with the client, time, request and response, with fields separated by |.
+    const ACCESS: &str = "remote_addr time_local request_method request_path status status_type \
+        bytes_sent request_time";
+    const ACCESS_ROWS: &[&str] = &[
+        "10.0.0.1|01/Jan/2024:10:00:00 +0000|GET|/|200|2|100|0.1",
+        "10.0.0.1|01/Jan/2024:10:00:30 +0000|GET|/|404|4|50|0.2",
+        "10.0.0.2|01/Jan/2024:11:00:00 +0000|POST|/api|500|5|300|1.5",
+        "10.0.0.2|02/Jan/2024:11:00:00 +0000|GET|/wp-login.php|404|4|10|0.05",
+        "-|02/Jan/2024:12:00:00 +0000|PROPFIND|/api|200|2|200|0.3",
+    ];
+
+    // Proxied requests, some of them cached and compressed, and one the client gave up on.
+    const UPSTREAM: &str = "request_path status upstream_addr upstream_status \
+        upstream_response_time upstream_connect_time upstream_header_time upstream_cache_status \
+        bytes_sent gzip_ratio request_length";
+    const UPSTREAM_ROWS: &[&str] = &[
+        "/a|200|10.0.1.1:80|200|0.5|0.1|0.3|HIT|1000|2.0|100",
+        "/a|502|10.0.1.2:80|502|1.0|0.2|0.9|MISS|100|-|300",
+        "/b|200|-|-|-|-|-|-|500|-|500",
+        "/b|499|-|-|-|-|-|-|0|-|100",
+    ];
+
+    // Requests over keepalive connections and TLS from people and a bot.
+    const CLIENTS: &str = "connection connection_requests remote_addr ssl_protocol ssl_cipher \
+        referer_domain vhost ua_browser ua_os ua_is_bot";
+    const CLIENTS_ROWS: &[&str] = &[
+        "1|1|10.0.0.1|TLSv1.3|AES|google.com|example.com|Firefox|Linux|0",
+        "1|2|10.0.0.1|TLSv1.3|AES|www.example.com|example.com|Firefox|Linux|0",
+        "2|1|10.0.0.2|TLSv1|RC4|NULL|example.com|Chrome|Windows|0",
+        "3|5|10.0.0.3|-|-|NULL|example.com|Googlebot|NULL|1",
+    ];
+
+    // Run the query against a log table of the given columns and rows, where NULL is a missing
+    // value, returning the rows it selects.
+    fn run(columns: &str, rows: &[&str], query: &str) -> Vec<Vec<Value>> {
+        let columns: Vec<&str> = columns.split_whitespace().collect();
+        let conn = Connection::open_in_memory().unwrap();
+        aggregates::register(&conn).unwrap();
+        let definitions = columns
+            .iter()
+            .map(|c| format!("{} {}", c, ColumnType::of(c).sql()))
+            .collect::<Vec<String>>()
+            .join(", ");
+        conn.execute_batch(&format!("CREATE TABLE log ({});", definitions))
+            .unwrap();
+        let insert = format!(
+            "INSERT INTO log VALUES ({})",
+            vec!["?"; columns.len()].join(", ")
+        );
+        for row in rows {
+            let values: Vec<Value> = columns
+                .iter()
+                .zip(row.split('|'))
+                .map(|(c, v)| match v {
+                    "NULL" => Value::Null,
+                    _ => ColumnType::of(c).value(v),
+                })
+                .collect();
+            conn.execute(&insert, &values).unwrap();
+        }
+
+        let mut stmt = conn.prepare(query).unwrap();
+        let selected = stmt
+            .query_map(params![], |r| {
+                (0..r.column_count()).map(|i| r.get(i)).collect()
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<Vec<Value>>>>()
+            .unwrap();
+        selected
+    }
+
+    fn options(args: &[&str]) -> Options {
+        Options::from_iter(std::iter::once("topngx").chain(args.iter().copied()))
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(String::from(s))
+    }
+
+    // The first two columns of each row, usually the value and its count.
+    fn counts(rows: Vec<Vec<Value>>) -> Vec<(Value, Value)> {
+        rows.into_iter()
+            .map(|r| (r[0].clone(), r[1].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn templates() {
+        let opts = options(&["--having", "2", "--limit", "5", "--where", "status < 500"]);
+        let query = expand_template(
+            "SELECT request_path FROM {table} {where} GROUP BY {group_by} HAVING {having} \
+            ORDER BY request_path LIMIT {limit}",
+            &opts,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            "SELECT request_path FROM log WHERE (status < 500) GROUP BY request_path \
+            HAVING COUNT(1) >= 2 ORDER BY request_path LIMIT 5"
+        );
+        // The minimum count does not need a count column in the template.
+        assert_eq!(run(ACCESS, ACCESS_ROWS, &query), vec![vec![text("/")]]);
+
+        let opts = options(&["--having", "COUNT(1) > 1"]);
+        let params = [(String::from("code"), String::from("404"))];
+        assert_eq!(
+            expand_template(
+                "SELECT {filter} FROM {table} WHERE status = {code} HAVING {having}",
+                &opts,
+                &params
+            )
+            .unwrap(),
+            "SELECT 1 FROM log WHERE status = 404 HAVING (COUNT(1) > 1)"
+        );
+        let err = expand_template("SELECT {a}, {b}", &opts, &params).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "no value for a, b in the query, pass it with --param"
+        );
+    }
+
+    #[test]
+    fn clauses() {
+        assert_eq!(having(&options(&[])), "count >= 1");
+        assert_eq!(
+            having(&options(&["--having", "avg_request_time > 1"])),
+            "(avg_request_time > 1)"
+        );
+
+        let opts = options(&["--group-by", "request_path, status,"]);
+        assert_eq!(group_by(&opts), ["request_path", "status"]);
+        let selected = selected_columns(group_by(&opts), "COUNT(1) AS count,\nAVG(x) AS 'avg_x'");
+        assert_eq!(selected, ["request_path", "status", "count", "avg_x"]);
+
+        let order = |o: &str| order_by(&options(&["--order-by", o]), &selected);
+        assert_eq!(
+            order("COUNT, status asc").unwrap(),
+            "\"count\" DESC, \"status\" ASC"
+        );
+        assert_eq!(order("'avg_x' desc").unwrap(), "\"avg_x\" DESC");
+        assert_eq!(
+            order("bytes").unwrap_err().to_string(),
+            "cannot order by bytes, the report has the columns request_path, status, count, avg_x"
+        );
+        assert!(order("count sideways").is_err());
+    }
+
+    #[test]
+    fn default_reports() {
+        let opts = options(&["--order-by", "count, request_path asc"]);
+        let report = default_report(&opts, false, false).unwrap();
+        assert_eq!(report.len(), 2);
+        let summary = run(ACCESS, ACCESS_ROWS, &report[0]);
+        assert_eq!(
+            summary,
+            vec![vec![
+                Value::Integer(5),
+                Value::Real(132.0),
+                Value::Integer(2),
+                Value::Integer(0),
+                Value::Integer(2),
+                Value::Integer(1),
+                Value::Real(40.0),
+                Value::Real(20.0),
+            ]]
+        );
+        assert_eq!(
+            counts(run(ACCESS, ACCESS_ROWS, &report[1])),
+            vec![
+                (text("/"), Value::Integer(2)),
+                (text("/api"), Value::Integer(2)),
+                (text("/wp-login.php"), Value::Integer(1)),
+            ]
+        );
+
+        // A report for each virtual host unless they are grouped by already.
+        assert_eq!(default_report(&opts, true, true).unwrap().len(), 3);
+        // The hosts are ordered by the columns of their own report.
+        let report = default_report(&options(&["--order-by", "vhost asc"]), true, true).unwrap();
+        assert!(report[1].ends_with("ORDER BY \"request_path\" ASC\nLIMIT 10;"));
+        assert!(report[2].ends_with("ORDER BY \"vhost\" ASC\nLIMIT 10;"));
+        let opts = options(&["--order-by", "request_path, count asc"]);
+        let report = default_report(&opts, true, true).unwrap();
+        assert!(report[2].contains("ORDER BY \"vhost\" DESC, \"count\" ASC"));
+        assert!(default_report(&options(&["--order-by", "vhost"]), true, false).is_err());
+        let opts = options(&["--group-by", "vhost"]);
+        assert_eq!(default_report(&opts, true, true).unwrap().len(), 2);
+
+        let opts = options(&["--error-log", "--group-by", "level"]);
+        let report = default_report(&opts, false, false).unwrap();
+        let rows = run(
+            "level",
+            &["error", "crit", "error"],
+            &report[0].replace("FROM errors", "FROM log"),
+        );
+        assert_eq!(
+            rows,
+            vec![vec![
+                Value::Integer(3),
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(0),
+                Value::Integer(0),
+            ]]
+        );
+    }
+
+    #[test]
+    fn timeseries_reports() {
+        let query = timeseries_query(&options(&[]), TIME_LOCAL, "day").unwrap();
+        let series = run(ACCESS, ACCESS_ROWS, &query);
+        let per_day: Vec<&Value> = series.iter().map(|r| &r[1]).collect();
+        assert_eq!(per_day, [&Value::Integer(3), &Value::Integer(2)]);
+
+        let opts = options(&["--format", "$remote_addr $msec"]);
+        assert_eq!(time_field(&opts, "rate").unwrap(), MSEC);
+        let err = time_field(&options(&["--format", "$remote_addr"]), "rate").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "rate needs $time_local, $time_iso8601 or $msec in the log format"
+        );
+    }
+
+    #[test]
+    fn rate_reports() {
+        let rate = rate_report(&options(&[]), TIME_LOCAL, None).unwrap();
+        assert_eq!(
+            run(ACCESS, ACCESS_ROWS, &rate[0]),
+            vec![vec![
+                Value::Integer(5),
+                Value::Integer(93600),
+                Value::Real(0.0)
+            ]]
+        );
+    }
+
+    #[test]
+    fn apdex_reports() {
+        let apdex = apdex_report(&options(&[]), 0.5).unwrap();
+        assert_eq!(
+            run(ACCESS, ACCESS_ROWS, &apdex[0]),
+            vec![vec![
+                Value::Integer(5),
+                Value::Integer(4),
+                Value::Integer(1),
+                Value::Integer(0),
+                Value::Real(0.9),
+            ]]
+        );
+        assert_eq!(run(ACCESS, ACCESS_ROWS, &apdex[1]).len(), 3);
+    }
+
+    #[test]
+    fn profile_reports() {
+        let profile = profile_report(&options(&[]), TIME_LOCAL);
+        assert_eq!(
+            counts(run(ACCESS, ACCESS_ROWS, &profile[0])),
+            vec![
+                (text("10"), Value::Integer(2)),
+                (text("11"), Value::Integer(2)),
+                (text("12"), Value::Integer(1)),
+            ]
+        );
+        assert_eq!(
+            counts(run(ACCESS, ACCESS_ROWS, &profile[1])),
+            vec![
+                (text("Mon"), Value::Integer(3)),
+                (text("Tue"), Value::Integer(2))
+            ]
+        );
+    }
+
+    #[test]
+    fn referrer_reports() {
+        let opts = options(&[]);
+        // Referrals from the site itself, with or without www., are left out.
+        assert_eq!(
+            run(
+                CLIENTS,
+                CLIENTS_ROWS,
+                &referrers_query(&opts, &[], true).unwrap()
+            ),
+            vec![vec![
+                text("google.com"),
+                Value::Integer(1),
+                Value::Real(100.0)
+            ]]
+        );
+        let hosts = [String::from("Google.com")];
+        let query = referrers_query(&opts, &hosts, true).unwrap();
+        assert!(run(CLIENTS, CLIENTS_ROWS, &query).is_empty());
+    }
+
+    #[test]
+    fn useragent_reports() {
+        let useragents = useragents_report(&options(&[])).unwrap();
+        let top: Vec<Vec<Value>> = useragents
+            .iter()
+            .map(|q| run(CLIENTS, CLIENTS_ROWS, q)[0].clone())
+            .collect();
+        assert_eq!(
+            top,
+            vec![
+                vec![text("Firefox"), Value::Integer(2), Value::Real(50.0)],
+                vec![text("Linux"), Value::Integer(2), Value::Real(50.0)],
+                vec![text("Googlebot"), Value::Integer(1), Value::Real(25.0)],
+            ]
+        );
+    }
+
+    #[test]
+    fn security_reports() {
+        let security = security_report(&options(&[]), 1);
+        assert_eq!(run(ACCESS, ACCESS_ROWS, &security[0]).len(), 2);
+        assert_eq!(
+            run(ACCESS, ACCESS_ROWS, &security[1]),
+            vec![vec![
+                text("/wp-login.php"),
+                Value::Integer(1),
+                Value::Integer(1)
+            ]]
+        );
+        assert_eq!(
+            run(ACCESS, ACCESS_ROWS, &security[2]),
+            vec![vec![text("PROPFIND"), Value::Integer(1), Value::Integer(1)]]
+        );
+    }
+
+    #[test]
+    fn ban_lists() {
+        let opts = options(&[]);
+        let ban = |args: &[&str]| {
+            let ban = Ban::from_iter(std::iter::once("ban").chain(args.iter().copied()));
+            run(ACCESS, ACCESS_ROWS, &ban_query(&opts, &ban).unwrap())
+        };
+
+        // The requests that did not come over IP are left out.
+        assert_eq!(
+            ban(&["--min-count", "1"]),
+            vec![vec![text("10.0.0.1")], vec![text("10.0.0.2")]]
+        );
+        assert_eq!(
+            ban(&["--min-count", "2", "--format", "iptables"]),
+            vec![
+                vec![text("iptables -A INPUT -s 10.0.0.1 -j DROP")],
+                vec![text("iptables -A INPUT -s 10.0.0.2 -j DROP")],
+            ]
+        );
+        assert_eq!(
+            ban(&["--min-count", "2", "--within", "1h", "--format", "nginx"]),
+            vec![vec![text("deny 10.0.0.1;")]]
+        );
+    }
+
+    #[test]
+    fn cache_reports() {
+        let opts = options(&["--order-by", "count, request_path asc"]);
+        let cache = cache_report(&opts).unwrap();
+        assert_eq!(
+            run(UPSTREAM, UPSTREAM_ROWS, &cache[0]),
+            vec![
+                vec![text("HIT"), Value::Integer(1), Value::Real(50.0)],
+                vec![text("MISS"), Value::Integer(1), Value::Real(50.0)],
+            ]
+        );
+        assert_eq!(
+            run(UPSTREAM, UPSTREAM_ROWS, &cache[1]),
+            vec![vec![
+                text("/a"),
+                Value::Integer(2),
+                Value::Real(50.0),
+                Value::Real(50.0),
+                Value::Real(0.0),
+                Value::Real(0.0),
+            ]]
+        );
+    }
+
+    #[test]
+    fn upstream_reports() {
+        let phases = upstream_report(&options(&[]), true, true, true).unwrap();
+        assert_eq!(
+            run(UPSTREAM, UPSTREAM_ROWS, &phases[0])[0][0],
+            Value::Integer(2)
+        );
+        assert_eq!(run(UPSTREAM, UPSTREAM_ROWS, &phases[1]).len(), 2);
+    }
+
+    #[test]
+    fn backend_reports() {
+        let opts = options(&["--order-by", "pct_5xx"]);
+        let query = backends_query(&opts, true, Some(UPSTREAM_RESPONSE_TIME)).unwrap();
+        let backends = run(UPSTREAM, UPSTREAM_ROWS, &query);
+        assert_eq!(backends.len(), 2);
+        assert_eq!(
+            backends[0][..3],
+            [text("10.0.1.2:80"), Value::Integer(1), Value::Real(100.0)]
+        );
+    }
+
+    #[test]
+    fn compression_reports() {
+        let compression = compression_report(&options(&[])).unwrap();
+        assert_eq!(
+            run(UPSTREAM, UPSTREAM_ROWS, &compression[0]),
+            vec![vec![
+                Value::Integer(4),
+                Value::Real(25.0),
+                Value::Real(2.0),
+                Value::Integer(1600),
+                Value::Integer(1000),
+            ]]
+        );
+    }
+
+    #[test]
+    fn size_reports() {
+        let sizes = sizes_report(&options(&[])).unwrap();
+        assert_eq!(
+            run(UPSTREAM, UPSTREAM_ROWS, &sizes[0])[0][3..],
+            [
+                Value::Integer(1000),
+                Value::Integer(1600),
+                Value::Real(38.46)
+            ]
+        );
+    }
+
+    #[test]
+    fn abort_reports() {
+        let aborts = aborts_report(&options(&[]), false).unwrap();
+        assert_eq!(
+            run(UPSTREAM, UPSTREAM_ROWS, &aborts[0]),
+            vec![vec![Value::Integer(1), Value::Real(25.0)]]
+        );
+        assert_eq!(
+            run(UPSTREAM, UPSTREAM_ROWS, &aborts[1]),
+            vec![vec![text("/b"), Value::Integer(1), Value::Real(50.0)]]
+        );
+    }
+
+    #[test]
+    fn method_reports() {
+        let methods = run(
+            ACCESS,
+            ACCESS_ROWS,
+            &methods_query(&options(&[]), false).unwrap(),
+        );
+        assert_eq!(
+            methods[0],
+            vec![
+                text("GET"),
+                Value::Integer(3),
+                Value::Real(60.0),
+                Value::Real(66.67),
+                Value::Real(0.0),
+            ]
+        );
+        assert_eq!(methods.len(), 3);
+    }
+
+    #[test]
+    fn tls_reports() {
+        let tls = tls_report(&options(&[]), true, true).unwrap();
+        assert_eq!(
+            run(CLIENTS, CLIENTS_ROWS, &tls[0]),
+            vec![
+                vec![
+                    text("TLSv1.3"),
+                    Value::Integer(2),
+                    Value::Real(66.67),
+                    Value::Integer(1),
+                    text("no")
+                ],
+                vec![
+                    text("TLSv1"),
+                    Value::Integer(1),
+                    Value::Real(33.33),
+                    Value::Integer(1),
+                    text("yes")
+                ],
+            ]
+        );
+        assert_eq!(run(CLIENTS, CLIENTS_ROWS, &tls[1]).len(), 2);
+    }
+
+    #[test]
+    fn keepalive_reports() {
+        let keepalive = keepalive_report(&options(&[]), true, true, false).unwrap();
+        assert_eq!(
+            run(CLIENTS, CLIENTS_ROWS, &keepalive[0]),
+            vec![vec![
+                Value::Integer(3),
+                Value::Real(2.67),
+                Value::Real(33.33)
+            ]]
+        );
+        let buckets: Vec<Value> = run(CLIENTS, CLIENTS_ROWS, &keepalive[1])
+            .into_iter()
+            .map(|r| r[0].clone())
+            .collect();
+        assert_eq!(buckets, [text("1"), text("2"), text("3-5")]);
+        assert_eq!(run(CLIENTS, CLIENTS_ROWS, &keepalive[2]).len(), 3);
+    }
+
+    #[test]
+    fn slow_reports() {
+        let query = slow_query(&options(&[]), 2).unwrap();
+        assert_eq!(run(ACCESS, ACCESS_ROWS, &query).len(), 2);
+    }
+
+    #[test]
+    fn heavy_reports() {
+        let heavy = heavy_report(
+            &options(&["--order-by", "sum_bytes_sent"]),
+            &["remote_addr", "status"],
+        )
+        .unwrap();
+        assert_eq!(run(ACCESS, ACCESS_ROWS, &heavy[0])[0][0], text("/api"));
+
+        // --human shows the sizes in units but leaves the share of the bytes a percentage.
+        let mut result = crate::output::QueryResult {
+            columns: ["request_path", "count", "sum_bytes_sent", "pct_bytes_sent"]
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            rows: run(ACCESS, ACCESS_ROWS, &heavy[0])
+                .into_iter()
+                .map(|row| row[..4].to_vec())
+                .collect(),
+        };
+        result.humanize();
+        assert_eq!(result.rows[0][2], text("500 B"));
+        assert!(matches!(result.rows[0][3], Value::Real(_)));
+        assert_eq!(
+            run(ACCESS, ACCESS_ROWS, &heavy[1])[0],
+            vec![
+                text("10.0.0.2"),
+                Value::Integer(500),
+                text("/api"),
+                Value::Integer(300),
+            ]
+        );
+    }
+}
@@ -0,0 +1,460 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use log::debug;
+
+use topngx::output::OutputFormat;
+use topngx::processor::Processor;
+
+const PROMPT: &str = "topngx> ";
+const CONTINUATION_PROMPT: &str = "   ...> ";
+// How many statements are kept in the history file.
+const HISTORY_SIZE: usize = 1000;
+
+const SCHEMA: &str = "SELECT sql FROM sqlite_master WHERE type = 'table'";
+
+const HELP: &str = "\
+Enter SQL statements terminated with a \";\" to run them against the parsed log.
+.help      Show this message.
+.schema    Show the tables and their columns.
+.quit      Leave the shell, as does Ctrl-D.
+";
+
+/// Run statements typed at the prompt until the input ends. The prompt reads from the terminal
+/// even when the log itself came in on standard input.
+pub(crate) fn run(processor: &Processor, output: OutputFormat, log_on_stdin: bool) -> Result<()> {
+    let mut editor = if log_on_stdin {
+        let tty = File::open("/dev/tty")?;
+        let fd = tty.as_raw_fd();
+        LineEditor::new(Box::new(tty), fd, true)
+    } else {
+        let fd = io::stdin().as_raw_fd();
+        LineEditor::new(Box::new(io::stdin()), fd, atty::is(atty::Stream::Stdin))
+    };
+    if editor.interactive {
+        println!(
+            "{} records loaded. Enter \".help\" for usage hints.",
+            processor.count()?
+        );
+    }
+
+    let mut statement = Statement::default();
+    loop {
+        let prompt = if statement.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        let line = match editor.read_line(prompt)? {
+            Input::Line(l) => l,
+            Input::Interrupt => {
+                statement = Statement::default();
+                continue;
+            }
+            Input::End => break,
+        };
+
+        let trimmed = line.trim();
+        if statement.is_empty() && trimmed.starts_with('.') {
+            editor.add_history(trimmed);
+            match trimmed {
+                ".quit" | ".exit" => break,
+                ".help" => print!("{}", HELP),
+                ".schema" => print_result(processor, output, SCHEMA)?,
+                c => eprintln!(
+                    "Error: unknown command {}, enter \".help\" for usage hints",
+                    c
+                ),
+            }
+            continue;
+        }
+        for complete in statement.push(&line) {
+            editor.add_history(&complete);
+            print_result(processor, output, &complete)?;
+        }
+    }
+
+    editor.save_history()
+}
+
+// Run a statement and print what it returns. Errors in the statement are shown without leaving the
+// shell.
+fn print_result(processor: &Processor, output: OutputFormat, statement: &str) -> Result<()> {
+    debug!("shell statement: {}", statement);
+    match processor.query(statement) {
+        Ok(result) => {
+            let stdout = io::stdout();
+            result.write(output, stdout.lock())?;
+        }
+        Err(e) => eprintln!("Error: {}", e),
+    }
+    Ok(())
+}
+
+/// The lines typed so far of a statement, which ends with a ";" that is not inside a string.
+#[derive(Default)]
+struct Statement {
+    text: String,
+    // The quote of the string or identifier the text ends in, if any.
+    quote: Option<char>,
+}
+
+impl Statement {
+    fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    // Add a line, returning the statements it completes. Several can be given on one line.
+    fn push(&mut self, line: &str) -> Vec<String> {
+        let mut complete = vec![];
+        for c in line.chars() {
+            if self.text.is_empty() && c.is_whitespace() {
+                continue;
+            }
+            self.text.push(c);
+            match (self.quote, c) {
+                (None, '\'' | '"') => self.quote = Some(c),
+                // A doubled quote inside a string is the quote itself, and leaves the string
+                // and enters it again here.
+                (Some(q), c) if q == c => self.quote = None,
+                (None, ';') => {
+                    complete.push(self.text.trim().to_string());
+                    self.text.clear();
+                }
+                _ => {}
+            }
+        }
+        if !self.text.is_empty() {
+            self.text.push('\n');
+        }
+        complete
+    }
+}
+
+/// What was entered at the prompt.
+enum Input {
+    Line(String),
+    /// Ctrl-C, which abandons the statement being typed.
+    Interrupt,
+    /// Ctrl-D on an empty line or the end of the input.
+    End,
+}
+
+/// A small line editor with history. When the input is not a terminal lines are read as they are.
+struct LineEditor {
+    input: BufReader<Box<dyn Read>>,
+    // The terminal the input comes from, which is put into raw mode while reading a line.
+    fd: i32,
+    interactive: bool,
+    history: Vec<String>,
+    history_path: Option<PathBuf>,
+}
+
+impl LineEditor {
+    fn new(input: Box<dyn Read>, fd: i32, interactive: bool) -> LineEditor {
+        let history_path = history_path();
+        let history = history_path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .map(|h| h.lines().map(unescape).collect())
+            .unwrap_or_default();
+
+        LineEditor {
+            input: BufReader::new(input),
+            fd,
+            interactive,
+            history: if interactive { history } else { vec![] },
+            history_path,
+        }
+    }
+
+    fn add_history(&mut self, entry: &str) {
+        if self.interactive && self.history.last().map(String::as_str) != Some(entry) {
+            self.history.push(entry.to_string());
+        }
+    }
+
+    // Write the most recent statements back to the history file.
+    fn save_history(&self) -> Result<()> {
+        let path = match &self.history_path {
+            Some(p) if self.interactive => p,
+            _ => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let start = self.history.len().saturating_sub(HISTORY_SIZE);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        for entry in &self.history[start..] {
+            writeln!(file, "{}", escape(entry))?;
+        }
+        Ok(())
+    }
+
+    fn read_line(&mut self, prompt: &str) -> Result<Input> {
+        if !self.interactive {
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(Input::End);
+            }
+            return Ok(Input::Line(line.trim_end_matches(['\r', '\n']).to_string()));
+        }
+
+        let _raw = RawMode::enable(self.fd)?;
+        let mut line: Vec<char> = vec![];
+        let mut cursor = 0;
+        // Where we are in the history, with the line being typed at the end.
+        let mut position = self.history.len();
+        let mut typed = vec![];
+        draw(prompt, &line, cursor)?;
+
+        loop {
+            let byte = match self.read_byte()? {
+                Some(b) => b,
+                None => return Ok(Input::End),
+            };
+            match byte {
+                b'\r' | b'\n' => {
+                    print!("\r\n");
+                    io::stdout().flush()?;
+                    return Ok(Input::Line(line.into_iter().collect()));
+                }
+                // Ctrl-C
+                3 => {
+                    print!("^C\r\n");
+                    io::stdout().flush()?;
+                    return Ok(Input::Interrupt);
+                }
+                // Ctrl-D
+                4 if line.is_empty() => {
+                    print!("\r\n");
+                    io::stdout().flush()?;
+                    return Ok(Input::End);
+                }
+                4 if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                // Ctrl-A and Ctrl-E
+                1 => cursor = 0,
+                5 => cursor = line.len(),
+                // Ctrl-U
+                21 => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                // Backspace
+                8 | 127 if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                // Escape sequences for the arrow, home, end and delete keys.
+                0x1b => {
+                    if self.read_byte()? != Some(b'[') {
+                        continue;
+                    }
+                    match self.read_byte()? {
+                        Some(b'A') if position > 0 => {
+                            if position == self.history.len() {
+                                typed = line.clone();
+                            }
+                            position -= 1;
+                            line = self.history[position].chars().collect();
+                            cursor = line.len();
+                        }
+                        Some(b'B') if position < self.history.len() => {
+                            position += 1;
+                            line = match self.history.get(position) {
+                                Some(h) => h.chars().collect(),
+                                None => typed.clone(),
+                            };
+                            cursor = line.len();
+                        }
+                        Some(b'C') if cursor < line.len() => cursor += 1,
+                        Some(b'D') if cursor > 0 => cursor -= 1,
+                        Some(b'H') => cursor = 0,
+                        Some(b'F') => cursor = line.len(),
+                        // The delete key, ESC [ 3 ~.
+                        Some(b'3') if self.read_byte()? == Some(b'~') && cursor < line.len() => {
+                            line.remove(cursor);
+                        }
+                        _ => {}
+                    }
+                }
+                b if b >= 0x20 && b != 127 => {
+                    if let Some(c) = self.read_char(b)? {
+                        line.insert(cursor, c);
+                        cursor += 1;
+                    }
+                }
+                _ => {}
+            }
+            draw(prompt, &line, cursor)?;
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut byte = [0];
+        match self.input.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Read the rest of a UTF-8 encoded character that starts with the given byte.
+    fn read_char(&mut self, first: u8) -> Result<Option<char>> {
+        let len = match first {
+            b if b < 0x80 => 1,
+            b if b >> 5 == 0b110 => 2,
+            b if b >> 4 == 0b1110 => 3,
+            _ => 4,
+        };
+        let mut bytes = vec![first];
+        for _ in 1..len {
+            match self.read_byte()? {
+                Some(b) => bytes.push(b),
+                None => return Ok(None),
+            }
+        }
+        Ok(std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|s| s.chars().next()))
+    }
+}
+
+// Redraw the prompt and line, then put the cursor back where it was.
+fn draw(prompt: &str, line: &[char], cursor: usize) -> Result<()> {
+    let text: String = line.iter().collect();
+    let mut out = format!("\r{}{}\x1b[K", prompt, text);
+    if cursor < line.len() {
+        out.push_str(&format!("\x1b[{}D", line.len() - cursor));
+    }
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(out.as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Puts the terminal into raw mode so keys can be handled one at a time, restoring it when dropped.
+struct RawMode {
+    fd: i32,
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable(fd: i32) -> io::Result<RawMode> {
+        // SAFETY: tcgetattr only writes to the termios struct we hand it.
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut raw = original;
+        raw.c_iflag &= !(libc::ICRNL | libc::IXON);
+        raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: the struct was filled in by tcgetattr above.
+        if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(RawMode { fd, original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read when raw mode was enabled.
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSAFLUSH, &self.original);
+        }
+    }
+}
+
+// $XDG_DATA_HOME/topngx/history or ~/.local/share/topngx/history.
+fn history_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_DATA_HOME").filter(|d| !d.is_empty()) {
+        Some(d) => PathBuf::from(d),
+        None => PathBuf::from(env::var_os("HOME")?)
+            .join(".local")
+            .join("share"),
+    };
+    Some(base.join("topngx").join("history"))
+}
+
+// Statements can span lines, so newlines are escaped to keep one entry per line in the file.
+fn escape(entry: &str) -> String {
+    entry.replace('\\', r"\\").replace('\n', r"\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut entry = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                entry.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                entry.push('\\');
+                chars.next();
+            }
+            (c, _) => entry.push(c),
+        }
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_entries() {
+        for entry in &["SELECT 1;", "SELECT\n  'a\\nb';", "\\", "a\\\\n\n"] {
+            let escaped = escape(entry);
+            assert!(!escaped.contains('\n'));
+            assert_eq!(unescape(&escaped), *entry);
+        }
+        assert_eq!(escape("SELECT\n'\\n';"), r"SELECT\n'\\n';");
+        // A backslash that does not start an escape is kept.
+        assert_eq!(unescape(r"a\tb\"), r"a\tb\");
+    }
+
+    #[test]
+    fn statements() {
+        let mut statement = Statement::default();
+        assert!(statement.push("   ").is_empty());
+        assert!(statement.is_empty());
+
+        assert!(statement.push("SELECT status,").is_empty());
+        assert_eq!(
+            statement.push("  COUNT(1) FROM log GROUP BY 1;"),
+            ["SELECT status,\n  COUNT(1) FROM log GROUP BY 1;"]
+        );
+        assert!(statement.is_empty());
+
+        assert_eq!(
+            statement.push("SELECT 1; SELECT 2;"),
+            ["SELECT 1;", "SELECT 2;"]
+        );
+
+        // Semicolons in strings and quoted identifiers, even across lines, do not end it.
+        assert!(statement.push("SELECT 'a;b', 'it''s;").is_empty());
+        assert!(statement.push(";', \"x;\"").is_empty());
+        assert_eq!(
+            statement.push("FROM log;"),
+            ["SELECT 'a;b', 'it''s;\n;', \"x;\"\nFROM log;"]
+        );
+    }
+}
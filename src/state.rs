@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Take, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+
+use super::gzip;
+use super::json;
+use super::processor::write_atomically;
+
+// How much of the end of a log is read at a time when looking for its last complete line.
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Where a log was read up to.
+#[derive(Debug)]
+struct Position {
+    inode: u64,
+    offset: u64,
+}
+
+/// Remembers how far each log was read, so that a later run, such as the next one from cron, only
+/// reads the lines added since. Logs are recognized by their inode, so a log that was rotated to
+/// another name carries on from where it was, and one that was truncated is read from the start.
+#[derive(Debug)]
+pub struct State {
+    path: PathBuf,
+    previous: HashMap<String, Position>,
+    current: HashMap<String, Position>,
+    // Whether there was no state file, so this is the first run.
+    first: bool,
+    // Whether logs that were never read start at their end rather than their start.
+    skip_new: bool,
+}
+
+impl State {
+    /// The default location, `$XDG_CACHE_HOME/topngx/state.json` or
+    /// `~/.cache/topngx/state.json`.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+            Some(d) => PathBuf::from(d),
+            None => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base.join("topngx").join("state.json"))
+    }
+
+    /// Load the state from the file, which is empty when the file does not exist yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<State> {
+        let path = path.as_ref();
+        let mut first = false;
+        let previous = match fs::read_to_string(path) {
+            Ok(s) => parse(&s).ok_or_else(|| anyhow!("invalid state file {}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                first = true;
+                HashMap::new()
+            }
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+
+        Ok(State {
+            path: path.to_path_buf(),
+            previous,
+            current: HashMap::new(),
+            first,
+            skip_new: false,
+        })
+    }
+
+    /// Whether there was no state saved yet.
+    pub fn is_first_run(&self) -> bool {
+        self.first
+    }
+
+    /// Start the logs that were never read at their end, so that only what is added to them
+    /// later is read.
+    pub fn skip_new_logs(&mut self) {
+        self.skip_new = true;
+    }
+
+    /// Open the log at where the previous run stopped, returning it along with the number of bytes
+    /// to read. Plain logs are read up to their last complete line, leaving a line that is still
+    /// being written for the next run, and compressed logs are skipped once they have been read.
+    pub fn resume(&mut self, access_log: &str) -> Result<(Take<File>, u64)> {
+        let mut file = File::open(access_log)?;
+        let metadata = file.metadata()?;
+        let (inode, len) = (metadata.ino(), metadata.len());
+        let previous = self
+            .previous
+            .get(access_log)
+            .filter(|p| p.inode == inode)
+            .or_else(|| self.previous.values().find(|p| p.inode == inode))
+            .map_or(if self.skip_new { len } else { 0 }, |p| p.offset);
+
+        let (start, end) = if gzip::is_compressed(access_log)? {
+            if previous == len {
+                (len, len)
+            } else {
+                (0, len)
+            }
+        } else {
+            // A log that is shorter than before was truncated and written again.
+            let start = if previous <= len { previous } else { 0 };
+            (start, last_line_end(&mut file, start, len)?)
+        };
+        debug!("reading {} from byte {} to {}", access_log, start, end);
+
+        self.current
+            .insert(access_log.to_string(), Position { inode, offset: end });
+        file.seek(SeekFrom::Start(start))?;
+        Ok((file.take(end - start), end - start))
+    }
+
+    /// Save where each log read in this run stopped, keeping the logs other runs read.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+
+        // The logs this run read under another name were rotated since.
+        let mut logs: Vec<(&String, &Position)> = self
+            .previous
+            .iter()
+            .filter(|(log, p)| {
+                !self.current.contains_key(*log)
+                    && !self.current.values().any(|c| c.inode == p.inode)
+            })
+            .chain(&self.current)
+            .collect();
+        logs.sort_by(|a, b| a.0.cmp(b.0));
+        write_atomically(&self.path, |w| {
+            writeln!(w, "{{")?;
+            for (i, (log, p)) in logs.iter().enumerate() {
+                let comma = if i + 1 < logs.len() { "," } else { "" };
+                writeln!(
+                    w,
+                    "  {}: {{\"inode\": {}, \"offset\": {}}}{}",
+                    json::quote(log),
+                    p.inode,
+                    p.offset,
+                    comma
+                )?;
+            }
+            writeln!(w, "}}")?;
+            Ok(())
+        })
+    }
+}
+
+// Read the positions of the logs from the JSON object they were saved as.
+fn parse(s: &str) -> Option<HashMap<String, Position>> {
+    let mut positions = HashMap::new();
+    for (log, position) in json::parse_object(s)? {
+        let position = json::parse_object(&position)?;
+        positions.insert(
+            log,
+            Position {
+                inode: position.get("inode")?.parse().ok()?,
+                offset: position.get("offset")?.parse().ok()?,
+            },
+        );
+    }
+    Some(positions)
+}
+
+// The offset just past the last newline between start and end, or start if there is none.
+fn last_line_end(file: &mut File, start: u64, end: u64) -> io::Result<u64> {
+    let mut chunk_end = end;
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    while chunk_end > start {
+        let chunk_start = chunk_end.saturating_sub(CHUNK_SIZE).max(start);
+        let chunk = &mut buf[..(chunk_end - chunk_start) as usize];
+        file.seek(SeekFrom::Start(chunk_start))?;
+        file.read_exact(chunk)?;
+        if let Some(i) = chunk.iter().rposition(|&b| b == b'\n') {
+            return Ok(chunk_start + i as u64 + 1);
+        }
+        chunk_end = chunk_start;
+    }
+    Ok(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumes() {
+        let dir = std::env::temp_dir().join(format!("topngx-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("access.log");
+        let log_name = log.to_str().unwrap();
+        let state_file = dir.join("state").join("state.json");
+        let read = |state: &mut State| {
+            let (mut input, _) = state.resume(log_name).unwrap();
+            let mut s = String::new();
+            input.read_to_string(&mut s).unwrap();
+            s
+        };
+
+        // The line still being written is left for the next run.
+        fs::write(&log, "first\nsecond\nthi").unwrap();
+        let mut state = State::load(&state_file).unwrap();
+        assert_eq!(read(&mut state), "first\nsecond\n");
+        state.save().unwrap();
+
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&log)
+            .unwrap()
+            .write_all(b"rd\n")
+            .unwrap();
+        let mut state = State::load(&state_file).unwrap();
+        assert_eq!(read(&mut state), "third\n");
+        state.save().unwrap();
+
+        // A truncated log is read from the start.
+        fs::write(&log, "new\n").unwrap();
+        let mut state = State::load(&state_file).unwrap();
+        assert_eq!(read(&mut state), "new\n");
+
+        // Logs never read before can start at their end.
+        let mut state = State::load(dir.join("other.json")).unwrap();
+        assert!(state.is_first_run());
+        state.skip_new_logs();
+        assert_eq!(read(&mut state), "");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
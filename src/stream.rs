@@ -0,0 +1,484 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+use rusqlite::types::{ToSqlOutput, Value};
+
+use super::output::QueryResult;
+use super::processor::Record;
+use super::{BYTES_SENT, REQUEST_PATH, STATUS_TYPE};
+
+// The fewest groups kept, however low the limit, since the counts are more accurate the more of
+// them there are room for.
+const MIN_CAPACITY: usize = 1000;
+// Keeps the values of several group by variables apart in a single key.
+const KEY_SEPARATOR: char = '\u{1f}';
+// The bits of the hash that pick a register, for 4096 registers and a typical error of 1.6%.
+const PRECISION: u32 = 12;
+
+// The relative error of the percentiles of request_time.
+const RELATIVE_ACCURACY: f64 = 0.01;
+// The percentiles of request_time in the summary.
+const PERCENTILES: &[u32] = &[50, 95, 99];
+
+/// The variables whose distinct values are counted in the summary when the log has them.
+pub(crate) const DISTINCT_FIELDS: &[&str] = &["remote_addr", REQUEST_PATH];
+
+/// The most frequent keys of an endless stream in bounded memory, found with the Space-Saving
+/// algorithm. Once full, a new key replaces the least frequent one and takes over its count, so the
+/// count of a key is never less than the times it was really seen and at most its error more.
+#[derive(Debug)]
+pub(crate) struct TopK<V> {
+    capacity: usize,
+    entries: HashMap<String, Entry<V>>,
+}
+
+/// A key being counted along with whatever else is tracked for it.
+#[derive(Debug)]
+pub(crate) struct Entry<V> {
+    pub(crate) count: u64,
+    /// The most the count can be over, which is the count of the key it replaced.
+    pub(crate) error: u64,
+    /// Tracked from when the key was last added, so it covers at least count - error of them.
+    pub(crate) value: V,
+}
+
+impl<V: Default> TopK<V> {
+    pub(crate) fn new(capacity: usize) -> TopK<V> {
+        TopK {
+            capacity: capacity.max(1),
+            entries: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// Count the key once, returning its entry to update.
+    pub(crate) fn observe(&mut self, key: &str) -> &mut Entry<V> {
+        if !self.entries.contains_key(key) {
+            let mut error = 0;
+            if self.entries.len() >= self.capacity {
+                // Finding the least frequent key is linear, which is cheap next to parsing the
+                // line for the few thousand keys kept.
+                let least = self
+                    .entries
+                    .iter()
+                    .min_by(|a, b| a.1.count.cmp(&b.1.count).then_with(|| a.0.cmp(b.0)))
+                    .map(|(k, _)| k.clone());
+                if let Some(least) = least {
+                    error = self.entries.remove(&least).map_or(0, |e| e.count);
+                }
+            }
+            self.entries.insert(
+                key.to_string(),
+                Entry {
+                    count: error,
+                    error,
+                    value: V::default(),
+                },
+            );
+        }
+
+        let entry = self.entries.get_mut(key).expect("the key was just added");
+        entry.count += 1;
+        entry
+    }
+
+    /// The keys by their count, most frequent first.
+    pub(crate) fn top(&self) -> Vec<(&str, &Entry<V>)> {
+        let mut top: Vec<(&str, &Entry<V>)> =
+            self.entries.iter().map(|(k, e)| (k.as_str(), e)).collect();
+        top.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(b.0)));
+        top
+    }
+}
+
+/// An estimate of the number of distinct values seen, in a few kilobytes however many there are,
+/// with the HyperLogLog algorithm. Each value is hashed to a register that keeps the longest run
+/// of leading zeros seen in the rest of the hash, which grows with the logarithm of the values.
+#[derive(Debug)]
+pub(crate) struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; 1 << PRECISION],
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub(crate) fn insert(&mut self, value: &str) {
+        // The default hasher has fixed keys, so the same value always lands in the same register.
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let register = (hash >> (64 - PRECISION)) as usize;
+        let rest = hash << PRECISION;
+        let zeros = (rest.leading_zeros() + 1).min(64 - PRECISION + 1) as u8;
+        if zeros > self.registers[register] {
+            self.registers[register] = zeros;
+        }
+    }
+
+    pub(crate) fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&r| 2f64.powi(-i32::from(r)))
+            .sum();
+        let estimate = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+
+        // Few values leave registers empty, where counting them is more accurate.
+        let empty = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && empty > 0 {
+            (m * (m / empty as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+/// Percentiles of an endless stream of numbers within a relative error, with the DDSketch
+/// algorithm. Values are counted in buckets whose bounds grow geometrically, so the memory depends
+/// on the range of the values rather than on how many there are: a few hundred buckets cover from
+/// a millisecond to hours.
+#[derive(Debug, Default)]
+pub(crate) struct Sketch {
+    buckets: BTreeMap<i32, u64>,
+    // Zero and negative values, which have no logarithm.
+    zeros: u64,
+    count: u64,
+}
+
+impl Sketch {
+    fn gamma() -> f64 {
+        (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+    }
+
+    pub(crate) fn insert(&mut self, value: f64) {
+        self.count += 1;
+        if value > 0.0 {
+            let bucket = (value.ln() / Sketch::gamma().ln()).ceil() as i32;
+            *self.buckets.entry(bucket).or_insert(0) += 1;
+        } else {
+            self.zeros += 1;
+        }
+    }
+
+    /// The value below which the percentage of values fall, or None without any values.
+    pub(crate) fn percentile(&self, percent: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (percent / 100.0 * (self.count - 1) as f64).round() as u64;
+        let mut seen = self.zeros;
+        if rank < seen {
+            return Some(0.0);
+        }
+        for (&bucket, &n) in &self.buckets {
+            seen += n;
+            if rank < seen {
+                // The middle of the bucket is within the relative accuracy of all its values.
+                let gamma = Sketch::gamma();
+                return Some(2.0 * gamma.powi(bucket) / (gamma + 1.0));
+            }
+        }
+        None
+    }
+}
+
+// The statistics of the default report for a group of requests.
+#[derive(Debug, Default)]
+struct Summary {
+    count: u64,
+    bytes_sent: Mean,
+    request_time: Mean,
+    // The requests with a 2XX, 3XX, 4XX and 5XX status.
+    statuses: [u64; 4],
+}
+
+#[derive(Debug, Default)]
+struct Mean {
+    sum: f64,
+    count: u64,
+}
+
+impl Mean {
+    fn add(&mut self, value: Option<f64>) {
+        if let Some(v) = value {
+            self.sum += v;
+            self.count += 1;
+        }
+    }
+
+    fn value(&self) -> Value {
+        if self.count == 0 {
+            Value::Null
+        } else {
+            Value::Real(self.sum / self.count as f64)
+        }
+    }
+}
+
+impl Summary {
+    fn observe(&mut self, record: &Record, has_request_time: bool) {
+        self.count += 1;
+        self.bytes_sent.add(number(&field(record, BYTES_SENT)));
+        if has_request_time {
+            self.request_time
+                .add(number(&field(record, "request_time")));
+        }
+        if let Some(class @ 2..=5) = number(&field(record, STATUS_TYPE)).map(|s| s as usize) {
+            self.statuses[class - 2] += 1;
+        }
+    }
+
+    // The same columns as the default report, with the count given since it may be an estimate.
+    fn row(&self, count: u64, has_request_time: bool) -> Vec<Value> {
+        let mut row = vec![Value::Integer(count as i64), self.bytes_sent.value()];
+        if has_request_time {
+            row.push(self.request_time.value());
+        }
+        row.extend(self.statuses.iter().map(|&n| Value::Integer(n as i64)));
+        for &n in &self.statuses[2..] {
+            row.push(if self.count == 0 {
+                Value::Null
+            } else {
+                let pct = 100.0 * n as f64 / self.count as f64;
+                Value::Real((pct * 100.0).round() / 100.0)
+            });
+        }
+        row
+    }
+}
+
+fn summary_columns(has_request_time: bool) -> Vec<String> {
+    let mut columns = vec!["count", "avg_bytes_sent"];
+    if has_request_time {
+        columns.push("avg_request_time");
+    }
+    columns.extend(&["2XX", "3XX", "4XX", "5XX", "pct_4xx", "pct_5xx"]);
+    columns.into_iter().map(String::from).collect()
+}
+
+#[derive(Debug, Default)]
+struct Group {
+    values: Vec<Value>,
+    summary: Summary,
+}
+
+/// The default report kept as running totals and a bounded summary of the top groups instead of
+/// a table of every record, so that following a busy log uses the same memory however long it
+/// runs. The counts of groups that only became frequent later are estimates, whose most they can
+/// be over is shown as error_bound, and their other statistics cover the time since they were
+/// last added. The distinct values of some variables and the percentiles of request_time are
+/// estimated for the summary.
+#[derive(Debug)]
+pub(crate) struct Stream {
+    distinct: Vec<(String, HyperLogLog)>,
+    group_by: Vec<String>,
+    has_request_time: bool,
+    limit: usize,
+    min_count: u64,
+    request_times: Sketch,
+    total: Summary,
+    groups: TopK<Group>,
+}
+
+impl Stream {
+    pub(crate) fn new(
+        group_by: Vec<String>,
+        distinct: Vec<String>,
+        has_request_time: bool,
+        limit: usize,
+        min_count: u64,
+    ) -> Stream {
+        Stream {
+            distinct: distinct
+                .into_iter()
+                .map(|d| (d, HyperLogLog::default()))
+                .collect(),
+            group_by,
+            has_request_time,
+            limit,
+            min_count,
+            request_times: Sketch::default(),
+            total: Summary::default(),
+            groups: TopK::new(limit.saturating_mul(10).max(MIN_CAPACITY)),
+        }
+    }
+
+    pub(crate) fn observe(&mut self, record: &Record) {
+        self.total.observe(record, self.has_request_time);
+        if self.has_request_time {
+            if let Some(t) = number(&field(record, "request_time")) {
+                self.request_times.insert(t);
+            }
+        }
+        for (name, values) in &mut self.distinct {
+            match field(record, name) {
+                Value::Null => {}
+                v => values.insert(&key_part(&v)),
+            }
+        }
+
+        let values: Vec<Value> = self.group_by.iter().map(|g| field(record, g)).collect();
+        let key = values
+            .iter()
+            .map(key_part)
+            .collect::<Vec<String>>()
+            .join(&KEY_SEPARATOR.to_string());
+        let entry = self.groups.observe(&key);
+        if entry.value.summary.count == 0 {
+            entry.value.values = values;
+        }
+        entry.value.summary.observe(record, self.has_request_time);
+    }
+
+    /// The number of records seen.
+    pub(crate) fn count(&self) -> u64 {
+        self.total.count
+    }
+
+    /// The overall summary followed by the top groups, like the default report.
+    pub(crate) fn results(&self) -> Vec<QueryResult> {
+        let mut summary = QueryResult {
+            columns: summary_columns(self.has_request_time),
+            rows: vec![self.total.row(self.total.count, self.has_request_time)],
+        };
+        if self.has_request_time {
+            for &p in PERCENTILES {
+                summary.columns.push(format!("p{}_request_time", p));
+                summary.rows[0].push(
+                    self.request_times
+                        .percentile(f64::from(p))
+                        .map_or(Value::Null, |t| Value::Real((t * 1000.0).round() / 1000.0)),
+                );
+            }
+        }
+        for (name, values) in &self.distinct {
+            summary.columns.push(format!("unique_{}", name));
+            summary.rows[0].push(Value::Integer(values.estimate() as i64));
+        }
+
+        let mut columns = self.group_by.clone();
+        columns.extend(summary_columns(self.has_request_time));
+        columns.push(String::from("error_bound"));
+        let rows = self
+            .groups
+            .top()
+            .into_iter()
+            .filter(|(_, e)| e.count >= self.min_count)
+            .take(self.limit)
+            .map(|(_, e)| {
+                let mut row = e.value.values.clone();
+                row.extend(e.value.summary.row(e.count, self.has_request_time));
+                row.push(Value::Integer(e.error as i64));
+                row
+            })
+            .collect();
+
+        vec![summary, QueryResult { columns, rows }]
+    }
+}
+
+// The value of a field of the record, or NULL when it has none.
+fn field(record: &Record, name: &str) -> Value {
+    record
+        .iter()
+        .find(|(k, _)| k.strip_prefix(':') == Some(name))
+        .and_then(|(_, v)| v.to_sql().ok())
+        .map_or(Value::Null, |v| match v {
+            ToSqlOutput::Borrowed(v) => v.into(),
+            ToSqlOutput::Owned(v) => v,
+            _ => Value::Null,
+        })
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Real(r) => Some(*r),
+        Value::Text(t) => t.parse().ok(),
+        _ => None,
+    }
+}
+
+fn key_part(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(r) => r.to_string(),
+        Value::Text(t) => t.clone(),
+        Value::Blob(b) => String::from_utf8_lossy(b).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heavy_hitters() {
+        let mut top: TopK<()> = TopK::new(10);
+        // Two frequent keys among a long tail of keys seen once.
+        for i in 0..100 {
+            top.observe("/a");
+            if i % 2 == 0 {
+                top.observe("/b");
+            }
+            top.observe(&format!("/tail{}", i));
+        }
+
+        let top = top.top();
+        assert_eq!(top.len(), 10);
+        assert_eq!(top[0].0, "/a");
+        assert_eq!(top[1].0, "/b");
+        // The counts are never under and at most the error over.
+        assert!(top[0].1.count >= 100 && top[0].1.count - top[0].1.error <= 100);
+        assert!(top[1].1.count >= 50 && top[1].1.count - top[1].1.error <= 50);
+    }
+
+    #[test]
+    fn distinct_counts() {
+        let mut values = HyperLogLog::default();
+        assert_eq!(values.estimate(), 0);
+        for _ in 0..3 {
+            values.insert("/a");
+        }
+        assert_eq!(values.estimate(), 1);
+
+        for i in 0..100_000 {
+            values.insert(&format!("10.0.{}.{}", i / 256, i % 256));
+        }
+        let error = (values.estimate() as f64 - 100_001.0).abs() / 100_001.0;
+        assert!(error < 0.05, "estimate {} is off", values.estimate());
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut times = Sketch::default();
+        assert_eq!(times.percentile(95.0), None);
+
+        // A millisecond to ten seconds, along with a few requests that took no time.
+        times.insert(0.0);
+        for i in 1..=10_000 {
+            times.insert(f64::from(i) / 1000.0);
+        }
+        assert_eq!(times.percentile(0.0), Some(0.0));
+        for &(p, exact) in &[(50.0, 5.0), (95.0, 9.5), (99.0, 9.9)] {
+            let t = times.percentile(p).unwrap();
+            assert!(
+                (t - exact).abs() / exact <= RELATIVE_ACCURACY,
+                "p{} is {}",
+                p,
+                t
+            );
+        }
+        assert!(times.buckets.len() < 500);
+    }
+}
@@ -0,0 +1,474 @@
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use super::options::Options;
+
+// Where the timezone database is unless $TZDIR says otherwise.
+const ZONEINFO: &str = "/usr/share/zoneinfo";
+
+extern "C" {
+    fn tzset();
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Dates given on the command line such as "2024-01-01", "2024-01-01 13:45" or
+// "2024-01-01T13:45:10+02:00".
+static DATE_TIME_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^(\d{4})-(\d{2})-(\d{2})(?:[ T](\d{2}):(\d{2})(?::(\d{2}))?)?\s*(Z|[+-]\d{2}:?\d{2})?$",
+    )
+    .unwrap()
+});
+
+/// A point in time as logged by NGINX, keeping the UTC offset it was written with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Timestamp {
+    /// Seconds since the Unix epoch.
+    pub(crate) epoch: i64,
+    /// The offset from UTC in seconds.
+    pub(crate) offset: i32,
+}
+
+impl Timestamp {
+    /// The wall clock time in the timezone it was logged in, as seconds since the epoch.
+    pub(crate) fn local(&self) -> i64 {
+        self.epoch + i64::from(self.offset)
+    }
+
+    /// The wall clock time in the given timezone, as seconds since the epoch.
+    pub(crate) fn in_zone(&self, zone: Zone) -> i64 {
+        match zone {
+            Zone::Logged => self.local(),
+            Zone::Utc => self.epoch,
+            Zone::Named => self.epoch + i64::from(named_offset(self.epoch)),
+        }
+    }
+
+    /// The time formatted for the timestamp variable, which is always in UTC so that it can be
+    /// compared and bucketed whatever timezone times are shown in.
+    pub(crate) fn format_utc(&self) -> String {
+        format_local(self.epoch)
+    }
+}
+
+/// The timezone that times are shown and compared in.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum Zone {
+    /// Whatever offset the time was logged with.
+    #[default]
+    Logged,
+    Utc,
+    /// The zone given with --timezone, which is set as the process timezone.
+    Named,
+}
+
+impl Zone {
+    /// The zone chosen with --utc or --timezone. A named zone becomes the timezone of the whole
+    /// process, so this has to be called before any threads are started.
+    pub(crate) fn from_options(opts: &Options) -> Result<Zone> {
+        let name = match &opts.timezone {
+            _ if opts.utc => return Ok(Zone::Utc),
+            Some(name) => name,
+            None => return Ok(Zone::Logged),
+        };
+        if name.eq_ignore_ascii_case("UTC") {
+            return Ok(Zone::Utc);
+        }
+
+        // The C library quietly falls back to UTC for zones it does not know, so check first.
+        let dir = env::var_os("TZDIR").map_or_else(|| PathBuf::from(ZONEINFO), PathBuf::from);
+        if name.starts_with('/') || name.contains("..") || !dir.join(name).is_file() {
+            return Err(anyhow!(
+                "unknown timezone {}, expected a name such as Europe/Berlin",
+                name
+            ));
+        }
+        if env::var("TZ").ok().as_deref() != Some(name.as_str()) {
+            env::set_var("TZ", name);
+            // SAFETY: tzset only reads $TZ to set up the timezone that localtime_r uses.
+            unsafe { tzset() };
+        }
+        Ok(Zone::Named)
+    }
+}
+
+// The offset from UTC in seconds of the process timezone at the given time.
+fn named_offset(epoch: i64) -> i32 {
+    let time = epoch as libc::time_t;
+    // SAFETY: localtime_r only writes to the struct we hand it.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i32
+}
+
+/// One end of a time range. Without an explicit offset it is compared against the time as it was
+/// written in the log rather than an absolute instant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Bound {
+    Absolute(i64),
+    Local(i64),
+}
+
+impl Bound {
+    /// Parse a date and time such as "2024-01-01 00:00", optionally followed by a UTC offset.
+    pub(crate) fn parse(s: &str) -> Result<Bound> {
+        let c = DATE_TIME_REGEX
+            .captures(s.trim())
+            .ok_or_else(|| anyhow!("invalid date {}, expected YYYY-MM-DD [HH:MM[:SS]]", s))?;
+        let num = |i: usize| c.get(i).map_or(0, |m| m.as_str().parse::<i64>().unwrap());
+
+        let (month, day) = (num(2), num(3));
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(anyhow!("invalid date {}", s));
+        }
+        let seconds =
+            days_from_civil(num(1), month, day) * 86400 + num(4) * 3600 + num(5) * 60 + num(6);
+
+        match c.get(7).map(|m| m.as_str()) {
+            None => Ok(Bound::Local(seconds)),
+            Some("Z") => Ok(Bound::Absolute(seconds)),
+            Some(o) => Ok(Bound::Absolute(
+                seconds
+                    - i64::from(parse_offset(o).ok_or_else(|| anyhow!("invalid offset {}", o))?),
+            )),
+        }
+    }
+
+    /// Compare the timestamp against this bound, returning how the timestamp is ordered. A bound
+    /// without an offset is taken to be in the given zone.
+    pub(crate) fn cmp_timestamp(&self, t: &Timestamp, zone: Zone) -> std::cmp::Ordering {
+        match *self {
+            Bound::Absolute(b) => t.epoch.cmp(&b),
+            Bound::Local(b) => t.in_zone(zone).cmp(&b),
+        }
+    }
+}
+
+/// Parse a duration such as "90s", "5m", "2h" or "1d". A number alone is in seconds.
+pub(crate) fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => 0,
+    };
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 && seconds > 0 => Ok(Duration::from_secs(n * seconds)),
+        _ => Err(anyhow!(
+            "invalid duration {}, expected a number followed by s, m, h or d",
+            s
+        )),
+    }
+}
+
+/// Parse the `$time_local` format, for example "02/Jan/2006:15:04:05 -0700". HAProxy logs the
+/// same date with milliseconds and no UTC offset, as in "02/Jan/2006:15:04:05.000", and like the
+/// error log that time is treated as if it were UTC.
+pub(crate) fn parse_time_local(s: &str) -> Option<Timestamp> {
+    let b = s.as_bytes();
+    let nginx = b.len() == 26 && b[20] == b' ';
+    let haproxy = b.len() == 24 && b[20] == b'.';
+    if !(nginx || haproxy) || b[2] != b'/' || b[6] != b'/' || b[11] != b':' {
+        return None;
+    }
+
+    let day = s.get(0..2)?.parse().ok()?;
+    let month_name = s.get(3..6)?;
+    let month = MONTHS.iter().position(|&m| m == month_name)? as i64 + 1;
+    let year = s.get(7..11)?.parse().ok()?;
+    let hour: i64 = s.get(12..14)?.parse().ok()?;
+    let minute: i64 = s.get(15..17)?.parse().ok()?;
+    let second: i64 = s.get(18..20)?.parse().ok()?;
+    let offset = if haproxy {
+        if !b[21..].iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        0
+    } else {
+        parse_offset(s.get(21..)?)?
+    };
+
+    let local = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Some(Timestamp {
+        epoch: local - i64::from(offset),
+        offset,
+    })
+}
+
+/// Parse the `$time_iso8601` format, for example "2006-01-02T15:04:05-07:00". Fractions of a
+/// second, as in the "2006-01-02T15:04:05.123456Z" of AWS load balancer logs, are ignored.
+pub(crate) fn parse_time_iso8601(s: &str) -> Option<Timestamp> {
+    let b = s.as_bytes();
+    if b.len() < 20 || b[4] != b'-' || b[7] != b'-' || b[10] != b'T' || b[13] != b':' {
+        return None;
+    }
+
+    let year = s.get(0..4)?.parse().ok()?;
+    let month = s.get(5..7)?.parse().ok()?;
+    let day = s.get(8..10)?.parse().ok()?;
+    let hour: i64 = s.get(11..13)?.parse().ok()?;
+    let minute: i64 = s.get(14..16)?.parse().ok()?;
+    let second: i64 = s.get(17..19)?.parse().ok()?;
+    let zone = s
+        .get(19..)?
+        .trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone {
+        "Z" => 0,
+        z => parse_offset(z)?,
+    };
+
+    let local = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Some(Timestamp {
+        epoch: local - i64::from(offset),
+        offset,
+    })
+}
+
+/// Parse the time written in the error log, for example "2006/01/02 15:04:05". The error log does not
+/// include the UTC offset so the time is treated as if it were UTC.
+pub(crate) fn parse_error_time(s: &str) -> Option<Timestamp> {
+    parse_date_time(s, b'/')
+}
+
+// Parse a UTC time such as "2006/01/02 15:04:05" with the given separator between the parts of the
+// date.
+fn parse_date_time(s: &str, separator: u8) -> Option<Timestamp> {
+    let b = s.as_bytes();
+    if b.len() != 19 || b[4] != separator || b[7] != separator || b[10] != b' ' {
+        return None;
+    }
+
+    let year = s.get(0..4)?.parse().ok()?;
+    let month = s.get(5..7)?.parse().ok()?;
+    let day = s.get(8..10)?.parse().ok()?;
+    let hour: i64 = s.get(11..13)?.parse().ok()?;
+    let minute: i64 = s.get(14..16)?.parse().ok()?;
+    let second: i64 = s.get(17..19)?.parse().ok()?;
+
+    Some(Timestamp {
+        epoch: days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second,
+        offset: 0,
+    })
+}
+
+/// Parse the `$msec` format, seconds since the epoch with milliseconds such as "1591510603.186".
+/// The milliseconds are dropped and the time is taken to be in UTC since no offset is logged.
+pub(crate) fn parse_msec(s: &str) -> Option<Timestamp> {
+    from_epoch(s.parse().ok()?)
+}
+
+/// The timestamp of a number of seconds since the epoch, in UTC.
+pub(crate) fn from_epoch(seconds: f64) -> Option<Timestamp> {
+    if !seconds.is_finite() {
+        return None;
+    }
+    Some(Timestamp {
+        epoch: seconds.floor() as i64,
+        offset: 0,
+    })
+}
+
+/// Parse a time in any of the formats NGINX logs it in, or the UTC "2006-01-02 15:04:05" of the
+/// timestamp variable.
+pub(crate) fn parse_any(s: &str) -> Option<Timestamp> {
+    parse_time_local(s)
+        .or_else(|| parse_time_iso8601(s))
+        .or_else(|| parse_error_time(s))
+        .or_else(|| parse_date_time(s, b'-'))
+}
+
+/// Format seconds since the epoch as "2006-01-02 15:04:05".
+pub(crate) fn format_local(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let time = seconds.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Parse a bucket size such as "minute", "hour", "day" or "15m" into seconds.
+pub(crate) fn parse_granularity(s: &str) -> Result<i64> {
+    let invalid = || {
+        anyhow!(
+            "invalid granularity {}, expected second, minute, hour, day or a number followed by s, m, h or d",
+            s
+        )
+    };
+    let (count, unit) = match s {
+        "second" => (1, 's'),
+        "minute" => (1, 'm'),
+        "hour" => (1, 'h'),
+        "day" => (1, 'd'),
+        _ => {
+            let unit = s.chars().last().ok_or_else(invalid)?;
+            let count: i64 = s[..s.len() - unit.len_utf8()]
+                .parse()
+                .map_err(|_| invalid())?;
+            (count, unit)
+        }
+    };
+    let unit = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => return Err(invalid()),
+    };
+    if count < 1 {
+        return Err(invalid());
+    }
+    Ok(count * unit)
+}
+
+// Parse an offset like "+0200", "-07:00" into seconds.
+fn parse_offset(s: &str) -> Option<i32> {
+    let sign = match s.get(0..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let digits = s[1..].replace(':', "");
+    if digits.len() != 4 {
+        return None;
+    }
+    let hours: i32 = digits[0..2].parse().ok()?;
+    let minutes: i32 = digits[2..4].parse().ok()?;
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+// The number of days since 1970-01-01 for a date in the proleptic Gregorian calendar.
+// See http://howardhinnant.github.io/date_algorithms.html#days_from_civil.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// The inverse of days_from_civil, giving the year, month and day.
+// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_local() {
+        let t = parse_time_local("06/Jun/2020:23:16:43 -0700").unwrap();
+        assert_eq!(t.epoch, 1_591_510_603);
+        assert_eq!(t.offset, -7 * 3600);
+        assert!(parse_time_local("06/Foo/2020:23:16:43 -0700").is_none());
+        let haproxy = parse_time_local("07/Jun/2020:06:16:43.186").unwrap();
+        assert_eq!((haproxy.epoch, haproxy.offset), (1_591_510_603, 0));
+        assert!(parse_time_local("07/Jun/2020:06:16:43.1x6").is_none());
+        assert_eq!(
+            parse_error_time("2020/06/07 06:16:43").unwrap().epoch,
+            1_591_510_603
+        );
+        assert_eq!(parse_time_iso8601("2020-06-06T23:16:43-07:00"), Some(t));
+        assert_eq!(
+            parse_time_iso8601("2020-06-07T06:16:43.186641Z")
+                .unwrap()
+                .epoch,
+            1_591_510_603
+        );
+        assert!(parse_time_iso8601("2020-06-07T06:16:43").is_none());
+        assert_eq!(parse_msec("1591510603.186").unwrap().epoch, 1_591_510_603);
+        assert_eq!(parse_msec("1591510603").unwrap().offset, 0);
+        assert!(parse_msec("-").is_none());
+    }
+
+    #[test]
+    fn buckets() {
+        let t = parse_any("06/Jun/2020:23:16:43 -0700").unwrap();
+        assert_eq!(format_local(t.local()), "2020-06-06 23:16:43");
+        assert_eq!(format_local(0), "1970-01-01 00:00:00");
+        assert_eq!(format_local(951_825_600), "2000-02-29 12:00:00");
+        assert_eq!(parse_any("2020-06-07 06:16:43").unwrap().epoch, t.epoch);
+        assert_eq!(parse_granularity("minute").unwrap(), 60);
+        assert_eq!(parse_granularity("15m").unwrap(), 900);
+        assert_eq!(parse_granularity("2d").unwrap(), 172_800);
+        assert!(parse_granularity("0s").is_err());
+        assert!(parse_granularity("week").is_err());
+        assert!(parse_granularity("").is_err());
+    }
+
+    #[test]
+    fn bounds() {
+        let t = parse_time_local("06/Jun/2020:23:16:43 -0700").unwrap();
+        assert_eq!(
+            Bound::parse("2020-06-06 23:16").unwrap(),
+            Bound::Local(1_591_485_360)
+        );
+        assert_eq!(
+            Bound::parse("2020-06-06 23:16")
+                .unwrap()
+                .cmp_timestamp(&t, Zone::Logged),
+            std::cmp::Ordering::Greater
+        );
+        assert_eq!(
+            Bound::parse("2020-06-07T06:16:43Z")
+                .unwrap()
+                .cmp_timestamp(&t, Zone::Logged),
+            std::cmp::Ordering::Equal
+        );
+        assert_eq!(
+            Bound::parse("2020-06-07 08:16:43 +02:00").unwrap(),
+            Bound::Absolute(t.epoch)
+        );
+        assert_eq!(
+            Bound::parse("2020-06-07 06:16:43")
+                .unwrap()
+                .cmp_timestamp(&t, Zone::Utc),
+            std::cmp::Ordering::Equal
+        );
+        assert_eq!(t.format_utc(), "2020-06-07 06:16:43");
+        assert!(Bound::parse("yesterday").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert!(parse_duration("5 minutes").is_err());
+        assert!(parse_duration("0m").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+}
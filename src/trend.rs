@@ -0,0 +1,112 @@
+use std::collections::{HashMap, VecDeque};
+
+use rusqlite::types::Value;
+
+use super::output::QueryResult;
+
+/// The name of the column the sparklines are shown in.
+pub(crate) const TREND: &str = "trend";
+// How many refreshes each sparkline covers.
+const WIDTH: usize = 20;
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// The recent history of the counts in the report, used to draw how the rate of each group changed
+/// between refreshes.
+#[derive(Debug, Default)]
+pub(crate) struct Trends {
+    // Keyed by the query and the values of the columns before the count.
+    groups: HashMap<(usize, Vec<String>), Group>,
+}
+
+#[derive(Debug, Default)]
+struct Group {
+    last: i64,
+    changes: VecDeque<i64>,
+}
+
+impl Trends {
+    /// Record the counts of the result of the given query and add a column with a sparkline of how
+    /// much each count grew on every refresh. Results without a count column are left alone.
+    pub(crate) fn update(&mut self, query: usize, result: &mut QueryResult) {
+        let count = match result.columns.iter().position(|c| c == "count") {
+            Some(c) => c,
+            None => return,
+        };
+
+        for row in &mut result.rows {
+            let total = match row.get(count) {
+                Some(Value::Integer(n)) => *n,
+                _ => continue,
+            };
+            let key = row[..count].iter().map(key_part).collect();
+            let group = self.groups.entry((query, key)).or_insert_with(|| Group {
+                last: total,
+                changes: VecDeque::new(),
+            });
+            if group.changes.len() == WIDTH {
+                group.changes.pop_front();
+            }
+            group.changes.push_back((total - group.last).max(0));
+            group.last = total;
+
+            row.push(Value::Text(sparkline(&group.changes)));
+        }
+        result.columns.push(String::from(TREND));
+    }
+}
+
+fn key_part(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(r) => r.to_string(),
+        Value::Text(t) => t.clone(),
+        Value::Blob(b) => String::from_utf8_lossy(b).into_owned(),
+    }
+}
+
+// Scale each value against the largest so every group shows its own shape.
+fn sparkline(values: &VecDeque<i64>) -> String {
+    let max = values.iter().copied().max().unwrap_or(0);
+    values
+        .iter()
+        .map(|&v| match max {
+            0 => BARS[0],
+            m => BARS[(v * (BARS.len() as i64 - 1) / m) as usize],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparklines() {
+        let mut trends = Trends::default();
+        let mut trend = |counts: &[i64]| {
+            let mut result = QueryResult {
+                columns: vec![String::from("request_path"), String::from("count")],
+                rows: counts
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &c)| vec![Value::Text(format!("/{}", i)), Value::Integer(c)])
+                    .collect(),
+            };
+            trends.update(1, &mut result);
+            assert_eq!(result.columns[2], TREND);
+            result
+                .rows
+                .into_iter()
+                .map(|r| match &r[2] {
+                    Value::Text(t) => t.clone(),
+                    v => panic!("unexpected {:?}", v),
+                })
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(trend(&[10, 5]), vec!["▁", "▁"]);
+        assert_eq!(trend(&[17, 6]), vec!["▁█", "▁█"]);
+        assert_eq!(trend(&[31, 6]), vec!["▁▄█", "▁█▁"]);
+    }
+}
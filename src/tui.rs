@@ -0,0 +1,57 @@
+use std::io::{self, Write};
+use std::time::Instant;
+
+use anyhow::Result;
+
+use topngx::processor::Processor;
+
+// ANSI sequences to move the cursor to the top left and clear the screen.
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+/// A minimal full screen view that redraws the report in place on every refresh.
+pub(crate) struct Screen {
+    access_log: String,
+    interval: u64,
+    started: Instant,
+}
+
+impl Screen {
+    pub(crate) fn new(access_log: &str, interval: u64) -> Screen {
+        Screen {
+            access_log: access_log.to_string(),
+            interval,
+            started: Instant::now(),
+        }
+    }
+
+    /// Render the report off screen first so the terminal never shows a half drawn frame.
+    pub(crate) fn draw(&self, processor: &Processor, unparsed: usize) -> Result<()> {
+        let mut frame = Vec::new();
+        let elapsed = self.started.elapsed().as_secs();
+        let unparsed = match unparsed {
+            0 => String::new(),
+            n => format!(" ({} lines unparsed)", n),
+        };
+        writeln!(
+            &mut frame,
+            "topngx - {} - {} requests{} in {:02}:{:02}:{:02} - refreshing every {}s",
+            self.access_log,
+            processor.count()?,
+            unparsed,
+            elapsed / 3600,
+            elapsed / 60 % 60,
+            elapsed % 60,
+            self.interval
+        )?;
+        writeln!(&mut frame)?;
+        processor.report_to(&mut frame)?;
+
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        out.write_all(CLEAR_SCREEN.as_bytes())?;
+        out.write_all(&frame)?;
+        out.flush()?;
+
+        Ok(())
+    }
+}
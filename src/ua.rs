@@ -0,0 +1,205 @@
+/// The client family a User-Agent header belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserAgent {
+    /// The browser, or the name of the bot or tool when it is not one.
+    pub browser: String,
+    /// The operating system.
+    pub os: String,
+    /// Whether the client is a crawler, monitoring service or command line tool.
+    pub is_bot: bool,
+}
+
+const OTHER: &str = "Other";
+
+// Well known crawlers and tools, matched case insensitively in order.
+const BOTS: &[(&str, &str)] = &[
+    ("googlebot", "Googlebot"),
+    ("bingbot", "Bingbot"),
+    ("yandexbot", "YandexBot"),
+    ("baiduspider", "Baiduspider"),
+    ("duckduckbot", "DuckDuckBot"),
+    ("applebot", "Applebot"),
+    ("facebookexternalhit", "Facebook"),
+    ("twitterbot", "Twitterbot"),
+    ("ahrefsbot", "AhrefsBot"),
+    ("semrushbot", "SemrushBot"),
+    ("mj12bot", "MJ12bot"),
+    ("petalbot", "PetalBot"),
+    ("gptbot", "GPTBot"),
+    ("curl/", "curl"),
+    ("wget/", "Wget"),
+    ("python-requests", "python-requests"),
+    ("python-urllib", "Python-urllib"),
+    ("go-http-client", "Go-http-client"),
+    ("headlesschrome", "HeadlessChrome"),
+];
+
+// Words that give away an automated client that is not in the list above, when they start a word.
+const BOT_WORDS: &[&str] = &[
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "scan",
+    "monitor",
+    "http-client",
+];
+
+// Browsers in the order they have to be checked in, since most of them also claim to be the ones
+// after them.
+const BROWSERS: &[(&str, &str)] = &[
+    ("edg/", "Edge"),
+    ("edge/", "Edge"),
+    ("edgios/", "Edge"),
+    ("opr/", "Opera"),
+    ("opera", "Opera"),
+    ("samsungbrowser/", "Samsung Internet"),
+    ("yabrowser/", "Yandex Browser"),
+    ("ucbrowser/", "UC Browser"),
+    ("vivaldi/", "Vivaldi"),
+    ("firefox/", "Firefox"),
+    ("fxios/", "Firefox"),
+    ("crios/", "Chrome"),
+    ("chromium/", "Chromium"),
+    ("chrome/", "Chrome"),
+    ("msie ", "Internet Explorer"),
+    ("trident/", "Internet Explorer"),
+];
+
+const OPERATING_SYSTEMS: &[(&str, &str)] = &[
+    ("windows phone", "Windows Phone"),
+    ("windows", "Windows"),
+    ("iphone", "iOS"),
+    ("ipad", "iOS"),
+    ("ipod", "iOS"),
+    ("android", "Android"),
+    ("cros ", "Chrome OS"),
+    ("mac os x", "macOS"),
+    ("macintosh", "macOS"),
+    ("linux", "Linux"),
+    ("freebsd", "FreeBSD"),
+];
+
+/// Whether the User-Agent is a crawler, monitoring service or command line tool, without working out
+/// the rest.
+pub fn is_bot(user_agent: &str) -> bool {
+    let ua = user_agent.to_ascii_lowercase();
+    BOTS.iter().any(|(needle, _)| ua.contains(needle)) || has_bot_word(user_agent, &ua)
+}
+
+// Whether one of the bot words starts a word of the header, given as it is and in lowercase. A
+// word starts after anything but a letter or digit, or with a capital as the Bot of "ExampleBot",
+// so that the bot of a Cubot phone is not taken for one.
+fn has_bot_word(user_agent: &str, ua: &str) -> bool {
+    BOT_WORDS.iter().any(|w| {
+        ua.match_indices(w).any(|(i, _)| {
+            !matches!(ua[..i].chars().next_back(), Some(c) if c.is_alphanumeric())
+                || user_agent.as_bytes()[i].is_ascii_uppercase()
+        })
+    })
+}
+
+/// Work out the browser, operating system and whether it is a bot from a User-Agent header.
+pub fn parse(user_agent: &str) -> UserAgent {
+    let ua = user_agent.to_ascii_lowercase();
+    let find = |list: &[(&str, &str)]| {
+        list.iter()
+            .find(|(needle, _)| ua.contains(needle))
+            .map(|(_, name)| name.to_string())
+    };
+    let os = find(OPERATING_SYSTEMS).unwrap_or_else(|| String::from(OTHER));
+
+    if let Some(bot) = find(BOTS) {
+        return UserAgent {
+            browser: bot,
+            os,
+            is_bot: true,
+        };
+    }
+    if has_bot_word(user_agent, &ua) {
+        // Use the product name, such as "ExampleCrawler" from "ExampleCrawler/1.0 (+http://...)".
+        let product = user_agent
+            .split(['/', ' ', ';'])
+            .find(|p| !p.is_empty() && *p != "Mozilla")
+            .unwrap_or(OTHER);
+        return UserAgent {
+            browser: product.to_string(),
+            os,
+            is_bot: true,
+        };
+    }
+
+    let browser = find(BROWSERS).unwrap_or_else(|| {
+        if ua.contains("safari/") && ua.contains("version/") {
+            String::from("Safari")
+        } else {
+            String::from(OTHER)
+        }
+    });
+    UserAgent {
+        browser,
+        os,
+        is_bot: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn family(ua: &str) -> (String, String, bool) {
+        let parsed = parse(ua);
+        (parsed.browser, parsed.os, parsed.is_bot)
+    }
+
+    #[test]
+    fn user_agents() {
+        let cases = vec![
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+                Chrome/120.0.0.0 Safari/537.36 Edg/120.0.0.0",
+                ("Edge", "Windows", false),
+            ),
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_1 like Mac OS X) AppleWebKit/605.1.15 \
+                (KHTML, like Gecko) Version/17.1 Mobile/15E148 Safari/604.1",
+                ("Safari", "iOS", false),
+            ),
+            (
+                "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+                ("Firefox", "Linux", false),
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 13; Pixel 7) AppleWebKit/537.36 (KHTML, like Gecko) \
+                Chrome/120.0.0.0 Mobile Safari/537.36",
+                ("Chrome", "Android", false),
+            ),
+            (
+                "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+                ("Googlebot", "Other", true),
+            ),
+            ("curl/7.54.0", ("curl", "Other", true)),
+            (
+                "ExampleCrawler/1.0 (+http://example.com)",
+                ("ExampleCrawler", "Other", true),
+            ),
+            ("uptime-monitor/2.0", ("uptime-monitor", "Other", true)),
+            (
+                "Mozilla/5.0 (Linux; Android 10; Cubot X19) AppleWebKit/537.36 (KHTML, like Gecko) \
+                Chrome/88.0.4324.181 Mobile Safari/537.36",
+                ("Chrome", "Android", false),
+            ),
+            ("-", ("Other", "Other", false)),
+        ];
+
+        for (ua, (browser, os, bot)) in cases {
+            assert_eq!(
+                family(ua),
+                (browser.to_string(), os.to_string(), bot),
+                "{}",
+                ua
+            );
+            assert_eq!(is_bot(ua), bot, "{}", ua);
+        }
+    }
+}